@@ -0,0 +1,130 @@
+//! A module that exposes parsing of merged-mining (AuxPoW) block headers. This
+//! is only available with the `auxpow` feature.
+//!
+//! Chains that support merged mining (such as Namecoin) allow the proof-of-work
+//! for a block to be done on a block of a parent chain instead. Such blocks set
+//! a flag in the header version and have the auxiliary proof-of-work data
+//! serialized after the standard 80-byte header, which changes the framing of
+//! the block. Bitcoin itself never uses this format, so mainnet blocks should
+//! always be parsed with the regular functions in the `parse` module.
+
+use crate::{BlockHeader, BlockParseError, Hash, LittleEndianSerialization, Transaction};
+
+/// The bit in the header version that indicates the header is followed by
+/// auxiliary proof-of-work data.
+pub const VERSION_AUXPOW: u32 = 1 << 8;
+
+/// The auxiliary proof-of-work data that follows a merged-mined block header. It
+/// proves that the hash of the block was committed to in the coinbase of a parent
+/// chain block, whose header has the actual proof-of-work.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuxPow {
+    /// The coinbase transaction of the parent block, which contains the
+    /// commitment to the merged-mined chain.
+    pub coinbase: Transaction,
+    /// The hash of the parent block. This is unused by the protocol, since the
+    /// parent header is included in full.
+    pub parent_hash: Hash,
+    /// The merkle branch linking the coinbase to the merkle root of the parent
+    /// block.
+    pub coinbase_branch: Vec<Hash>,
+    /// The index of the coinbase in the parent block's merkle tree, which
+    /// determines the side of each branch hash. This is always zero.
+    pub coinbase_index: u32,
+    /// The merkle branch linking the block to the root of the merged-mining
+    /// tree committed to in the coinbase, when several chains are merge-mined.
+    pub chain_branch: Vec<Hash>,
+    /// The index of the block in the merged-mining tree.
+    pub chain_index: u32,
+    /// The header of the parent block, whose hash must meet the target of the
+    /// merged-mined block.
+    pub parent_header: BlockHeader,
+}
+
+fn read_branch(bytes: &[u8], ix: &mut usize) -> Result<(Vec<Hash>, u32), BlockParseError> {
+    let count = usize::deserialize_le(bytes, ix)?;
+    let mut branch = Vec::with_capacity(count.min(bytes.len() / 32));
+    for _ in 0..count {
+        branch.push(Hash::deserialize_le(bytes, ix)?);
+    }
+    let index = u32::deserialize_le(bytes, ix)?;
+    Ok((branch, index))
+}
+
+/// Parses a block header that may be followed by auxiliary proof-of-work data.
+/// The base 80-byte header is read first, and if its version has the
+/// `VERSION_AUXPOW` bit set, the AuxPoW data is read after it. As with the
+/// `deserialize_le` functions, the index is updated to point to whatever is
+/// after the header (normally the transaction count of the block).
+pub fn parse_auxpow_header(bytes: &[u8], ix: &mut usize) -> Result<(BlockHeader, Option<AuxPow>), BlockParseError> {
+    let header = BlockHeader::deserialize_le(bytes, ix)?;
+    if header.version & VERSION_AUXPOW == 0 {
+        return Ok((header, None));
+    }
+
+    let coinbase = Transaction::deserialize_le(bytes, ix)?;
+    let parent_hash = Hash::deserialize_le(bytes, ix)?;
+    let (coinbase_branch, coinbase_index) = read_branch(bytes, ix)?;
+    let (chain_branch, chain_index) = read_branch(bytes, ix)?;
+    let parent_header = BlockHeader::deserialize_le(bytes, ix)?;
+    let auxpow = AuxPow {
+        coinbase,
+        parent_hash,
+        coinbase_branch,
+        coinbase_index,
+        chain_branch,
+        chain_index,
+        parent_header,
+    };
+    Ok((header, Some(auxpow)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_blockfile;
+    use crate::parse::tests::read_testdata;
+    use super::*;
+
+    #[test]
+    fn auxpow_header() {
+        let parent = parse_blockfile(&read_testdata("block_265458.dat")).unwrap().pop().unwrap();
+        let mut header = parse_blockfile(&read_testdata("block_0.dat")).unwrap().pop().unwrap().header;
+
+        // Without the version bit, only the base header is read
+        let mut bytes = Vec::new();
+        header.serialize_le(&mut bytes);
+        bytes.push(1);
+        let mut ix = 0;
+        assert_eq!(parse_auxpow_header(&bytes, &mut ix).unwrap(), (header.clone(), None));
+        assert_eq!(ix, 80);
+
+        header.version |= VERSION_AUXPOW;
+        let mut bytes = Vec::new();
+        header.serialize_le(&mut bytes);
+        parent.transactions[0].serialize_le(&mut bytes);
+        parent.id().serialize_le(&mut bytes);
+        let branch = parent.merkle_proof(0).unwrap();
+        branch.len().serialize_le(&mut bytes);
+        branch.iter().for_each(|hash| hash.serialize_le(&mut bytes));
+        0u32.serialize_le(&mut bytes);
+        0usize.serialize_le(&mut bytes);
+        0u32.serialize_le(&mut bytes);
+        parent.header.serialize_le(&mut bytes);
+        let end = bytes.len();
+        bytes.push(1);
+
+        let mut ix = 0;
+        let (parsed, auxpow) = parse_auxpow_header(&bytes, &mut ix).unwrap();
+        assert_eq!(ix, end);
+        assert_eq!(parsed, header);
+        let auxpow = auxpow.unwrap();
+        assert_eq!(auxpow.coinbase, parent.transactions[0]);
+        assert_eq!(auxpow.parent_hash, parent.id());
+        assert_eq!(auxpow.coinbase_branch, branch);
+        assert_eq!(auxpow.coinbase_index, 0);
+        assert!(auxpow.chain_branch.is_empty());
+        assert_eq!(auxpow.parent_header, parent.header);
+
+        assert!(parse_auxpow_header(&bytes[..end - 1], &mut 0).is_err());
+    }
+}
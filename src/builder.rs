@@ -1,22 +1,43 @@
 //! A high-level module to build a validation pipeline.
+//!
+//! The pipeline logs validation results with the `PIPELINE_LOG_TARGET` target
+//! and orphanage evictions with the `ORPHANAGE_LOG_TARGET` target, so that they
+//! can be filtered separately from each other and from the validator itself.
 
 use crate::{Block, Hash, LittleEndianSerialization, Network};
-use crate::validator::{BlockValidator, ValidationResult};
+use crate::parse::BlockReader;
+use crate::validator::{BlockValidator, ValidationResult, ValidatorConfig};
 use log::{trace, warn};
 use std::collections::HashSet;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread::{JoinHandle, self};
+use std::time::{Duration, Instant};
+
+/// The target used for log records about blocks moving through the pipeline.
+pub const PIPELINE_LOG_TARGET: &str = "blocktastic::pipeline";
+/// The target used for log records emitted by the orphanage.
+pub const ORPHANAGE_LOG_TARGET: &str = "blocktastic::orphanage";
 
 const ARBITRARY_ORPHANAGE_SIZE: usize = 128;
 
+/// How long an orphan is kept by default before it is assumed that its parent
+/// is never going to arrive.
+pub const DEFAULT_MAX_ORPHAN_AGE: Duration = Duration::from_secs(20 * 60);
+
 enum ValidatorMessage {
     NewBlock(Block),
+    // Where to send blocks the validator is done with, if anywhere
+    SetRecycler(Option<Sender<Block>>),
     Shutdown,
 }
 
 enum OrphanageMessage {
     NewOrphan(Block),
     NewParent(Hash, Sender<ValidatorMessage>),
+    // Drops orphans that have been waiting for their parent for too long
+    Tick,
     Shutdown,
 }
 
@@ -27,35 +48,84 @@ enum OrphanageMessage {
 pub struct BlockChainBuilder {
     network: Network,
     deduplicator: HashSet<Hash>,
+    // Only present if buffer reuse is enabled, along with the receiving end of
+    // the blocks the validator is done with
+    reader: Option<(BlockReader, Receiver<Block>)>,
     orphanage_tx: Sender<OrphanageMessage>,
-    orphanage_join: JoinHandle<()>,
+    // The join handles are taken when the pipeline is shut down
+    orphanage_join: Option<JoinHandle<()>>,
     validator_tx: Sender<ValidatorMessage>,
-    validator_join: JoinHandle<()>,
+    // Returns the number of blocks the validator handled
+    validator_join: Option<JoinHandle<usize>>,
+    // Set to make the threads stop without handling their pending messages
+    cancelled: Arc<AtomicBool>,
 }
 
 impl BlockChainBuilder {
     /// Create a validation pipeline for the given network.
     pub fn new(network: Network) -> Self {
-        let (orphanage_tx, orphanage_join) = Self::spawn_orphanage();
-        let (validator_tx, validator_join) = Self::spawn_validator(orphanage_tx.clone());
+        Self::with_max_orphan_age(network, DEFAULT_MAX_ORPHAN_AGE)
+    }
+
+    /// Create a validation pipeline for the given network, where blocks whose
+    /// parent hasn't been validated are held for at most `max_orphan_age` before
+    /// being dropped.
+    pub fn with_max_orphan_age(network: Network, max_orphan_age: Duration) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (orphanage_tx, orphanage_join) = Self::spawn_orphanage(max_orphan_age, cancelled.clone());
+        let (validator_tx, validator_join) = Self::spawn_validator(ValidatorConfig::for_network(&network), orphanage_tx.clone(), cancelled.clone());
         BlockChainBuilder {
             network,
             deduplicator: HashSet::new(),
+            reader: None,
             orphanage_tx,
-            orphanage_join,
+            orphanage_join: Some(orphanage_join),
             validator_tx,
-            validator_join,
+            validator_join: Some(validator_join),
+            cancelled,
+        }
+    }
+
+    /// Enables or disables reuse of parsing buffers in `ingest`. When enabled,
+    /// blocks that the pipeline is done with (because they are invalid, for a
+    /// different network, duplicates of already-ingested data, or have been
+    /// archived by the validator) are handed back and their script buffers are
+    /// reused for parsing subsequent blocks, which reduces allocations when
+    /// ingesting long runs of blocks. The parsed blocks are the same either way.
+    /// Disabled by default.
+    pub fn set_buffer_reuse(&mut self, enabled: bool) {
+        if enabled == self.reader.is_some() {
+            return;
         }
+        let recycler = if enabled {
+            let (tx, rx) = channel();
+            self.reader = Some((BlockReader::new(), rx));
+            Some(tx)
+        } else {
+            self.reader = None;
+            None
+        };
+        // If the validator has shut down there's nothing to recycle anyway
+        let _ = self.validator_tx.send(ValidatorMessage::SetRecycler(recycler));
     }
 
-    fn spawn_orphanage() -> (Sender<OrphanageMessage>, JoinHandle<()>) {
+    fn spawn_orphanage(max_age: Duration, cancelled: Arc<AtomicBool>) -> (Sender<OrphanageMessage>, JoinHandle<()>) {
         let (tx, rx) = channel();
         let join_handle = thread::spawn(move|| {
-            let mut orphanage = Orphanage::new(ARBITRARY_ORPHANAGE_SIZE);
+            let mut orphanage = Orphanage::new(ARBITRARY_ORPHANAGE_SIZE, max_age);
             loop {
-                match rx.recv().unwrap() {
+                // If every sender is gone there can't be any more messages
+                let message = match rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                match message {
                     OrphanageMessage::NewOrphan(b) => orphanage.take_orphan(b),
                     OrphanageMessage::NewParent(h, validator_tx) => orphanage.find_children(h, validator_tx),
+                    OrphanageMessage::Tick => orphanage.expire(Instant::now()),
                     OrphanageMessage::Shutdown => break,
                 };
             }
@@ -63,20 +133,47 @@ impl BlockChainBuilder {
         (tx, join_handle)
     }
 
-    fn spawn_validator(orphanage_tx: Sender<OrphanageMessage>) -> (Sender<ValidatorMessage>, JoinHandle<()>) {
+    fn spawn_validator(config: ValidatorConfig, orphanage_tx: Sender<OrphanageMessage>, cancelled: Arc<AtomicBool>) -> (Sender<ValidatorMessage>, JoinHandle<usize>) {
         let (tx, rx) = channel();
         let validator_tx = tx.clone();
         let join_handle = thread::spawn(move|| {
-            let mut validator = BlockValidator::new();
-            while let ValidatorMessage::NewBlock(block) = rx.recv().unwrap() {
+            let mut validator = BlockValidator::with_config(config);
+            let mut handled = 0;
+            loop {
+                // If every sender is gone there can't be any more messages
+                let message = match rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                let block = match message {
+                    ValidatorMessage::NewBlock(block) => block,
+                    ValidatorMessage::SetRecycler(Some(recycler_tx)) => {
+                        // The builder may have disabled reuse or been dropped in the meantime
+                        validator.set_block_recycler(move |block| { let _ = recycler_tx.send(block); });
+                        continue;
+                    }
+                    ValidatorMessage::SetRecycler(None) => {
+                        validator.set_block_recycler(drop);
+                        continue;
+                    }
+                    ValidatorMessage::Shutdown => break,
+                };
+                handled += 1;
                 let validation_result = validator.handle_block(block);
-                trace!("Validation result: {:?}", &validation_result);
+                trace!(target: PIPELINE_LOG_TARGET, "Validation result: {:?}", &validation_result);
                 match validation_result {
-                    ValidationResult::Valid(id) => orphanage_tx.send(OrphanageMessage::NewParent(id, validator_tx.clone())).unwrap(),
+                    // The orphanage may already have stopped if shutdown was cancelled
+                    ValidationResult::Valid(id) => { let _ = orphanage_tx.send(OrphanageMessage::NewParent(id, validator_tx.clone())); }
                     ValidationResult::Invalid(_) => (),
-                    ValidationResult::Orphan(b) => orphanage_tx.send(OrphanageMessage::NewOrphan(b)).unwrap(),
+                    ValidationResult::Orphan(b) => { let _ = orphanage_tx.send(OrphanageMessage::NewOrphan(b)); }
+                    // Only returned from handle_header, which the pipeline doesn't use
+                    ValidationResult::OrphanHeader(_) => (),
                 };
-            };
+            }
+            handled
         });
         (tx, join_handle)
     }
@@ -94,13 +191,28 @@ impl BlockChainBuilder {
     /// Note that blocks that are syntactically valid but are otherwise invalid (e.g. for a
     /// different network, or attempt to spend unspendable outputs) will still be accepted
     /// by this function, but will not end up in the final blockchain.
+    ///
+    /// Each call also prompts the orphanage to drop orphans that are older than
+    /// the maximum orphan age.
     pub fn ingest(&mut self, bytes: &[u8]) -> usize {
+        // If the orphanage has shut down there's nothing to expire
+        let _ = self.orphanage_tx.send(OrphanageMessage::Tick);
+        if let Some((reader, recycled)) = &mut self.reader {
+            for block in recycled.try_iter() {
+                reader.recycle(block);
+            }
+        }
         let mut ix = 0;
         while ix < bytes.len() {
             let last_good_ix = ix;
-            match Block::deserialize_le(bytes, &mut ix) {
+            let parsed = match &mut self.reader {
+                Some((reader, _)) => reader.read_block(bytes, &mut ix),
+                None => Block::deserialize_le(bytes, &mut ix),
+            };
+            match parsed {
                 Ok(block) => {
                     if block.network != self.network {
+                        self.discard(block);
                         continue;
                     }
 
@@ -110,6 +222,7 @@ impl BlockChainBuilder {
                     let bytes_hash = Hash(hmac_sha256::Hash::hash(&bytes[last_good_ix..ix]));
                     if !self.deduplicator.insert(bytes_hash) {
                         // We've already seen this block
+                        self.discard(block);
                         continue;
                     }
                     if self.validator_tx.send(ValidatorMessage::NewBlock(block)).is_err() {
@@ -124,27 +237,67 @@ impl BlockChainBuilder {
         ix
     }
 
+    fn discard(&mut self, block: Block) {
+        if let Some((reader, _)) = &mut self.reader {
+            reader.recycle(block);
+        }
+    }
+
     /// Perform an orderly shutdown of the various components for this pipeline.
-    pub fn shutdown(self) {
+    /// This waits for all the blocks that have already been ingested to be
+    /// validated, which may take a long time if there is a large backlog.
+    pub fn shutdown(mut self) {
         self.validator_tx.send(ValidatorMessage::Shutdown).unwrap();
         self.orphanage_tx.send(OrphanageMessage::Shutdown).unwrap();
-        self.validator_join.join().unwrap();
-        self.orphanage_join.join().unwrap();
+        self.validator_join.take().unwrap().join().unwrap();
+        self.orphanage_join.take().unwrap().join().unwrap();
+    }
+
+    /// Shut down the pipeline without waiting for pending work. Unlike `shutdown`,
+    /// blocks that have been ingested but not yet validated are discarded; the
+    /// validator and orphanage stop as soon as they finish the message they are
+    /// currently handling. Returns the number of blocks that were validated.
+    pub fn shutdown_now(mut self) -> usize {
+        self.cancelled.store(true, Ordering::Relaxed);
+        // The threads check for cancellation when they get a message, so make
+        // sure they get one even if their queues are empty
+        let _ = self.validator_tx.send(ValidatorMessage::Shutdown);
+        let _ = self.orphanage_tx.send(OrphanageMessage::Shutdown);
+        let handled = self.validator_join.take().unwrap().join().unwrap();
+        self.orphanage_join.take().unwrap().join().unwrap();
+        handled
+    }
+}
+
+impl Drop for BlockChainBuilder {
+    /// Dropping the pipeline without calling `shutdown` still stops its threads
+    /// once the blocks that were already ingested have been validated, but
+    /// doesn't wait for that to happen.
+    fn drop(&mut self) {
+        // If the pipeline was already shut down the threads have stopped and these
+        // fail, which is fine
+        let _ = self.validator_tx.send(ValidatorMessage::Shutdown);
+        let _ = self.orphanage_tx.send(OrphanageMessage::Shutdown);
     }
 }
 
 /// An orphanage stores blocks that are currently orphans in the hope that they
 /// are received out-of-order and can be attached to the chain later. It has a
 /// maximum size and evicts entries in FIFO order if they do not get parented.
+/// Entries are also evicted once they are older than the maximum age, so that
+/// orphans whose parent never arrives don't stay around indefinitely.
 struct Orphanage {
     size: usize,
-    orphans: Vec<Block>,
+    max_age: Duration,
+    // Each orphan along with the time it was received, in order of arrival
+    orphans: Vec<(Block, Instant)>,
 }
 
 impl Orphanage {
-    fn new(size: usize) -> Self {
+    fn new(size: usize, max_age: Duration) -> Self {
         Self {
             size,
+            max_age,
             orphans: Vec::with_capacity(size),
         }
     }
@@ -153,10 +306,22 @@ impl Orphanage {
     /// if the orphanage is at capacity.
     fn take_orphan(&mut self, block: Block) {
         while self.orphans.len() >= self.size {
-            let evicted = self.orphans.remove(0);
-            warn!("Orphanage evicting block {}", evicted.id());
+            let (evicted, _) = self.orphans.remove(0);
+            warn!(target: ORPHANAGE_LOG_TARGET, hash:% = evicted.id(); "Orphanage evicting block {}", evicted.id());
+        }
+        self.orphans.push((block, Instant::now()));
+    }
+
+    /// Evict all orphans that were received more than the maximum age before
+    /// `now`.
+    fn expire(&mut self, now: Instant) {
+        // Orphans are in order of arrival, so the expired ones are at the front
+        let expired = self.orphans.iter()
+            .take_while(|(_, received)| now.saturating_duration_since(*received) > self.max_age)
+            .count();
+        for (evicted, _) in self.orphans.drain(..expired) {
+            warn!(target: ORPHANAGE_LOG_TARGET, hash:% = evicted.id(); "Orphanage evicting block {} after waiting too long for its parent", evicted.id());
         }
-        self.orphans.push(block);
     }
 
     /// Ask the orphanage to find orphans that are children of the given parent,
@@ -166,10 +331,10 @@ impl Orphanage {
         // TODO: Replace this with self.orphans.drain_filter once that is stable
         let mut i = 0;
         while i < self.orphans.len() {
-            if self.orphans[i].header.prev_block_hash == parent_id {
+            if self.orphans[i].0.header.prev_block_hash == parent_id {
                 // The validator shuts down before the orphanage, so make sure not to discard
                 // orphans that fail to get sent.
-                let child = self.orphans.get(i).unwrap();
+                let (child, _) = self.orphans.get(i).unwrap();
                 if validator_tx.send(ValidatorMessage::NewBlock(child.clone())).is_ok() {
                     self.orphans.remove(i);
                 } else {
@@ -181,3 +346,108 @@ impl Orphanage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_blockfile;
+    use crate::parse::tests::read_testdata;
+    use super::*;
+
+    #[test]
+    fn shutdown_now() {
+        let mut builder = BlockChainBuilder::new(Network::MainNet);
+        // Wait for the validator to hand back an invalid block, so that it is
+        // known to have handled exactly one block
+        builder.set_buffer_reuse(true);
+        let mut data = read_testdata("block_0.dat");
+        data[8 + 76] ^= 1;
+        assert_eq!(builder.ingest(&data), data.len());
+        builder.reader.as_ref().unwrap().1.recv_timeout(Duration::from_secs(60)).unwrap();
+
+        // Cancelling before queueing more blocks means none of them are handled.
+        // This bypasses ingest, which would deduplicate them. The validator stops
+        // when it gets the first one, after which sending fails.
+        builder.cancelled.store(true, Ordering::Relaxed);
+        let block_0 = parse_blockfile(&read_testdata("block_0.dat")).unwrap().pop().unwrap();
+        for _ in 0..100 {
+            let _ = builder.validator_tx.send(ValidatorMessage::NewBlock(block_0.clone()));
+        }
+        assert_eq!(builder.shutdown_now(), 1);
+    }
+
+    #[test]
+    fn buffer_reuse() {
+        let mut builder = BlockChainBuilder::new(Network::MainNet);
+        builder.set_buffer_reuse(true);
+        // Take the parsed blocks instead of validating them
+        let (tx, rx) = channel();
+        let validator_tx = std::mem::replace(&mut builder.validator_tx, tx);
+        let files = ["block_481829.dat", "block_481829.dat", "block_265458.dat"];
+        for file in files {
+            let data = read_testdata(file);
+            assert_eq!(builder.ingest(&data), data.len());
+        }
+        // The duplicate was recycled and its buffers used for the last block
+        assert!(builder.reader.as_ref().unwrap().0.reused > 0);
+        for file in ["block_481829.dat", "block_265458.dat"] {
+            let block = match rx.try_recv() {
+                Ok(ValidatorMessage::NewBlock(block)) => block,
+                _ => panic!("Expected a block from {}", file),
+            };
+            let mut serialized = Vec::new();
+            block.serialize_le(&mut serialized);
+            assert_eq!(serialized, read_testdata(file));
+        }
+        assert!(rx.try_recv().is_err());
+
+        // Blocks the validator is done with come back to the builder
+        builder.validator_tx = validator_tx;
+        let mut data = read_testdata("block_0.dat");
+        // Break the proof of work by changing the nonce
+        data[8 + 76] ^= 1;
+        assert_eq!(builder.ingest(&data), data.len());
+        let invalid = builder.reader.as_ref().unwrap().1.recv_timeout(Duration::from_secs(60)).unwrap();
+        let mut serialized = Vec::new();
+        invalid.serialize_le(&mut serialized);
+        assert_eq!(serialized, data);
+        builder.shutdown();
+    }
+
+    #[test]
+    fn drop_without_shutdown() {
+        let mut builder = BlockChainBuilder::new(Network::MainNet);
+        let data = read_testdata("block_0.dat");
+        assert_eq!(builder.ingest(&data), data.len());
+        let validator_join = builder.validator_join.take().unwrap();
+        let orphanage_join = builder.orphanage_join.take().unwrap();
+        drop(builder);
+        assert_eq!(validator_join.join().unwrap(), 1);
+        assert!(orphanage_join.join().is_ok());
+    }
+
+    #[test]
+    fn orphan_expiry() {
+        let blocks: Vec<Block> = ["block_0.dat", "block_265458.dat"]
+            .iter()
+            .map(|file| parse_blockfile(&read_testdata(file)).unwrap().pop().unwrap())
+            .collect();
+        let max_age = Duration::from_secs(60);
+        let mut orphanage = Orphanage::new(ARBITRARY_ORPHANAGE_SIZE, max_age);
+        orphanage.take_orphan(blocks[0].clone());
+        orphanage.take_orphan(blocks[1].clone());
+        // Pretend the second orphan arrived a while after the first
+        let first_received = orphanage.orphans[0].1;
+        orphanage.orphans[1].1 = first_received + max_age;
+
+        // Nothing has expired yet, even though the orphanage isn't full
+        orphanage.expire(first_received + max_age);
+        assert_eq!(orphanage.orphans.len(), 2);
+
+        orphanage.expire(first_received + max_age + Duration::from_secs(1));
+        assert_eq!(orphanage.orphans.len(), 1);
+        assert_eq!(orphanage.orphans[0].0.id(), blocks[1].id());
+
+        orphanage.expire(first_received + max_age * 3);
+        assert!(orphanage.orphans.is_empty());
+    }
+}
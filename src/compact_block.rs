@@ -0,0 +1,311 @@
+//! A module that exposes parsing of compact blocks, as described in BIP152.
+//! A compact block has the block header and a short id for each transaction,
+//! which lets a peer that already has most of the transactions in its mempool
+//! reconstruct the block without them being sent again. Some transactions
+//! (generally at least the coinbase) are sent in full as prefilled transactions.
+//!
+//! Only version 2 compact blocks are supported, whose short ids are computed
+//! from the wtxid of each transaction.
+
+use crate::{Block, BlockHeader, BlockParseError, Hash, LittleEndianSerialization, Network, Transaction};
+use crate::hash::siphash24;
+use crate::parse::read_bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+const SHORT_ID_SIZE: usize = 6;
+const SHORT_ID_MASK: u64 = (1 << (8 * SHORT_ID_SIZE)) - 1;
+// The smallest possible serialized transaction is 60 bytes, which bounds the
+// number of transactions that can fit in a block of MAX_BLOCK_SERIALIZED_SIZE.
+const MAX_TRANSACTION_COUNT: usize = 4_000_000 / 60;
+
+/// Computes the short id of the transaction with the given wtxid, for a compact
+/// block whose SipHash key is (key0, key1). The key is derived from the block
+/// header and the compact block nonce; see `CompactBlock::short_id_key`. The
+/// short id is the low 6 bytes of the SipHash-2-4 of the wtxid.
+pub fn short_txid(wtxid: &Hash, key0: u64, key1: u64) -> u64 {
+    siphash24(key0, key1, &wtxid.reverse().0) & SHORT_ID_MASK
+}
+
+/// A transaction that is sent in full as part of a compact block, along with
+/// its index in the block.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrefilledTransaction {
+    /// The index of the transaction in the block.
+    pub index: usize,
+    /// The transaction itself.
+    pub transaction: Transaction,
+}
+
+/// The payload of a `cmpctblock` message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompactBlock {
+    /// The header of the block.
+    pub header: BlockHeader,
+    /// A nonce that is used along with the header to key the short ids, so that
+    /// collisions can't be engineered ahead of time.
+    pub nonce: u64,
+    /// The short ids of the transactions that aren't prefilled, in block order.
+    /// Only the low 48 bits of each are used.
+    pub short_ids: Vec<u64>,
+    /// The transactions that are sent in full, in increasing order of index.
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+/// The result of reconstructing a compact block from a mempool.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Reconstruction {
+    /// The transactions of the block, in order. Transactions that couldn't be
+    /// found are None.
+    pub transactions: Vec<Option<Transaction>>,
+    /// The indices of the transactions that couldn't be found, in increasing
+    /// order. These need to be requested from the peer with `getblocktxn`.
+    pub missing: Vec<usize>,
+}
+
+impl Reconstruction {
+    /// Returns the reconstructed block if no transactions are missing. The
+    /// network is not part of a compact block and so must be provided. Note
+    /// that short id collisions can cause the wrong transaction to be used, so
+    /// the merkle root of the block should be checked.
+    pub fn into_block(self, header: BlockHeader, network: Network) -> Option<Block> {
+        let transactions = self.transactions.into_iter().collect::<Option<Vec<Transaction>>>()?;
+        Some(Block {
+            network,
+            header,
+            transactions,
+        })
+    }
+}
+
+impl CompactBlock {
+    /// Creates a compact block for the given block, with the transactions at the
+    /// given indices (which must be in increasing order) prefilled. The coinbase
+    /// transaction is always prefilled, since it can never be in a mempool.
+    pub fn from_block(block: &Block, nonce: u64, prefill: &[usize]) -> Self {
+        let mut compact = CompactBlock {
+            header: block.header.clone(),
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if index == 0 || prefill.contains(&index) {
+                compact.prefilled.push(PrefilledTransaction { index, transaction: transaction.clone() });
+            } else {
+                compact.short_ids.push(compact.short_id(&transaction.wtxid()));
+            }
+        }
+        compact
+    }
+
+    /// Returns the number of transactions in the block.
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Returns the SipHash key for the short ids in this compact block, which is
+    /// derived from the first 16 bytes of the SHA-256 of the header and nonce.
+    pub fn short_id_key(&self) -> (u64, u64) {
+        let mut serialized = Vec::with_capacity(88);
+        self.header.serialize_le(&mut serialized);
+        self.nonce.serialize_le(&mut serialized);
+        let hash = Sha256::digest(&serialized);
+        (u64::from_le_bytes(hash[0..8].try_into().unwrap()), u64::from_le_bytes(hash[8..16].try_into().unwrap()))
+    }
+
+    /// Returns the short id for the transaction with the given wtxid in this
+    /// compact block.
+    pub fn short_id(&self, wtxid: &Hash) -> u64 {
+        let (key0, key1) = self.short_id_key();
+        short_txid(wtxid, key0, key1)
+    }
+
+    /// Reconstructs as much of the block as possible, using the prefilled
+    /// transactions and the transactions in the given mempool. Transactions
+    /// in the mempool that aren't in the block are ignored. If more than one
+    /// mempool transaction matches a short id, that transaction is treated as
+    /// missing. Returns an error if two transactions in the block have the same
+    /// short id, in which case the full block needs to be requested.
+    pub fn reconstruct<'a>(&self, mempool: impl IntoIterator<Item = &'a Transaction>) -> Result<Reconstruction, BlockParseError> {
+        let (key0, key1) = self.short_id_key();
+        let count = self.transaction_count();
+        let mut transactions = vec![None; count];
+        for prefilled in &self.prefilled {
+            match transactions.get_mut(prefilled.index) {
+                Some(slot @ None) => *slot = Some(prefilled.transaction.clone()),
+                _ => return Err(BlockParseError::new(format!("Invalid prefilled transaction index {} in compact block", prefilled.index))),
+            }
+        }
+
+        // Map from short id to the index of the block transaction with that id
+        let mut indices = HashMap::with_capacity(self.short_ids.len());
+        let mut short_ids = self.short_ids.iter();
+        for (index, slot) in transactions.iter().enumerate() {
+            if slot.is_none() {
+                // Each prefilled index is distinct and in range, so there is a short
+                // id for each remaining slot
+                let short_id = *short_ids.next().unwrap() & SHORT_ID_MASK;
+                if indices.insert(short_id, index).is_some() {
+                    return Err(BlockParseError::new(format!("Duplicate short id {:012x} in compact block", short_id)));
+                }
+            }
+        }
+
+        let mut collisions = Vec::new();
+        for transaction in mempool {
+            let short_id = short_txid(&transaction.wtxid(), key0, key1);
+            if let Some(index) = indices.get(&short_id) {
+                if transactions[*index].is_some() {
+                    collisions.push(*index);
+                } else {
+                    transactions[*index] = Some(transaction.clone());
+                }
+            }
+        }
+        for index in collisions {
+            transactions[index] = None;
+        }
+
+        let missing = transactions.iter().enumerate().filter(|(_, t)| t.is_none()).map(|(index, _)| index).collect();
+        Ok(Reconstruction {
+            transactions,
+            missing,
+        })
+    }
+}
+
+impl LittleEndianSerialization for CompactBlock {
+    fn serialize_le(&self, dest: &mut Vec<u8>) {
+        self.header.serialize_le(dest);
+        self.nonce.serialize_le(dest);
+        self.short_ids.len().serialize_le(dest);
+        for short_id in &self.short_ids {
+            dest.extend(&short_id.to_le_bytes()[..SHORT_ID_SIZE]);
+        }
+        self.prefilled.len().serialize_le(dest);
+        // Indices are differentially encoded, relative to one past the previous index
+        let mut next_index = 0;
+        for prefilled in &self.prefilled {
+            (prefilled.index - next_index).serialize_le(dest);
+            prefilled.transaction.serialize_le(dest);
+            next_index = prefilled.index + 1;
+        }
+    }
+
+    fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
+        let header = BlockHeader::deserialize_le(bytes, ix)?;
+        let nonce = u64::deserialize_le(bytes, ix)?;
+
+        let count_ix = *ix;
+        let short_id_count = usize::deserialize_le(bytes, ix)?;
+        if short_id_count > MAX_TRANSACTION_COUNT {
+            return Err(BlockParseError::new(format!("Too many short ids ({}) in compact block at index {}", short_id_count, count_ix)));
+        }
+        let short_id_bytes = read_bytes(bytes, ix, short_id_count * SHORT_ID_SIZE)?;
+        let short_ids = short_id_bytes.chunks_exact(SHORT_ID_SIZE).map(|chunk| {
+            let mut short_id = [0u8; 8];
+            short_id[..SHORT_ID_SIZE].copy_from_slice(chunk);
+            u64::from_le_bytes(short_id)
+        }).collect::<Vec<u64>>();
+
+        let count_ix = *ix;
+        let prefilled_count = usize::deserialize_le(bytes, ix)?;
+        // short_id_count is bounded already, so this can't underflow, whereas
+        // adding the counts could overflow
+        if prefilled_count > MAX_TRANSACTION_COUNT - short_id_count {
+            return Err(BlockParseError::new(format!("Too many prefilled transactions ({}) in compact block at index {}", prefilled_count, count_ix)));
+        }
+        let mut prefilled = Vec::with_capacity(prefilled_count);
+        let mut next_index = 0usize;
+        for _ in 0..prefilled_count {
+            let index_ix = *ix;
+            let index = next_index.checked_add(usize::deserialize_le(bytes, ix)?)
+                .filter(|index| *index < short_id_count + prefilled_count)
+                .ok_or_else(|| BlockParseError::new(format!("Prefilled transaction index out of range at index {}", index_ix)))?;
+            let transaction = Transaction::deserialize_le(bytes, ix)?;
+            prefilled.push(PrefilledTransaction { index, transaction });
+            next_index = index + 1;
+        }
+
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+        })
+    }
+}
+
+/// Parses the payload of a P2P `cmpctblock` message (without the message
+/// envelope) into a compact block.
+pub fn parse_compact_block(bytes: &[u8]) -> Result<CompactBlock, BlockParseError> {
+    let mut ix = 0;
+    let compact = CompactBlock::deserialize_le(bytes, &mut ix)?;
+    if ix != bytes.len() {
+        return Err(BlockParseError::new(format!("Unexpected data after compact block at index {}", ix)));
+    }
+    Ok(compact)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_blockfile;
+    use crate::parse::tests::read_testdata;
+    use super::*;
+
+    #[test]
+    fn siphash_reference() {
+        // Test vector from the SipHash paper
+        let data = (0..15).collect::<Vec<u8>>();
+        assert_eq!(siphash24(0x0706050403020100, 0x0f0e0d0c0b0a0908, &data), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn short_ids() {
+        // The reference SipHash vector for a 32-byte message, which is also used
+        // by Bitcoin Core's tests of the SipHash of a uint256
+        let wtxid = Hash::from_display_hex("1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100").unwrap();
+        assert_eq!(siphash24(0x0706050403020100, 0x0f0e0d0c0b0a0908, &wtxid.reverse().0), 0x7127512f72f27cce);
+        assert_eq!(short_txid(&wtxid, 0x0706050403020100, 0x0f0e0d0c0b0a0908), 0x512f72f27cce);
+    }
+
+    #[test]
+    fn reconstruct() {
+        let block = parse_blockfile(&read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        let compact = CompactBlock::from_block(&block, 0x1234_5678_9abc_def0, &[5]);
+        assert_eq!(compact.prefilled.len(), 2);
+        assert_eq!(compact.transaction_count(), block.transactions.len());
+
+        let mut serialized = Vec::new();
+        compact.serialize_le(&mut serialized);
+        let parsed = parse_compact_block(&serialized).unwrap();
+        assert_eq!(parsed, compact);
+        assert!(parse_compact_block(&serialized[..serialized.len() - 1]).is_err());
+
+        // A huge prefilled count is rejected rather than overflowing
+        let mut huge = Vec::new();
+        compact.header.serialize_le(&mut huge);
+        compact.nonce.serialize_le(&mut huge);
+        huge.push(1);
+        huge.extend_from_slice(&[0; SHORT_ID_SIZE]);
+        huge.push(0xff);
+        huge.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse_compact_block(&huge).is_err());
+
+        // With the full mempool (in a different order), everything is found
+        let mut mempool = block.transactions[1..].to_vec();
+        mempool.reverse();
+        let reconstruction = parsed.reconstruct(&mempool).unwrap();
+        assert!(reconstruction.missing.is_empty());
+        let reconstructed = reconstruction.into_block(parsed.header.clone(), block.network.clone()).unwrap();
+        assert_eq!(reconstructed, block);
+
+        // Missing transactions are reported, but prefilled ones are never missing
+        let partial = mempool.iter().filter(|t| t.txid() != block.transactions[3].txid() && t.txid() != block.transactions[5].txid());
+        let reconstruction = parsed.reconstruct(partial).unwrap();
+        assert_eq!(reconstruction.missing, vec![3]);
+        assert!(reconstruction.transactions[5].is_some());
+        assert!(reconstruction.into_block(parsed.header.clone(), block.network.clone()).is_none());
+    }
+}
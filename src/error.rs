@@ -1,17 +1,35 @@
+use crate::Hash;
+
 /// An error encountered during block parsing. This indicates the
 /// block data is not structurally valid. Details are provided in
 /// a freeform string message.
 #[derive(Debug)]
 pub struct BlockParseError {
     msg: String,
+    trailing_data: Option<(usize, usize)>,
 }
 
 impl BlockParseError {
     pub(crate) fn new(msg: String) -> Self {
         BlockParseError {
             msg,
+            trailing_data: None,
+        }
+    }
+
+    pub(crate) fn trailing(block_count: usize, trailing_bytes: usize, ix: usize) -> Self {
+        BlockParseError {
+            msg: format!("Parsed {} full blocks but found {} trailing bytes at index {} that are not a complete block", block_count, trailing_bytes, ix),
+            trailing_data: Some((block_count, trailing_bytes)),
         }
     }
+
+    /// If parsing failed because the data ended with something other than a
+    /// complete block (e.g. a truncated block or garbage), returns the number
+    /// of full blocks that parsed and the number of trailing bytes after them.
+    pub fn trailing_data(&self) -> Option<(usize, usize)> {
+        self.trailing_data
+    }
 }
 
 impl std::fmt::Display for BlockParseError {
@@ -24,24 +42,152 @@ impl std::error::Error for BlockParseError {
 }
 
 /// An error encountered during block validation. This indicates the
-/// block was not sufficiently valid to be added to the blockchain.
-/// Details are provided in a freeform string message.
+/// block was not sufficiently valid to be added to the blockchain. The
+/// variants identify the check that failed and carry the relevant values;
+/// errors that don't have a specific variant yet are provided in a
+/// freeform string message.
+#[allow(missing_docs)]
 #[derive(Debug)]
-pub struct BlockValidationError {
-    msg: String,
+#[non_exhaustive]
+pub enum BlockValidationError {
+    /// The block's parent has been archived, so the block would create a fork
+    /// that is too deep to be accepted.
+    ArchivedParent { block: Hash, parent: Hash, parent_height: usize },
+    /// The block would be at or below the height of the most recent archived
+    /// block, e.g. a genesis block given after a UTXO snapshot was loaded.
+    BelowArchivedHeight { block: Hash, height: usize, archived_height: usize },
+    /// The block version is lower than the minimum required at its height,
+    /// which is raised as soft forks such as BIP34 activate.
+    ObsoleteVersion { height: usize, minimum: u32, actual: u32 },
+    /// The block is at a checkpointed height but doesn't match the checkpoint.
+    CheckpointMismatch { height: usize, expected: Hash, actual: Hash },
+    /// The block timestamp is further ahead of the current time than the
+    /// validator's tolerance of `max_future_block_time` seconds.
+    TimestampTooFarAhead { timestamp: u32, now: u64, max_future_block_time: u64 },
+    /// The block timestamp is not after the timestamp of its parent.
+    TimestampNotAfterParent { timestamp: u32, parent_timestamp: u32 },
+    /// The target could not be computed from the `bits` in the header.
+    InvalidTarget { bits: u32 },
+    /// The block hash was not below the target, so the proof-of-work is invalid.
+    PowTooHigh { hash: Hash, target: Hash },
+    /// The difficulty changed at a height that is not a retarget height.
+    PrematureDifficultyChange { height: usize },
+    /// The block has no transactions, not even a coinbase.
+    NoTransactions,
+    /// The merkle root in the header doesn't match the transactions.
+    MerkleMismatch { computed: Hash, header: Hash },
+    /// The transactions produce a mutated merkle tree (CVE-2012-2459).
+    MutatedMerkleTree { merkle_root: Hash },
+    /// The coinbase scriptSig length is outside the allowed range.
+    BadCoinbaseLength { length: usize },
+    /// Two transactions in the block have the same txid.
+    DuplicateTransaction { txid: Hash },
+    /// A transaction has the same txid as an earlier transaction that still has
+    /// unspent outputs, which it would overwrite (BIP30).
+    OverwritesUnspentTransaction { txid: Hash },
+    /// A transaction spends the coinbase of the same block.
+    CoinbaseSpentInSameBlock { txid: Hash, vin: u32 },
+    /// The scripts of one of a transaction's inputs fail verification.
+    ScriptVerificationFailed { txid: Hash, reason: String },
+    /// The block's signature operations exceed the maximum allowed cost.
+    TooManySigops { sigops: usize, cost: usize, max_cost: usize },
+    /// A transaction has witness data but the block has no witness commitment.
+    UnexpectedWitness { txid: Hash },
+    /// The coinbase witness isn't the single 32-byte reserved value.
+    BadWitnessReservedValue,
+    /// The witness commitment in the coinbase doesn't match the transactions.
+    WitnessCommitmentMismatch { computed: Hash, commitment: Hash },
+    /// The block to roll back to has already been archived.
+    RollbackIntoArchive { block: Hash, height: usize },
+    /// The block is not known to the validator.
+    UnknownBlock { block: Hash },
+    /// The height given for a UTXO snapshot doesn't match the height of its
+    /// base block in the header chain.
+    SnapshotHeightMismatch { block: Hash, expected: usize, actual: usize },
+    /// A transaction's locktime hasn't been reached yet.
+    NonFinalTransaction { txid: Hash, locktime: u32 },
+    /// A transaction input spends an output whose value isn't known.
+    MissingPrevout { txid: Hash, vin: u32, prevout: (Hash, u32) },
+    /// A transaction's outputs are worth more than its inputs.
+    OutputsExceedInputs { txid: Hash, input_value: u64, output_value: u64 },
+    /// One of a transaction's input or output values, or their sum, exceeds
+    /// the maximum amount of money.
+    ValueOverflow { txid: Hash, value: u64 },
+    /// A value, or a sum of values, exceeds the maximum amount of money.
+    ValueOutOfRange { value: u64 },
+    /// Some other validation failure, described in a freeform string message.
+    Other(String),
 }
 
 impl BlockValidationError {
     pub(crate) fn new(msg: String) -> Self {
-        BlockValidationError {
-            msg,
-        }
+        BlockValidationError::Other(msg)
     }
 }
 
 impl std::fmt::Display for BlockValidationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
+        match self {
+            BlockValidationError::ArchivedParent { block, parent, parent_height } =>
+                write!(f, "Candidate block {} has a previous block {} that is archived at height {}", block, parent, parent_height),
+            BlockValidationError::BelowArchivedHeight { block, height, archived_height } =>
+                write!(f, "Candidate block {} at height {} is not above the most recent archived block at height {}", block, height, archived_height),
+            BlockValidationError::ObsoleteVersion { height, minimum, actual } =>
+                write!(f, "Block at height {} with obsolete version: expected at least {} but got {}", height, minimum, actual),
+            BlockValidationError::CheckpointMismatch { height, expected, actual } =>
+                write!(f, "Block {} at height {} does not match checkpoint {}", actual, height, expected),
+            BlockValidationError::TimestampTooFarAhead { timestamp, now, max_future_block_time } =>
+                write!(f, "Block timestamp {} was more than {} seconds in the future from current timestamp {}", timestamp, max_future_block_time, now),
+            BlockValidationError::TimestampNotAfterParent { timestamp, parent_timestamp } =>
+                write!(f, "Block with time {} was not newer than parent block with time {}", timestamp, parent_timestamp),
+            BlockValidationError::InvalidTarget { bits } =>
+                write!(f, "Target difficulty could not be computed from {:#x}", bits),
+            BlockValidationError::PowTooHigh { hash, target } =>
+                write!(f, "Block header hash {} was not less than the target hash {}", hash, target),
+            BlockValidationError::PrematureDifficultyChange { height } =>
+                write!(f, "Block changed the difficulty threshold prematurely; height {} is {} mod 2016", height, height % 2016),
+            BlockValidationError::NoTransactions =>
+                write!(f, "Block has no transactions"),
+            BlockValidationError::MerkleMismatch { computed, header } =>
+                write!(f, "Block with incorrect merkle root: expected {} but got {}", computed, header),
+            BlockValidationError::MutatedMerkleTree { merkle_root } =>
+                write!(f, "Block has duplicate transactions that produce a mutated merkle tree with root {}", merkle_root),
+            BlockValidationError::BadCoinbaseLength { length } =>
+                write!(f, "Coinbase scriptSig length {} is outside the allowed range 2..=100", length),
+            BlockValidationError::DuplicateTransaction { txid } =>
+                write!(f, "Transaction {} appears more than once in the block", txid),
+            BlockValidationError::OverwritesUnspentTransaction { txid } =>
+                write!(f, "Transaction {} duplicates an earlier transaction with unspent outputs", txid),
+            BlockValidationError::CoinbaseSpentInSameBlock { txid, vin } =>
+                write!(f, "Transaction {} input {} spends the coinbase of the same block", txid, vin),
+            BlockValidationError::ScriptVerificationFailed { txid, reason } =>
+                write!(f, "Transaction {} fails script verification: {}", txid, reason),
+            BlockValidationError::TooManySigops { sigops, cost, max_cost } =>
+                write!(f, "Block has {} sigops, with cost {} exceeding the maximum of {}", sigops, cost, max_cost),
+            BlockValidationError::UnexpectedWitness { txid } =>
+                write!(f, "Transaction {} has witness data but the block has no witness commitment", txid),
+            BlockValidationError::BadWitnessReservedValue =>
+                write!(f, "Coinbase witness must be a single 32-byte reserved value when there is a witness commitment"),
+            BlockValidationError::WitnessCommitmentMismatch { computed, commitment } =>
+                write!(f, "Block with incorrect witness commitment: expected {} but got {}", computed, commitment),
+            BlockValidationError::RollbackIntoArchive { block, height } =>
+                write!(f, "Cannot roll back to block {} which is archived at height {}", block, height),
+            BlockValidationError::UnknownBlock { block } =>
+                write!(f, "Block {} is not known", block),
+            BlockValidationError::SnapshotHeightMismatch { block, expected, actual } =>
+                write!(f, "UTXO snapshot block {} is at height {} in the header chain, not {}", block, expected, actual),
+            BlockValidationError::NonFinalTransaction { txid, locktime } =>
+                write!(f, "Transaction {} is not final; its locktime {} has not been reached", txid, locktime),
+            BlockValidationError::MissingPrevout { txid, vin, prevout } =>
+                write!(f, "Transaction {} input {} spends unknown output {}:{}", txid, vin, prevout.0, prevout.1),
+            BlockValidationError::OutputsExceedInputs { txid, input_value, output_value } =>
+                write!(f, "Transaction {} has outputs worth {} exceeding its inputs worth {}", txid, output_value, input_value),
+            BlockValidationError::ValueOverflow { txid, value } =>
+                write!(f, "Transaction {} has input or output values adding up to {}, exceeding the maximum money supply of {} satoshis", txid, value, crate::MAX_MONEY),
+            BlockValidationError::ValueOutOfRange { value } =>
+                write!(f, "Value {} exceeds the maximum money supply of {} satoshis", value, crate::MAX_MONEY),
+            BlockValidationError::Other(msg) => write!(f, "{}", msg),
+        }
     }
 }
 
@@ -50,7 +196,8 @@ impl std::error::Error for BlockValidationError {
 
 /// An error during script validation. This can be either a parsing error
 /// or an actual validation error, and the enum variants represent these
-/// possibilities.
+/// possibilities. The `Display` output only says which of the two it is; the
+/// underlying error is available through `source()`.
 #[derive(Debug)]
 pub enum ScriptError {
     /// The script failed to be parsed.
@@ -58,3 +205,21 @@ pub enum ScriptError {
     /// The script failed to validate.
     Validation(BlockValidationError),
 }
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Parse(_) => write!(f, "Script parse error"),
+            ScriptError::Validation(_) => write!(f, "Script validation error"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScriptError::Parse(e) => Some(e),
+            ScriptError::Validation(e) => Some(e),
+        }
+    }
+}
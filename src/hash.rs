@@ -1,8 +1,137 @@
-use crate::{Hash, LittleEndianSerialization};
+use crate::{BlockHeader, Hash, LittleEndianSerialization};
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
 
 pub(crate) fn double_sha256(obj: &dyn LittleEndianSerialization) -> Hash {
     let mut serialized = Vec::new();
     obj.serialize_le(&mut serialized);
-    let first_hash = hmac_sha256::Hash::hash(&serialized);
-    Hash(hmac_sha256::Hash::hash(&first_hash)).reverse()
+    Hash(sha256d(&serialized)).reverse()
+}
+
+/// Like double_sha256, but feeds the serialization into the hasher as it is
+/// produced rather than collecting it into a buffer first, so that hashing a
+/// large object doesn't allocate its whole serialization.
+pub(crate) fn double_sha256_streaming<T: LittleEndianSerialization>(obj: &T) -> Hash {
+    let mut writer = HashWriter(hmac_sha256::Hash::new());
+    // Writing into the hasher never fails
+    obj.serialize_to(&mut writer).unwrap();
+    Hash(hmac_sha256::Hash::hash(&writer.0.finalize())).reverse()
+}
+
+/// Adapter that allows a SHA-256 hasher to be used as a `Write` destination.
+struct HashWriter(hmac_sha256::Hash);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes a double SHA-256 hash of the given bytes, without any reversal.
+pub(crate) fn sha256d(bytes: &[u8]) -> [u8; 32] {
+    hmac_sha256::Hash::hash(&hmac_sha256::Hash::hash(bytes))
+}
+
+/// Computes a BIP340 tagged hash, which is SHA-256 of the data prefixed by two
+/// copies of the SHA-256 of the tag. The tag makes hashes used for different
+/// purposes distinct from each other.
+pub(crate) fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    Sha256::new().chain_update(tag_hash).chain_update(tag_hash).chain_update(data).finalize().into()
+}
+
+/// Computes SipHash-2-4 of the given bytes with the key (k0, k1), as used for
+/// the short transaction ids in compact blocks.
+pub(crate) fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+    // The last block has the remaining bytes and the length in the top byte
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let m = u64::from_le_bytes(last) | ((data.len() as u64) << 56);
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Computes RIPEMD-160 of SHA-256 of the given bytes, as used for public key
+/// and script hashes.
+pub(crate) fn hash160(bytes: &[u8]) -> [u8; 20] {
+    ripemd::Ripemd160::digest(Sha256::digest(bytes)).into()
+}
+
+/// Computes the parent node in a merkle tree from the two child nodes. Unlike
+/// double_sha256 this operates on (and returns) hashes in their natural byte
+/// order, without any reversal.
+pub(crate) fn merkle_parent(left: &Hash, right: &Hash) -> Hash {
+    let first_hash = hmac_sha256::Hash::hash(&[left.0, right.0].concat());
+    Hash(hmac_sha256::Hash::hash(&first_hash))
+}
+
+/// The SHA-256 state after hashing the first 64 bytes of a serialized block
+/// header. Only the last 16 bytes of the header (the end of the merkle root,
+/// the time, the bits, and the nonce) need to be hashed on top of this to get
+/// the header hash, which saves a SHA-256 block per attempt when mining.
+pub(crate) struct HeaderMidstate {
+    state: Sha256,
+    tail: [u8; 12],
+}
+
+impl HeaderMidstate {
+    pub(crate) fn new(header: &BlockHeader) -> Self {
+        let mut serialized = Vec::with_capacity(80);
+        header.serialize_le(&mut serialized);
+        let mut state = Sha256::new();
+        state.update(&serialized[..64]);
+        HeaderMidstate {
+            state,
+            tail: serialized[64..76].try_into().unwrap(),
+        }
+    }
+
+    /// Returns the id of the header with the given nonce, which is the same as
+    /// `BlockHeader::id` with the nonce replaced.
+    pub(crate) fn id(&self, nonce: u32) -> Hash {
+        let mut state = self.state.clone();
+        state.update(self.tail);
+        state.update(nonce.to_le_bytes());
+        Hash(hmac_sha256::Hash::hash(&state.finalize())).reverse()
+    }
 }
@@ -1,20 +1,43 @@
 #![deny(warnings, missing_docs, clippy::all)]
-#![forbid(unsafe_code)]
+// Memory-mapping a file is inherently unsafe, so with the mmap feature the
+// unsafe MappedBlockFile::open is the single place allowed to use unsafe code.
+#![cfg_attr(not(feature = "mmap"), forbid(unsafe_code))]
+#![cfg_attr(feature = "mmap", deny(unsafe_code))]
 
 //! This crate provides a full validation node for the Bitcoin protocol.
 
+#[cfg(feature = "auxpow")]
+pub mod auxpow;
 pub mod builder;
+pub mod compact_block;
 mod error;
 mod hash;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod p2p;
 pub mod parse;
+pub mod policy;
 pub mod script;
+pub mod sighash;
+pub mod template;
 pub mod validator;
 
 pub use error::{BlockParseError, BlockValidationError, ScriptError};
 
 use bitflags::bitflags;
+use std::collections::HashMap;
 use std::fmt;
 
+/// The `bits` of the difficulty-1 target, which is used as the reference point
+/// for the difficulty of other targets.
+const DIFFICULTY_1_BITS: u32 = 0x1d00ffff;
+
+/// The maximum number of satoshis that can ever exist (21 million BTC). No
+/// single output, nor any sum of output values, can exceed this.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
 /// Trait implemented by most of the data structures that are part of the
 /// network protocol (Block, BlockHeader, etc.). This allows convenient
 /// serialization and deserialization from a Rust-friendly data structure
@@ -24,6 +47,15 @@ pub trait LittleEndianSerialization {
     /// vector. The bytes are appended to the end of the Vec.
     fn serialize_le(&self, dest: &mut Vec<u8>);
 
+    /// Serializes the object in little-endian format to the given writer. The
+    /// default implementation buffers the whole serialization first; types that
+    /// can be large override this to write their serialization piece by piece.
+    fn serialize_to<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<()> where Self: Sized {
+        let mut serialized = Vec::new();
+        self.serialize_le(&mut serialized);
+        dest.write_all(&serialized)
+    }
+
     /// Constructs an object given serialized bytes in little-endian format.
     /// This is the reverse operation of the serialize_le function, although
     /// it takes a byte array and an index into the array, and mutates the
@@ -33,21 +65,24 @@ pub trait LittleEndianSerialization {
 
 /// The network being operated on. This is part of the block header.
 #[allow(missing_docs)]
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub enum Network {
     MainNet,
     TestNet3,
+    #[default]
     RegTest,
-}
-
-impl Default for Network {
-    fn default() -> Self {
-        Self::RegTest
-    }
+    /// A network with an unrecognized magic value, which is stored as it
+    /// appears in the serialized data. Blocks for such networks can still be
+    /// parsed and then filtered out.
+    Unknown([u8; 4]),
 }
 
 /// Object representing a SHA256 hash. Contains the raw 32-byte array that
-/// is the hash.
+/// is the hash, stored in display order (i.e. the order used by block
+/// explorers and RPC interfaces, with the proof-of-work zeroes at the front
+/// of block hashes). The serialized form used on the wire and on disk is the
+/// reverse of this, which Bitcoin Core refers to as "internal byte order";
+/// `serialize_le` and `deserialize_le` take care of the reversal.
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, PartialOrd)]
 pub struct Hash([u8; 32]);
 
@@ -64,12 +99,39 @@ impl Hash {
         Hash(hash_bytes)
     }
 
+    /// Returns the hash as a hex string in display order, as shown by block
+    /// explorers. This is the same as the `Display` implementation.
+    pub fn to_display_hex(&self) -> String {
+        self.to_string()
+    }
+
+    /// Returns the hash as a hex string in internal byte order, i.e. the order
+    /// in which the bytes appear in serialized blocks and transactions.
+    pub fn to_internal_hex(&self) -> String {
+        self.reverse().to_string()
+    }
+
+    /// Parses a 64-digit hex string in display order, as produced by
+    /// `to_display_hex`.
+    pub fn from_display_hex(hex: &str) -> Result<Self, BlockParseError> {
+        let bytes = parse::hex_to_bytes(hex)?;
+        let bytes = bytes.try_into().map_err(|b: Vec<u8>| BlockParseError::new(format!("Hash hex string has {} bytes instead of 32", b.len())))?;
+        Ok(Hash(bytes))
+    }
+
+    /// Parses a 64-digit hex string in internal byte order, as produced by
+    /// `to_internal_hex`.
+    pub fn from_internal_hex(hex: &str) -> Result<Self, BlockParseError> {
+        Self::from_display_hex(hex).map(|h| h.reverse())
+    }
+
     /// Computes a target hash given the "bits" value from a block header.
     /// The high byte of `bits` is used as an exponent and the remaining
     /// bytes are used as the coefficient in the target calculation formula.
     /// target = coefficient * 2^(8*(exponent–3)). This function will return
     /// None in case of overflow. Note that the Bitcoin Core reference
-    /// implementation is much more strict here about what is allowed.
+    /// implementation is much more strict here about what is allowed; use
+    /// `from_bits_strict` to get the same behaviour.
     pub fn from_bits(bits: u32) -> Option<Self> {
         let mut target_bytes = [0; 32];
         let mut coefficient = bits & 0x00ffffff;
@@ -112,8 +174,45 @@ impl Hash {
 
         Some(Hash(target_bytes))
     }
+
+    /// Same as `from_bits`, but matches the Bitcoin Core reference implementation
+    /// by treating the 0x00800000 bit of `bits` as a sign bit rather than as part
+    /// of the coefficient. Returns None if the target is negative (i.e. the sign
+    /// bit is set with a nonzero coefficient) or overflows 256 bits.
+    pub fn from_bits_strict(bits: u32) -> Option<Self> {
+        const SIGN_BIT: u32 = 0x00800000;
+        if bits & SIGN_BIT != 0 && bits & 0x007fffff != 0 {
+            return None;
+        }
+        Self::from_bits(bits & !SIGN_BIT)
+    }
+
+    /// The inverse of `from_bits`: encodes the hash, interpreted as a 256-bit
+    /// target, in the compact `bits` format used in block headers. Only the
+    /// three most significant bytes of the target are kept, so the result may
+    /// decode to a slightly smaller target. As in the Bitcoin Core reference
+    /// implementation, the coefficient never has the 0x00800000 sign bit set;
+    /// if it would, the coefficient is shifted down a byte and the exponent
+    /// incremented instead.
+    pub fn to_bits(&self) -> u32 {
+        let first_nonzero = match self.0.iter().position(|b| *b != 0) {
+            Some(ix) => ix,
+            None => return 0,
+        };
+        let mut exponent = (self.0.len() - first_nonzero) as u32;
+        let mut coefficient = (0..3).fold(0u32, |coefficient, offset| {
+            let byte = self.0.get(first_nonzero + offset).copied().unwrap_or(0);
+            (coefficient << 8) | u32::from(byte)
+        });
+        if coefficient & 0x00800000 != 0 {
+            coefficient >>= 8;
+            exponent += 1;
+        }
+        (exponent << 24) | coefficient
+    }
 }
 
+/// Formats the hash as hex in display order; see `Hash::to_display_hex`.
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for v in self.0 {
@@ -144,7 +243,7 @@ pub enum Opcode {
     If, // 0x63
     NotIf, // 0x64
 
-    Invalid(u8), // 0x65 - 0x66, 0xba - 0xff
+    Invalid(u8), // 0x65 - 0x66, 0xbb - 0xff
     // VerIf, // 0x65, invalid
     // VerNotIf, // 0x66, invalid
 
@@ -229,6 +328,8 @@ pub enum Opcode {
 
     CheckLockTimeVerify, // 0xb1
     CheckSequenceVerify, // 0xb2
+
+    CheckSigAdd, // 0xba, tapscript only
 }
 
 #[allow(missing_docs)]
@@ -238,24 +339,31 @@ pub struct Script {
 }
 
 #[allow(missing_docs)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TransactionInput {
     pub txid: Hash,
     pub vout: u32,
     pub unlock_script: Vec<u8>,
     pub sequence: u32,
     pub witness_stuff: Vec<Vec<u8>>,
+    /// The value of the output being spent. This isn't part of the serialized
+    /// transaction, so it is None after parsing, but it can be filled in from
+    /// the UTXO set for signature hashing and fee computation.
+    pub prevout_value: Option<u64>,
+    /// The lock script of the output being spent. As with `prevout_value`, this
+    /// isn't serialized and is None after parsing.
+    pub prevout_script: Option<Vec<u8>>,
 }
 
 #[allow(missing_docs)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TransactionOutput {
     pub value: u64,
     pub lock_script: Vec<u8>,
 }
 
 #[allow(missing_docs)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Transaction {
     pub version: u32,
     pub flags: TransactionFlags,
@@ -265,6 +373,54 @@ pub struct Transaction {
 }
 
 impl Transaction {
+    /// Computes the transaction id, which is a double SHA-256 hash of the
+    /// transaction serialized without any witness data.
+    pub fn txid(&self) -> Hash {
+        hash::double_sha256(&self.strip_witness_data())
+    }
+
+    /// Returns true if the transaction has witness data, i.e. it is serialized in
+    /// the extended format with the witness flag or any of its inputs has a
+    /// witness.
+    pub fn has_witness(&self) -> bool {
+        self.flags.contains(TransactionFlags::WITNESS) || self.inputs.iter().any(|input| !input.witness_stuff.is_empty())
+    }
+
+    /// Returns true if this is a coinbase transaction, i.e. one that has a
+    /// single input that doesn't spend any previous output.
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].txid == Hash::zero() && self.inputs[0].vout == 0xffffffff
+    }
+
+    /// Returns the output at the given index in this transaction, if there is one.
+    pub fn output(&self, vout: u32) -> Option<&TransactionOutput> {
+        self.outputs.get(usize::try_from(vout).ok()?)
+    }
+
+    /// Returns the index of the input in this transaction that spends the given
+    /// outpoint (i.e. output `vout` of transaction `txid`), if there is one.
+    pub fn input_spending(&self, txid: &Hash, vout: u32) -> Option<usize> {
+        self.inputs.iter().position(|input| input.txid == *txid && input.vout == vout)
+    }
+
+    /// Computes the witness transaction id, which is a double SHA-256 hash of the
+    /// full transaction serialization including witness data. For transactions
+    /// without witness data this is the same as the txid.
+    pub fn wtxid(&self) -> Hash {
+        hash::double_sha256_streaming(self)
+    }
+
+    /// Computes the transaction weight as defined in BIP141. This is three
+    /// times the size of the transaction serialized without witness data, plus
+    /// the size of the full serialization.
+    pub fn weight(&self) -> usize {
+        let mut stripped = Vec::new();
+        self.strip_witness_data().serialize_le(&mut stripped);
+        let mut full = Vec::new();
+        self.serialize_le(&mut full);
+        stripped.len() * 3 + full.len()
+    }
+
     fn strip_witness_data(&self) -> Transaction {
         Transaction {
             version: self.version,
@@ -277,7 +433,7 @@ impl Transaction {
 }
 
 #[allow(missing_docs)]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct BlockHeader {
     pub version: u32,
     pub prev_block_hash: Hash,
@@ -287,8 +443,109 @@ pub struct BlockHeader {
     pub nonce: u32,
 }
 
+impl BlockHeader {
+    /// Computes the block hash, which is a double SHA-256 hash of the block header.
+    pub fn id(&self) -> Hash {
+        hash::double_sha256(self)
+    }
+
+    /// Returns the canonical 80-byte serialization of the header. This is exactly
+    /// what gets hashed to produce the block id (and checked against the target
+    /// for proof-of-work), and what gets sent in P2P `headers` messages.
+    pub fn to_bytes(&self) -> [u8; 80] {
+        let mut serialized = Vec::with_capacity(80);
+        self.serialize_le(&mut serialized);
+        serialized.try_into().expect("Block header serialization should be exactly 80 bytes")
+    }
+
+    /// Returns the target hash from the header's `bits`. The block id must be less
+    /// than this target for the proof-of-work to be valid. Returns None if the
+    /// target could not be computed; see `Hash::from_bits`.
+    pub fn target(&self) -> Option<Hash> {
+        Hash::from_bits(self.bits)
+    }
+
+    /// Searches for a nonce that makes the block id less than the target, so that
+    /// the header has valid proof-of-work. The search starts at the current nonce
+    /// and increments it, so this is only practical for low difficulties such as
+    /// RegTest. Returns true if such a nonce was found, in which case the header
+    /// is updated with it. Returns false if the target is invalid or the nonce
+    /// space was exhausted.
+    pub fn mine(&mut self) -> bool {
+        let target = match Hash::from_bits_strict(self.bits) {
+            Some(target) => target,
+            None => return false,
+        };
+        let midstate = hash::HeaderMidstate::new(self);
+        loop {
+            if midstate.id(self.nonce) < target {
+                return true;
+            }
+            self.nonce = match self.nonce.checked_add(1) {
+                Some(nonce) => nonce,
+                None => return false,
+            };
+        }
+    }
+
+    /// Returns the difficulty of this header, which is how many times harder it
+    /// is to meet this header's target than the difficulty-1 target (the target
+    /// of the genesis block). This is the number that block explorers show as
+    /// the difficulty. Returns NaN if the target could not be computed.
+    pub fn difficulty(&self) -> f64 {
+        let to_f64 = |hash: Hash| hash.0.iter().fold(0.0, |acc, byte| acc * 256.0 + f64::from(*byte));
+        match (Hash::from_bits(DIFFICULTY_1_BITS), self.target()) {
+            (Some(max_target), Some(target)) => to_f64(max_target) / to_f64(target),
+            _ => f64::NAN,
+        }
+    }
+
+    /// Returns the expected number of hashes needed to find a header that meets
+    /// this header's target, which is 2^256 / (target + 1). This is how much the
+    /// block counts towards the total work of a chain. Returns 0 if the target
+    /// could not be computed, and saturates at `u128::MAX` for targets below
+    /// 2^128, which are far harder than anything ever mined.
+    pub fn work(&self) -> u128 {
+        let target = match self.target() {
+            Some(target) => target,
+            None => return 0,
+        };
+        let hi = u128::from_be_bytes(target.0[..16].try_into().unwrap());
+        let lo = u128::from_be_bytes(target.0[16..].try_into().unwrap());
+        if hi == 0 {
+            return u128::MAX;
+        }
+        if hi == u128::MAX && lo == u128::MAX {
+            return 1;
+        }
+        // As in the reference implementation, 2^256 / (target + 1) is computed as
+        // !target / (target + 1) + 1, which keeps everything within 256 bits. This
+        // is a long division one bit at a time; the quotient is known to fit in 128
+        // bits because the target is at least 2^128.
+        let (divisor_lo, carry) = lo.overflowing_add(1);
+        let divisor = (hi + u128::from(carry), divisor_lo);
+        let dividend = (!hi, !lo);
+        let mut remainder = (0u128, 0u128);
+        let mut quotient = 0u128;
+        for bit in (0..256).rev() {
+            let dividend_bit = if bit >= 128 { dividend.0 >> (bit - 128) } else { dividend.1 >> bit } & 1;
+            // The remainder is less than the divisor, so shifting it can overflow
+            // 256 bits if the divisor is above 2^255
+            let overflow = remainder.0 >> 127 != 0;
+            remainder = ((remainder.0 << 1) | (remainder.1 >> 127), (remainder.1 << 1) | dividend_bit);
+            quotient <<= 1;
+            if overflow || remainder >= divisor {
+                let (rem_lo, borrow) = remainder.1.overflowing_sub(divisor.1);
+                remainder = (remainder.0.wrapping_sub(divisor.0).wrapping_sub(u128::from(borrow)), rem_lo);
+                quotient |= 1;
+            }
+        }
+        quotient + 1
+    }
+}
+
 #[allow(missing_docs)]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Block {
     pub network: Network,
     pub header: BlockHeader,
@@ -298,51 +555,253 @@ pub struct Block {
 impl Block {
     /// Computes the block hash, which is a double SHA-256 hash of the block header.
     pub fn id(&self) -> Hash {
-        hash::double_sha256(&self.header)
+        self.header.id()
+    }
+
+    /// Returns the coinbase transaction, which must be the first transaction in
+    /// the block. Returns None if the block is empty or if the first transaction
+    /// isn't a coinbase (in which case the block is invalid).
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first().filter(|t| t.is_coinbase())
+    }
+
+    /// Returns the size in bytes of the serialized block, excluding the network
+    /// magic and size prefix. This is the size that is recorded in the size
+    /// prefix of the block.
+    pub fn serialized_size(&self) -> usize {
+        80 + parse::compact_size_len(self.transactions.len()) + self.transactions.iter().map(Transaction::serialized_len).sum::<usize>()
+    }
+
+    /// Iterates over all the transaction outputs in the block, in order. Each
+    /// output is yielded along with its outpoint, i.e. the id of the containing
+    /// transaction and the index of the output within that transaction.
+    pub fn iter_outputs(&self) -> impl Iterator<Item = (Hash, u32, &TransactionOutput)> {
+        self.transactions.iter().flat_map(|transaction| {
+            let txid = transaction.txid();
+            transaction.outputs.iter().enumerate().map(move |(vout, output)| (txid, vout as u32, output))
+        })
+    }
+
+    /// Iterates over all the transaction inputs in the block, in order. Each
+    /// input is yielded along with the id of the containing transaction and
+    /// the index of the input within that transaction.
+    pub fn iter_inputs(&self) -> impl Iterator<Item = (Hash, u32, &TransactionInput)> {
+        self.transactions.iter().flat_map(|transaction| {
+            let txid = transaction.txid();
+            transaction.inputs.iter().enumerate().map(move |(vin, input)| (txid, vin as u32, input))
+        })
+    }
+
+    /// Computes the total fees paid by the transactions in this block, i.e. the
+    /// sum of the input values minus the sum of the output values across all the
+    /// non-coinbase transactions. The values of the outputs being spent must be
+    /// provided in `prevouts`, keyed by txid and output index. Outputs created
+    /// earlier in the same block are looked up in the block itself, so they
+    /// don't need to be in `prevouts`.
+    pub fn total_fees(&self, prevouts: &HashMap<(Hash, u32), u64>) -> Result<u64, BlockValidationError> {
+        // Only outputs of transactions before the one being looked at can be spent,
+        // so each transaction's outputs are added after its inputs are resolved
+        let mut block_outputs: HashMap<(Hash, u32), u64> = HashMap::new();
+        let mut fees = Vec::new();
+        for transaction in &self.transactions {
+            let txid = transaction.txid();
+            if !transaction.is_coinbase() {
+                let mut input_values = Vec::with_capacity(transaction.inputs.len());
+                for (vin, input) in transaction.inputs.iter().enumerate() {
+                    let prevout = (input.txid, input.vout);
+                    let value = prevouts.get(&prevout).or_else(|| block_outputs.get(&prevout))
+                        .ok_or(BlockValidationError::MissingPrevout { txid, vin: vin as u32, prevout })?;
+                    input_values.push(*value);
+                }
+                let input_value = checked_sum_transaction_values(txid, input_values)?;
+                let output_value = checked_sum_transaction_values(txid, transaction.outputs.iter().map(|output| output.value))?;
+                let fee = input_value.checked_sub(output_value)
+                    .ok_or(BlockValidationError::OutputsExceedInputs { txid, input_value, output_value })?;
+                fees.push(fee);
+            }
+            block_outputs.extend(transaction.outputs.iter().enumerate().map(|(vout, output)| ((txid, vout as u32), output.value)));
+        }
+        checked_sum_values(fees)
+    }
+
+    /// Returns the total value of all the outputs of all the transactions in the
+    /// block, including the coinbase, as shown by block explorers. The sum
+    /// saturates at `u64::MAX` rather than overflowing; that can only happen for
+    /// an invalid block, since the total money supply is far smaller. Use
+    /// `checked_sum_values` instead where invalid values need to be detected.
+    pub fn total_output_value(&self) -> u64 {
+        self.transactions.iter()
+            .flat_map(|transaction| transaction.outputs.iter())
+            .fold(0u64, |sum, output| sum.saturating_add(output.value))
     }
 
     /// Computes the merkle root of the block by hashing the transactions in a merkle
     /// tree format. Note that this computes the merkle root and doesn't just return
-    /// the merkle root from the header.
+    /// the merkle root from the header. By convention the merkle root of a block
+    /// with no transactions is `Hash::zero()`, although such a block is never valid.
     pub fn computed_merkle_root(&self) -> Hash {
-        if self.transactions.is_empty() {
-            return Hash::zero();
-        }
+        self.compute_merkle_tree().0
+    }
 
-        let adjust_count = |count| {
-            match count {
-                1 => 1,
-                c if (c % 2) == 1 => c + 1,
-                c => c,
-            }
-        };
+    /// Returns true if the merkle tree for this block has two identical sibling
+    /// nodes. Since odd layers are padded by duplicating the last hash, such a
+    /// block has the same merkle root as the block with the duplicated
+    /// transactions removed (CVE-2012-2459). A block like this is always invalid,
+    /// but the block it is mutated from may not be.
+    pub(crate) fn is_merkle_tree_mutated(&self) -> bool {
+        self.compute_merkle_tree().1
+    }
 
-        let mut layer_size = adjust_count(self.transactions.len());
-        let mut layer_hashes = Vec::with_capacity(layer_size);
-        for transaction in &self.transactions {
-            layer_hashes.push(hash::double_sha256(&transaction.strip_witness_data()).reverse());
+    // Computes the merkle root and whether or not the tree is mutated.
+    fn compute_merkle_tree(&self) -> (Hash, bool) {
+        compute_merkle_tree(self.transactions.iter().map(|t| t.txid().reverse()).collect())
+    }
+
+    /// Computes the witness merkle root of the block, as defined in BIP141. This
+    /// is like the merkle root but computed using the wtxid of each transaction
+    /// instead of the txid. The coinbase transaction's wtxid is taken to be zero.
+    pub fn computed_witness_merkle_root(&self) -> Hash {
+        let leaf_hashes = self.transactions.iter().enumerate().map(|(i, t)| {
+            if i == 0 { Hash::zero() } else { t.wtxid().reverse() }
+        }).collect();
+        compute_merkle_tree(leaf_hashes).0
+    }
+
+    /// Returns the witness commitment from the coinbase transaction, if there is
+    /// one. Per BIP141 this is the last coinbase output whose script starts with
+    /// the 6-byte header `OP_RETURN 0x24 0xaa21a9ed`, followed by the 32-byte
+    /// commitment. Outputs with any other prefix are not commitments.
+    pub fn witness_commitment(&self) -> Option<Hash> {
+        let coinbase = self.coinbase()?;
+        coinbase.outputs.iter().rev()
+            .find(|output| output.lock_script.len() >= 38 && output.lock_script[0..6] == WITNESS_COMMITMENT_HEADER)
+            .map(|output| {
+                let mut commitment = [0; 32];
+                commitment.copy_from_slice(&output.lock_script[6..38]);
+                Hash(commitment).reverse()
+            })
+    }
+
+    /// Computes a merkle proof for the transaction at the given index in the
+    /// block. The proof is the list of sibling hashes along the path from the
+    /// transaction to the merkle root, starting at the bottom of the tree. This
+    /// can be checked against the merkle root using `verify_merkle_proof`.
+    /// Returns None if the index is out of range.
+    pub fn merkle_proof(&self, tx_index: usize) -> Option<Vec<Hash>> {
+        if tx_index >= self.transactions.len() {
+            return None;
         }
 
-        while layer_size > 1 {
-            if layer_size > layer_hashes.len() {
+        let mut layer_hashes = self.transactions.iter().map(|t| t.txid().reverse()).collect::<Vec<Hash>>();
+        let mut index = tx_index;
+        let mut branch = Vec::new();
+        while layer_hashes.len() > 1 {
+            // Odd layers get the last hash duplicated, same as in computed_merkle_root
+            if (layer_hashes.len() % 2) == 1 {
                 layer_hashes.push(*layer_hashes.last().unwrap());
             }
-            assert!(layer_hashes.len() == layer_size);
-            assert!((layer_size % 2) == 0);
-
-            let next_layer_size = adjust_count(layer_size / 2);
-            let mut next_hashes = Vec::with_capacity(next_layer_size);
-            for i in (0..layer_size).step_by(2) {
-                let first_hash = hmac_sha256::Hash::hash(&[layer_hashes[i].0, layer_hashes[i + 1].0].concat());
-                let second_hash = hmac_sha256::Hash::hash(&first_hash);
-                next_hashes.push(Hash(second_hash));
+            branch.push(layer_hashes[index ^ 1].reverse());
+            layer_hashes = layer_hashes.chunks(2).map(|pair| hash::merkle_parent(&pair[0], &pair[1])).collect();
+            index /= 2;
+        }
+        Some(branch)
+    }
+}
+
+// Computes the merkle root and whether or not the tree is mutated, given the
+// leaf hashes in their natural byte order.
+fn compute_merkle_tree(leaf_hashes: Vec<Hash>) -> (Hash, bool) {
+    if leaf_hashes.is_empty() {
+        return (Hash::zero(), false);
+    }
+
+    let adjust_count = |count| {
+        match count {
+            1 => 1,
+            c if (c % 2) == 1 => c + 1,
+            c => c,
+        }
+    };
+
+    let mut layer_size = adjust_count(leaf_hashes.len());
+    let mut layer_hashes = leaf_hashes;
+
+    let mut mutated = false;
+    while layer_size > 1 {
+        let unpadded_size = layer_hashes.len();
+        if layer_size > layer_hashes.len() {
+            layer_hashes.push(*layer_hashes.last().unwrap());
+        }
+        assert!(layer_hashes.len() == layer_size);
+        assert!((layer_size % 2) == 0);
+
+        let next_layer_size = adjust_count(layer_size / 2);
+        let mut next_hashes = Vec::with_capacity(next_layer_size);
+        for i in (0..layer_size).step_by(2) {
+            if i + 1 < unpadded_size && layer_hashes[i] == layer_hashes[i + 1] {
+                mutated = true;
             }
+            next_hashes.push(hash::merkle_parent(&layer_hashes[i], &layer_hashes[i + 1]));
+        }
+
+        layer_size = next_layer_size;
+        layer_hashes = next_hashes;
+    }
 
-            layer_size = next_layer_size;
-            layer_hashes = next_hashes;
+    (layer_hashes.first().unwrap().reverse(), mutated)
+}
+
+/// Verifies a merkle proof, as produced by `Block::merkle_proof`. Given the id
+/// of a transaction and its index in the block, this hashes up the tree using
+/// the sibling hashes in the branch, and checks that the result is the given
+/// merkle root.
+pub fn verify_merkle_proof(txid: Hash, index: usize, branch: &[Hash], root: Hash) -> bool {
+    let mut computed = txid.reverse();
+    let mut index = index;
+    for sibling in branch {
+        computed = if index % 2 == 0 {
+            hash::merkle_parent(&computed, &sibling.reverse())
+        } else {
+            hash::merkle_parent(&sibling.reverse(), &computed)
+        };
+        index /= 2;
+    }
+    computed.reverse() == root
+}
+
+/// Sums the given values, which are amounts in satoshis. Returns an error if
+/// any of the values, or the sum so far, exceeds `MAX_MONEY`; as with Bitcoin
+/// Core's `MoneyRange` checks, this keeps the sum from ever overflowing a
+/// `u64`. Values in blocks and transactions are controlled by whoever created
+/// them, so they should always be summed with this function rather than with
+/// plain addition.
+pub fn checked_sum_values(values: impl IntoIterator<Item = u64>) -> Result<u64, BlockValidationError> {
+    let mut sum = 0u64;
+    for value in values {
+        if value > MAX_MONEY {
+            return Err(BlockValidationError::ValueOutOfRange { value });
         }
+        sum += value;
+        if sum > MAX_MONEY {
+            return Err(BlockValidationError::ValueOutOfRange { value: sum });
+        }
+    }
+    Ok(sum)
+}
+
+// Same as `checked_sum_values`, for the input or output values of the given
+// transaction, so that the error says which transaction is at fault.
+pub(crate) fn checked_sum_transaction_values(txid: Hash, values: impl IntoIterator<Item = u64>) -> Result<u64, BlockValidationError> {
+    checked_sum_values(values).map_err(|e| match e {
+        BlockValidationError::ValueOutOfRange { value } => BlockValidationError::ValueOverflow { txid, value },
+        e => e,
+    })
+}
 
-        layer_hashes.first().unwrap().reverse()
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.outputs.iter().fold(0u64, |sum, output| sum.saturating_add(output.value));
+        write!(f, "txid:{} version:{} inputs:{} outputs:{} value:{} locktime:{}", self.txid(), self.version, self.inputs.len(), self.outputs.len(), value, self.locktime)
     }
 }
 
@@ -353,7 +812,7 @@ impl fmt::Display for Block {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
@@ -384,4 +843,198 @@ mod tests {
         assert_eq!(Hash::from_bits(0xffabcdef), None);
         assert_eq!(Hash::from_bits(0xff000000).unwrap().to_string(), "0000000000000000000000000000000000000000000000000000000000000000");
     }
+
+    #[test]
+    fn bits_to_hash_strict() {
+        assert_eq!(Hash::from_bits_strict(0x1d00ffff), Hash::from_bits(0x1d00ffff));
+        assert_eq!(Hash::from_bits_strict(0x207fffff).unwrap().to_string(), "7fffff0000000000000000000000000000000000000000000000000000000000");
+
+        // The sign bit makes the target negative
+        assert!(Hash::from_bits(0x20ffffff).is_some());
+        assert_eq!(Hash::from_bits_strict(0x20ffffff), None);
+        assert_eq!(Hash::from_bits_strict(0x1d80ffff), None);
+        assert_eq!(Hash::from_bits_strict(0x03800001), None);
+        // Negative zero is just zero
+        assert_eq!(Hash::from_bits_strict(0x03800000), Some(Hash::zero()));
+
+        // Targets that don't fit in 256 bits
+        assert_eq!(Hash::from_bits_strict(0x2101cdef), None);
+        assert_eq!(Hash::from_bits_strict(0x220001ef), None);
+        assert_eq!(Hash::from_bits_strict(0x23000001), None);
+        assert_eq!(Hash::from_bits_strict(0x220000ef).unwrap().to_string(), "ef00000000000000000000000000000000000000000000000000000000000000");
+    }
+
+    #[test]
+    fn header_midstate() {
+        let mut header = template::BlockTemplate::new(Hash([0x42; 32])).time(1234567890).bits(0x1d00ffff).build().header;
+        let midstate = hash::HeaderMidstate::new(&header);
+        for nonce in [0, 1, 2, 0x12345678, u32::MAX] {
+            header.nonce = nonce;
+            assert_eq!(midstate.id(nonce), header.id());
+        }
+    }
+
+    // Builds a transaction spending the given outputs and creating outputs
+    // with the given values, for tests that don't care about the scripts.
+    pub(crate) fn transaction(spends: &[(Hash, u32)], values: &[u64]) -> Transaction {
+        Transaction {
+            version: 1,
+            flags: TransactionFlags::empty(),
+            inputs: spends.iter().map(|(txid, vout)| TransactionInput {
+                txid: *txid,
+                vout: *vout,
+                unlock_script: vec![0x51],
+                sequence: 0xffffffff,
+                witness_stuff: vec![],
+                prevout_value: None,
+                prevout_script: None,
+            }).collect(),
+            outputs: values.iter().map(|value| TransactionOutput {
+                value: *value,
+                lock_script: vec![0x51],
+            }).collect(),
+            locktime: 0,
+        }
+    }
+
+    #[test]
+    fn total_fees() {
+        let funding_a = Hash([0xaa; 32]);
+        let funding_b = Hash([0xbb; 32]);
+        let coinbase = transaction(&[(Hash::zero(), 0xffffffff)], &[5_000_000_000]);
+        let tx_1 = transaction(&[(funding_a, 0), (funding_b, 1)], &[700, 200]);
+        // Spends an output of tx_1, which isn't in the prevout map
+        let tx_2 = transaction(&[(tx_1.txid(), 0)], &[650]);
+        let block = template::BlockTemplate::new(Hash::zero()).transactions(vec![coinbase.clone(), tx_1.clone(), tx_2]).build();
+
+        let mut prevouts = HashMap::new();
+        prevouts.insert((funding_a, 0), 1000);
+        prevouts.insert((funding_b, 1), 500);
+        // (1000 + 500 - 700 - 200) + (700 - 650)
+        assert_eq!(block.total_fees(&prevouts).unwrap(), 650);
+
+        // Outputs created later in the block can't be spent
+        let tx_2 = block.transactions[2].clone();
+        let block = template::BlockTemplate::new(Hash::zero()).transactions(vec![coinbase.clone(), tx_2.clone(), tx_1.clone()]).build();
+        match block.total_fees(&prevouts) {
+            Err(BlockValidationError::MissingPrevout { txid, vin: 0, prevout }) => {
+                assert_eq!(txid, tx_2.txid());
+                assert_eq!(prevout, (tx_1.txid(), 0));
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // A coinbase-only block has no fees
+        let block = template::BlockTemplate::new(Hash::zero()).transaction(coinbase.clone()).build();
+        assert_eq!(block.total_fees(&HashMap::new()).unwrap(), 0);
+
+        prevouts.remove(&(funding_b, 1));
+        let block = template::BlockTemplate::new(Hash::zero()).transactions(vec![coinbase.clone(), tx_1.clone()]).build();
+        match block.total_fees(&prevouts) {
+            Err(BlockValidationError::MissingPrevout { txid, vin: 1, prevout }) => {
+                assert_eq!(txid, tx_1.txid());
+                assert_eq!(prevout, (funding_b, 1));
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        prevouts.insert((funding_b, 1), 100);
+        let tx_3 = transaction(&[(tx_1.txid(), 0)], &[800]);
+        let tx_3_txid = tx_3.txid();
+        let block = template::BlockTemplate::new(Hash::zero()).transactions(vec![coinbase.clone(), tx_1.clone(), tx_3]).build();
+        match block.total_fees(&prevouts) {
+            Err(BlockValidationError::OutputsExceedInputs { txid, input_value: 700, output_value: 800 }) => assert_eq!(txid, tx_3_txid),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Input values adding up to more than MAX_MONEY are reported with the txid
+        prevouts.insert((funding_a, 0), MAX_MONEY);
+        let block = template::BlockTemplate::new(Hash::zero()).transactions(vec![coinbase, tx_1.clone()]).build();
+        match block.total_fees(&prevouts) {
+            Err(BlockValidationError::ValueOverflow { txid, value }) => {
+                assert_eq!(txid, tx_1.txid());
+                assert_eq!(value, MAX_MONEY + 100);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn value_summation() {
+        assert_eq!(checked_sum_values([]).unwrap(), 0);
+        assert_eq!(checked_sum_values([MAX_MONEY - 1, 1]).unwrap(), MAX_MONEY);
+        match checked_sum_values([MAX_MONEY, 1]) {
+            Err(BlockValidationError::ValueOutOfRange { value }) => assert_eq!(value, MAX_MONEY + 1),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match checked_sum_values([5, MAX_MONEY + 1]) {
+            Err(BlockValidationError::ValueOutOfRange { value }) => assert_eq!(value, MAX_MONEY + 1),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // The sum is checked as it goes, so values that would overflow a u64
+        // are caught once they exceed MAX_MONEY
+        match checked_sum_values(vec![MAX_MONEY; 10_000]) {
+            Err(BlockValidationError::ValueOutOfRange { value }) => assert_eq!(value, 2 * MAX_MONEY),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match checked_sum_values([MAX_MONEY, u64::MAX]) {
+            Err(BlockValidationError::ValueOutOfRange { value }) => assert_eq!(value, u64::MAX),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn hash_to_bits() {
+        for bits in [0x1d00ffff, 0x1903a30c, 0x1b0404cb, 0x207fffff, 0x170331db, 0x03123456, 0x01120000] {
+            let target = Hash::from_bits(bits).unwrap();
+            assert_eq!(target.to_bits(), bits, "{:#x}", bits);
+            assert_eq!(Hash::from_bits(target.to_bits()), Some(target));
+        }
+        assert_eq!(Hash::zero().to_bits(), 0);
+
+        // A coefficient with the high bit set gets a zero byte prepended
+        let mut target = Hash::zero();
+        target.0[31] = 0x80;
+        assert_eq!(target.to_bits(), 0x02008000);
+        assert_eq!(Hash::from_bits_strict(target.to_bits()), Some(target));
+        target.0[0] = 0xff;
+        assert_eq!(target.to_bits(), 0x2100ff00);
+
+        // Non-minimal encodings are normalized, and low bytes are truncated
+        assert_eq!(Hash::from_bits(0x04000001).unwrap().to_bits(), 0x02010000);
+        let mut target = Hash::from_bits(0x1d00ffff).unwrap();
+        target.0[31] = 0x01;
+        assert_eq!(target.to_bits(), 0x1d00ffff);
+    }
+
+    #[test]
+    fn coinbase() {
+        let block_0 = parse::parse_blockfile(&parse::tests::read_testdata("block_0.dat")).unwrap().pop().unwrap();
+        assert_eq!(block_0.coinbase(), Some(&block_0.transactions[0]));
+
+        let spend = transaction(&[(Hash([0xaa; 32]), 0)], &[1000]);
+        let block = template::BlockTemplate::new(Hash::zero()).transaction(spend).build();
+        assert_eq!(block.coinbase(), None);
+        let block = template::BlockTemplate::new(Hash::zero()).build();
+        assert_eq!(block.coinbase(), None);
+    }
+
+    #[test]
+    fn header_to_bytes() {
+        let block_0 = parse::parse_blockfile(&parse::tests::read_testdata("block_0.dat")).unwrap().pop().unwrap();
+        let bytes = block_0.header.to_bytes();
+        assert_eq!(Hash(hash::sha256d(&bytes)).reverse(), block_0.id());
+        assert_eq!(BlockHeader::deserialize_le(&bytes, &mut 0).unwrap(), block_0.header);
+    }
+
+    #[test]
+    fn total_output_value() {
+        let block_0 = parse::parse_blockfile(&parse::tests::read_testdata("block_0.dat")).unwrap().pop().unwrap();
+        assert_eq!(block_0.total_output_value(), 5_000_000_000);
+
+        let block = template::BlockTemplate::new(Hash::zero())
+            .transactions(vec![transaction(&[], &[u64::MAX - 1]), transaction(&[], &[1, 1])])
+            .build();
+        assert_eq!(block.total_output_value(), u64::MAX);
+    }
 }
@@ -0,0 +1,95 @@
+//! A module that exposes parsing of blocks from memory-mapped files. This is
+//! only available with the `mmap` feature.
+
+use crate::{Block, BlockParseError, LittleEndianSerialization};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// A block file (such as Bitcoin Core's `blk*.dat` files) that is memory-mapped
+/// rather than read into memory, so that large files can be parsed without
+/// copying them. The blocks in the file can be iterated with `blocks`. The
+/// parsed blocks are copies of the data, so they remain valid after the mapping
+/// is dropped.
+pub struct MappedBlockFile {
+    mmap: Mmap,
+}
+
+impl MappedBlockFile {
+    /// Memory-maps the file at the given path.
+    ///
+    /// # Safety
+    ///
+    /// The contents of a memory-mapped file can change underneath the mapping if
+    /// the file is modified by another process (or truncated, which may crash
+    /// this process with a SIGBUS). The caller must ensure that the file isn't
+    /// modified while it is mapped, i.e. for as long as the returned value and
+    /// anything borrowed from it are alive; in the case of Bitcoin Core this
+    /// means only mapping block files that are no longer being written to.
+    #[allow(unsafe_code)]
+    pub unsafe fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the caller guarantees that the file isn't modified while mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Self {
+            mmap,
+        })
+    }
+
+    /// Returns the raw bytes of the file.
+    pub fn bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Returns an iterator over the blocks in the file, in file order. The same
+    /// rules as `parse_blockfile` apply; if a block fails to parse, the error is
+    /// returned and the iteration stops.
+    pub fn blocks(&self) -> Blocks<'_> {
+        Blocks {
+            bytes: &self.mmap,
+            ix: 0,
+            failed: false,
+        }
+    }
+}
+
+/// An iterator over the blocks in a `MappedBlockFile`.
+pub struct Blocks<'a> {
+    bytes: &'a [u8],
+    ix: usize,
+    failed: bool,
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<Block, BlockParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.ix >= self.bytes.len() {
+            return None;
+        }
+        let result = Block::deserialize_le(self.bytes, &mut self.ix);
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_blockfile;
+    use super::*;
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn mapped_block_0() {
+        // Safety: the test data is never modified
+        let file = unsafe { MappedBlockFile::open("testdata/block_0.dat") }.unwrap();
+        let blocks = file.blocks().collect::<Result<Vec<Block>, BlockParseError>>().unwrap();
+        let expected = parse_blockfile(file.bytes()).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].id(), expected[0].id());
+        assert_eq!(blocks[0].id().to_string(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+
+        assert!(unsafe { MappedBlockFile::open("testdata/nonexistent.dat") }.is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! A module that exposes parsing and serialization of P2P network messages.
+//! Each message is wrapped in an envelope containing the network magic, the
+//! command name, and the length and checksum of the payload.
+
+use crate::{Block, BlockHeader, BlockParseError, LittleEndianSerialization, Network};
+use crate::hash::sha256d;
+use crate::parse::{read_bytes, IntoUsize};
+
+const COMMAND_SIZE: usize = 12;
+
+/// The maximum number of headers that a peer may send in a single `headers`
+/// message.
+pub const MAX_HEADERS_COUNT: usize = 2000;
+
+/// The payload of a P2P message. Only some commands are currently supported.
+#[derive(Debug)]
+pub enum Payload {
+    /// A `block` message, containing a single full block.
+    Block(Block),
+    /// A `headers` message, containing a list of block headers.
+    Headers(Vec<BlockHeader>),
+}
+
+impl Payload {
+    /// Returns the command name for this payload, as used in the message envelope.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Payload::Block(_) => "block",
+            Payload::Headers(_) => "headers",
+        }
+    }
+
+    fn serialize(&self, dest: &mut Vec<u8>) {
+        match self {
+            Payload::Block(block) => block.serialize_raw(dest),
+            Payload::Headers(headers) => {
+                headers.len().serialize_le(dest);
+                for header in headers {
+                    header.serialize_le(dest);
+                    // Each header is followed by an empty transaction count
+                    0usize.serialize_le(dest);
+                }
+            }
+        }
+    }
+
+    fn deserialize(command: &str, bytes: &[u8], ix: &mut usize, network: &Network) -> Result<Self, BlockParseError> {
+        match command {
+            "block" => Ok(Payload::Block(Block::deserialize_raw(bytes, ix, network.clone())?)),
+            "headers" => Ok(Payload::Headers(read_headers(bytes, ix)?)),
+            _ => Err(BlockParseError::new(format!("Unsupported P2P message command {:?}", command))),
+        }
+    }
+}
+
+fn read_headers(bytes: &[u8], ix: &mut usize) -> Result<Vec<BlockHeader>, BlockParseError> {
+    let count_ix = *ix;
+    let count = usize::deserialize_le(bytes, ix)?;
+    if count > MAX_HEADERS_COUNT {
+        return Err(BlockParseError::new(format!("Too many headers ({}) in headers message at index {}", count, count_ix)));
+    }
+    let mut headers = Vec::with_capacity(count);
+    for _ in 0..count {
+        headers.push(BlockHeader::deserialize_le(bytes, ix)?);
+        let transaction_count = usize::deserialize_le(bytes, ix)?;
+        if transaction_count != 0 {
+            return Err(BlockParseError::new(format!("Unexpected transaction count {} in headers message at index {}", transaction_count, *ix - 1)));
+        }
+    }
+    Ok(headers)
+}
+
+/// Parses the payload of a P2P `headers` message (without the message envelope)
+/// into a list of block headers. Each header in the payload is followed by a
+/// transaction count, which must be zero.
+pub fn parse_headers_message(bytes: &[u8]) -> Result<Vec<BlockHeader>, BlockParseError> {
+    let mut ix = 0;
+    let headers = read_headers(bytes, &mut ix)?;
+    if ix != bytes.len() {
+        return Err(BlockParseError::new(format!("Unexpected data after headers message at index {}", ix)));
+    }
+    Ok(headers)
+}
+
+/// A single P2P message, including the network it is for.
+#[derive(Debug)]
+pub struct Message {
+    /// The network the message is for, which is encoded in the envelope as
+    /// the network magic.
+    pub network: Network,
+    /// The message payload.
+    pub payload: Payload,
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = sha256d(payload);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+impl LittleEndianSerialization for Message {
+    fn serialize_le(&self, dest: &mut Vec<u8>) {
+        self.network.serialize_le(dest);
+        let mut command = [0u8; COMMAND_SIZE];
+        let name = self.payload.command().as_bytes();
+        command[..name.len()].copy_from_slice(name);
+        dest.extend(command);
+
+        let mut payload = Vec::new();
+        self.payload.serialize(&mut payload);
+        (payload.len() as u32).serialize_le(dest);
+        dest.extend(checksum(&payload));
+        dest.extend(payload);
+    }
+
+    fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
+        let network = Network::deserialize_le(bytes, ix)?;
+        if let Network::Unknown(magic) = network {
+            return Err(BlockParseError::new(format!("Unrecognized network magic value {:02x?} at index {}", magic, *ix - 4)));
+        }
+        let command_ix = *ix;
+        let command = read_bytes(bytes, ix, COMMAND_SIZE)?;
+        // The command is ASCII, padded with trailing nulls
+        let name_len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+        if command[name_len..].iter().any(|b| *b != 0) || !command[..name_len].is_ascii() {
+            return Err(BlockParseError::new(format!("Malformed P2P message command at index {}", command_ix)));
+        }
+        let command = String::from_utf8_lossy(&command[..name_len]).into_owned();
+
+        let length = u32::deserialize_le(bytes, ix)?.usize()?;
+        let expected_checksum = read_bytes(bytes, ix, 4)?;
+        let payload_bytes = read_bytes(bytes, ix, length)?;
+        if checksum(&payload_bytes) != expected_checksum[..] {
+            return Err(BlockParseError::new(format!("Checksum mismatch for P2P {:?} message at index {}", command, command_ix - 4)));
+        }
+
+        let mut payload_ix = 0;
+        let payload = Payload::deserialize(&command, &payload_bytes, &mut payload_ix, &network)?;
+        if payload_ix != payload_bytes.len() {
+            return Err(BlockParseError::new(format!("Unexpected data after P2P {:?} message payload; payload ended at {} but length was {}", command, payload_ix, length)));
+        }
+
+        Ok(Message {
+            network,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_blockfile;
+    use crate::parse::tests::read_testdata;
+    use crate::Hash;
+    use super::*;
+
+    #[test]
+    fn block_message_round_trip() {
+        let data = read_testdata("block_0.dat");
+        let block_0 = parse_blockfile(&data).unwrap().pop().unwrap();
+        let message = Message {
+            network: Network::MainNet,
+            payload: Payload::Block(block_0.clone()),
+        };
+        let mut serialized = Vec::new();
+        message.serialize_le(&mut serialized);
+
+        assert_eq!(serialized[0..4], [0xf9, 0xbe, 0xb4, 0xd9]);
+        assert_eq!(&serialized[4..16], b"block\0\0\0\0\0\0\0");
+        assert_eq!(serialized[16..20], [0x1d, 0x01, 0x00, 0x00]);
+        assert_eq!(serialized[20..24], checksum(&data[8..]));
+        assert_eq!(serialized[24..], data[8..]);
+
+        let mut ix = 0;
+        let parsed = Message::deserialize_le(&serialized, &mut ix).unwrap();
+        assert_eq!(ix, serialized.len());
+        assert_eq!(parsed.network, Network::MainNet);
+        match parsed.payload {
+            Payload::Block(block) => assert_eq!(block.id(), block_0.id()),
+            payload => panic!("Unexpected payload {:?}", payload),
+        };
+
+        let mut corrupted = serialized.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(Message::deserialize_le(&corrupted, &mut 0).is_err());
+
+        let mut unknown = serialized.clone();
+        unknown[4..9].copy_from_slice(b"bloop");
+        assert!(Message::deserialize_le(&unknown, &mut 0).is_err());
+
+        // Messages for unknown networks are rejected
+        let mut foreign = serialized;
+        foreign[0..4].copy_from_slice(&[0x01, 0x02, 0x03, 0x04]);
+        assert!(Message::deserialize_le(&foreign, &mut 0).is_err());
+    }
+
+    #[test]
+    fn absurd_transaction_count() {
+        // A header followed by a transaction count of u64::MAX, in an envelope
+        // with a valid checksum
+        let mut payload = read_testdata("block_0.dat")[8..88].to_vec();
+        payload.push(0xff);
+        payload.extend(u64::MAX.to_le_bytes());
+        let mut serialized = vec![0xf9, 0xbe, 0xb4, 0xd9];
+        serialized.extend(b"block\0\0\0\0\0\0\0");
+        serialized.extend((payload.len() as u32).to_le_bytes());
+        serialized.extend(checksum(&payload));
+        serialized.extend(&payload);
+
+        let err = Message::deserialize_le(&serialized, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), format!("Block transaction count {} at index 80 exceeds the remaining data", u64::MAX));
+    }
+
+    #[test]
+    fn headers_message_round_trip() {
+        let blocks = ["block_0.dat", "block_265458.dat", "block_481829.dat"]
+            .iter()
+            .map(|file| parse_blockfile(&read_testdata(file)).unwrap().pop().unwrap())
+            .collect::<Vec<Block>>();
+        let message = Message {
+            network: Network::MainNet,
+            payload: Payload::Headers(blocks.iter().map(|b| b.header.clone()).collect()),
+        };
+        let mut serialized = Vec::new();
+        message.serialize_le(&mut serialized);
+        assert_eq!(&serialized[4..16], b"headers\0\0\0\0\0");
+        assert_eq!(serialized.len(), 24 + 1 + 3 * 81);
+
+        let parsed = Message::deserialize_le(&serialized, &mut 0).unwrap();
+        match parsed.payload {
+            Payload::Headers(headers) => {
+                assert_eq!(headers.len(), 3);
+                for (header, block) in headers.iter().zip(blocks.iter()) {
+                    assert_eq!(header.merkle_root, block.header.merkle_root);
+                    assert_eq!(header.nonce, block.header.nonce);
+                }
+            }
+            payload => panic!("Unexpected payload {:?}", payload),
+        };
+    }
+
+    #[test]
+    fn parse_headers() {
+        let mut payload = vec![2];
+        for file in ["block_0.dat", "block_265458.dat"] {
+            // Skip the magic and size prefix to get the 80-byte header
+            payload.extend(&read_testdata(file)[8..88]);
+            payload.push(0);
+        }
+        let headers = parse_headers_message(&payload).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].prev_block_hash, Hash([0; 32]));
+        assert_eq!(headers[1].merkle_root.to_string(), "501174c68520c1d23bea38774b2dac1d26d4a6c34daef6638762731e78ab1c06");
+
+        let mut bad_count = payload.clone();
+        bad_count[81] = 1;
+        assert!(parse_headers_message(&bad_count).is_err());
+
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        assert!(parse_headers_message(&trailing).is_err());
+
+        assert!(parse_headers_message(&payload[..payload.len() - 1]).is_err());
+        assert!(parse_headers_message(&[0xfd, 0xd1, 0x07]).is_err());
+        assert_eq!(parse_headers_message(&[0]).unwrap().len(), 0);
+    }
+}
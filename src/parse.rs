@@ -1,6 +1,13 @@
 //! A module that exposes a block parsing API.
 
 use crate::{Block, BlockHeader, BlockParseError, Hash, LittleEndianSerialization, Network, Transaction, TransactionFlags, TransactionInput, TransactionOutput};
+use log::warn;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// The target used for log records emitted while parsing.
+pub const PARSE_LOG_TARGET: &str = "blocktastic::parse";
 
 impl LittleEndianSerialization for Network {
     fn serialize_le(&self, dest: &mut Vec<u8>) {
@@ -8,6 +15,7 @@ impl LittleEndianSerialization for Network {
             Network::MainNet => dest.extend(vec![0xf9, 0xbe, 0xb4, 0xd9]),
             Network::TestNet3 => dest.extend(vec![0x0b, 0x11, 0x09, 0x07]),
             Network::RegTest => dest.extend(vec![0xfa, 0xbf, 0xb5, 0xda]),
+            Network::Unknown(magic) => dest.extend(magic),
         }
     }
 
@@ -16,7 +24,7 @@ impl LittleEndianSerialization for Network {
             0xd9b4bef9 => Ok(Network::MainNet),
             0x0709110b => Ok(Network::TestNet3),
             0xdab5bffa => Ok(Network::RegTest),
-            magic => Err(BlockParseError::new(format!("Unrecognized network magic value {:#x} at index {}", magic, *ix - 4))),
+            magic => Ok(Network::Unknown(magic.to_le_bytes())),
         }
     }
 }
@@ -120,15 +128,29 @@ impl LittleEndianSerialization for usize {
     }
 
     fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
-        match u8::deserialize_le(bytes, ix)? {
-            val @ 0..=0xfc => Ok(val as u64),
-            0xfd => u16::deserialize_le(bytes, ix).map(|x| x as u64),
-            0xfe => u32::deserialize_le(bytes, ix).map(|x| x as u64),
-            0xff => u64::deserialize_le(bytes, ix),
-        }?.usize()
+        read_compact_size(bytes, ix, false)
     }
 }
 
+/// Reads a CompactSize (variable-length integer), which is how counts and lengths
+/// are encoded in blocks and transactions. This is the same as `usize::deserialize_le`,
+/// except that if `strict` is set the value must use the shortest possible
+/// encoding; for example `0xfd 0x05 0x00` is rejected because 5 fits in a single
+/// byte. Bitcoin Core rejects such non-canonical encodings.
+pub fn read_compact_size(bytes: &[u8], ix: &mut usize, strict: bool) -> Result<usize, BlockParseError> {
+    let start_ix = *ix;
+    let (value, min) = match u8::deserialize_le(bytes, ix)? {
+        val @ 0..=0xfc => (val as u64, 0),
+        0xfd => (u16::deserialize_le(bytes, ix)? as u64, 0xfd),
+        0xfe => (u32::deserialize_le(bytes, ix)? as u64, 0x10000),
+        0xff => (u64::deserialize_le(bytes, ix)?, 0x100000000),
+    };
+    if strict && value < min {
+        return Err(BlockParseError::new(format!("Non-canonical CompactSize encoding of {} at index {}", value, start_ix)));
+    }
+    value.usize()
+}
+
 impl LittleEndianSerialization for Hash {
     fn serialize_le(&self, dest: &mut Vec<u8>) {
         dest.extend(self.0.iter().rev());
@@ -154,97 +176,203 @@ impl LittleEndianSerialization for TransactionFlags {
 
     fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
         let b = u8::deserialize_le(bytes, ix)?;
-        TransactionFlags::from_bits(b).ok_or_else(|| BlockParseError::new(format!("Unrecognized transaction flags at index {}", *ix - 1)))
+        TransactionFlags::from_bits(b).ok_or_else(|| BlockParseError::new(format!("Unrecognized transaction flags 0x{:02x} at index {}", b, *ix - 1)))
+    }
+}
+
+impl TransactionFlags {
+    /// Same as `deserialize_le`, but silently drops any flag bits that aren't
+    /// recognized instead of returning an error. This is useful for tools that
+    /// want to tolerate flags introduced by future protocol upgrades; note that
+    /// the dropped bits are not preserved when the flags are serialized again.
+    pub fn deserialize_lenient(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> {
+        let b = u8::deserialize_le(bytes, ix)?;
+        Ok(TransactionFlags::from_bits_truncate(b))
     }
 }
 
 impl LittleEndianSerialization for Transaction {
     fn serialize_le(&self, dest: &mut Vec<u8>) {
-        self.version.serialize_le(dest);
+        // Writing into a Vec never fails
+        self.serialize_to(dest).unwrap();
+    }
+
+    fn serialize_to<W: Write>(&self, dest: &mut W) -> io::Result<()> {
+        // Scripts and witness items are written out directly, and only the small
+        // fields between them are collected into a buffer first.
+        let mut buffer = Vec::with_capacity(64);
+        self.version.serialize_le(&mut buffer);
         if !self.flags.is_empty() {
-            dest.push(0);
-            self.flags.serialize_le(dest);
+            buffer.push(0);
+            self.flags.serialize_le(&mut buffer);
         }
-        self.inputs.len().serialize_le(dest);
+        self.inputs.len().serialize_le(&mut buffer);
         for input in &self.inputs {
-            input.txid.serialize_le(dest);
-            input.vout.serialize_le(dest);
-            input.unlock_script.len().serialize_le(dest);
-            dest.extend(&input.unlock_script);
-            input.sequence.serialize_le(dest);
+            input.txid.serialize_le(&mut buffer);
+            input.vout.serialize_le(&mut buffer);
+            input.unlock_script.len().serialize_le(&mut buffer);
+            write_with_buffer(dest, &mut buffer, &input.unlock_script)?;
+            input.sequence.serialize_le(&mut buffer);
         }
-        self.outputs.len().serialize_le(dest);
+        self.outputs.len().serialize_le(&mut buffer);
         for output in &self.outputs {
-            output.value.serialize_le(dest);
-            output.lock_script.len().serialize_le(dest);
-            dest.extend(&output.lock_script);
+            output.value.serialize_le(&mut buffer);
+            output.lock_script.len().serialize_le(&mut buffer);
+            write_with_buffer(dest, &mut buffer, &output.lock_script)?;
         }
         if self.flags.contains(TransactionFlags::WITNESS) {
             for input in &self.inputs {
-                input.witness_stuff.len().serialize_le(dest);
+                input.witness_stuff.len().serialize_le(&mut buffer);
                 for witness in &input.witness_stuff {
-                    witness.len().serialize_le(dest);
-                    dest.extend(witness);
+                    witness.len().serialize_le(&mut buffer);
+                    write_with_buffer(dest, &mut buffer, witness)?;
                 }
             }
         }
-        self.locktime.serialize_le(dest);
+        self.locktime.serialize_le(&mut buffer);
+        dest.write_all(&buffer)
     }
 
     fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
-        let version = u32::deserialize_le(bytes, ix)?;
-        let count = usize::deserialize_le(bytes, ix)?;
-        let (flags, input_count) = if count == 0 /* && allow_witness*/ {
-            (TransactionFlags::deserialize_le(bytes, ix)?, usize::deserialize_le(bytes, ix)?)
-        } else {
-            (TransactionFlags::empty(), count)
-        };
-        let mut inputs = Vec::with_capacity(input_count);
-        for _ in 0..input_count {
-            let txid = Hash::deserialize_le(bytes, ix)?;
-            let vout = u32::deserialize_le(bytes, ix)?;
-            let unlock_script = read_bytearray(bytes, ix)?;
-            let sequence = u32::deserialize_le(bytes, ix)?;
-
-            inputs.push(TransactionInput {
-                txid,
-                vout,
-                unlock_script,
-                sequence,
-                witness_stuff: vec![],
-            })
-        }
-        let output_count = usize::deserialize_le(bytes, ix)?;
-        let mut outputs = Vec::with_capacity(output_count);
-        for _ in 0..output_count {
-            let value = u64::deserialize_le(bytes, ix)?;
-            let lock_script = read_bytearray(bytes, ix)?;
-
-            outputs.push(TransactionOutput {
-                value,
-                lock_script,
-            })
-        }
-        if flags.contains(TransactionFlags::WITNESS) {
-            for input in inputs.iter_mut() {
-                let outer_count = usize::deserialize_le(bytes, ix)?;
-                let mut witness_stuff = Vec::with_capacity(outer_count);
-                for _ in 0..outer_count {
-                    witness_stuff.push(read_bytearray(bytes, ix)?);
-                }
-                input.witness_stuff = witness_stuff;
-            }
+        read_transaction(bytes, ix, false, &mut BlockReader::default())
+    }
+}
+
+// Writes out and clears the buffer, followed by the given bytes.
+fn write_with_buffer<W: Write>(dest: &mut W, buffer: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    dest.write_all(buffer)?;
+    buffer.clear();
+    dest.write_all(bytes)
+}
+
+// Returns the number of bytes in the CompactSize encoding of the given value.
+pub(crate) fn compact_size_len(value: usize) -> usize {
+    match value {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ if value <= 0xffffffff => 5,
+        _ => 9,
+    }
+}
+
+impl Transaction {
+    // Returns the length of the serialization, without serializing it.
+    pub(crate) fn serialized_len(&self) -> usize {
+        let script_len = |script: &[u8]| compact_size_len(script.len()) + script.len();
+        let mut len = 4 + compact_size_len(self.inputs.len()) + compact_size_len(self.outputs.len()) + 4;
+        if !self.flags.is_empty() {
+            len += 2;
         }
-        let locktime = u32::deserialize_le(bytes, ix)?;
+        len += self.inputs.iter().map(|input| 32 + 4 + script_len(&input.unlock_script) + 4).sum::<usize>();
+        len += self.outputs.iter().map(|output| 8 + script_len(&output.lock_script)).sum::<usize>();
+        if self.flags.contains(TransactionFlags::WITNESS) {
+            len += self.inputs.iter().map(|input| {
+                compact_size_len(input.witness_stuff.len()) + input.witness_stuff.iter().map(|witness| script_len(witness)).sum::<usize>()
+            }).sum::<usize>();
+        }
+        len
+    }
 
-        Ok(Transaction {
-            version,
-            flags,
-            inputs,
-            outputs,
-            locktime,
+    /// Same as `deserialize_le`, but rejects non-canonical CompactSize encodings
+    /// of the counts and lengths in the transaction; see `read_compact_size`.
+    pub fn deserialize_strict(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> {
+        read_transaction(bytes, ix, true, &mut BlockReader::default())
+    }
+}
+
+// The smallest possible serializations of an input (a txid, vout, empty unlock
+// script, and sequence), of an output (a value and empty lock script), and of a
+// transaction (a version, one input, no outputs, and a locktime).
+const MIN_INPUT_SIZE: usize = 32 + 4 + 1 + 4;
+const MIN_OUTPUT_SIZE: usize = 8 + 1;
+const MIN_TRANSACTION_SIZE: usize = 4 + 1 + MIN_INPUT_SIZE + 1 + 4;
+
+// Checks that `count` items of at least `min_size` bytes each could fit in the
+// data remaining after `ix`, so that absurd counts are rejected before looping
+// over them. `count_ix` is the index of the count, for the error message.
+fn check_count(bytes: &[u8], ix: usize, count: usize, min_size: usize, what: &str, count_ix: usize) -> Result<(), BlockParseError> {
+    if count > bytes.len().saturating_sub(ix) / min_size {
+        return Err(BlockParseError::new(format!("{} count {} at index {} exceeds the remaining data", what, count, count_ix)));
+    }
+    Ok(())
+}
+
+fn read_transaction(bytes: &[u8], ix: &mut usize, strict: bool, reader: &mut BlockReader) -> Result<Transaction, BlockParseError> {
+    let version = u32::deserialize_le(bytes, ix)?;
+    let marker_ix = *ix;
+    let count = read_compact_size(bytes, ix, strict)?;
+    let (flags, input_count, input_count_ix) = if count == 0 /* && allow_witness*/ {
+        // The extended format is indicated by a 0x00 marker byte (where the input
+        // count would be) followed by a nonzero flag byte.
+        if *ix != marker_ix + 1 {
+            return Err(BlockParseError::new(format!("Transaction marker at index {} is not 0x00", marker_ix)));
+        }
+        let flags = TransactionFlags::deserialize_le(bytes, ix)?;
+        if flags.is_empty() {
+            return Err(BlockParseError::new(format!("Transaction flag at index {} is zero", *ix - 1)));
+        }
+        let input_count_ix = *ix;
+        (flags, read_compact_size(bytes, ix, strict)?, input_count_ix)
+    } else {
+        (TransactionFlags::empty(), count, marker_ix)
+    };
+    check_count(bytes, *ix, input_count, MIN_INPUT_SIZE, "Transaction input", input_count_ix)?;
+    let mut inputs = Vec::with_capacity(input_count);
+    for _ in 0..input_count {
+        let txid = Hash::deserialize_le(bytes, ix)?;
+        let vout = u32::deserialize_le(bytes, ix)?;
+        let unlock_script = reader.read_bytearray(bytes, ix, strict)?;
+        let sequence = u32::deserialize_le(bytes, ix)?;
+
+        inputs.push(TransactionInput {
+            txid,
+            vout,
+            unlock_script,
+            sequence,
+            witness_stuff: vec![],
+            prevout_value: None,
+            prevout_script: None,
+        })
+    }
+    let count_ix = *ix;
+    let output_count = read_compact_size(bytes, ix, strict)?;
+    check_count(bytes, *ix, output_count, MIN_OUTPUT_SIZE, "Transaction output", count_ix)?;
+    let mut outputs = Vec::with_capacity(output_count);
+    for _ in 0..output_count {
+        let value = u64::deserialize_le(bytes, ix)?;
+        let lock_script = reader.read_bytearray(bytes, ix, strict)?;
+
+        outputs.push(TransactionOutput {
+            value,
+            lock_script,
         })
     }
+    if flags.contains(TransactionFlags::WITNESS) {
+        for input in inputs.iter_mut() {
+            let count_ix = *ix;
+            let outer_count = read_compact_size(bytes, ix, strict)?;
+            // Each witness item has at least its length byte
+            check_count(bytes, *ix, outer_count, 1, "Transaction witness item", count_ix)?;
+            let mut witness_stuff = Vec::with_capacity(outer_count);
+            for _ in 0..outer_count {
+                witness_stuff.push(reader.read_bytearray(bytes, ix, strict)?);
+            }
+            input.witness_stuff = witness_stuff;
+        }
+        // Transactions without witness data must use the non-extended format
+        if inputs.iter().all(|input| input.witness_stuff.is_empty()) {
+            return Err(BlockParseError::new(format!("Transaction with witness flag has no witness data, ending at index {}", *ix)));
+        }
+    }
+    let locktime = u32::deserialize_le(bytes, ix)?;
+
+    Ok(Transaction {
+        version,
+        flags,
+        inputs,
+        outputs,
+        locktime,
+    })
 }
 
 impl LittleEndianSerialization for BlockHeader {
@@ -276,56 +404,102 @@ impl LittleEndianSerialization for BlockHeader {
     }
 }
 
-impl LittleEndianSerialization for Block {
-    fn serialize_le(&self, dest: &mut Vec<u8>) {
-        self.network.serialize_le(dest);
-        let mut size_ix = dest.len();
-        0u32.serialize_le(dest);
+impl Block {
+    /// Serializes the block without the network magic and size prefix. This
+    /// is the format used for blocks in P2P messages and RPC responses.
+    pub fn serialize_raw(&self, dest: &mut Vec<u8>) {
         self.header.serialize_le(dest);
         self.transactions.len().serialize_le(dest);
         for transaction in &self.transactions {
             transaction.serialize_le(dest);
         }
-        let end_ix = dest.len();
-        let size = (end_ix - (size_ix + 4)) as u32;
+    }
 
-        let mut serialized_size = Vec::with_capacity(4);
-        size.serialize_le(&mut serialized_size);
-        for b in serialized_size {
-            dest[size_ix] = b;
-            size_ix += 1;
-        }
+    /// Constructs a block from bytes that don't have the network magic and
+    /// size prefix, i.e. just the block header followed by the transactions.
+    /// Since the network can't be determined from the bytes, it must be
+    /// provided. As with `deserialize_le`, the index is updated to point to
+    /// whatever is after the block.
+    pub fn deserialize_raw(bytes: &[u8], ix: &mut usize, network: Network) -> Result<Self, BlockParseError> {
+        read_raw_block(bytes, ix, network, false, &mut BlockReader::default())
     }
 
-    fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
-        let network = Network::deserialize_le(bytes, ix)?;
-        let size = u32::deserialize_le(bytes, ix)?.usize()?;
-        let end = *ix + size;
+    /// Same as `deserialize_le`, but rejects non-canonical CompactSize encodings
+    /// of the transaction count and of the counts and lengths in the
+    /// transactions; see `read_compact_size`.
+    pub fn deserialize_strict(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> {
+        read_block(bytes, ix, true, &mut BlockReader::default())
+    }
+}
 
-        let header = BlockHeader::deserialize_le(bytes, ix)?;
-        let transaction_count = usize::deserialize_le(bytes, ix)?;
-        let mut transactions = Vec::with_capacity(transaction_count);
-        for _ in 0..transaction_count {
-            transactions.push(Transaction::deserialize_le(bytes, ix)?);
-        }
+fn read_raw_block(bytes: &[u8], ix: &mut usize, network: Network, strict: bool, reader: &mut BlockReader) -> Result<Block, BlockParseError> {
+    let header = BlockHeader::deserialize_le(bytes, ix)?;
+    let count_ix = *ix;
+    let transaction_count = read_compact_size(bytes, ix, strict)?;
+    check_count(bytes, *ix, transaction_count, MIN_TRANSACTION_SIZE, "Block transaction", count_ix)?;
+    let mut transactions = Vec::with_capacity(transaction_count);
+    for _ in 0..transaction_count {
+        transactions.push(read_transaction(bytes, ix, strict, reader)?);
+    }
 
-        if *ix != end {
-            return Err(BlockParseError::new(format!("Unexpected read index after block; expected {} but got {}", end, *ix)));
+    Ok(Block {
+        network,
+        header,
+        transactions,
+    })
+}
+
+fn read_block(bytes: &[u8], ix: &mut usize, strict: bool, reader: &mut BlockReader) -> Result<Block, BlockParseError> {
+    let network = Network::deserialize_le(bytes, ix)?;
+    let size = u32::deserialize_le(bytes, ix)?.usize()?;
+    let end = block_end(*ix, size)?;
+
+    let block = read_raw_block(bytes, ix, network, strict, reader)?;
+
+    if *ix != end {
+        return Err(BlockParseError::new(format!("Unexpected read index after block; expected {} but got {}", end, *ix)));
+    }
+
+    Ok(block)
+}
+
+// Returns the index just past a block of the given size starting at `ix`. On
+// 32-bit targets a huge size can overflow, which must not wrap around.
+fn block_end(ix: usize, size: usize) -> Result<usize, BlockParseError> {
+    ix.checked_add(size).ok_or_else(|| BlockParseError::new(format!("Block size {} at index {} overflows", size, ix)))
+}
+
+impl LittleEndianSerialization for Block {
+    fn serialize_le(&self, dest: &mut Vec<u8>) {
+        // Writing into a Vec never fails
+        self.serialize_to(dest).unwrap();
+    }
+
+    fn serialize_to<W: Write>(&self, dest: &mut W) -> io::Result<()> {
+        // The size prefix comes first, so it is computed from the lengths of the
+        // transactions before they are written out.
+        let mut buffer = Vec::new();
+        self.network.serialize_le(&mut buffer);
+        (self.serialized_size() as u32).serialize_le(&mut buffer);
+        self.header.serialize_le(&mut buffer);
+        self.transactions.len().serialize_le(&mut buffer);
+        dest.write_all(&buffer)?;
+        for transaction in &self.transactions {
+            transaction.serialize_to(dest)?;
         }
+        Ok(())
+    }
 
-        Ok(Block {
-            network,
-            header,
-            transactions,
-        })
+    fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
+        read_block(bytes, ix, false, &mut BlockReader::default())
     }
 }
 
 pub(crate) fn read_bytes(bytes: &[u8], ix: &mut usize, count: usize) -> Result<Vec<u8>, BlockParseError> {
-    let end = *ix + count;
-    if bytes.len() < end {
-        return Err(BlockParseError::new(format!("Unexpected end of input reading {} bytes at index {}", count, *ix)));
-    }
+    let end = match ix.checked_add(count) {
+        Some(end) if end <= bytes.len() => end,
+        _ => return Err(BlockParseError::new(format!("Unexpected end of input reading {} bytes at index {}", count, *ix))),
+    };
 
     let mut result = Vec::with_capacity(count);
     result.extend_from_slice(&bytes[*ix..end]);
@@ -333,11 +507,175 @@ pub(crate) fn read_bytes(bytes: &[u8], ix: &mut usize, count: usize) -> Result<V
     Ok(result)
 }
 
-pub(crate) fn read_bytearray(bytes: &[u8], ix: &mut usize) -> Result<Vec<u8>, BlockParseError> {
-    let count = usize::deserialize_le(bytes, ix)?;
+pub(crate) fn read_bytearray(bytes: &[u8], ix: &mut usize, strict: bool) -> Result<Vec<u8>, BlockParseError> {
+    let count = read_compact_size(bytes, ix, strict)?;
     read_bytes(bytes, ix, count)
 }
 
+/// A block parser that reuses the script buffers of blocks that are no longer
+/// needed. Parsing a block normally allocates a fresh `Vec` for every script
+/// and witness item; blocks handed back via `recycle` have their buffers kept
+/// around instead, and subsequent calls to `read_block` fill those buffers
+/// rather than allocating new ones where they are large enough. The parsed
+/// blocks are identical to the ones produced by `Block::deserialize_le`.
+#[derive(Debug, Default)]
+pub struct BlockReader {
+    // Spare buffers, keyed by their capacity
+    spare: BTreeMap<usize, Vec<Vec<u8>>>,
+    spare_count: usize,
+    pub(crate) reused: usize,
+}
+
+// Bounds the memory held on to by a BlockReader; buffers recycled beyond this
+// are just freed.
+const MAX_SPARE_BUFFERS: usize = 1 << 16;
+
+impl BlockReader {
+    /// Creates a reader with no spare buffers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a block in the same format as `Block::deserialize_le`, taking
+    /// script buffers from the ones previously recycled where possible.
+    pub fn read_block(&mut self, bytes: &[u8], ix: &mut usize) -> Result<Block, BlockParseError> {
+        read_block(bytes, ix, false, self)
+    }
+
+    /// Takes ownership of a block that is no longer needed and keeps its
+    /// script and witness buffers for reuse by later calls to `read_block`.
+    pub fn recycle(&mut self, block: Block) {
+        for transaction in block.transactions {
+            for input in transaction.inputs {
+                self.keep(input.unlock_script);
+                for witness in input.witness_stuff {
+                    self.keep(witness);
+                }
+            }
+            for output in transaction.outputs {
+                self.keep(output.lock_script);
+            }
+        }
+    }
+
+    fn keep(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > 0 && self.spare_count < MAX_SPARE_BUFFERS {
+            buffer.clear();
+            self.spare_count += 1;
+            self.spare.entry(buffer.capacity()).or_default().push(buffer);
+        }
+    }
+
+    fn read_bytearray(&mut self, bytes: &[u8], ix: &mut usize, strict: bool) -> Result<Vec<u8>, BlockParseError> {
+        if self.spare.is_empty() {
+            return read_bytearray(bytes, ix, strict);
+        }
+        let count = read_compact_size(bytes, ix, strict)?;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        // Use the smallest spare buffer that fits, so that large buffers aren't
+        // wasted on small scripts
+        let capacity = match self.spare.range(count..).next() {
+            Some((&capacity, _)) => capacity,
+            None => return read_bytes(bytes, ix, count),
+        };
+        let end = match ix.checked_add(count) {
+            Some(end) if end <= bytes.len() => end,
+            _ => return Err(BlockParseError::new(format!("Unexpected end of input reading {} bytes at index {}", count, *ix))),
+        };
+        let buffers = self.spare.get_mut(&capacity).unwrap();
+        let mut result = buffers.pop().unwrap();
+        self.spare_count -= 1;
+        if buffers.is_empty() {
+            self.spare.remove(&capacity);
+        }
+        result.extend_from_slice(&bytes[*ix..end]);
+        *ix = end;
+        self.reused += 1;
+        Ok(result)
+    }
+}
+
+// Same as read_bytes but just advances the index, without copying the bytes.
+fn skip_bytes(bytes: &[u8], ix: &mut usize, count: usize) -> Result<(), BlockParseError> {
+    if bytes.len() - *ix < count {
+        return Err(BlockParseError::new(format!("Unexpected end of input reading {} bytes at index {}", count, *ix)));
+    }
+    *ix += count;
+    Ok(())
+}
+
+// Same as read_bytearray but just advances the index, without copying the bytes.
+fn skip_bytearray(bytes: &[u8], ix: &mut usize) -> Result<(), BlockParseError> {
+    let count = usize::deserialize_le(bytes, ix)?;
+    skip_bytes(bytes, ix, count)
+}
+
+// Advances the index past a serialized transaction, following the same format
+// as Transaction::deserialize_le.
+fn skip_transaction(bytes: &[u8], ix: &mut usize) -> Result<(), BlockParseError> {
+    skip_bytes(bytes, ix, 4)?; // version
+    let count = usize::deserialize_le(bytes, ix)?;
+    let (flags, input_count) = if count == 0 {
+        (TransactionFlags::deserialize_le(bytes, ix)?, usize::deserialize_le(bytes, ix)?)
+    } else {
+        (TransactionFlags::empty(), count)
+    };
+    for _ in 0..input_count {
+        skip_bytes(bytes, ix, 32 + 4)?; // txid and vout
+        skip_bytearray(bytes, ix)?;
+        skip_bytes(bytes, ix, 4)?; // sequence
+    }
+    let output_count = usize::deserialize_le(bytes, ix)?;
+    for _ in 0..output_count {
+        skip_bytes(bytes, ix, 8)?; // value
+        skip_bytearray(bytes, ix)?;
+    }
+    if flags.contains(TransactionFlags::WITNESS) {
+        for _ in 0..input_count {
+            let item_count = usize::deserialize_le(bytes, ix)?;
+            for _ in 0..item_count {
+                skip_bytearray(bytes, ix)?;
+            }
+        }
+    }
+    skip_bytes(bytes, ix, 4) // locktime
+}
+
+/// Counts the transactions in a block without fully parsing it. The bytes at the
+/// index should be a block in the standard protocol format (starting with the
+/// network magic header). The transactions are walked using their length fields
+/// but none of their contents are copied, which is much faster than parsing the
+/// block when only the count is needed. As with `Block::deserialize_le`, the index
+/// is updated to point to whatever is after the block.
+pub fn count_transactions(bytes: &[u8], ix: &mut usize) -> Result<usize, BlockParseError> {
+    Network::deserialize_le(bytes, ix)?;
+    let size = u32::deserialize_le(bytes, ix)?.usize()?;
+    let end = block_end(*ix, size)?;
+
+    skip_bytes(bytes, ix, 80)?; // header
+    let transaction_count = usize::deserialize_le(bytes, ix)?;
+    for _ in 0..transaction_count {
+        skip_transaction(bytes, ix)?;
+    }
+
+    if *ix != end {
+        return Err(BlockParseError::new(format!("Unexpected read index after block; expected {} but got {}", end, *ix)));
+    }
+    Ok(transaction_count)
+}
+
+pub(crate) fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, BlockParseError> {
+    if hex.len() % 2 != 0 {
+        return Err(BlockParseError::new(format!("Hex string {:?} has an odd number of digits", hex)));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| BlockParseError::new(format!("Hex string {:?} contains invalid characters", hex)))
+}
+
 pub(crate) trait IntoUsize {
     fn usize(self) -> Result<usize, BlockParseError>;
 }
@@ -360,17 +698,13 @@ impl IntoUsize for u32 {
 
 impl IntoUsize for u16 {
     fn usize(self) -> Result<usize, BlockParseError> {
-        // If the count doesn't fit into a usize then maybe this is running on something with a
-        // small usize. Maybe we should handle that case? Punting on it for now.
-        usize::try_from(self).map_err(|_| BlockParseError::new(format!("Unable to fit value {} into usize", self)))
+        Ok(usize::from(self))
     }
 }
 
 impl IntoUsize for u8 {
     fn usize(self) -> Result<usize, BlockParseError> {
-        // If the count doesn't fit into a usize then maybe this is running on something with a
-        // small usize. Maybe we should handle that case? Punting on it for now.
-        usize::try_from(self).map_err(|_| BlockParseError::new(format!("Unable to fit value {} into usize", self)))
+        Ok(usize::from(self))
     }
 }
 
@@ -379,29 +713,450 @@ impl IntoUsize for u8 {
 /// If multiple blocks are present they are assumed to be concatenated in the byte array
 /// and are parsed as such.
 /// If the data is fully parsed into blocks, the list of blocks is returned. Otherwise,
-/// a error is returned indicating why parsing failed.
+/// a error is returned indicating why parsing failed. If the failure is because the
+/// data after the last full block is not framed as a complete block, the error's
+/// `trailing_data` indicates how many blocks parsed and how many bytes were left.
 pub fn parse_blockfile(bytes: &[u8]) -> Result<Vec<Block>, BlockParseError> {
     let mut ix = 0;
     let mut blocks = Vec::new();
     while ix < bytes.len() {
+        if !has_complete_framing(bytes, ix) {
+            return Err(BlockParseError::trailing(blocks.len(), bytes.len() - ix, ix));
+        }
         blocks.push(Block::deserialize_le(bytes, &mut ix)?);
     }
     Ok(blocks)
 }
 
+/// Same as `parse_blockfile`, but doesn't give up on the first block that fails to
+/// parse. Instead, the data is scanned forward from the start of the bad block to
+/// the next occurrence of a known network magic value, and parsing resumes from
+/// there. Returns the blocks that parsed along with the byte ranges that were
+/// skipped.
+pub fn parse_blockfile_lenient(bytes: &[u8]) -> (Vec<Block>, Vec<Range<usize>>) {
+    let mut ix = 0;
+    let mut blocks = Vec::new();
+    let mut skipped = Vec::new();
+    while ix < bytes.len() {
+        let start = ix;
+        match Block::deserialize_le(bytes, &mut ix) {
+            Ok(block) => blocks.push(block),
+            Err(e) => {
+                ix = find_known_magic(bytes, start + 1).unwrap_or(bytes.len());
+                warn!(target: PARSE_LOG_TARGET, start, end = ix; "Skipping bytes {}..{} after failing to parse block: {}", start, ix, e);
+                skipped.push(start..ix);
+            }
+        }
+    }
+    (blocks, skipped)
+}
+
+// Returns the index of the first network magic value at or after `from`,
+// ignoring magic values for unknown networks.
+fn find_known_magic(bytes: &[u8], from: usize) -> Option<usize> {
+    (from..bytes.len()).find(|ix| {
+        matches!(Network::deserialize_le(bytes, &mut { *ix }), Ok(network) if !matches!(network, Network::Unknown(_)))
+    })
+}
+
+// Returns true if the bytes at `ix` start with a network magic and a size
+// prefix that fits in the remaining data.
+fn has_complete_framing(bytes: &[u8], ix: usize) -> bool {
+    let mut ix = ix;
+    if Network::deserialize_le(bytes, &mut ix).is_err() {
+        return false;
+    }
+    match u32::deserialize_le(bytes, &mut ix) {
+        Ok(size) => size as usize <= bytes.len() - ix,
+        Err(_) => false,
+    }
+}
+
+/// Finds the byte range of each block in raw byte data containing concatenated
+/// blocks in the standard protocol format, as accepted by `parse_blockfile`. Each
+/// range includes the block's network magic and size prefix. Only the framing is
+/// read, so the blocks themselves aren't checked to be valid. If the data after
+/// the last complete block is not framed as a complete block, an error is returned
+/// with `trailing_data` set as for `parse_blockfile`.
+pub fn block_boundaries(bytes: &[u8]) -> Result<Vec<Range<usize>>, BlockParseError> {
+    let mut boundaries = Vec::new();
+    let mut ix = 0;
+    while ix < bytes.len() {
+        if !has_complete_framing(bytes, ix) {
+            return Err(BlockParseError::trailing(boundaries.len(), bytes.len() - ix, ix));
+        }
+        let start = ix;
+        ix += 4;
+        ix += u32::deserialize_le(bytes, &mut ix)?.usize()?;
+        boundaries.push(start..ix);
+    }
+    Ok(boundaries)
+}
+
+/// Same as `parse_blockfile`, but decodes the blocks in parallel. A first pass over
+/// the data finds the block boundaries with `block_boundaries`, and the blocks are
+/// then deserialized concurrently. The blocks are returned in the same order as
+/// they appear in the data. If more than one block fails to parse, the error
+/// returned may be for any of them.
+#[cfg(feature = "parallel")]
+pub fn parse_blockfile_parallel(bytes: &[u8]) -> Result<Vec<Block>, BlockParseError> {
+    use rayon::prelude::*;
+
+    block_boundaries(bytes)?.into_par_iter()
+        .map(|range| Block::deserialize_le(bytes, &mut { range.start }))
+        .collect()
+}
+
+/// Parse raw byte data into a single block. The bytes provided should be a single
+/// block without the network magic and size prefix (e.g. as returned by the
+/// `getblock` RPC with verbosity 0). The network the block is for must be provided.
+/// Returns an error if the data doesn't parse as a block or if there is extra data
+/// after the block.
+pub fn parse_raw_block(bytes: &[u8], network: Network) -> Result<Block, BlockParseError> {
+    let mut ix = 0;
+    let block = Block::deserialize_raw(bytes, &mut ix, network)?;
+    if ix != bytes.len() {
+        return Err(BlockParseError::new(format!("Unexpected data after block; block ended at index {} but there are {} bytes", ix, bytes.len())));
+    }
+    Ok(block)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::fs::File;
     use std::io::Read;
     use super::*;
 
-    fn read_testdata(file: &str) -> Vec<u8> {
-        let mut file = File::open(&format!("testdata/{}", file)).unwrap();
+    pub(crate) fn read_testdata(file: &str) -> Vec<u8> {
+        let mut file = File::open(format!("testdata/{}", file)).unwrap();
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes).unwrap();
         bytes
     }
 
+    #[test]
+    fn test_hash_hex() {
+        let data = read_testdata("block_0.dat");
+        let block_0 = parse_blockfile(&data).unwrap().pop().unwrap();
+        let display = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+        let internal = "6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000";
+        assert_eq!(block_0.id().to_string(), display);
+        assert_eq!(block_0.id().to_display_hex(), display);
+        assert_eq!(block_0.id().to_internal_hex(), internal);
+        assert_eq!(Hash::from_display_hex(display).unwrap(), block_0.id());
+        assert_eq!(Hash::from_internal_hex(internal).unwrap(), block_0.id());
+
+        // The internal order is the order of the bytes on the wire
+        let mut serialized = Vec::new();
+        block_0.id().serialize_le(&mut serialized);
+        assert_eq!(serialized, hex_to_bytes(internal).unwrap());
+
+        assert!(Hash::from_display_hex("0019d6").is_err());
+        assert!(Hash::from_display_hex(&display.replace('0', "g")).is_err());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel() {
+        let mut data = Vec::new();
+        for file in ["block_0.dat", "block_265458.dat", "block_481829.dat"] {
+            data.extend(read_testdata(file));
+        }
+        let serial = parse_blockfile(&data).unwrap();
+        let parallel = parse_blockfile_parallel(&data).unwrap();
+        assert_eq!(serial.len(), 3);
+        assert_eq!(parallel.iter().map(|b| b.id()).collect::<Vec<_>>(), serial.iter().map(|b| b.id()).collect::<Vec<_>>());
+        let reserialize = |blocks: &[Block]| {
+            let mut bytes = Vec::new();
+            blocks.iter().for_each(|b| b.serialize_le(&mut bytes));
+            bytes
+        };
+        assert_eq!(reserialize(&parallel), data);
+
+        assert_eq!(parse_blockfile_parallel(&data[..data.len() - 1]).unwrap_err().trailing_data(), parse_blockfile(&data[..data.len() - 1]).unwrap_err().trailing_data());
+    }
+
+    #[test]
+    fn test_block_boundaries() {
+        let files = ["block_0.dat", "block_265458.dat", "block_481829.dat"];
+        let mut data = Vec::new();
+        let mut expected = Vec::new();
+        for file in files {
+            let block = read_testdata(file);
+            expected.push(data.len()..data.len() + block.len());
+            data.extend(block);
+        }
+        let boundaries = block_boundaries(&data).unwrap();
+        assert_eq!(boundaries, expected);
+        for (range, file) in boundaries.iter().zip(files) {
+            assert_eq!(data[range.clone()], read_testdata(file)[..]);
+        }
+        assert!(block_boundaries(&[]).unwrap().is_empty());
+        assert_eq!(block_boundaries(&data[..data.len() - 1]).unwrap_err().trailing_data(), Some((2, expected[2].len() - 1)));
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let mut data = read_testdata("block_0.dat");
+        data.extend(read_testdata("block_265458.dat"));
+        let length = data.len();
+        data.extend([0xab; 10]);
+        let err = parse_blockfile(&data).unwrap_err();
+        assert_eq!(err.trailing_data(), Some((2, 10)));
+        assert_eq!(err.to_string(), format!("Parsed 2 full blocks but found 10 trailing bytes at index {} that are not a complete block", length));
+
+        // A truncated block is also trailing data
+        let block_0 = read_testdata("block_0.dat");
+        let err = parse_blockfile(&block_0[..block_0.len() - 1]).unwrap_err();
+        assert_eq!(err.trailing_data(), Some((0, block_0.len() - 1)));
+
+        // But a complete block with bad contents isn't
+        let mut corrupt = block_0.clone();
+        corrupt[88] = 0x02; // The transaction count
+        corrupt.extend(&block_0);
+        assert_eq!(parse_blockfile(&corrupt).unwrap_err().trailing_data(), None);
+    }
+
+    #[test]
+    fn test_unknown_network() {
+        let block_0 = read_testdata("block_0.dat");
+        let mut data = block_0.clone();
+        data[0..4].copy_from_slice(&[0x01, 0x02, 0x03, 0x04]);
+        data.extend(&block_0);
+        let blocks = parse_blockfile(&data).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].network, Network::Unknown([0x01, 0x02, 0x03, 0x04]));
+        assert_eq!(blocks[0].id(), blocks[1].id());
+        assert_eq!(blocks[1].network, Network::MainNet);
+
+        let mut serialized = Vec::new();
+        blocks[0].serialize_le(&mut serialized);
+        assert_eq!(serialized, data[..block_0.len()]);
+    }
+
+    #[test]
+    fn test_lenient() {
+        let block_0 = read_testdata("block_0.dat");
+        let mut corrupt = read_testdata("block_265458.dat");
+        corrupt[88] = 0x00; // The transaction count
+        let block_481829 = read_testdata("block_481829.dat");
+        let data = [block_0.as_slice(), &corrupt, &block_481829].concat();
+        assert!(parse_blockfile(&data).is_err());
+
+        let (blocks, skipped) = parse_blockfile_lenient(&data);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].id(), parse_blockfile(&block_0).unwrap()[0].id());
+        assert_eq!(blocks[1].id(), parse_blockfile(&block_481829).unwrap()[0].id());
+        assert_eq!(skipped, vec![block_0.len()..block_0.len() + corrupt.len()]);
+
+        // Garbage at the end is skipped too
+        let data = [block_0.as_slice(), &[0xab; 10]].concat();
+        let (blocks, skipped) = parse_blockfile_lenient(&data);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(skipped, vec![block_0.len()..data.len()]);
+    }
+
+    #[test]
+    fn test_count_transactions() {
+        let mut data = Vec::new();
+        for file in ["block_0.dat", "block_265458.dat", "block_481829.dat"] {
+            data.extend(read_testdata(file));
+        }
+        let blocks = parse_blockfile(&data).unwrap();
+        let mut ix = 0;
+        let mut parse_ix = 0;
+        for block in blocks {
+            assert_eq!(count_transactions(&data, &mut ix).unwrap(), block.transactions.len());
+            Block::deserialize_le(&data, &mut parse_ix).unwrap();
+            assert_eq!(ix, parse_ix);
+        }
+        assert_eq!(ix, data.len());
+
+        let block_0 = read_testdata("block_0.dat");
+        assert!(count_transactions(&block_0[..block_0.len() - 1], &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_block_reader() {
+        let data = read_testdata("block_481829.dat");
+        let mut reader = BlockReader::new();
+        let first = reader.read_block(&data, &mut 0).unwrap();
+        assert_eq!(reader.reused, 0);
+        let script_count: usize = first.transactions.iter().map(|t| {
+            t.inputs.iter().map(|i| (!i.unlock_script.is_empty()) as usize + i.witness_stuff.iter().filter(|w| !w.is_empty()).count()).sum::<usize>()
+                + t.outputs.iter().filter(|o| !o.lock_script.is_empty()).count()
+        }).sum();
+        reader.recycle(first);
+        assert_eq!(reader.spare_count, script_count);
+        assert_eq!(reader.spare.values().map(Vec::len).sum::<usize>(), script_count);
+
+        let mut ix = 0;
+        let second = reader.read_block(&data, &mut ix).unwrap();
+        assert_eq!(ix, data.len());
+        // Every script allocation is avoided when parsing the same block again
+        assert_eq!(reader.reused, script_count);
+        assert!(reader.spare.is_empty());
+        assert_eq!(second, Block::deserialize_le(&data, &mut 0).unwrap());
+        let mut serialized = Vec::new();
+        second.serialize_le(&mut serialized);
+        assert_eq!(serialized, data);
+    }
+
+    #[test]
+    fn test_block_size_overflow() {
+        assert_eq!(block_end(8, 285).unwrap(), 293);
+        let err = block_end(usize::MAX - 3, 4).unwrap_err();
+        assert_eq!(err.to_string(), format!("Block size 4 at index {} overflows", usize::MAX - 3));
+        #[cfg(target_pointer_width = "32")]
+        {
+            let mut data = read_testdata("block_0.dat");
+            data[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(Block::deserialize_le(&data, &mut 0).is_err());
+        }
+    }
+
+    #[test]
+    fn test_has_witness() {
+        let block_265458 = parse_blockfile(&read_testdata("block_265458.dat")).unwrap().pop().unwrap();
+        assert!(block_265458.transactions.iter().all(|t| !t.has_witness()));
+
+        let block_481829 = parse_blockfile(&read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        let segwit = block_481829.transactions.iter().filter(|t| t.has_witness()).count();
+        assert!(segwit > 0);
+        assert!(segwit < block_481829.transactions.len());
+        let legacy = block_481829.transactions.iter().find(|t| !t.has_witness()).unwrap();
+        assert!(legacy.flags.is_empty());
+        assert_eq!(legacy.txid(), legacy.wtxid());
+
+        let mut stripped = block_481829.transactions.iter().find(|t| t.has_witness()).unwrap().clone();
+        assert_ne!(stripped.txid(), stripped.wtxid());
+        stripped.flags = TransactionFlags::empty();
+        assert!(stripped.has_witness());
+        for input in &mut stripped.inputs {
+            input.witness_stuff.clear();
+        }
+        assert!(!stripped.has_witness());
+    }
+
+    #[test]
+    fn test_absurd_counts() {
+        // A version, then a claimed million inputs, in a 50-byte buffer
+        let mut data = vec![0x01, 0x00, 0x00, 0x00, 0xfe, 0x40, 0x42, 0x0f, 0x00];
+        data.resize(50, 0);
+        let err = Transaction::deserialize_le(&data, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), "Transaction input count 1000000 at index 4 exceeds the remaining data");
+
+        // One input, then a million outputs
+        let block_0 = read_testdata("block_0.dat");
+        let coinbase = &block_0[89..block_0.len()];
+        let outputs_ix = 4 + 1 + 41 + coinbase[4 + 1 + 36] as usize;
+        let mut data = coinbase[..outputs_ix].to_vec();
+        data.extend([0xfe, 0x40, 0x42, 0x0f, 0x00]);
+        data.resize(outputs_ix + 50, 0);
+        let err = Transaction::deserialize_le(&data, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), format!("Transaction output count 1000000 at index {} exceeds the remaining data", outputs_ix));
+
+        // One input with a script length that would overflow the index
+        let mut data = vec![0x01, 0x00, 0x00, 0x00, 0x01];
+        data.extend([0; 36]);
+        data.push(0xff);
+        data.extend(u64::MAX.to_le_bytes());
+        assert!(Transaction::deserialize_le(&data, &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_unknown_transaction_flags() {
+        let err = TransactionFlags::deserialize_le(&[0x00, 0x02], &mut 1).unwrap_err();
+        assert_eq!(err.to_string(), "Unrecognized transaction flags 0x02 at index 1");
+        let mut ix = 1;
+        assert_eq!(TransactionFlags::deserialize_lenient(&[0x00, 0x02], &mut ix).unwrap(), TransactionFlags::empty());
+        assert_eq!(ix, 2);
+        assert_eq!(TransactionFlags::deserialize_lenient(&[0x03], &mut 0).unwrap(), TransactionFlags::WITNESS);
+        assert!(TransactionFlags::deserialize_lenient(&[], &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_strict_compact_size() {
+        assert_eq!(read_compact_size(&[0x05], &mut 0, true).unwrap(), 5);
+        assert_eq!(read_compact_size(&[0xfd, 0x05, 0x00], &mut 0, false).unwrap(), 5);
+        let err = read_compact_size(&[0xfd, 0x05, 0x00], &mut 0, true).unwrap_err();
+        assert_eq!(err.to_string(), "Non-canonical CompactSize encoding of 5 at index 0");
+        assert_eq!(read_compact_size(&[0xfd, 0xfd, 0x00], &mut 0, true).unwrap(), 0xfd);
+        assert!(read_compact_size(&[0xfe, 0xff, 0xff, 0x00, 0x00], &mut 0, true).is_err());
+        assert!(read_compact_size(&[0xfe, 0x00, 0x00, 0x01, 0x00], &mut 0, true).is_ok());
+
+        let data = read_testdata("block_0.dat");
+        let block_0 = Block::deserialize_strict(&data, &mut 0).unwrap();
+        let transaction = Transaction::deserialize_strict(&data[89..], &mut 0).unwrap();
+        assert_eq!(transaction, block_0.transactions[0]);
+
+        // Encode the transaction count of 1 as 0xfd 0x01 0x00, adjusting the block size
+        let mut non_canonical = data[..88].to_vec();
+        non_canonical[4] += 2;
+        non_canonical.extend([0xfd, 0x01, 0x00]);
+        non_canonical.extend(&data[89..]);
+        assert_eq!(parse_blockfile(&non_canonical).unwrap().pop().unwrap(), block_0);
+        let err = Block::deserialize_strict(&non_canonical, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), "Non-canonical CompactSize encoding of 1 at index 88");
+    }
+
+    #[test]
+    fn test_witness_marker_and_flag() {
+        let witness_tx = |witness_stuff: Vec<Vec<u8>>| {
+            let mut transaction = crate::tests::transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+            transaction.version = 2;
+            transaction.flags = TransactionFlags::WITNESS;
+            transaction.inputs[0].unlock_script = vec![];
+            transaction.inputs[0].witness_stuff = witness_stuff;
+            let mut bytes = Vec::new();
+            transaction.serialize_le(&mut bytes);
+            bytes
+        };
+
+        let bytes = witness_tx(vec![vec![0x01, 0x02]]);
+        let mut ix = 0;
+        let parsed = Transaction::deserialize_le(&bytes, &mut ix).unwrap();
+        assert_eq!(ix, bytes.len());
+        assert_eq!(parsed.inputs[0].witness_stuff, vec![vec![0x01, 0x02]]);
+
+        // A non-canonical encoding of the zero marker
+        let bad_marker = [&bytes[..4], &[0xfd, 0x00, 0x00], &bytes[5..]].concat();
+        let err = Transaction::deserialize_le(&bad_marker, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), "Transaction marker at index 4 is not 0x00");
+
+        let mut zero_flag = bytes.clone();
+        zero_flag[5] = 0x00;
+        let err = Transaction::deserialize_le(&zero_flag, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), "Transaction flag at index 5 is zero");
+
+        let empty_witness = witness_tx(vec![]);
+        let err = Transaction::deserialize_le(&empty_witness, &mut 0).unwrap_err();
+        assert_eq!(err.to_string(), format!("Transaction with witness flag has no witness data, ending at index {}", empty_witness.len() - 4));
+    }
+
+    #[test]
+    fn test_prevout_fields() {
+        let data = read_testdata("block_481829.dat");
+        let mut block = parse_blockfile(&data).unwrap().pop().unwrap();
+        assert!(block.iter_inputs().all(|(_, _, input)| input.prevout_value.is_none() && input.prevout_script.is_none()));
+
+        let txid = block.transactions[1].txid();
+        let input = &mut block.transactions[1].inputs[0];
+        input.prevout_value = Some(12345);
+        input.prevout_script = Some(vec![0x51]);
+        assert_eq!(block.transactions[1].txid(), txid);
+        let mut serialized = Vec::new();
+        block.serialize_le(&mut serialized);
+        assert_eq!(serialized, data);
+    }
+
+    #[test]
+    fn test_transaction_display() {
+        let data = read_testdata("block_0.dat");
+        let block_0 = parse_blockfile(&data).unwrap().pop().unwrap();
+        assert_eq!(block_0.transactions[0].to_string(), "txid:4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b version:1 inputs:1 outputs:1 value:5000000000 locktime:0");
+    }
+
     #[test]
     fn test_block_0() {
         let data = read_testdata("block_0.dat");
@@ -412,6 +1167,7 @@ mod tests {
         let mut serialized = Vec::new();
         block_0.serialize_le(&mut serialized);
         assert_eq!(data, serialized);
+        assert_eq!(block_0.serialized_size(), 285);
     }
 
     #[test]
@@ -437,4 +1193,135 @@ mod tests {
         block_265458.serialize_le(&mut serialized);
         assert_eq!(data, serialized);
     }
+
+    #[test]
+    fn test_block_equality() {
+        let block = parse_blockfile(&read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        let mut serialized = Vec::new();
+        block.serialize_le(&mut serialized);
+        let round_tripped = parse_blockfile(&serialized).unwrap().pop().unwrap();
+        assert_eq!(round_tripped, block);
+
+        let mut modified = round_tripped.clone();
+        modified.transactions[1].outputs[0].value += 1;
+        assert_ne!(modified, block);
+        assert_eq!(modified.header, block.header);
+        let mut modified = round_tripped;
+        modified.header.nonce += 1;
+        assert_ne!(modified, block);
+        assert_eq!(modified.transactions, block.transactions);
+    }
+
+    #[test]
+    fn test_raw_block() {
+        let data = read_testdata("block_0.dat");
+        let raw_block_0 = parse_raw_block(&data[8..], Network::MainNet).unwrap();
+        let block_0 = parse_blockfile(&data).unwrap().pop().unwrap();
+        assert_eq!(raw_block_0.id(), block_0.id());
+        assert_eq!(raw_block_0.network, Network::MainNet);
+
+        let mut serialized = Vec::new();
+        raw_block_0.serialize_raw(&mut serialized);
+        assert_eq!(serialized, &data[8..]);
+        serialized.clear();
+        raw_block_0.serialize_le(&mut serialized);
+        assert_eq!(serialized, data);
+
+        assert!(parse_raw_block(&data[8..data.len() - 1], Network::MainNet).is_err());
+        assert!(parse_raw_block(&[&data[8..], &[0]].concat(), Network::MainNet).is_err());
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let data = read_testdata("block_481829.dat");
+        let block_481829 = parse_blockfile(&data).unwrap().pop().unwrap();
+        let root = block_481829.header.merkle_root;
+
+        let txid = block_481829.transactions[1234].txid();
+        let branch = block_481829.merkle_proof(1234).unwrap();
+        assert_eq!(branch.len(), 11);
+        assert!(crate::verify_merkle_proof(txid, 1234, &branch, root));
+        assert!(!crate::verify_merkle_proof(txid, 1235, &branch, root));
+        assert!(!crate::verify_merkle_proof(block_481829.transactions[1235].txid(), 1234, &branch, root));
+
+        // The last transaction's path goes through duplicated nodes in the odd layers
+        let last = block_481829.transactions.len() - 1;
+        let branch = block_481829.merkle_proof(last).unwrap();
+        assert!(crate::verify_merkle_proof(block_481829.transactions[last].txid(), last, &branch, root));
+
+        assert_eq!(block_481829.merkle_proof(block_481829.transactions.len()), None);
+    }
+
+    #[test]
+    fn test_iter_outputs() {
+        let data = read_testdata("block_265458.dat");
+        let block_265458 = parse_blockfile(&data).unwrap().pop().unwrap();
+        let output_count = block_265458.transactions.iter().map(|t| t.outputs.len()).sum::<usize>();
+        assert_eq!(block_265458.iter_outputs().count(), output_count);
+        assert_eq!(block_265458.iter_outputs().count(), 860);
+
+        let (txid, vout, output) = block_265458.iter_outputs().next().unwrap();
+        assert_eq!(txid.to_string(), "24e7cac9db3fb824c5303b8d4deb93c3a00d86640802d975549350a3d2058150");
+        assert_eq!(vout, 0);
+        assert_eq!(output.value, 111269096);
+
+        let input_count = block_265458.transactions.iter().map(|t| t.inputs.len()).sum::<usize>();
+        assert_eq!(block_265458.iter_inputs().count(), input_count);
+    }
+
+    #[test]
+    fn test_outpoint_access() {
+        let data = read_testdata("block_265458.dat");
+        let block_265458 = parse_blockfile(&data).unwrap().pop().unwrap();
+        let transaction = block_265458.transactions.iter().find(|t| t.outputs.len() > 2 && t.inputs.len() > 1).unwrap();
+
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            assert_eq!(transaction.output(vout as u32).unwrap().value, output.value);
+        }
+        assert!(transaction.output(transaction.outputs.len() as u32).is_none());
+
+        for (vin, input) in transaction.inputs.iter().enumerate() {
+            assert_eq!(transaction.input_spending(&input.txid, input.vout), Some(vin));
+        }
+        let input = &transaction.inputs[1];
+        assert_eq!(transaction.input_spending(&input.txid, input.vout + 1000), None);
+        assert_eq!(transaction.input_spending(&Hash([0x11; 32]), input.vout), None);
+    }
+
+    #[test]
+    fn test_difficulty() {
+        let block_0 = parse_blockfile(&read_testdata("block_0.dat")).unwrap().pop().unwrap();
+        assert_eq!(block_0.header.target().unwrap().to_string(), "00000000ffff0000000000000000000000000000000000000000000000000000");
+        assert_eq!(block_0.header.difficulty(), 1.0);
+
+        let block_481829 = parse_blockfile(&read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        assert_eq!(block_481829.header.bits, 0x18013ce9);
+        assert!((block_481829.header.difficulty() - 888171856257.3206).abs() < 1e-3);
+        assert!(block_481829.header.id() < block_481829.header.target().unwrap());
+
+        assert_eq!(block_0.header.work(), 0x100010001);
+        assert_eq!(block_481829.header.work(), 3814727283971761869673);
+        let with_bits = |bits| BlockHeader { bits, ..BlockHeader::default() };
+        assert_eq!(with_bits(0x207fffff).work(), 2);
+        assert_eq!(with_bits(0x2100ffff).work(), 1);
+        assert_eq!(with_bits(0x03000001).work(), u128::MAX);
+        assert_eq!(with_bits(0x2200ffff).work(), 0);
+    }
+
+    #[test]
+    fn test_streaming_hash() {
+        let data = read_testdata("block_481829.dat");
+        let block_481829 = parse_blockfile(&data).unwrap().pop().unwrap();
+        let mut streamed = Vec::new();
+        block_481829.serialize_to(&mut streamed).unwrap();
+        assert_eq!(streamed, data);
+        assert_eq!(block_481829.serialized_size(), data.len() - 8);
+        assert_eq!(crate::hash::double_sha256_streaming(&block_481829), crate::hash::double_sha256(&block_481829));
+        for transaction in &block_481829.transactions {
+            let mut serialized = Vec::new();
+            transaction.serialize_le(&mut serialized);
+            assert_eq!(transaction.serialized_len(), serialized.len());
+            assert_eq!(crate::hash::double_sha256_streaming(transaction), crate::hash::double_sha256(transaction));
+        }
+    }
 }
@@ -0,0 +1,88 @@
+//! A module that exposes transaction relay policy checks. These are the
+//! "standardness" rules that nodes apply before relaying a transaction or
+//! accepting it into their mempool. A transaction that is non-standard is
+//! not necessarily invalid, and may still be included in a valid block.
+
+use crate::{LittleEndianSerialization, Transaction, TransactionOutput};
+use crate::script::witness_program;
+
+/// The maximum weight of a standard transaction. This is equivalent to a
+/// size of 100,000 bytes for a transaction with no witness data.
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+
+/// The default fee rate, in satoshis per 1000 bytes, used to compute the dust
+/// threshold of outputs.
+pub const DEFAULT_DUST_RELAY_FEE: u64 = 3000;
+
+/// Scripts longer than this can never be spent.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Checks whether the given output is dust, i.e. whether its value is less
+/// than the fee it would cost to spend it at the given fee rate (in satoshis
+/// per 1000 bytes). The cost includes the output itself along with a typical
+/// input spending it, which is smaller for witness outputs since witness data
+/// is discounted. Unspendable outputs (such as OP_RETURN outputs) are never
+/// dust. This is a relay policy rule, not a consensus rule; dust outputs are
+/// valid in blocks.
+pub fn is_dust(output: &TransactionOutput, dust_relay_fee_per_kb: u64) -> bool {
+    if output.lock_script.first() == Some(&0x6a) || output.lock_script.len() > MAX_SCRIPT_SIZE {
+        return false;
+    }
+    let mut serialized = Vec::new();
+    output.value.serialize_le(&mut serialized);
+    output.lock_script.len().serialize_le(&mut serialized);
+    serialized.extend(&output.lock_script);
+    // Outpoint, scriptSig length, and sequence, plus a 107-byte signature and
+    // public key which is discounted to a quarter for witness outputs
+    let spend_size = if witness_program(&output.lock_script).is_some() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    let threshold = (serialized.len() + spend_size) as u64 * dust_relay_fee_per_kb / 1000;
+    output.value < threshold
+}
+
+/// Checks whether the given transaction is standard, i.e. whether it would be
+/// relayed by default by Bitcoin Core. Returns false if the transaction violates
+/// any of the standardness rules implemented here.
+pub fn is_standard(transaction: &Transaction) -> bool {
+    transaction.weight() <= MAX_STANDARD_TX_WEIGHT
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Hash, TransactionOutput};
+    use super::*;
+
+    fn transaction(lock_script_size: usize) -> Transaction {
+        let mut transaction = crate::tests::transaction(&[(Hash::zero(), 0)], &[5000]);
+        transaction.outputs[0].lock_script = vec![0x6a; lock_script_size];
+        transaction
+    }
+
+    #[test]
+    fn dust() {
+        let p2pkh = |value| TransactionOutput { value, lock_script: crate::script::p2pkh_script(&[0x11; 20]) };
+        // A P2PKH output and the input spending it are 182 bytes, so the
+        // threshold is 546 satoshis
+        assert!(is_dust(&p2pkh(545), DEFAULT_DUST_RELAY_FEE));
+        assert!(!is_dust(&p2pkh(546), DEFAULT_DUST_RELAY_FEE));
+        assert!(!is_dust(&p2pkh(100_000), DEFAULT_DUST_RELAY_FEE));
+        assert!(is_dust(&p2pkh(546), 4000));
+
+        // A P2WPKH output is 31 bytes and the discounted input is 67 bytes
+        let p2wpkh = |value| TransactionOutput { value, lock_script: [&[0x00, 0x14][..], &[0x11; 20]].concat() };
+        assert!(is_dust(&p2wpkh(293), DEFAULT_DUST_RELAY_FEE));
+        assert!(!is_dust(&p2wpkh(294), DEFAULT_DUST_RELAY_FEE));
+
+        let op_return = TransactionOutput { value: 0, lock_script: vec![0x6a, 0x01, 0x00] };
+        assert!(!is_dust(&op_return, DEFAULT_DUST_RELAY_FEE));
+    }
+
+    #[test]
+    fn standard_size() {
+        assert!(is_standard(&transaction(25)));
+        assert!(!is_standard(&transaction(100_000)));
+    }
+}
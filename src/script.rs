@@ -1,27 +1,253 @@
 //! A module that exposes a script parsing and verification API.
 
-use crate::{BlockParseError, BlockValidationError, LittleEndianSerialization, Opcode, Script, ScriptError};
-use crate::parse::{read_bytes, IntoUsize};
+use crate::{BlockParseError, BlockValidationError, LittleEndianSerialization, Opcode, Script, ScriptError, Transaction};
+use crate::hash::{hash160, sha256d};
+use crate::parse::{hex_to_bytes, read_bytes, IntoUsize};
+use crate::sighash::{legacy_sighash, segwit_v0_sighash, tapleaf_hash, taproot_sighash, SIGHASH_DEFAULT, TAPSCRIPT_LEAF_VERSION};
+use bitflags::bitflags;
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use k256::schnorr;
+use ripemd::Ripemd160;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+bitflags! {
+    /// Flags that enable optional rules during script verification. The values
+    /// match the corresponding `SCRIPT_VERIFY_*` flags in Bitcoin Core.
+    pub struct ScriptFlags : u32 {
+        /// Evaluate the redeem script of pay-to-script-hash spends (BIP16). This
+        /// has been consensus since 2012.
+        const P2SH = 1 << 0;
+        /// Require signatures to be strictly DER-encoded (BIP66). This has been
+        /// consensus since block 363725; without it, signatures in the lax DER
+        /// encoding accepted before then are also verified.
+        const DERSIG = 1 << 2;
+        /// Require the S value of signatures to be in the lower half of the curve
+        /// order (BIP146). This is a standardness rule (policy) for all inputs,
+        /// legacy and segwit alike; it has never been consensus.
+        const LOW_S = 1 << 3;
+        /// Require the extra element consumed by CHECKMULTISIG to be empty (BIP147).
+        /// This has been consensus since segwit activated.
+        const NULLDUMMY = 1 << 4;
+        /// Require the unlock script to only contain pushes. This is a standardness
+        /// rule, and consensus for P2SH spends.
+        const SIGPUSHONLY = 1 << 5;
+        /// Fail if one of the NOPs reserved for soft-fork upgrades (`OP_NOP1` and
+        /// `OP_NOP4` through `OP_NOP10`) is executed. This is a standardness rule;
+        /// plain `OP_NOP` is always allowed.
+        const DISCOURAGE_UPGRADABLE_NOPS = 1 << 7;
+        /// Require exactly one element to be left on the stack after execution.
+        /// This is a standardness rule.
+        const CLEANSTACK = 1 << 8;
+        /// Verify segwit version 0 spends (BIP141 and BIP143), including P2WPKH
+        /// and P2WSH outputs and P2SH-wrapped versions of them. This has been
+        /// consensus since block 481824.
+        const WITNESS = 1 << 11;
+        /// Verify taproot spends (BIP341), i.e. spends of native version 1
+        /// witness programs with a 32-byte program. Only key-path spends are
+        /// verified so far; script-path spends are rejected as unsupported rather
+        /// than accepted unverified. This has been consensus since block 709632.
+        const TAPROOT = 1 << 17;
+        /// Execute scripts with the tapscript rules (BIP342), as for the leaf
+        /// script of a taproot script-path spend. Signatures are Schnorr
+        /// signatures (BIP340) over the BIP341 sighash, OP_CHECKSIGADD is
+        /// available, and CHECKMULTISIG is not. Any OP_SUCCESSx opcode makes the
+        /// script succeed. Without this flag OP_CHECKSIGADD is an invalid opcode.
+        /// This selects how scripts are executed rather than being a rule, so it
+        /// isn't part of `consensus`. (Bitcoin Core
+        /// selects this with the signature version rather than a flag, so this
+        /// uses a bit that Bitcoin Core doesn't.)
+        const TAPSCRIPT = 1 << 31;
+    }
+}
+
+impl ScriptFlags {
+    /// The rules that are enforced by consensus for blocks today. This is what
+    /// `verify` uses.
+    pub fn consensus() -> Self {
+        ScriptFlags::P2SH | ScriptFlags::DERSIG | ScriptFlags::NULLDUMMY | ScriptFlags::WITNESS | ScriptFlags::TAPROOT
+    }
+
+    /// The rules that Bitcoin Core enforces when relaying transactions, which
+    /// are the consensus rules plus the standardness rules.
+    pub fn standard() -> Self {
+        Self::consensus() | ScriptFlags::LOW_S | ScriptFlags::DISCOURAGE_UPGRADABLE_NOPS | ScriptFlags::CLEANSTACK
+    }
+}
+
+/// Half of the order of the secp256k1 curve, as big-endian bytes. Signatures
+/// with an S value above this are high-S.
+const HALF_CURVE_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// The number of signature operations that a CHECKMULTISIG counts for when the
+/// number of public keys is not known.
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+/// The maximum size in bytes of an element on the stack.
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// The maximum number of elements in the witness stack of a P2WSH spend, not
+/// counting the witness script.
+pub const MAX_P2WSH_STACK_ITEMS: usize = 100;
+/// The maximum size in bytes of the witness script of a P2WSH spend.
+pub const MAX_P2WSH_SCRIPT_SIZE: usize = 3600;
+/// The maximum number of non-push opcodes in a script, where each public key
+/// of a CHECKMULTISIG also counts as an opcode. This doesn't apply to tapscript.
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+/// The maximum number of elements on the stack and the alt stack combined.
+pub const MAX_STACK_SIZE: usize = 1000;
+/// The default limit on the work done executing the scripts of an input; see
+/// `VerifyContext::work_budget`. This is well above what any script within the
+/// consensus limits of `MAX_OPS_PER_SCRIPT` opcodes and `MAX_STACK_SIZE` stack
+/// entries can use.
+pub const DEFAULT_WORK_BUDGET: u64 = 1_000_000;
+
+/// Standard names for all the opcodes that have one. Some opcodes have multiple
+/// names, in which case the first one listed is the canonical name.
+const OPCODE_NAMES: &[(&str, u8)] = &[
+    ("OP_0", 0x00), ("OP_FALSE", 0x00),
+    ("OP_PUSHDATA1", 0x4c), ("OP_PUSHDATA2", 0x4d), ("OP_PUSHDATA4", 0x4e),
+    ("OP_1NEGATE", 0x4f), ("OP_RESERVED", 0x50),
+    ("OP_1", 0x51), ("OP_TRUE", 0x51), ("OP_2", 0x52), ("OP_3", 0x53), ("OP_4", 0x54),
+    ("OP_5", 0x55), ("OP_6", 0x56), ("OP_7", 0x57), ("OP_8", 0x58),
+    ("OP_9", 0x59), ("OP_10", 0x5a), ("OP_11", 0x5b), ("OP_12", 0x5c),
+    ("OP_13", 0x5d), ("OP_14", 0x5e), ("OP_15", 0x5f), ("OP_16", 0x60),
+    ("OP_NOP", 0x61), ("OP_VER", 0x62), ("OP_IF", 0x63), ("OP_NOTIF", 0x64),
+    ("OP_VERIF", 0x65), ("OP_VERNOTIF", 0x66), ("OP_ELSE", 0x67), ("OP_ENDIF", 0x68),
+    ("OP_VERIFY", 0x69), ("OP_RETURN", 0x6a),
+    ("OP_TOALTSTACK", 0x6b), ("OP_FROMALTSTACK", 0x6c), ("OP_2DROP", 0x6d), ("OP_2DUP", 0x6e),
+    ("OP_3DUP", 0x6f), ("OP_2OVER", 0x70), ("OP_2ROT", 0x71), ("OP_2SWAP", 0x72),
+    ("OP_IFDUP", 0x73), ("OP_DEPTH", 0x74), ("OP_DROP", 0x75), ("OP_DUP", 0x76),
+    ("OP_NIP", 0x77), ("OP_OVER", 0x78), ("OP_PICK", 0x79), ("OP_ROLL", 0x7a),
+    ("OP_ROT", 0x7b), ("OP_SWAP", 0x7c), ("OP_TUCK", 0x7d),
+    ("OP_CAT", 0x7e), ("OP_SUBSTR", 0x7f), ("OP_LEFT", 0x80), ("OP_RIGHT", 0x81), ("OP_SIZE", 0x82),
+    ("OP_INVERT", 0x83), ("OP_AND", 0x84), ("OP_OR", 0x85), ("OP_XOR", 0x86),
+    ("OP_EQUAL", 0x87), ("OP_EQUALVERIFY", 0x88), ("OP_RESERVED1", 0x89), ("OP_RESERVED2", 0x8a),
+    ("OP_1ADD", 0x8b), ("OP_1SUB", 0x8c), ("OP_2MUL", 0x8d), ("OP_2DIV", 0x8e),
+    ("OP_NEGATE", 0x8f), ("OP_ABS", 0x90), ("OP_NOT", 0x91), ("OP_0NOTEQUAL", 0x92),
+    ("OP_ADD", 0x93), ("OP_SUB", 0x94), ("OP_MUL", 0x95), ("OP_DIV", 0x96),
+    ("OP_MOD", 0x97), ("OP_LSHIFT", 0x98), ("OP_RSHIFT", 0x99),
+    ("OP_BOOLAND", 0x9a), ("OP_BOOLOR", 0x9b), ("OP_NUMEQUAL", 0x9c), ("OP_NUMEQUALVERIFY", 0x9d),
+    ("OP_NUMNOTEQUAL", 0x9e), ("OP_LESSTHAN", 0x9f), ("OP_GREATERTHAN", 0xa0),
+    ("OP_LESSTHANOREQUAL", 0xa1), ("OP_GREATERTHANOREQUAL", 0xa2),
+    ("OP_MIN", 0xa3), ("OP_MAX", 0xa4), ("OP_WITHIN", 0xa5),
+    ("OP_RIPEMD160", 0xa6), ("OP_SHA1", 0xa7), ("OP_SHA256", 0xa8), ("OP_HASH160", 0xa9),
+    ("OP_HASH256", 0xaa), ("OP_CODESEPARATOR", 0xab), ("OP_CHECKSIG", 0xac),
+    ("OP_CHECKSIGVERIFY", 0xad), ("OP_CHECKMULTISIG", 0xae), ("OP_CHECKMULTISIGVERIFY", 0xaf),
+    ("OP_NOP1", 0xb0), ("OP_CHECKLOCKTIMEVERIFY", 0xb1), ("OP_NOP2", 0xb1),
+    ("OP_CHECKSEQUENCEVERIFY", 0xb2), ("OP_NOP3", 0xb2), ("OP_NOP4", 0xb3), ("OP_NOP5", 0xb4),
+    ("OP_NOP6", 0xb5), ("OP_NOP7", 0xb6), ("OP_NOP8", 0xb7), ("OP_NOP9", 0xb8), ("OP_NOP10", 0xb9),
+    ("OP_CHECKSIGADD", 0xba),
+];
 
 impl LittleEndianSerialization for Opcode {
-    fn serialize_le(&self, _dest: &mut Vec<u8>) {
-        unimplemented!("Will implement this once I have script validation done to lock down the Opcode enum");
+    /// Serializes the opcode. Push opcodes are serialized using the smallest
+    /// push opcode that can hold the data.
+    fn serialize_le(&self, dest: &mut Vec<u8>) {
+        let byte = match self {
+            Opcode::PushArray(v) => {
+                if v.len() <= 0x4b {
+                    dest.push(v.len() as u8);
+                } else if v.len() <= 0xff {
+                    dest.push(0x4c);
+                    (v.len() as u8).serialize_le(dest);
+                } else if v.len() <= 0xffff {
+                    dest.push(0x4d);
+                    (v.len() as u16).serialize_le(dest);
+                } else {
+                    dest.push(0x4e);
+                    (v.len() as u32).serialize_le(dest);
+                }
+                dest.extend(v);
+                return;
+            }
+            Opcode::PushNumber(0) => 0x00,
+            Opcode::PushNumber(v) => (v + 0x50) as u8,
+            Opcode::Reserved(v) | Opcode::Nop(v) | Opcode::Invalid(v) | Opcode::Disabled(v) => *v,
+            Opcode::If => 0x63,
+            Opcode::NotIf => 0x64,
+            Opcode::Else => 0x67,
+            Opcode::EndIf => 0x68,
+            Opcode::Verify => 0x69,
+            Opcode::Return => 0x6a,
+            Opcode::ToAltStack => 0x6b,
+            Opcode::FromAltStack => 0x6c,
+            Opcode::Drop2 => 0x6d,
+            Opcode::Dup2 => 0x6e,
+            Opcode::Dup3 => 0x6f,
+            Opcode::Over2 => 0x70,
+            Opcode::Rot2 => 0x71,
+            Opcode::Swap2 => 0x72,
+            Opcode::IfDup => 0x73,
+            Opcode::Depth => 0x74,
+            Opcode::Drop => 0x75,
+            Opcode::Dup => 0x76,
+            Opcode::Nip => 0x77,
+            Opcode::Over => 0x78,
+            Opcode::Pick => 0x79,
+            Opcode::Roll => 0x7a,
+            Opcode::Rot => 0x7b,
+            Opcode::Swap => 0x7c,
+            Opcode::Tuck => 0x7d,
+            Opcode::Size => 0x82,
+            Opcode::Equal => 0x87,
+            Opcode::EqualVerify => 0x88,
+            Opcode::Add1 => 0x8b,
+            Opcode::Sub1 => 0x8c,
+            Opcode::Negate => 0x8f,
+            Opcode::Abs => 0x90,
+            Opcode::Not => 0x91,
+            Opcode::NotEqual0 => 0x92,
+            Opcode::Add => 0x93,
+            Opcode::Sub => 0x94,
+            Opcode::BoolAnd => 0x9a,
+            Opcode::BoolOr => 0x9b,
+            Opcode::NumEqual => 0x9c,
+            Opcode::NumEqualVerify => 0x9d,
+            Opcode::NumNotEqual => 0x9e,
+            Opcode::LessThan => 0x9f,
+            Opcode::GreaterThan => 0xa0,
+            Opcode::LessThanOrEqual => 0xa1,
+            Opcode::GreaterThanOrEqual => 0xa2,
+            Opcode::Min => 0xa3,
+            Opcode::Max => 0xa4,
+            Opcode::Within => 0xa5,
+            Opcode::RIPEMD160 => 0xa6,
+            Opcode::SHA1 => 0xa7,
+            Opcode::SHA256 => 0xa8,
+            Opcode::Hash160 => 0xa9,
+            Opcode::Hash256 => 0xaa,
+            Opcode::CodeSeparator => 0xab,
+            Opcode::CheckSig => 0xac,
+            Opcode::CheckSigVerify => 0xad,
+            Opcode::CheckMultisig => 0xae,
+            Opcode::CheckMultisigVerify => 0xaf,
+            Opcode::CheckLockTimeVerify => 0xb1,
+            Opcode::CheckSequenceVerify => 0xb2,
+            Opcode::CheckSigAdd => 0xba,
+        };
+        dest.push(byte);
     }
 
     fn deserialize_le(bytes: &[u8], ix: &mut usize) -> Result<Self, BlockParseError> where Self: Sized {
+        let opcode_ix = *ix;
         match u8::deserialize_le(bytes, ix)? {
-            v @ 0x00..=0x4b => Ok(Opcode::PushArray(read_bytes(bytes, ix, v.usize()?)?)),
+            v @ 0x00..=0x4b => Ok(Opcode::PushArray(read_push(bytes, ix, v.usize()?, opcode_ix)?)),
             0x4c => {
                 let count = u8::deserialize_le(bytes, ix)?.usize()?;
-                Ok(Opcode::PushArray(read_bytes(bytes, ix, count)?))
+                Ok(Opcode::PushArray(read_push(bytes, ix, count, opcode_ix)?))
             }
             0x4d => {
                 let count = u16::deserialize_le(bytes, ix)?.usize()?;
-                Ok(Opcode::PushArray(read_bytes(bytes, ix, count)?))
+                Ok(Opcode::PushArray(read_push(bytes, ix, count, opcode_ix)?))
             }
             0x4e => {
                 let count = u32::deserialize_le(bytes, ix)?.usize()?;
-                Ok(Opcode::PushArray(read_bytes(bytes, ix, count)?))
+                Ok(Opcode::PushArray(read_push(bytes, ix, count, opcode_ix)?))
             }
             v @ 0x4f => Ok(Opcode::PushNumber(v as i8 - 0x50)),
             v @ 0x50 => Ok(Opcode::Reserved(v)),
@@ -96,11 +322,22 @@ impl LittleEndianSerialization for Opcode {
             0xb1 => Ok(Opcode::CheckLockTimeVerify),
             0xb2 => Ok(Opcode::CheckSequenceVerify),
             v @ 0xb3..=0xb9 => Ok(Opcode::Nop(v)),
-            v @ 0xba..=0xff => Ok(Opcode::Invalid(v)),
+            0xba => Ok(Opcode::CheckSigAdd),
+            v @ 0xbb..=0xff => Ok(Opcode::Invalid(v)),
         }
     }
 }
 
+// Reads the data for a push opcode that started at opcode_ix. This is the same
+// as read_bytes but produces a more useful error message if the script is too
+// short to contain the data.
+fn read_push(bytes: &[u8], ix: &mut usize, count: usize, opcode_ix: usize) -> Result<Vec<u8>, BlockParseError> {
+    if bytes.len() - *ix < count {
+        return Err(BlockParseError::new(format!("Push of {} bytes at offset {} exceeds script length {}", count, opcode_ix, bytes.len())));
+    }
+    read_bytes(bytes, ix, count)
+}
+
 /// Parses the given script from raw bytes into a list of opcodes encapsulated
 /// in the Script structure. Note that this only does structural/syntax checking,
 /// and allows invalid opcodes to be in the returned Script.
@@ -117,23 +354,651 @@ pub fn parse_script(bytes: &[u8]) -> Result<Script, BlockParseError> {
     })
 }
 
+/// Parses the given bytes into opcodes like `parse_script`, but also returns the
+/// byte offset at which each opcode starts. This allows mapping each opcode (for
+/// example, a line of disassembly) back to the raw bytes of the script.
+pub fn parse_script_with_offsets(bytes: &[u8]) -> Result<Vec<(usize, Opcode)>, BlockParseError> {
+    let mut opcodes = Vec::new();
+
+    let mut ix = 0;
+    while ix < bytes.len() {
+        let offset = ix;
+        opcodes.push((offset, Opcode::deserialize_le(bytes, &mut ix)?));
+    }
+    Ok(opcodes)
+}
+
+// Parses as much of the given script as possible, stopping at the first opcode
+// that can't be parsed. This matches how signature operations are counted in
+// scripts that may not be syntactically valid (such as output scripts, which
+// aren't parsed until they are spent).
+fn parse_script_prefix(bytes: &[u8]) -> Script {
+    let mut opcodes = Vec::new();
+    let mut ix = 0;
+    while let Ok(opcode) = Opcode::deserialize_le(bytes, &mut ix) {
+        opcodes.push(opcode);
+    }
+    Script {
+        opcodes,
+    }
+}
+
+/// Counts the signature operations in the given raw script, as used for the
+/// per-block limit. Unlike `parse_script` this never fails; if the script
+/// isn't syntactically valid, only the opcodes before the error are counted.
+/// See `Script::sigop_count` for the meaning of `accurate`.
+pub fn count_sigops(bytes: &[u8], accurate: bool) -> usize {
+    parse_script_prefix(bytes).sigop_count(accurate)
+}
+
+/// Counts the signature operations in the redeem script of a pay-to-script-hash
+/// (BIP16) spend. The lock script is that of the output being spent, and the
+/// unlock script is the spending input's scriptSig, the last push of which is
+/// the redeem script. Returns zero if the lock script is not P2SH or if the
+/// unlock script is not push-only.
+pub fn p2sh_sigop_count(lock: &[u8], unlock: &[u8]) -> usize {
+    if !is_p2sh(lock) {
+        return 0;
+    }
+    p2sh_redeem_script(unlock).map_or(0, |redeem_script| count_sigops(&redeem_script, true))
+}
+
+/// Counts the signature operations in the witness of a segwit (BIP141) spend,
+/// each of which costs a quarter as much as a legacy sigop towards the block
+/// limit. The lock script is that of the output being spent; if it is P2SH,
+/// the redeem script at the end of the unlock script is checked for a witness
+/// program instead. A P2WPKH spend counts as one sigop, and a P2WSH spend
+/// counts the sigops in its witness script (the last witness item) accurately.
+/// Returns zero for anything else, including future witness versions.
+pub fn witness_sigop_count(lock: &[u8], unlock: &[u8], witness: &[Vec<u8>]) -> usize {
+    let redeem_script;
+    let program = match witness_program(lock) {
+        Some(program) => program,
+        None if is_p2sh(lock) => {
+            redeem_script = p2sh_redeem_script(unlock);
+            match redeem_script.as_deref().and_then(witness_program) {
+                Some(program) => program,
+                None => return 0,
+            }
+        }
+        None => return 0,
+    };
+    match program {
+        (0, program) if program.len() == 20 => 1,
+        (0, program) if program.len() == 32 => witness.last().map_or(0, |witness_script| count_sigops(witness_script, true)),
+        _ => 0,
+    }
+}
+
+// Returns the redeem script of a P2SH spend, which is the last push of the
+// unlock script, or None if the unlock script isn't push-only.
+fn p2sh_redeem_script(unlock: &[u8]) -> Option<Vec<u8>> {
+    let mut unlock = parse_script(unlock).ok()?;
+    if !unlock.is_push_only() {
+        return None;
+    }
+    match unlock.opcodes.pop() {
+        Some(Opcode::PushArray(redeem_script)) => Some(redeem_script),
+        _ => None,
+    }
+}
+
+/// Returns the data pushed by the script that look like public keys, i.e. 33-byte
+/// pushes starting with 0x02 or 0x03 (compressed keys) and 65-byte pushes starting
+/// with 0x04 (uncompressed keys). This is a heuristic: the pushes are not checked
+/// to be valid curve points, and arbitrary data can happen to match.
+pub fn extract_pubkeys(script: &Script) -> Vec<Vec<u8>> {
+    script.opcodes.iter().filter_map(|op| match op {
+        Opcode::PushArray(data) => match (data.len(), data.first()) {
+            (33, Some(0x02 | 0x03)) | (65, Some(0x04)) => Some(data.clone()),
+            _ => None,
+        },
+        _ => None,
+    }).collect()
+}
+
+/// Returns the P2PKH lock script for the given public key hash, i.e.
+/// `OP_DUP OP_HASH160 <hash160> OP_EQUALVERIFY OP_CHECKSIG`.
+pub fn p2pkh_script(hash160: &[u8; 20]) -> Vec<u8> {
+    [&[0x76, 0xa9, 0x14], hash160.as_slice(), &[0x88, 0xac]].concat()
+}
+
+/// Returns the P2SH lock script for the given script hash, i.e.
+/// `OP_HASH160 <hash160> OP_EQUAL`.
+pub fn p2sh_script(hash160: &[u8; 20]) -> Vec<u8> {
+    [&[0xa9, 0x14], hash160.as_slice(), &[0x87]].concat()
+}
+
+/// Returns the P2WPKH lock script for the given public key hash, i.e.
+/// `OP_0 <hash160>`.
+pub fn p2wpkh_script(hash160: &[u8; 20]) -> Vec<u8> {
+    [&[0x00, 0x14], hash160.as_slice()].concat()
+}
+
+/// Returns the P2WSH lock script for the given SHA-256 script hash, i.e.
+/// `OP_0 <sha256>`.
+pub fn p2wsh_script(sha256: &[u8; 32]) -> Vec<u8> {
+    [&[0x00, 0x20], sha256.as_slice()].concat()
+}
+
+/// Checks the witness of a P2WSH spend against the limits on its size. The last
+/// element of the witness is the witness script, which can be at most 3600 bytes;
+/// the other elements form the initial stack for executing it, which can have at
+/// most 100 elements of at most 520 bytes each.
+/// This is checked when verifying P2WSH spends, but can also be used to check
+/// witnesses up front.
+pub fn check_p2wsh_witness(witness: &[Vec<u8>]) -> Result<(), BlockValidationError> {
+    let (witness_script, stack) = match witness.split_last() {
+        Some(split) => split,
+        None => return Err(BlockValidationError::new(String::from("P2WSH witness is empty"))),
+    };
+    if witness_script.len() > MAX_P2WSH_SCRIPT_SIZE {
+        return Err(BlockValidationError::new(format!("P2WSH witness script of {} bytes exceeds the maximum of {}", witness_script.len(), MAX_P2WSH_SCRIPT_SIZE)));
+    }
+    if stack.len() > MAX_P2WSH_STACK_ITEMS {
+        return Err(BlockValidationError::new(format!("P2WSH witness stack has {} elements, exceeding the maximum of {}", stack.len(), MAX_P2WSH_STACK_ITEMS)));
+    }
+    if let Some(element) = stack.iter().find(|element| element.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+        return Err(BlockValidationError::new(format!("P2WSH witness element of {} bytes exceeds the maximum of {}", element.len(), MAX_SCRIPT_ELEMENT_SIZE)));
+    }
+    Ok(())
+}
+
+// Returns true if the given lock script is the P2SH template, i.e.
+// OP_HASH160 <20 bytes> OP_EQUAL.
+fn is_p2sh(lock: &[u8]) -> bool {
+    lock.len() == 23 && lock[0] == 0xa9 && lock[1] == 0x14 && lock[22] == 0x87
+}
+
+// If the given script is a witness program (BIP141), i.e. a version number
+// from OP_0 to OP_16 followed by a single push of 2 to 40 bytes, returns the
+// version and the pushed program.
+pub(crate) fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 || usize::from(script[1]) != script.len() - 2 {
+        return None;
+    }
+    match script[0] {
+        0x00 => Some((0, &script[2..])),
+        v @ 0x51..=0x60 => Some((v - 0x50, &script[2..])),
+        _ => None,
+    }
+}
+
+// Removes all pushes of the given data from the script, at opcode boundaries.
+// The legacy sighash algorithm does this with the signature being checked, as
+// a signature can't commit to itself.
+fn find_and_delete(script: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut push = Vec::new();
+    Opcode::PushArray(data.to_vec()).serialize_le(&mut push);
+    let mut result = Vec::with_capacity(script.len());
+    let mut ix = 0;
+    while ix < script.len() {
+        let start = ix;
+        if Opcode::deserialize_le(script, &mut ix).is_err() {
+            result.extend(&script[start..]);
+            break;
+        }
+        if script[start..ix] != push[..] {
+            result.extend(&script[start..ix]);
+        }
+    }
+    result
+}
+
+/// Checks the signatures for the signature opcodes when verifying scripts.
+/// `DefaultVerifier` is used unless another verifier is given through
+/// `VerifyContext`, which allows using a different library, or collecting the
+/// signatures to verify them in a batch. A batching verifier can return true
+/// for every signature, as long as the caller treats the input as invalid if
+/// the batch later fails; note that this also accepts scripts that only
+/// succeed because a signature fails, such as `<sig> <pubkey> OP_CHECKSIG
+/// OP_NOT`.
+pub trait SignatureVerifier {
+    /// Verifies an ECDSA signature (DER-encoded, without the sighash type byte)
+    /// against the given SEC1-encoded public key and sighash. Invalid keys and
+    /// signatures should just fail to verify. High-S signatures are valid by
+    /// consensus.
+    fn verify_ecdsa(&self, der: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool;
+
+    /// Verifies a BIP340 Schnorr signature (without the sighash type byte)
+    /// against the given x-only public key and sighash. Invalid keys and
+    /// signatures should just fail to verify.
+    fn verify_schnorr(&self, sig: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool;
+}
+
+impl fmt::Debug for dyn SignatureVerifier + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SignatureVerifier")
+    }
+}
+
+/// The built-in signature verifier, which verifies each signature as it is
+/// checked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultVerifier;
+
+impl SignatureVerifier for DefaultVerifier {
+    fn verify_ecdsa(&self, der: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool {
+        verify_ecdsa(der, pubkey, sighash)
+    }
+
+    fn verify_schnorr(&self, sig: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool {
+        verify_schnorr(sig, pubkey, sighash)
+    }
+}
+
+// Verifies a BIP340 Schnorr signature against the given x-only public key and
+// sighash. Invalid keys and signatures fail to verify.
+fn verify_schnorr(sig: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool {
+    let key = match schnorr::VerifyingKey::from_bytes(pubkey) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = match schnorr::Signature::try_from(sig) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    key.verify_prehash(sighash, &signature).is_ok()
+}
+
+// Verifies an ECDSA signature (DER-encoded, without the sighash type byte)
+// against the given SEC1-encoded public key and sighash. Invalid keys and
+// signatures just fail to verify.
+fn verify_ecdsa(der: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool {
+    let key = match VerifyingKey::from_sec1_bytes(pubkey) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = match Signature::from_der(der) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    // Consensus allows high-S signatures, but the library only verifies low-S
+    // ones, so normalize first.
+    let signature = signature.normalize_s().unwrap_or(signature);
+    key.verify_prehash(sighash, &signature).is_ok()
+}
+
+/// Checks that the given signature (including the trailing sighash type byte)
+/// uses the strict DER encoding required by BIP66. That is, it must be a DER
+/// sequence of exactly two integers R and S, each of which is positive and
+/// has no unnecessary leading zero bytes, with no trailing data other than
+/// the sighash type.
+pub fn is_valid_der_signature(sig: &[u8]) -> bool {
+    // Format: 0x30 [total-length] 0x02 [R-length] [R] 0x02 [S-length] [S] [sighash]
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+    if sig[0] != 0x30 || usize::from(sig[1]) != sig.len() - 3 {
+        return false;
+    }
+    let r_len = usize::from(sig[3]);
+    if 5 + r_len >= sig.len() {
+        return false;
+    }
+    let s_len = usize::from(sig[5 + r_len]);
+    if r_len + s_len + 7 != sig.len() {
+        return false;
+    }
+    for (tag_ix, len) in [(2, r_len), (r_len + 4, s_len)] {
+        let int = &sig[tag_ix + 2..tag_ix + 2 + len];
+        if sig[tag_ix] != 0x02 || len == 0 {
+            return false;
+        }
+        // Negative numbers are not allowed
+        if int[0] & 0x80 != 0 {
+            return false;
+        }
+        // A leading zero byte is only allowed if it is needed to keep the
+        // number from being negative
+        if len > 1 && int[0] == 0x00 && int[1] & 0x80 == 0 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Checks that the S value of the given strictly DER-encoded signature (including
+/// the trailing sighash type byte) is in the lower half of the curve order. For
+/// any signature there is an equivalent one with the S value negated, so this
+/// removes a source of transaction malleability. Returns false if the signature
+/// is not strictly DER-encoded.
+pub fn is_low_s(sig: &[u8]) -> bool {
+    if !is_valid_der_signature(sig) {
+        return false;
+    }
+    let r_len = usize::from(sig[3]);
+    let s_len = usize::from(sig[5 + r_len]);
+    let s = &sig[6 + r_len..6 + r_len + s_len];
+    // Strip the leading zero byte, if any, and compare as big-endian numbers
+    let s = if s[0] == 0x00 { &s[1..] } else { s };
+    match s.len() {
+        0..=31 => true,
+        32 => s <= &HALF_CURVE_ORDER[..],
+        _ => false,
+    }
+}
+
+// Parses an ECDSA signature (without the sighash type byte) the way Bitcoin
+// Core did before BIP66, accepting the lax DER encodings that OpenSSL allowed,
+// and returns it re-encoded as strict DER. Returns None if the signature can't
+// be parsed or its R or S value is out of range, in which case it can never
+// verify.
+fn lax_der_to_strict(der: &[u8]) -> Option<Vec<u8>> {
+    // Reads a length byte, or a long-form length, returning the length and
+    // advancing ix past it
+    fn read_length(der: &[u8], ix: &mut usize) -> Option<usize> {
+        let first = *der.get(*ix)?;
+        *ix += 1;
+        if first & 0x80 == 0 {
+            return Some(usize::from(first));
+        }
+        let mut count = usize::from(first & 0x7f);
+        if count > der.len() - *ix {
+            return None;
+        }
+        while count > 0 && der[*ix] == 0x00 {
+            *ix += 1;
+            count -= 1;
+        }
+        if count >= std::mem::size_of::<usize>() {
+            return None;
+        }
+        let mut len = 0;
+        for _ in 0..count {
+            len = (len << 8) + usize::from(der[*ix]);
+            *ix += 1;
+        }
+        Some(len)
+    }
+
+    // Reads an integer, returning it as a 32-byte big-endian number, or
+    // None inside the Some if it is too large
+    fn read_integer(der: &[u8], ix: &mut usize) -> Option<Option<[u8; 32]>> {
+        if der.get(*ix) != Some(&0x02) {
+            return None;
+        }
+        *ix += 1;
+        let len = read_length(der, ix)?;
+        if len > der.len() - *ix {
+            return None;
+        }
+        let mut int = &der[*ix..*ix + len];
+        *ix += len;
+        while let Some((0x00, rest)) = int.split_first() {
+            int = rest;
+        }
+        if int.len() > 32 {
+            return Some(None);
+        }
+        let mut bytes = [0; 32];
+        bytes[32 - int.len()..].copy_from_slice(int);
+        Some(Some(bytes))
+    }
+
+    // The sequence tag and length; the length isn't checked, and trailing
+    // data after the integers is ignored
+    if der.first() != Some(&0x30) {
+        return None;
+    }
+    let mut ix = 1;
+    let first = *der.get(ix)?;
+    ix += 1;
+    if first & 0x80 != 0 {
+        let count = usize::from(first & 0x7f);
+        if count > der.len() - ix {
+            return None;
+        }
+        ix += count;
+    }
+    let r = read_integer(der, &mut ix)?;
+    let s = read_integer(der, &mut ix)?;
+    let signature = Signature::from_scalars(r?, s?).ok()?;
+    Some(signature.to_der().as_bytes().to_vec())
+}
+
+/// Assembles a script from a human-readable string into raw bytes. The string
+/// should consist of whitespace-separated tokens, each of which is either an
+/// opcode name (e.g. `OP_DUP`) or hex-encoded data in angle brackets (e.g.
+/// `<89abcdef>`). Data is pushed using the smallest push opcode that can hold
+/// it; small numbers should be pushed using `OP_1` through `OP_16` instead.
+/// An explicit `OP_PUSHDATA1`, `OP_PUSHDATA2`, or `OP_PUSHDATA4` may precede
+/// data, but an error is returned if that is not the minimal encoding. Use
+/// `from_asm_nonminimal` to produce such scripts.
+pub fn from_asm(asm: &str) -> Result<Vec<u8>, BlockParseError> {
+    assemble_script(asm, true)
+}
+
+/// Same as `from_asm`, except that explicit `OP_PUSHDATA1`, `OP_PUSHDATA2`,
+/// and `OP_PUSHDATA4` prefixes on data are always honored, even if a smaller
+/// push opcode could have been used. This is useful for producing scripts
+/// that test the handling of non-minimal pushes.
+pub fn from_asm_nonminimal(asm: &str) -> Result<Vec<u8>, BlockParseError> {
+    assemble_script(asm, false)
+}
+
+/// Returns the opcode byte with the given name, e.g. 0xac for `OP_CHECKSIG`.
+/// Alternative names such as `OP_TRUE` and `OP_NOP2` are also recognized.
+pub fn opcode_from_name(name: &str) -> Option<u8> {
+    OPCODE_NAMES.iter().find(|(n, _)| *n == name).map(|(_, byte)| *byte)
+}
+
+/// Returns the canonical name of the given opcode byte. Pushes of 1 to 75
+/// bytes (0x01 to 0x4b) and invalid opcodes don't have a name.
+pub fn opcode_name(byte: u8) -> Option<&'static str> {
+    OPCODE_NAMES.iter().find(|(_, b)| *b == byte).map(|(name, _)| *name)
+}
+
+fn assemble_script(asm: &str, minimal: bool) -> Result<Vec<u8>, BlockParseError> {
+    let mut script = Vec::new();
+    let mut tokens = asm.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if let Some(hex) = token.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+            Opcode::PushArray(hex_to_bytes(hex)?).serialize_le(&mut script);
+            continue;
+        }
+
+        let byte = match opcode_from_name(token) {
+            Some(byte) => byte,
+            None => return Err(BlockParseError::new(format!("Unrecognized opcode {} in script assembly", token))),
+        };
+        if !(0x4c..=0x4e).contains(&byte) {
+            script.push(byte);
+            continue;
+        }
+
+        // Explicit push opcode; the data must follow
+        let data = match tokens.next().and_then(|t| t.strip_prefix('<')).and_then(|t| t.strip_suffix('>')) {
+            Some(hex) => hex_to_bytes(hex)?,
+            None => return Err(BlockParseError::new(format!("Expected data after {} in script assembly", token))),
+        };
+        let mut minimal_push = Vec::new();
+        Opcode::PushArray(data.clone()).serialize_le(&mut minimal_push);
+        if minimal_push[0] == byte {
+            script.extend(minimal_push);
+            continue;
+        }
+        if minimal {
+            return Err(BlockParseError::new(format!("Non-minimal push of {} bytes using {} in script assembly", data.len(), token)));
+        }
+        let too_long = || BlockParseError::new(format!("Too much data for {}: {} bytes", token, data.len()));
+        script.push(byte);
+        match byte {
+            0x4c => u8::try_from(data.len()).map_err(|_| too_long())?.serialize_le(&mut script),
+            0x4d => u16::try_from(data.len()).map_err(|_| too_long())?.serialize_le(&mut script),
+            _ => u32::try_from(data.len()).map_err(|_| too_long())?.serialize_le(&mut script),
+        };
+        script.extend(data);
+    }
+    Ok(script)
+}
+
+/// Disassembles the script into the format accepted by `from_asm`: opcode
+/// names separated by spaces, with pushed data shown as `<hex>`. The push
+/// opcodes themselves aren't shown, so non-minimal pushes look the same as
+/// minimal ones. Invalid opcodes are shown as their hex value, e.g. `0xbb`,
+/// and can't be assembled again.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (ix, opcode) in self.opcodes.iter().enumerate() {
+            if ix > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", opcode)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a single opcode as it appears in the disassembly of a script; see
+/// the `Display` implementation of `Script`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Opcode::PushArray(data) if !data.is_empty() => {
+                f.write_str("<")?;
+                for v in data {
+                    write!(f, "{:02x}", v)?;
+                }
+                f.write_str(">")
+            }
+            _ => {
+                let mut bytes = Vec::new();
+                self.serialize_le(&mut bytes);
+                match opcode_name(bytes[0]) {
+                    Some(name) => f.write_str(name),
+                    None => write!(f, "0x{:02x}", bytes[0]),
+                }
+            }
+        }
+    }
+}
+
 impl Script {
-    fn validate(self) -> Result<Self, BlockValidationError> {
+    /// Counts the signature operations in the script. CHECKSIG and CHECKSIGVERIFY
+    /// count as one each. In accurate mode, a CHECKMULTISIG or CHECKMULTISIGVERIFY
+    /// that is immediately preceded by OP_1 through OP_16 counts as that number
+    /// of operations; otherwise (and always in non-accurate mode) it counts as 20.
+    /// Consensus uses non-accurate counting for lock and unlock scripts, and
+    /// accurate counting for P2SH redeem scripts.
+    pub fn sigop_count(&self, accurate: bool) -> usize {
+        let mut count = 0;
+        let mut last_opcode: Option<&Opcode> = None;
         for opcode in &self.opcodes {
-            if let Opcode::Invalid(op) = opcode {
-                return Err(BlockValidationError::new(format!("Invalid opcode {} found in script", op)));
+            count += match opcode {
+                Opcode::CheckSig | Opcode::CheckSigVerify => 1,
+                Opcode::CheckMultisig | Opcode::CheckMultisigVerify => match last_opcode {
+                    Some(Opcode::PushNumber(n @ 1..=16)) if accurate => *n as usize,
+                    _ => MAX_PUBKEYS_PER_MULTISIG,
+                },
+                _ => 0,
+            };
+            last_opcode = Some(opcode);
+        }
+        count
+    }
+
+    /// Returns true if the script only pushes data onto the stack, i.e. every
+    /// opcode is a data push or a number push (including OP_0 and OP_1NEGATE).
+    /// P2SH requires the unlock script to be push-only.
+    pub fn is_push_only(&self) -> bool {
+        self.opcodes.iter().all(|op| matches!(op, Opcode::PushArray(_) | Opcode::PushNumber(_)))
+    }
+
+    // Returns true if the script contains an OP_SUCCESSx opcode, which makes a
+    // tapscript succeed unconditionally (BIP342).
+    fn has_op_success(&self) -> bool {
+        self.opcodes.iter().any(|opcode| match opcode {
+            Opcode::Reserved(op) | Opcode::Disabled(op) | Opcode::Invalid(op) =>
+                matches!(op, 0x50 | 0x62 | 0x7e..=0x81 | 0x83..=0x86 | 0x89..=0x8a | 0x8d..=0x8e | 0x95..=0x99 | 0xbb..=0xfe),
+            _ => false,
+        })
+    }
+
+    // Checks for opcodes that make the script invalid wherever they appear,
+    // including in branches that don't get executed.
+    fn validate(self, flags: ScriptFlags) -> Result<Self, BlockValidationError> {
+        // In tapscript most of these opcodes are OP_SUCCESSx instead, which take
+        // precedence over everything else in the script
+        if flags.contains(ScriptFlags::TAPSCRIPT) && self.has_op_success() {
+            return Ok(self);
+        }
+        for opcode in &self.opcodes {
+            match opcode {
+                Opcode::Invalid(op) => return Err(BlockValidationError::new(format!("Invalid opcode {} found in script", op))),
+                Opcode::CheckSigAdd if !flags.contains(ScriptFlags::TAPSCRIPT) =>
+                    return Err(BlockValidationError::new(String::from("Invalid opcode OP_CHECKSIGADD found in non-tapscript script"))),
+                Opcode::Disabled(op) => return Err(BlockValidationError::new(format!("Disabled opcode {} found in script", op))),
+                _ => (),
             }
         }
         Ok(self)
     }
 }
 
+/// Splits a coinbase scriptSig into the block height pushed at its start as
+/// required by BIP34, and the bytes that follow it (the extranonce, and often
+/// an ASCII tag identifying the miner). The height is None if the script
+/// doesn't start with a minimally-encoded push of a non-negative number, in
+/// which case all of the script is returned as the trailing bytes.
+///
+/// Coinbases from before BIP34 activated have no height, but many of them start
+/// with a push of the difficulty bits or some other number, which is returned
+/// as if it were a height; callers should only rely on the height for blocks
+/// where BIP34 was enforced.
+pub fn parse_coinbase_script(script: &[u8]) -> (Option<u64>, Vec<u8>) {
+    let mut ix = 0;
+    let height = match Opcode::deserialize_le(script, &mut ix) {
+        Ok(Opcode::PushNumber(n)) if n >= 0 => Some(n as u64),
+        Ok(Opcode::PushArray(data)) if data.len() <= 8 => {
+            let magnitude = data.iter().rev().fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            let negative = data.last().is_some_and(|last| last & 0x80 != 0);
+            if !negative && encode_number(magnitude as i64) == data {
+                Some(magnitude)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    match height {
+        Some(_) => (height, script[ix..].to_vec()),
+        None => (None, script.to_vec()),
+    }
+}
+
 #[derive(Clone)]
 enum StackEntry {
     Bytes(Vec<u8>),
     Number(i64),
 }
 
+// Encodes a number in the minimal script number format: little-endian bytes
+// of the magnitude, with the sign in the top bit of the last byte.
+fn encode_number(number: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut magnitude = number.unsigned_abs();
+    while magnitude > 0 {
+        bytes.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    if let Some(last) = bytes.last_mut() {
+        if *last & 0x80 != 0 {
+            bytes.push(if number < 0 { 0x80 } else { 0x00 });
+        } else if number < 0 {
+            *last |= 0x80;
+        }
+    }
+    bytes
+}
+
+fn entry_bytes(entry: &StackEntry) -> Vec<u8> {
+    match entry {
+        StackEntry::Bytes(v) => v.clone(),
+        StackEntry::Number(v) => encode_number(*v),
+    }
+}
+
 impl StackEntry {
     fn as_bool(&self) -> bool {
         match self {
@@ -143,23 +1008,179 @@ impl StackEntry {
     }
 }
 
-struct Executor {
+// The input whose scripts are being executed, which signatures commit to,
+// along with the value of the output it spends. Taproot signatures also commit
+// to the lock script and value of the output spent by every input, so those
+// can only be checked if `prevouts` is provided. The signatures are checked
+// with `verifier`.
+#[derive(Clone, Copy)]
+struct SigningContext<'a> {
+    transaction: &'a Transaction,
+    input_index: usize,
+    amount: u64,
+    prevouts: Option<&'a [(Vec<u8>, u64)]>,
+    verifier: &'a dyn SignatureVerifier,
+}
+
+impl<'a> SigningContext<'a> {
+    fn new(transaction: &'a Transaction, input_index: usize, prevouts: &'a [(Vec<u8>, u64)]) -> Self {
+        SigningContext {
+            transaction,
+            input_index,
+            amount: prevouts[input_index].1,
+            prevouts: Some(prevouts),
+            verifier: &DefaultVerifier,
+        }
+    }
+
+    fn taproot_sighash(&self, sighash_type: u32, annex: Option<&[u8]>, leaf_hash: Option<&[u8; 32]>) -> Option<[u8; 32]> {
+        taproot_sighash(self.transaction, self.input_index, self.prevouts?, sighash_type, annex, leaf_hash)
+    }
+}
+
+/// The transaction context needed to verify the scripts of a single input,
+/// including its signatures. Signatures commit to the spending transaction and,
+/// for segwit inputs, to the value of the output being spent.
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyContext<'a> {
+    /// The spending transaction, or None if there is no transaction, in which
+    /// case no signature is valid.
+    pub transaction: Option<&'a Transaction>,
+    /// The index of the input being verified in the spending transaction.
+    pub input_index: usize,
+    /// The lock script of the output being spent.
+    pub prevout_script: &'a [u8],
+    /// The value of the output being spent.
+    pub amount: u64,
+    /// The maximum amount of work that executing the input's scripts may do
+    /// before verification fails. Each opcode costs one unit, and OP_PICK and
+    /// OP_ROLL additionally cost the depth of the stack entry they copy or move,
+    /// so that scripts shuffling a large stack can't use excessive CPU time.
+    /// Defaults to `DEFAULT_WORK_BUDGET`.
+    pub work_budget: u64,
+    /// The verifier used to check signatures. Defaults to `DefaultVerifier`.
+    pub verifier: &'a dyn SignatureVerifier,
+}
+
+impl<'a> VerifyContext<'a> {
+    /// Creates a context for verifying the given input of the transaction,
+    /// which spends an output with the given lock script and value.
+    pub fn new(transaction: &'a Transaction, input_index: usize, prevout_script: &'a [u8], amount: u64) -> Self {
+        VerifyContext {
+            transaction: Some(transaction),
+            input_index,
+            prevout_script,
+            amount,
+            work_budget: DEFAULT_WORK_BUDGET,
+            verifier: &DefaultVerifier,
+        }
+    }
+
+    /// Creates a context with just a lock script and no transaction, for
+    /// scripts that don't check signatures. This behaves like `verify`.
+    pub fn without_transaction(prevout_script: &'a [u8]) -> Self {
+        VerifyContext {
+            transaction: None,
+            input_index: 0,
+            prevout_script,
+            amount: 0,
+            work_budget: DEFAULT_WORK_BUDGET,
+            verifier: &DefaultVerifier,
+        }
+    }
+}
+
+/// A single step of script execution, as recorded by `verify_with_trace`.
+#[derive(Clone, Debug)]
+pub struct TraceStep {
+    /// The opcode that was executed, formatted as in the script disassembly.
+    pub opcode: String,
+    /// The contents of the main stack just before the opcode was executed,
+    /// from bottom to top.
+    pub stack: Vec<Vec<u8>>,
+}
+
+struct Executor<'a> {
     stack: Vec<StackEntry>,
     alt_stack: Vec<StackEntry>,
+    flags: ScriptFlags,
+    context: Option<SigningContext<'a>>,
+    // The raw bytes of the script being executed, which are part of the sighash
+    script_code: Vec<u8>,
+    // Whether signatures use the segwit version 0 sighash
+    segwit: bool,
+    // The steps executed so far, if tracing is enabled
+    trace: Option<Vec<TraceStep>>,
+    // The work that may still be done before execution is aborted
+    work_remaining: u64,
+    // Whether a tapscript with an OP_SUCCESSx opcode was executed
+    op_success: bool,
+}
+
+// The state that carries over between all the scripts executed to verify a
+// single input.
+struct InputState {
+    // The steps executed so far, if tracing is enabled
+    trace: Option<Vec<TraceStep>>,
+    // The work that may still be done before execution is aborted
+    work_remaining: u64,
+}
+
+impl InputState {
+    fn new(work_budget: u64) -> Self {
+        Self {
+            trace: None,
+            work_remaining: work_budget,
+        }
+    }
 }
 
 fn empty_err() -> BlockValidationError {
     BlockValidationError::new(String::from("Stack is empty when attempting to read item"))
 }
 
-impl Executor {
-    fn new() -> Self {
+fn too_many_ops_err() -> BlockValidationError {
+    BlockValidationError::new(format!("Script has more than {} opcodes", MAX_OPS_PER_SCRIPT))
+}
+
+impl<'a> Executor<'a> {
+    fn new(flags: ScriptFlags, context: Option<SigningContext<'a>>) -> Self {
         Self {
             stack: Vec::new(),
             alt_stack: Vec::new(),
+            flags,
+            context,
+            script_code: Vec::new(),
+            segwit: false,
+            trace: None,
+            work_remaining: 0,
+            op_success: false,
         }
     }
 
+    // Executes the script, appending the steps to the state's trace if it is
+    // Some, and deducting the work done from the state's budget.
+    fn run(&mut self, script: Script, state: &mut InputState) -> Result<(), ScriptError> {
+        self.trace = state.trace.take();
+        self.work_remaining = state.work_remaining;
+        let result = self.execute(script);
+        state.trace = self.trace.take();
+        state.work_remaining = self.work_remaining;
+        result.map_err(ScriptError::Validation)
+    }
+
+    fn charge_work(&mut self, work: u64) -> Result<(), BlockValidationError> {
+        self.work_remaining = self.work_remaining.checked_sub(work)
+            .ok_or_else(|| BlockValidationError::new(String::from("Script execution exceeded the work budget")))?;
+        Ok(())
+    }
+
+    // Returns true if execution succeeded, i.e. the top stack entry is true or
+    // the script was a tapscript with an OP_SUCCESSx opcode.
+    fn succeeded(&self) -> bool {
+        self.op_success || self.stack.last().is_some_and(StackEntry::as_bool)
+    }
+
     fn top_bool(&mut self) -> Result<bool, BlockValidationError> {
         let as_bool = match self.stack.pop() {
             None => return Err(empty_err()),
@@ -177,6 +1198,84 @@ impl Executor {
         Ok(as_number)
     }
 
+    fn top_bytes(&mut self) -> Result<Vec<u8>, BlockValidationError> {
+        match self.stack.pop() {
+            None => Err(empty_err()),
+            Some(StackEntry::Bytes(v)) => Ok(v),
+            Some(StackEntry::Number(v)) => Ok(encode_number(v)),
+        }
+    }
+
+    // Checks a signature for CHECKSIG and CHECKMULTISIG. Empty signatures are
+    // allowed (and always fail to verify), but with the DERSIG or LOW_S flags
+    // any other signature must be strictly DER-encoded. Without those flags a
+    // signature in the lax DER encoding accepted before BIP66 is re-encoded
+    // and verified, and one that can't be parsed at all fails to verify.
+    fn check_signature(&self, sig: &[u8], pubkey: &[u8]) -> Result<bool, BlockValidationError> {
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        let mut strict_der = None;
+        if !is_valid_der_signature(sig) {
+            if self.flags.intersects(ScriptFlags::DERSIG | ScriptFlags::LOW_S) {
+                return Err(BlockValidationError::new(String::from("Signature is not strictly DER-encoded")));
+            }
+            strict_der = match lax_der_to_strict(&sig[..sig.len() - 1]) {
+                Some(der) => Some(der),
+                None => return Ok(false),
+            };
+        }
+        if self.flags.contains(ScriptFlags::LOW_S) && !is_low_s(sig) {
+            return Err(BlockValidationError::new(String::from("Signature has a high S value")));
+        }
+        // Without a transaction there is no sighash, so no signature is valid
+        let context = match self.context {
+            Some(context) => context,
+            None => return Ok(false),
+        };
+        let (sighash_type, der) = sig.split_last().expect("Signature was checked to be non-empty");
+        let der = strict_der.as_deref().unwrap_or(der);
+        let sighash_type = u32::from(*sighash_type);
+        let sighash = if self.segwit {
+            segwit_v0_sighash(context.transaction, context.input_index, &self.script_code, context.amount, sighash_type)
+        } else {
+            let script_code = find_and_delete(&self.script_code, sig);
+            legacy_sighash(context.transaction, context.input_index, &script_code, sighash_type)
+        };
+        Ok(context.verifier.verify_ecdsa(der, pubkey, &sighash))
+    }
+
+    // Checks a Schnorr signature for the tapscript signature opcodes. As for
+    // `check_signature` an empty signature fails to verify, but any other
+    // signature that fails to verify is an error. Public keys that aren't 32
+    // bytes are reserved for future upgrades, and any signature for them
+    // verifies.
+    fn check_schnorr_signature(&self, sig: &[u8], pubkey: &[u8]) -> Result<bool, BlockValidationError> {
+        if pubkey.is_empty() {
+            return Err(BlockValidationError::new(String::from("Public key is empty")));
+        }
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        if pubkey.len() != 32 {
+            return Ok(true);
+        }
+        let (signature, sighash_type) = match sig.len() {
+            64 => (sig, SIGHASH_DEFAULT),
+            65 if sig[64] != 0 => (&sig[..64], u32::from(sig[64])),
+            _ => return Err(BlockValidationError::new(format!("Schnorr signature has invalid length {}", sig.len()))),
+        };
+        let leaf_hash = tapleaf_hash(TAPSCRIPT_LEAF_VERSION, &self.script_code);
+        let verified = self.context.is_some_and(|context| {
+            context.taproot_sighash(sighash_type, None, Some(&leaf_hash))
+                .is_some_and(|sighash| context.verifier.verify_schnorr(signature, pubkey, &sighash))
+        });
+        if !verified {
+            return Err(BlockValidationError::new(String::from("Schnorr signature failed to verify")));
+        }
+        Ok(true)
+    }
+
     fn stack_at_least(&self, depth: usize) -> Result<(), BlockValidationError> {
         if self.stack.len() < depth {
             return Err(empty_err());
@@ -185,14 +1284,41 @@ impl Executor {
     }
 
     fn execute(&mut self, script: Script) -> Result<(), BlockValidationError> {
+        if self.flags.contains(ScriptFlags::TAPSCRIPT) && script.has_op_success() {
+            self.op_success = true;
+            return Ok(());
+        }
+        let op_limit = !self.flags.contains(ScriptFlags::TAPSCRIPT);
+        let mut op_count = 0;
         for opcode in script.opcodes {
+            if let Some(trace) = self.trace.as_mut() {
+                trace.push(TraceStep {
+                    opcode: opcode.to_string(),
+                    stack: self.stack.iter().map(entry_bytes).collect(),
+                });
+            }
+            self.charge_work(1)?;
+            // Pushes, including OP_RESERVED which is numbered among them, don't count
+            if op_limit && !matches!(opcode, Opcode::PushArray(_) | Opcode::PushNumber(_) | Opcode::Reserved(0x50)) {
+                op_count += 1;
+                if op_count > MAX_OPS_PER_SCRIPT {
+                    return Err(too_many_ops_err());
+                }
+            }
             match opcode {
+                // Larger pushes can be parsed, but fail when executed. Opcodes that
+                // produce bytes either copy existing stack entries or produce hashes,
+                // so they can't exceed the limit.
+                Opcode::PushArray(v) if v.len() > MAX_SCRIPT_ELEMENT_SIZE =>
+                    return Err(BlockValidationError::new(format!("Push of {} bytes exceeds the maximum of {}", v.len(), MAX_SCRIPT_ELEMENT_SIZE))),
                 Opcode::PushArray(v) => self.stack.push(StackEntry::Bytes(v)),
                 Opcode::PushNumber(v) => self.stack.push(StackEntry::Number(v.into())),
 
                 Opcode::Reserved(op) => return Err(BlockValidationError::new(format!("Unexpected reserved opcode {}", op))),
                 Opcode::Disabled(op) => return Err(BlockValidationError::new(format!("Unexpected disabled opcode {}", op))),
                 Opcode::Invalid(_) => panic!("Invalid opcodes should have already gotten filtered out"),
+                Opcode::Nop(op) if op != 0x61 && self.flags.contains(ScriptFlags::DISCOURAGE_UPGRADABLE_NOPS) =>
+                    return Err(BlockValidationError::new(format!("Upgradable NOP {} is discouraged", op))),
                 Opcode::Nop(_) => (),
 /*
     TODO
@@ -203,7 +1329,8 @@ impl Executor {
 */
 
                 Opcode::Verify => {
-                    if !self.top_bool()? {
+                    let top = self.top_bool()?;
+                    if !top {
                         return Err(BlockValidationError::new(String::from("Top stack entry evaluted to false for VERIFY opcode")));
                     }
                 }
@@ -277,6 +1404,7 @@ impl Executor {
                     let number = usize::try_from(number).map_err(|_| BlockValidationError::new(format!("Unable to fit top stack item {} in a usize", number)))?;
                     let depth = number.checked_add(1).ok_or_else(|| BlockValidationError::new(String::from("Overflow during PICK operation")))?;
                     self.stack_at_least(depth)?;
+                    self.charge_work(depth as u64)?;
                     self.stack.push(self.stack[self.stack.len() - depth].clone());
                 }
                 Opcode::Roll => {
@@ -284,6 +1412,7 @@ impl Executor {
                     let number = usize::try_from(number).map_err(|_| BlockValidationError::new(format!("Unable to fit top stack item {} in a usize", number)))?;
                     let depth = number.checked_add(1).ok_or_else(|| BlockValidationError::new(String::from("Overflow during ROLL operation")))?;
                     self.stack_at_least(depth)?;
+                    self.charge_work(depth as u64)?;
                     let removed = self.stack.remove(self.stack.len() - depth);
                     self.stack.push(removed);
                 }
@@ -302,13 +1431,25 @@ impl Executor {
                     let copy = self.stack[self.stack.len() - 1].clone();
                     self.stack.insert(self.stack.len() - 2, copy);
                 }
+                Opcode::Size => {
+                    let top = self.stack.last().ok_or_else(empty_err)?;
+                    let size = i64::try_from(entry_bytes(top).len()).map_err(|_| BlockValidationError::new(String::from("Stack entry size is too large for i64")))?;
+                    self.stack.push(StackEntry::Number(size));
+                }
+                Opcode::Equal | Opcode::EqualVerify => {
+                    let first = self.top_bytes()?;
+                    let second = self.top_bytes()?;
+                    let equal = first == second;
+                    if matches!(opcode, Opcode::EqualVerify) {
+                        if !equal {
+                            return Err(BlockValidationError::new(String::from("Stack entries were not equal for EQUALVERIFY opcode")));
+                        }
+                    } else {
+                        self.stack.push(StackEntry::Number(equal.into()));
+                    }
+                }
 /*
     TODO
-    Opcode::Size, // 0x82
-
-    Opcode::Equal, // 0x87
-    Opcode::EqualVerify, // 0x88
-
     Opcode::Add1, // 0x8b
     Opcode::Sub1, // 0x8c
     Opcode::Negate, // 0x8f
@@ -330,22 +1471,120 @@ impl Executor {
     Opcode::Min, // 0xa3
     Opcode::Max, // 0xa4
     Opcode::Within, // 0xa5
-
-    Opcode::RIPEMD160, // 0xa6
-    Opcode::SHA1, // 0xa7
-    Opcode::SHA256, // 0xa8
-    Opcode::Hash160, // 0xa9
-    Opcode::Hash256, // 0xaa
+*/
+                Opcode::RIPEMD160 => {
+                    let data = self.top_bytes()?;
+                    self.stack.push(StackEntry::Bytes(Ripemd160::digest(data).to_vec()));
+                }
+                Opcode::SHA1 => {
+                    let data = self.top_bytes()?;
+                    self.stack.push(StackEntry::Bytes(Sha1::digest(data).to_vec()));
+                }
+                Opcode::SHA256 => {
+                    let data = self.top_bytes()?;
+                    self.stack.push(StackEntry::Bytes(Sha256::digest(data).to_vec()));
+                }
+                Opcode::Hash160 => {
+                    let data = self.top_bytes()?;
+                    self.stack.push(StackEntry::Bytes(hash160(&data).to_vec()));
+                }
+                Opcode::Hash256 => {
+                    let data = self.top_bytes()?;
+                    self.stack.push(StackEntry::Bytes(sha256d(&data).to_vec()));
+                }
+/*
+    TODO
     Opcode::CodeSeparator, // 0xab
-    Opcode::CheckSig, // 0xac
-    Opcode::CheckSigVerify, // 0xad
-    Opcode::CheckMultisig, // 0xae
-    Opcode::CheckMultisigVerify, // 0xaf
+*/
+                Opcode::CheckSig | Opcode::CheckSigVerify => {
+                    let pubkey = self.top_bytes()?;
+                    let sig = self.top_bytes()?;
+                    let valid = if self.flags.contains(ScriptFlags::TAPSCRIPT) {
+                        self.check_schnorr_signature(&sig, &pubkey)?
+                    } else {
+                        self.check_signature(&sig, &pubkey)?
+                    };
+                    if matches!(opcode, Opcode::CheckSigVerify) {
+                        if !valid {
+                            return Err(BlockValidationError::new(String::from("Signature check failed for CHECKSIGVERIFY opcode")));
+                        }
+                    } else {
+                        self.stack.push(StackEntry::Number(valid.into()));
+                    }
+                }
+                Opcode::CheckMultisig | Opcode::CheckMultisigVerify if self.flags.contains(ScriptFlags::TAPSCRIPT) =>
+                    return Err(BlockValidationError::new(String::from("CHECKMULTISIG is not available in tapscript"))),
+                Opcode::CheckMultisig | Opcode::CheckMultisigVerify => {
+                    let key_count = self.top_number()?;
+                    let key_count = usize::try_from(key_count).ok().filter(|n| *n <= MAX_PUBKEYS_PER_MULTISIG)
+                        .ok_or_else(|| BlockValidationError::new(format!("Invalid public key count {} for CHECKMULTISIG", key_count)))?;
+                    op_count += key_count;
+                    if op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(too_many_ops_err());
+                    }
+                    self.stack_at_least(key_count)?;
+                    let pubkeys = self.stack.split_off(self.stack.len() - key_count);
+                    let sig_count = self.top_number()?;
+                    let sig_count = usize::try_from(sig_count).ok().filter(|n| *n <= key_count)
+                        .ok_or_else(|| BlockValidationError::new(format!("Invalid signature count {} for CHECKMULTISIG with {} keys", sig_count, key_count)))?;
+                    self.stack_at_least(sig_count)?;
+                    let sigs = self.stack.split_off(self.stack.len() - sig_count);
+                    // An extra stack item is consumed due to an off-by-one bug in the
+                    // original implementation.
+                    let dummy = self.stack.pop().ok_or_else(empty_err)?;
+                    if self.flags.contains(ScriptFlags::NULLDUMMY) && !entry_bytes(&dummy).is_empty() {
+                        return Err(BlockValidationError::new(String::from("Extra element consumed by CHECKMULTISIG is not empty")));
+                    }
 
+                    // Signatures must be in the same order as the keys they match, so
+                    // walk through the keys looking for a match for each signature.
+                    let mut keys = pubkeys.iter().rev();
+                    let mut valid = true;
+                    for sig in sigs.iter().rev() {
+                        let sig = entry_bytes(sig);
+                        loop {
+                            let pubkey = match keys.next() {
+                                Some(pubkey) => entry_bytes(pubkey),
+                                None => {
+                                    valid = false;
+                                    break;
+                                }
+                            };
+                            if self.check_signature(&sig, &pubkey)? {
+                                break;
+                            }
+                        }
+                        if !valid {
+                            break;
+                        }
+                    }
+                    if matches!(opcode, Opcode::CheckMultisigVerify) {
+                        if !valid {
+                            return Err(BlockValidationError::new(String::from("Signature check failed for CHECKMULTISIGVERIFY opcode")));
+                        }
+                    } else {
+                        self.stack.push(StackEntry::Number(valid.into()));
+                    }
+                }
+/*
+    TODO
     Opcode::CheckLockTimeVerify, // 0xb1
     Opcode::CheckSequenceVerify, // 0xb2
 */
-                _ => (),
+                Opcode::CheckSigAdd => {
+                    let pubkey = self.top_bytes()?;
+                    let number = self.top_number()?;
+                    let sig = self.top_bytes()?;
+                    let valid = self.check_schnorr_signature(&sig, &pubkey)?;
+                    let sum = number.checked_add(valid.into()).ok_or_else(|| BlockValidationError::new(String::from("Overflow during CHECKSIGADD operation")))?;
+                    self.stack.push(StackEntry::Number(sum));
+                }
+                // The opcodes in the TODO lists above. Skipping them would let
+                // e.g. `OP_0 OP_IF <anything> OP_ENDIF` verify, so fail instead.
+                _ => return Err(BlockValidationError::new(format!("Opcode {} is not supported yet", opcode))),
+            }
+            if self.stack.len() + self.alt_stack.len() > MAX_STACK_SIZE {
+                return Err(BlockValidationError::new(format!("Stack size exceeds the maximum of {}", MAX_STACK_SIZE)));
             }
         }
         Ok(())
@@ -355,14 +1594,1167 @@ impl Executor {
 /// Verifies the given lock and unlock scripts. This does the three steps of
 /// script parsing (fails if syntax is incorrect), script validation (fails
 /// if invalid opcodes are used), and script verification (runs the scripts
-/// and ensures that the unlock script correctly unlocks the output from the
-/// lock script).
+/// and checks that the unlock script correctly unlocks the output from the
+/// lock script, i.e. that execution leaves a true value on the stack). The
+/// rules enforced are the current consensus rules, as given by
+/// `ScriptFlags::consensus`.
+///
+/// There is no transaction to compute the sighash from, so signature checks
+/// always fail; use `verify_transaction` to verify signed inputs.
 pub fn verify(lock: &[u8], unlock: &[u8]) -> Result<bool, ScriptError> {
-    let lock = parse_script(lock).map_err(ScriptError::Parse)?.validate().map_err(ScriptError::Validation)?;
-    let unlock = parse_script(unlock).map_err(ScriptError::Parse)?.validate().map_err(ScriptError::Validation)?;
+    verify_with_flags(lock, unlock, ScriptFlags::consensus())
+}
+
+/// Same as `verify`, but with exactly the rules selected by the given flags
+/// being enforced, which allows checking blocks from before a rule activated
+/// or checking standardness.
+pub fn verify_with_flags(lock: &[u8], unlock: &[u8], flags: ScriptFlags) -> Result<bool, ScriptError> {
+    verify_input(lock, unlock, &[], flags, None, &mut InputState::new(DEFAULT_WORK_BUDGET))
+}
 
-    let mut executor = Executor::new();
-    executor.execute(unlock).map_err(ScriptError::Validation)?;
-    executor.execute(lock).map_err(ScriptError::Validation)?;
+/// Same as `verify_with_flags`, but also returns a trace of every opcode that
+/// was executed along with the stack before it, for debugging scripts that
+/// don't verify. If verification fails with an error, the last step of the
+/// trace is the opcode that failed. The trace is empty if the scripts fail to
+/// parse.
+pub fn verify_with_trace(lock: &[u8], unlock: &[u8], flags: ScriptFlags) -> (Result<bool, ScriptError>, Vec<TraceStep>) {
+    let mut state = InputState::new(DEFAULT_WORK_BUDGET);
+    state.trace = Some(Vec::new());
+    let result = verify_input(lock, unlock, &[], flags, None, &mut state);
+    (result, state.trace.unwrap_or_default())
+}
+
+/// Verifies the unlock scripts and witnesses of all the inputs of the given
+/// transaction against the outputs they spend. `prevouts` has the lock script
+/// and value of the output spent by each input, in the same order as the
+/// inputs. Legacy, P2SH and segwit version 0 spends are verified with the
+/// current consensus rules, including their signatures. A coinbase transaction
+/// doesn't spend any outputs, so there is nothing to verify.
+///
+/// Verification is partial: flow control (IF/NOTIF/ELSE/ENDIF), the arithmetic
+/// opcodes, CODESEPARATOR, CHECKLOCKTIMEVERIFY and CHECKSEQUENCEVERIFY aren't
+/// implemented yet, and nor are taproot script-path spends. Scripts that use
+/// them return an error rather than being accepted unchecked.
+///
+/// Returns `Ok(false)` if an input's scripts run without error but leave a
+/// false value on the stack (e.g. because a signature is invalid).
+pub fn verify_transaction(tx: &Transaction, prevouts: &[(Vec<u8>, u64)]) -> Result<bool, ScriptError> {
+    if tx.is_coinbase() {
+        return Ok(true);
+    }
+    if prevouts.len() != tx.inputs.len() {
+        return Err(ScriptError::Validation(BlockValidationError::new(format!("Transaction has {} inputs but {} prevouts were provided", tx.inputs.len(), prevouts.len()))));
+    }
+    for (input_index, (input, (lock, _))) in tx.inputs.iter().zip(prevouts).enumerate() {
+        let context = SigningContext::new(tx, input_index, prevouts);
+        if !verify_input(lock, &input.unlock_script, &input.witness_stuff, ScriptFlags::consensus(), Some(context), &mut InputState::new(DEFAULT_WORK_BUDGET))? {
+            return Ok(false);
+        }
+    }
     Ok(true)
 }
+
+/// Verifies a single input against the output it spends, as described by the
+/// context, with the rules selected by the given flags. `unlock` and `witness`
+/// are the unlock script and witness of the input. Unlike `verify_with_flags`,
+/// signatures are checked against the context's transaction, although taproot
+/// signatures can't be checked since they commit to the outputs spent by all
+/// the inputs; use `verify_transaction` for those. Use
+/// `VerifyContext::without_transaction` for scripts without signatures.
+pub fn verify_with_context(context: &VerifyContext, unlock: &[u8], witness: &[Vec<u8>], flags: ScriptFlags) -> Result<bool, ScriptError> {
+    let signing_context = match context.transaction {
+        Some(transaction) if context.input_index >= transaction.inputs.len() => {
+            return Err(validation_err(format!("Input index {} is out of range for transaction with {} inputs", context.input_index, transaction.inputs.len())));
+        }
+        Some(transaction) => Some(SigningContext {
+            transaction,
+            input_index: context.input_index,
+            amount: context.amount,
+            prevouts: None,
+            verifier: context.verifier,
+        }),
+        None => None,
+    };
+    verify_input(context.prevout_script, unlock, witness, flags, signing_context, &mut InputState::new(context.work_budget))
+}
+
+fn validation_err(msg: String) -> ScriptError {
+    ScriptError::Validation(BlockValidationError::new(msg))
+}
+
+fn parse_and_validate(bytes: &[u8], flags: ScriptFlags) -> Result<Script, ScriptError> {
+    parse_script(bytes).map_err(ScriptError::Parse)?.validate(flags).map_err(ScriptError::Validation)
+}
+
+// Verifies a single input, given the lock script of the output it spends and
+// its unlock script and witness. If the state's trace is Some, the executed
+// steps are appended to it.
+fn verify_input(lock: &[u8], unlock: &[u8], witness: &[Vec<u8>], flags: ScriptFlags, context: Option<SigningContext>, state: &mut InputState) -> Result<bool, ScriptError> {
+    let lock_script = parse_and_validate(lock, flags)?;
+    let unlock_script = parse_and_validate(unlock, flags)?;
+    let push_only = unlock_script.is_push_only();
+    if flags.contains(ScriptFlags::SIGPUSHONLY) && !push_only {
+        return Err(validation_err(String::from("Unlock script is not push-only")));
+    }
+
+    let mut executor = Executor::new(flags, context);
+    executor.script_code = unlock.to_vec();
+    executor.run(unlock_script, state)?;
+    let unlock_stack = executor.stack.clone();
+    executor.script_code = lock.to_vec();
+    executor.run(lock_script, state)?;
+    if !executor.succeeded() {
+        return Ok(false);
+    }
+
+    let mut witness_verified = false;
+    if let Some((version, program)) = witness_program(lock).filter(|_| flags.contains(ScriptFlags::WITNESS)) {
+        if !unlock.is_empty() {
+            return Err(validation_err(String::from("Unlock script is not empty for a witness program spend")));
+        }
+        if !verify_witness_program(version, program, witness, false, flags, context, state)? {
+            return Ok(false);
+        }
+        witness_verified = true;
+        // The lock script's own stack is left as is, and isn't subject to CLEANSTACK
+        executor.stack.truncate(1);
+    }
+
+    if flags.contains(ScriptFlags::P2SH) && is_p2sh(lock) {
+        if !push_only {
+            return Err(validation_err(String::from("Unlock script of a P2SH spend is not push-only")));
+        }
+        // The lock script checked the hash of the redeem script; now run it on
+        // the rest of the stack left by the unlock script.
+        let mut stack = unlock_stack;
+        let redeem = entry_bytes(&stack.pop().ok_or_else(|| ScriptError::Validation(empty_err()))?);
+        let redeem_script = parse_and_validate(&redeem, flags)?;
+        executor = Executor::new(flags, context);
+        executor.stack = stack;
+        executor.script_code = redeem.clone();
+        executor.run(redeem_script, state)?;
+        if !executor.succeeded() {
+            return Ok(false);
+        }
+
+        if let Some((version, program)) = witness_program(&redeem).filter(|_| flags.contains(ScriptFlags::WITNESS)) {
+            let mut expected_unlock = Vec::new();
+            Opcode::PushArray(redeem.clone()).serialize_le(&mut expected_unlock);
+            if unlock != expected_unlock {
+                return Err(validation_err(String::from("Unlock script of a P2SH-wrapped witness program spend must only push the redeem script")));
+            }
+            if !verify_witness_program(version, program, witness, true, flags, context, state)? {
+                return Ok(false);
+            }
+            witness_verified = true;
+            executor.stack.truncate(1);
+        }
+    }
+
+    if flags.contains(ScriptFlags::CLEANSTACK) && executor.stack.len() != 1 {
+        return Err(validation_err(format!("Stack has {} elements after execution instead of 1", executor.stack.len())));
+    }
+    if flags.contains(ScriptFlags::WITNESS) && !witness_verified && !witness.is_empty() {
+        return Err(validation_err(String::from("Witness data provided for an input that doesn't spend a witness program")));
+    }
+    Ok(true)
+}
+
+// Verifies the witness of an input spending a witness program with the given
+// version, which is wrapped in P2SH if `p2sh_wrapped` is set. Version 0 (P2WPKH
+// and P2WSH) and native version 1 with a 32-byte program (taproot) are defined;
+// other programs are reserved for future soft forks and so always succeed.
+fn verify_witness_program(version: u8, program: &[u8], witness: &[Vec<u8>], p2sh_wrapped: bool, flags: ScriptFlags, context: Option<SigningContext>, state: &mut InputState) -> Result<bool, ScriptError> {
+    if version == 1 && program.len() == 32 && !p2sh_wrapped && flags.contains(ScriptFlags::TAPROOT) {
+        return verify_taproot(program, witness, context);
+    }
+    if version != 0 {
+        return Ok(true);
+    }
+    let (script_code, stack) = match <&[u8; 20]>::try_from(program) {
+        Ok(pubkey_hash) => {
+            if witness.len() != 2 {
+                return Err(validation_err(format!("P2WPKH witness has {} elements instead of 2", witness.len())));
+            }
+            // The witness is executed as if it were spending a P2PKH output
+            (p2pkh_script(pubkey_hash), witness)
+        }
+        Err(_) if program.len() == 32 => {
+            check_p2wsh_witness(witness).map_err(ScriptError::Validation)?;
+            let (witness_script, stack) = witness.split_last().expect("P2WSH witness was checked to be non-empty");
+            if Sha256::digest(witness_script)[..] != *program {
+                return Err(validation_err(String::from("P2WSH witness script does not match the hash in the witness program")));
+            }
+            (witness_script.clone(), stack)
+        }
+        Err(_) => return Err(validation_err(format!("Version 0 witness program has invalid length {}", program.len()))),
+    };
+    if let Some(element) = stack.iter().find(|element| element.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+        return Err(validation_err(format!("Witness element of {} bytes exceeds the maximum of {}", element.len(), MAX_SCRIPT_ELEMENT_SIZE)));
+    }
+
+    let script = parse_and_validate(&script_code, flags)?;
+    let mut executor = Executor::new(flags, context);
+    executor.stack = stack.iter().map(|element| StackEntry::Bytes(element.clone())).collect();
+    executor.script_code = script_code;
+    executor.segwit = true;
+    executor.run(script, state)?;
+    // Unlike legacy scripts, witness scripts must always leave a clean stack
+    if executor.stack.len() != 1 {
+        return Err(validation_err(format!("Witness script left {} elements on the stack instead of 1", executor.stack.len())));
+    }
+    Ok(executor.succeeded())
+}
+
+// Verifies the witness of a taproot spend, where `output_key` is the x-only
+// public key in the witness program. A key-path spend is a single signature
+// for the output key, plus an optional annex.
+fn verify_taproot(output_key: &[u8], witness: &[Vec<u8>], context: Option<SigningContext>) -> Result<bool, ScriptError> {
+    let (annex, stack) = match witness.split_last() {
+        None => return Err(validation_err(String::from("Taproot spend has an empty witness"))),
+        Some((last, rest)) if !rest.is_empty() && last.first() == Some(&0x50) => (Some(last.as_slice()), rest),
+        Some(_) => (None, witness),
+    };
+    let sig = match stack {
+        [sig] => sig,
+        // TODO: verify script-path spends, which have the script and control block
+        // as the last two elements. Until then they fail rather than being
+        // accepted without running the script.
+        _ => return Err(validation_err(String::from("Taproot script-path spends are not supported"))),
+    };
+    let (signature, sighash_type) = match sig.len() {
+        64 => (&sig[..], SIGHASH_DEFAULT),
+        65 if sig[64] != 0 => (&sig[..64], u32::from(sig[64])),
+        _ => return Err(validation_err(format!("Schnorr signature has invalid length {}", sig.len()))),
+    };
+    // Without a transaction there is no sighash, so no signature is valid
+    let context = match context {
+        Some(context) => context,
+        None => return Ok(false),
+    };
+    // Taproot signatures commit to all the outputs being spent
+    if context.prevouts.is_none() {
+        return Ok(false);
+    }
+    let sighash = context.taproot_sighash(sighash_type, annex, None)
+        .ok_or_else(|| validation_err(format!("Invalid sighash type {:#x} for taproot signature", sighash_type)))?;
+    Ok(context.verifier.verify_schnorr(signature, output_key, &sighash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn push_overrun() {
+        let script = parse_script(&[0x76, 0x4c, 0x03, 0xaa, 0xbb, 0xcc, 0xac]).unwrap();
+        assert_eq!(script.opcodes.len(), 3);
+        assert!(matches!(&script.opcodes[1], Opcode::PushArray(v) if v == &[0xaa, 0xbb, 0xcc]));
+
+        let err = parse_script(&[0x76, 0xa9, 0x4c, 0xff, 0x01, 0x02, 0x03]).unwrap_err();
+        assert_eq!(err.to_string(), "Push of 255 bytes at offset 2 exceeds script length 7");
+
+        let err = parse_script(&[0x02, 0x01]).unwrap_err();
+        assert_eq!(err.to_string(), "Push of 2 bytes at offset 0 exceeds script length 2");
+    }
+
+    #[test]
+    fn assemble() {
+        let p2pkh = from_asm("OP_DUP OP_HASH160 <89abcdefabbaabbaabbaabbaabbaabbaabbaabba> OP_EQUALVERIFY OP_CHECKSIG").unwrap();
+        assert_eq!(p2pkh, [
+            &[0x76, 0xa9, 0x14][..],
+            &[0x89, 0xab, 0xcd, 0xef, 0xab, 0xba, 0xab, 0xba, 0xab, 0xba, 0xab, 0xba, 0xab, 0xba, 0xab, 0xba, 0xab, 0xba, 0xab, 0xba],
+            &[0x88, 0xac],
+        ].concat());
+        assert_eq!(from_asm("OP_0 OP_1 OP_TRUE OP_16 OP_1NEGATE <>").unwrap(), [0x00, 0x51, 0x51, 0x60, 0x4f, 0x00]);
+        assert!(from_asm("OP_BOGUS").is_err());
+        assert!(from_asm("<abc>").is_err());
+    }
+
+    #[test]
+    fn disassemble() {
+        let p2pkh = from_asm("OP_DUP OP_HASH160 <89abcdefabbaabbaabbaabbaabbaabbaabbaabba> OP_EQUALVERIFY OP_CHECKSIG").unwrap();
+        assert_eq!(parse_script(&p2pkh).unwrap().to_string(), "OP_DUP OP_HASH160 <89abcdefabbaabbaabbaabbaabbaabbaabbaabba> OP_EQUALVERIFY OP_CHECKSIG");
+        assert_eq!(parse_script(&[0x00, 0x51, 0x4f, 0xb1, 0xb3, 0xba, 0xbb]).unwrap().to_string(), "OP_0 OP_1 OP_1NEGATE OP_CHECKLOCKTIMEVERIFY OP_NOP4 OP_CHECKSIGADD 0xbb");
+        assert_eq!(parse_script(&[]).unwrap().to_string(), "");
+
+        assert_eq!(opcode_from_name("OP_CHECKSIG"), Some(0xac));
+        assert_eq!(opcode_from_name("OP_TRUE"), Some(0x51));
+        assert_eq!(opcode_from_name("OP_NOP2"), Some(0xb1));
+        assert_eq!(opcode_from_name("OP_BOGUS"), None);
+        assert_eq!(opcode_name(0x51), Some("OP_1"));
+        assert_eq!(opcode_name(0x14), None);
+
+        // The parseable scripts in a real block survive a round trip
+        let data = crate::parse::tests::read_testdata("block_265458.dat");
+        let block = crate::parse::parse_blockfile(&data).unwrap().pop().unwrap();
+        let scripts = block.transactions.iter().skip(1).flat_map(|tx| {
+            tx.inputs.iter().map(|input| &input.unlock_script).chain(tx.outputs.iter().map(|output| &output.lock_script))
+        });
+        for script in scripts.filter(|script| parse_script(script).is_ok()) {
+            let asm = parse_script(script).unwrap().to_string();
+            assert_eq!(&from_asm(&asm).unwrap(), script, "Round trip failed for {}", asm);
+        }
+    }
+
+    #[test]
+    fn assemble_push_boundaries() {
+        let asm = |len: usize| format!("<{}>", "ab".repeat(len));
+
+        let script = from_asm(&asm(75)).unwrap();
+        assert_eq!(script.len(), 76);
+        assert_eq!(script[0], 75);
+
+        let script = from_asm(&asm(76)).unwrap();
+        assert_eq!(script.len(), 78);
+        assert_eq!(script[..2], [0x4c, 76]);
+
+        let script = from_asm(&asm(255)).unwrap();
+        assert_eq!(script[..2], [0x4c, 0xff]);
+
+        let script = from_asm(&asm(256)).unwrap();
+        assert_eq!(script[..3], [0x4d, 0x00, 0x01]);
+
+        let script = from_asm(&asm(65536)).unwrap();
+        assert_eq!(script[..5], [0x4e, 0x00, 0x00, 0x01, 0x00]);
+
+        // Explicit push opcodes are fine if they are minimal anyway
+        assert_eq!(from_asm(&format!("OP_PUSHDATA1 {}", asm(76))).unwrap(), from_asm(&asm(76)).unwrap());
+        assert!(from_asm(&format!("OP_PUSHDATA1 {}", asm(75))).is_err());
+        assert!(from_asm("OP_PUSHDATA1").is_err());
+
+        let script = from_asm_nonminimal(&format!("OP_PUSHDATA1 {}", asm(75))).unwrap();
+        assert_eq!(script[..2], [0x4c, 75]);
+        let script = from_asm_nonminimal("OP_PUSHDATA2 <ab>").unwrap();
+        assert_eq!(script, [0x4d, 0x01, 0x00, 0xab]);
+        let script = from_asm_nonminimal("OP_PUSHDATA4 <ab>").unwrap();
+        assert_eq!(script, [0x4e, 0x01, 0x00, 0x00, 0x00, 0xab]);
+        assert!(from_asm_nonminimal(&format!("OP_PUSHDATA1 {}", asm(256))).is_err());
+
+        // Everything assembled parses back into the same data
+        let script = parse_script(&from_asm_nonminimal(&format!("OP_PUSHDATA2 {}", asm(3))).unwrap()).unwrap();
+        assert!(matches!(&script.opcodes[0], Opcode::PushArray(v) if v == &[0xab, 0xab, 0xab]));
+    }
+
+    #[test]
+    fn sigops() {
+        let count = |asm: &str, accurate: bool| parse_script(&from_asm(asm).unwrap()).unwrap().sigop_count(accurate);
+        let key = format!("<{}>", "02".repeat(33));
+
+        let p2pkh = "OP_DUP OP_HASH160 <89abcdefabbaabbaabbaabbaabbaabbaabbaabba> OP_EQUALVERIFY OP_CHECKSIG";
+        assert_eq!(count(p2pkh, false), 1);
+        assert_eq!(count(p2pkh, true), 1);
+
+        let multisig = format!("OP_2 {} {} {} OP_3 OP_CHECKMULTISIG", key, key, key);
+        assert_eq!(count(&multisig, false), 20);
+        assert_eq!(count(&multisig, true), 3);
+        assert_eq!(count("OP_CHECKMULTISIGVERIFY", true), 20);
+        assert_eq!(count("OP_0 OP_CHECKMULTISIG", true), 20);
+        assert_eq!(count("OP_CHECKSIG OP_CHECKSIGVERIFY OP_16 OP_CHECKMULTISIGVERIFY", true), 18);
+
+        // Counting stops at the first parse error
+        assert_eq!(count_sigops(&[0xac, 0xac, 0x4c, 0x05, 0xac], false), 2);
+
+        let redeem_script = from_asm(&multisig).unwrap();
+        let lock = from_asm("OP_HASH160 <0000000000000000000000000000000000000000> OP_EQUAL").unwrap();
+        let unlock = from_asm(&format!("OP_0 <30> <30> <{}>", redeem_script.iter().map(|b| format!("{:02x}", b)).collect::<String>())).unwrap();
+        assert_eq!(p2sh_sigop_count(&lock, &unlock), 3);
+        assert_eq!(p2sh_sigop_count(&from_asm(p2pkh).unwrap(), &unlock), 0);
+        let non_push_unlock = [&[0x76][..], &unlock].concat();
+        assert_eq!(p2sh_sigop_count(&lock, &non_push_unlock), 0);
+
+        // P2WPKH counts as one sigop, P2WSH counts its witness script accurately,
+        // and either can be wrapped in P2SH
+        let p2wpkh = p2wpkh_script(&[0; 20]);
+        let p2wsh = p2wsh_script(&[0; 32]);
+        assert_eq!(witness_sigop_count(&p2wpkh, &[], &[]), 1);
+        assert_eq!(witness_sigop_count(&p2wsh, &[], &[vec![0x30], redeem_script.clone()]), 3);
+        assert_eq!(witness_sigop_count(&p2wsh, &[], &[]), 0);
+        let mut wrapped = Vec::new();
+        Opcode::PushArray(p2wsh).serialize_le(&mut wrapped);
+        let witness = [redeem_script];
+        assert_eq!(witness_sigop_count(&lock, &wrapped, &witness), 3);
+        assert_eq!(witness_sigop_count(&lock, &unlock, &witness), 0);
+        assert_eq!(witness_sigop_count(&from_asm(p2pkh).unwrap(), &[], &witness), 0);
+        // Future witness versions don't have any sigops yet
+        assert_eq!(witness_sigop_count(&[&[0x51, 0x20][..], &[0; 32]].concat(), &[], &[vec![0xac]]), 0);
+    }
+
+    #[test]
+    fn script_error_source() {
+        fn verify_boxed(lock: &[u8], unlock: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(verify(lock, unlock)?)
+        }
+
+        assert!(verify_boxed(&[0x51], &[0x51]).unwrap());
+        let err = verify_boxed(&[0x51], &[0x4c, 0x05]).unwrap_err();
+        assert_eq!(err.to_string(), "Script parse error");
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "Push of 5 bytes at offset 0 exceeds script length 2");
+        assert!(source.source().is_none());
+
+        let err = verify_boxed(&[0x6a], &[0x51]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ScriptError>(), Some(ScriptError::Validation(_))));
+        assert_eq!(err.to_string(), "Script validation error");
+        assert_eq!(err.source().unwrap().to_string(), "Encountered RETURN opcode");
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Builds a DER signature (with a SIGHASH_ALL byte) from the given R and S values
+    fn der_signature(r: &[u8], s: &[u8]) -> Vec<u8> {
+        let mut sig = vec![0x30, (r.len() + s.len() + 4) as u8, 0x02, r.len() as u8];
+        sig.extend(r);
+        sig.extend([0x02, s.len() as u8]);
+        sig.extend(s);
+        sig.push(0x01);
+        sig
+    }
+
+    #[test]
+    fn der_signatures() {
+        let r = [0x7f; 32];
+        let s = [0x11; 32];
+        let canonical = der_signature(&r, &s);
+        assert_eq!(canonical.len(), 71);
+        assert!(is_valid_der_signature(&canonical));
+
+        // R with the high bit set needs a single leading zero byte
+        let mut high_r = vec![0x00];
+        high_r.extend([0x80; 32]);
+        assert!(is_valid_der_signature(&der_signature(&high_r, &s)));
+        assert!(!is_valid_der_signature(&der_signature(&high_r[1..], &s)));
+
+        // Over-padded R
+        let mut padded_r = vec![0x00];
+        padded_r.extend(r);
+        assert!(!is_valid_der_signature(&der_signature(&padded_r, &s)));
+
+        // Negative S
+        assert!(!is_valid_der_signature(&der_signature(&r, &[0x81; 32])));
+
+        // Structural problems
+        assert!(!is_valid_der_signature(&canonical[..8]));
+        assert!(!is_valid_der_signature(&der_signature(&r, &[])));
+        let mut bad_length = canonical.clone();
+        bad_length[1] += 1;
+        assert!(!is_valid_der_signature(&bad_length));
+        let mut bad_tag = canonical.clone();
+        bad_tag[2] = 0x03;
+        assert!(!is_valid_der_signature(&bad_tag));
+        let mut trailing = canonical.clone();
+        trailing.insert(70, 0x00);
+        assert!(!is_valid_der_signature(&trailing));
+
+        // CHECKSIG and CHECKMULTISIG reject non-DER signatures outright, rather
+        // than just failing the signature check.
+        let pubkey = [0x02; 33];
+        let checksig = |sig: &[u8]| verify(&from_asm("OP_CHECKSIG").unwrap(), &from_asm(&format!("<{}> <{}>", hex(sig), hex(&pubkey))).unwrap());
+        assert!(checksig(&canonical).is_ok());
+        assert!(checksig(&[]).is_ok());
+        assert!(checksig(&der_signature(&padded_r, &s)).is_err());
+        let multisig = |sig: &[u8]| verify(&from_asm(&format!("OP_1 <{}> OP_1 OP_CHECKMULTISIG", hex(&pubkey))).unwrap(), &from_asm(&format!("OP_0 <{}>", hex(sig))).unwrap());
+        assert!(multisig(&canonical).is_ok());
+        assert!(multisig(&der_signature(&r, &[0x81; 32])).is_err());
+    }
+
+    #[test]
+    fn number_encoding() {
+        assert_eq!(encode_number(0), Vec::<u8>::new());
+        assert_eq!(encode_number(1), [0x01]);
+        assert_eq!(encode_number(-1), [0x81]);
+        assert_eq!(encode_number(127), [0x7f]);
+        assert_eq!(encode_number(128), [0x80, 0x00]);
+        assert_eq!(encode_number(-128), [0x80, 0x80]);
+        assert_eq!(encode_number(255), [0xff, 0x00]);
+        assert_eq!(encode_number(256), [0x00, 0x01]);
+        assert_eq!(encode_number(-256), [0x00, 0x81]);
+    }
+
+    #[test]
+    fn coinbase_script() {
+        let block = crate::parse::parse_blockfile(&crate::parse::tests::read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        let (height, tag) = parse_coinbase_script(&block.transactions[0].inputs[0].unlock_script);
+        assert_eq!(height, Some(481829));
+        assert_eq!(&tag[..5], &[0x04, 0x59, 0x48, 0x9e, 0x59]);
+        assert!(String::from_utf8_lossy(&tag).contains("/Bixin/BitcoinEnterprise/NYA/"));
+
+        assert_eq!(parse_coinbase_script(&[0x51, 0xaa]), (Some(1), vec![0xaa]));
+        assert_eq!(parse_coinbase_script(&[0x02, 0x80, 0x00]), (Some(128), vec![]));
+        // Pre-BIP34 coinbases may start with anything, including bytes that
+        // aren't a number or aren't even a valid script
+        assert_eq!(parse_coinbase_script(&[]), (None, vec![]));
+        assert_eq!(parse_coinbase_script(&[0x02, 0x05, 0x00, 0xaa]), (None, vec![0x02, 0x05, 0x00, 0xaa]));
+        assert_eq!(parse_coinbase_script(&[0x01, 0x81]), (None, vec![0x01, 0x81]));
+        assert_eq!(parse_coinbase_script(b"/P2SH/"), (None, b"/P2SH/".to_vec()));
+    }
+
+    #[test]
+    fn low_s() {
+        let r = [0x7f; 32];
+        assert!(is_low_s(&der_signature(&r, &[0x11; 32])));
+        assert!(is_low_s(&der_signature(&r, &[0x01])));
+        assert!(is_low_s(&der_signature(&r, &HALF_CURVE_ORDER)));
+
+        let mut high_s = HALF_CURVE_ORDER;
+        high_s[31] += 1;
+        assert!(!is_low_s(&der_signature(&r, &high_s)));
+        let mut padded_high_s = vec![0x00];
+        padded_high_s.extend([0xff; 32]);
+        assert!(!is_low_s(&der_signature(&r, &padded_high_s)));
+        assert!(!is_low_s(&[0x30]));
+
+        // High-S signatures are only rejected if the flag is set
+        let lock = from_asm("OP_CHECKSIG").unwrap();
+        let unlock = from_asm(&format!("<{}> <{}>", hex(&der_signature(&r, &high_s)), hex(&[0x02; 33]))).unwrap();
+        assert!(verify(&lock, &unlock).is_ok());
+        let err = verify_with_flags(&lock, &unlock, ScriptFlags::LOW_S).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Signature has a high S value");
+        let unlock = from_asm(&format!("<{}> <{}>", hex(&der_signature(&r, &[0x11; 32])), hex(&[0x02; 33]))).unwrap();
+        assert!(verify_with_flags(&lock, &unlock, ScriptFlags::LOW_S).is_ok());
+    }
+
+    #[test]
+    fn push_only() {
+        let script = parse_script(&from_asm("OP_0 <3044> OP_1NEGATE OP_16 <>").unwrap()).unwrap();
+        assert!(script.is_push_only());
+        assert!(parse_script(&[]).unwrap().is_push_only());
+        let script = parse_script(&from_asm("<3044> OP_DUP").unwrap()).unwrap();
+        assert!(!script.is_push_only());
+    }
+
+    #[test]
+    fn disabled_opcodes() {
+        let lock = from_asm("OP_1").unwrap();
+        let unlock = from_asm("OP_0 OP_IF <01> <02> OP_CAT OP_ENDIF").unwrap();
+        let err = verify(&lock, &unlock).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Disabled opcode 126 found in script");
+
+        let lock = from_asm("OP_0 OP_IF OP_MUL OP_ENDIF OP_1").unwrap();
+        assert!(verify(&lock, &[]).is_err());
+    }
+
+    #[test]
+    fn unsupported_opcodes() {
+        // Flow control isn't implemented, so the branch that isn't taken can't
+        // be skipped; this must not verify as if the IF weren't there.
+        let lock = from_asm("OP_0 OP_IF OP_0 OP_ENDIF OP_1").unwrap();
+        let err = verify(&lock, &[]).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Opcode OP_IF is not supported yet");
+        assert!(verify(&from_asm("OP_1 OP_1 OP_ADD").unwrap(), &[]).is_err());
+    }
+
+    #[test]
+    fn pubkey_extraction() {
+        let compressed = [[0x02].as_slice(), &[0x11; 32]].concat();
+        let uncompressed = [[0x04].as_slice(), &[0x22; 64]].concat();
+
+        let p2pk = parse_script(&from_asm(&format!("<{}> OP_CHECKSIG", hex(&uncompressed))).unwrap()).unwrap();
+        assert_eq!(extract_pubkeys(&p2pk), vec![uncompressed.clone()]);
+
+        // The 33-byte push with an 0x04 prefix isn't a valid key encoding
+        let bad_prefix = [[0x04].as_slice(), &[0x33; 32]].concat();
+        let multisig = parse_script(&from_asm(&format!("OP_1 <{}> <{}> <{}> OP_3 OP_CHECKMULTISIG", hex(&compressed), hex(&bad_prefix), hex(&uncompressed))).unwrap()).unwrap();
+        assert_eq!(extract_pubkeys(&multisig), vec![compressed, uncompressed]);
+
+        let p2pkh = parse_script(&from_asm("OP_DUP OP_HASH160 <89abcdefabbaabbaabbaabbaabbaabbaabbaabba> OP_EQUALVERIFY OP_CHECKSIG").unwrap()).unwrap();
+        assert!(extract_pubkeys(&p2pkh).is_empty());
+    }
+
+    #[test]
+    fn standard_lock_scripts() {
+        let hash160 = [0xab; 20];
+        let sha256 = [0xcd; 32];
+        assert_eq!(p2pkh_script(&hash160), from_asm(&format!("OP_DUP OP_HASH160 <{}> OP_EQUALVERIFY OP_CHECKSIG", hex(&hash160))).unwrap());
+        assert_eq!(p2sh_script(&hash160), from_asm(&format!("OP_HASH160 <{}> OP_EQUAL", hex(&hash160))).unwrap());
+        assert_eq!(p2wpkh_script(&hash160), from_asm(&format!("OP_0 <{}>", hex(&hash160))).unwrap());
+        assert_eq!(p2wsh_script(&sha256), from_asm(&format!("OP_0 <{}>", hex(&sha256))).unwrap());
+
+        assert_eq!(hex(&p2pkh_script(&hash160)), format!("76a914{}88ac", hex(&hash160)));
+        assert_eq!(hex(&p2sh_script(&hash160)), format!("a914{}87", hex(&hash160)));
+        assert_eq!(hex(&p2wpkh_script(&hash160)), format!("0014{}", hex(&hash160)));
+        assert_eq!(hex(&p2wsh_script(&sha256)), format!("0020{}", hex(&sha256)));
+        assert!(is_p2sh(&p2sh_script(&hash160)));
+    }
+
+    #[test]
+    fn p2wsh_witness_limits() {
+        let witness_script = from_asm("OP_DROP OP_1").unwrap();
+        assert!(check_p2wsh_witness(&[vec![0x01; 520], witness_script.clone()]).is_ok());
+        assert!(check_p2wsh_witness(std::slice::from_ref(&witness_script)).is_ok());
+        assert!(check_p2wsh_witness(&[]).is_err());
+
+        let mut witness = vec![vec![0x01]; 100];
+        witness.push(witness_script.clone());
+        assert!(check_p2wsh_witness(&witness).is_ok());
+        witness.insert(0, vec![0x01]);
+        let err = check_p2wsh_witness(&witness).unwrap_err();
+        assert_eq!(err.to_string(), "P2WSH witness stack has 101 elements, exceeding the maximum of 100");
+
+        let err = check_p2wsh_witness(&[vec![0x01; 521], witness_script]).unwrap_err();
+        assert_eq!(err.to_string(), "P2WSH witness element of 521 bytes exceeds the maximum of 520");
+
+        assert!(check_p2wsh_witness(&[vec![0x51; 3600]]).is_ok());
+        assert!(check_p2wsh_witness(&[vec![0x51; 3601]]).is_err());
+    }
+
+    #[test]
+    fn push_size_limit() {
+        let lock = from_asm("OP_DROP OP_1").unwrap();
+        let unlock = from_asm(&format!("<{}>", hex(&[0xab; 520]))).unwrap();
+        assert!(verify(&lock, &unlock).unwrap());
+
+        let unlock = from_asm(&format!("<{}>", hex(&[0xab; 521]))).unwrap();
+        assert!(parse_script(&unlock).is_ok());
+        let err = verify(&lock, &unlock).unwrap_err();
+        assert!(matches!(err, ScriptError::Validation(_)));
+        assert_eq!(err.source().unwrap().to_string(), "Push of 521 bytes exceeds the maximum of 520");
+    }
+
+    #[test]
+    fn verification_flags() {
+        let lock = from_asm("OP_CHECKSIG OP_DROP OP_1").unwrap();
+        let unlock = from_asm(&format!("<{}> <{}>", hex(&[0x30, 0x01]), hex(&[0x02; 33]))).unwrap();
+        assert!(verify_with_flags(&lock, &unlock, ScriptFlags::empty()).is_ok());
+        assert!(verify_with_flags(&lock, &unlock, ScriptFlags::DERSIG).is_err());
+        assert!(verify(&lock, &unlock).is_err());
+
+        let lock = from_asm(&format!("OP_1 <{}> OP_1 OP_CHECKMULTISIG OP_DROP OP_1", hex(&[0x02; 33]))).unwrap();
+        assert!(verify_with_flags(&lock, &from_asm("<01> <>").unwrap(), ScriptFlags::empty()).is_ok());
+        let err = verify_with_flags(&lock, &from_asm("<01> <>").unwrap(), ScriptFlags::NULLDUMMY).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Extra element consumed by CHECKMULTISIG is not empty");
+        assert!(verify(&lock, &from_asm("OP_0 <>").unwrap()).is_ok());
+
+        let lock = from_asm("OP_DROP").unwrap();
+        let unlock = from_asm("OP_1 OP_DUP").unwrap();
+        assert!(verify(&lock, &unlock).is_ok());
+        let err = verify_with_flags(&lock, &unlock, ScriptFlags::SIGPUSHONLY).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Unlock script is not push-only");
+
+        let unlock = from_asm("OP_1 OP_1").unwrap();
+        assert!(verify(&lock, &unlock).is_ok());
+        assert!(verify_with_flags(&lock, &unlock, ScriptFlags::CLEANSTACK).is_ok());
+        let unlock = from_asm("OP_1 OP_1 OP_1").unwrap();
+        let err = verify_with_flags(&lock, &unlock, ScriptFlags::standard()).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Stack has 2 elements after execution instead of 1");
+    }
+
+    #[test]
+    fn upgradable_nops() {
+        let lock = from_asm("OP_NOP4 OP_1").unwrap();
+        assert!(verify(&lock, &[]).unwrap());
+        let err = verify_with_flags(&lock, &[], ScriptFlags::DISCOURAGE_UPGRADABLE_NOPS).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Upgradable NOP 179 is discouraged");
+
+        // Plain OP_NOP isn't affected by the flag
+        let lock = from_asm("OP_NOP OP_1").unwrap();
+        assert!(verify_with_flags(&lock, &[], ScriptFlags::DISCOURAGE_UPGRADABLE_NOPS).unwrap());
+    }
+
+    #[test]
+    fn size_opcode() {
+        // OP_SIZE leaves the entry in place and pushes its length
+        let lock = from_asm("OP_SIZE OP_0 OP_EQUALVERIFY OP_SIZE OP_EQUALVERIFY OP_1").unwrap();
+        assert!(verify(&lock, &from_asm("<>").unwrap()).unwrap());
+
+        let lock = from_asm("OP_SIZE OP_5 OP_EQUALVERIFY <0102030405> OP_EQUAL").unwrap();
+        assert!(verify(&lock, &from_asm("<0102030405>").unwrap()).unwrap());
+        assert!(verify(&lock, &from_asm("<01020304>").unwrap()).is_err());
+
+        // Numbers are measured by their minimal encoding: 16 is one byte, and
+        // -1 is 0x81
+        let lock = from_asm("OP_SIZE OP_1 OP_EQUALVERIFY OP_16 OP_EQUAL").unwrap();
+        assert!(verify(&lock, &from_asm("OP_16").unwrap()).unwrap());
+        let lock = from_asm("OP_SIZE OP_1 OP_EQUALVERIFY OP_DROP OP_1").unwrap();
+        assert!(verify(&lock, &from_asm("OP_1NEGATE").unwrap()).unwrap());
+
+        let err = verify(&from_asm("OP_SIZE").unwrap(), &[]).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Stack is empty when attempting to read item");
+    }
+
+    #[test]
+    fn opcode_offsets() {
+        let p2pkh = p2pkh_script(&[0x89; 20]);
+        let opcodes = parse_script_with_offsets(&p2pkh).unwrap();
+        let offsets = opcodes.iter().map(|(offset, opcode)| (*offset, opcode.to_string())).collect::<Vec<(usize, String)>>();
+        assert_eq!(offsets, vec![
+            (0, "OP_DUP".to_string()),
+            (1, "OP_HASH160".to_string()),
+            (2, format!("<{}>", "89".repeat(20))),
+            (23, "OP_EQUALVERIFY".to_string()),
+            (24, "OP_CHECKSIG".to_string()),
+        ]);
+        assert!(parse_script_with_offsets(&[]).unwrap().is_empty());
+        assert!(parse_script_with_offsets(&p2pkh[..10]).is_err());
+    }
+
+    #[test]
+    fn execution_trace() {
+        let pubkey = [0x02; 33];
+        let sig = der_signature(&[0x11; 32], &[0x22; 32]);
+        let unlock = from_asm(&format!("<{}> <{}>", hex(&sig), hex(&pubkey))).unwrap();
+
+        // The public key doesn't match the hash in the lock script
+        let lock = p2pkh_script(&[0xab; 20]);
+        let (result, trace) = verify_with_trace(&lock, &unlock, ScriptFlags::consensus());
+        assert!(result.is_err());
+        let opcodes: Vec<&str> = trace.iter().map(|step| step.opcode.as_str()).collect();
+        assert_eq!(opcodes, [&format!("<{}>", hex(&sig)), &format!("<{}>", hex(&pubkey)), "OP_DUP", "OP_HASH160", &format!("<{}>", hex(&[0xab; 20])), "OP_EQUALVERIFY"]);
+        let last = trace.last().unwrap();
+        assert_eq!(last.stack, [sig.clone(), pubkey.to_vec(), hash160(&pubkey).to_vec(), vec![0xab; 20]]);
+        assert!(trace[0].stack.is_empty());
+
+        // With the right hash, execution gets as far as the signature check
+        let lock = p2pkh_script(&hash160(&pubkey));
+        let (result, trace) = verify_with_trace(&lock, &unlock, ScriptFlags::consensus());
+        assert!(!result.unwrap());
+        assert_eq!(trace.len(), 7);
+        assert_eq!(trace.last().unwrap().opcode, "OP_CHECKSIG");
+
+        let (result, trace) = verify_with_trace(&[0x4c], &unlock, ScriptFlags::consensus());
+        assert!(result.is_err());
+        assert!(trace.is_empty());
+    }
+
+    // Returns the prevout for each input of a transaction whose inputs are all
+    // P2PKH spends, rebuilt from the public key in the unlock script. The
+    // amounts aren't committed to by legacy signatures, so they're all zero.
+    fn p2pkh_prevouts(tx: &Transaction) -> Vec<(Vec<u8>, u64)> {
+        tx.inputs.iter().map(|input| {
+            let unlock = parse_script(&input.unlock_script).unwrap();
+            let pubkey = extract_pubkeys(&unlock).pop().unwrap();
+            (p2pkh_script(&hash160(&pubkey)), 0)
+        }).collect()
+    }
+
+    #[test]
+    fn transaction_verification() {
+        let data = crate::parse::tests::read_testdata("block_265458.dat");
+        let block = crate::parse::parse_blockfile(&data).unwrap().pop().unwrap();
+        assert!(verify_transaction(&block.transactions[0], &[]).unwrap());
+
+        let tx = &block.transactions[33];
+        assert_eq!(tx.inputs.len(), 3);
+        let prevouts = p2pkh_prevouts(tx);
+        assert!(verify_transaction(tx, &prevouts).unwrap());
+
+        // Without the transaction, the signatures can't be verified
+        assert!(!verify(&prevouts[0].0, &tx.inputs[0].unlock_script).unwrap());
+
+        // Changing an output invalidates the signatures, as does spending a
+        // different output
+        let mut modified = tx.clone();
+        modified.outputs[0].value += 1;
+        assert!(!verify_transaction(&modified, &prevouts).unwrap());
+        let mut swapped = prevouts.clone();
+        swapped.swap(0, 1);
+        let err = verify_transaction(tx, &swapped).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Stack entries were not equal for EQUALVERIFY opcode");
+        assert!(verify_transaction(tx, &prevouts[..2]).is_err());
+    }
+
+    #[test]
+    fn work_budget() {
+        // Fill the stack with 998 entries and push the depth, and then repeatedly
+        // roll the bottom entry up to just below the depth, which stays within
+        // the opcode and stack limits
+        let unlock = vec![0x51; 998];
+        let lock = [vec![0x74], [0x76, 0x7a, 0x7c].repeat(66)].concat();
+        assert!(verify(&lock, &unlock).unwrap());
+
+        let mut context = VerifyContext::without_transaction(&lock);
+        assert!(verify_with_context(&context, &unlock, &[], ScriptFlags::consensus()).unwrap());
+        context.work_budget = 50_000;
+        let err = verify_with_context(&context, &unlock, &[], ScriptFlags::consensus()).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Script execution exceeded the work budget");
+
+        // Every opcode counts, including those in the unlock script
+        context.work_budget = 997;
+        assert!(verify_with_context(&context, &unlock, &[], ScriptFlags::consensus()).is_err());
+        let (result, trace) = verify_with_trace(&[0x75], &unlock, ScriptFlags::consensus());
+        assert!(result.is_ok());
+        assert_eq!(trace.len(), 999);
+    }
+
+    #[test]
+    fn script_limits() {
+        // Pushes don't count towards the opcode limit, but NOPs do
+        let nops = |count| [vec![0x61; count], vec![0x51]].concat();
+        assert!(verify(&nops(MAX_OPS_PER_SCRIPT), &[0x51; 100]).unwrap());
+        let err = verify(&nops(MAX_OPS_PER_SCRIPT + 1), &[]).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Script has more than 201 opcodes");
+        // Each script is counted separately
+        assert!(verify(&nops(MAX_OPS_PER_SCRIPT), &[0x61; MAX_OPS_PER_SCRIPT]).unwrap());
+        // The keys of a CHECKMULTISIG count as opcodes too; this is a 1-of-16
+        // with an empty signature, which fails without an error
+        let multisig = |nops| [vec![0x61; nops], vec![0x00, 0x00, 0x51], vec![0x00; 16], vec![0x60, 0xae]].concat();
+        assert!(!verify(&multisig(184), &[]).unwrap());
+        let err = verify(&multisig(185), &[]).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Script has more than 201 opcodes");
+
+        // The stack and alt stack together can have at most 1000 entries
+        assert!(verify(&[0x51], &[0x51; 999]).unwrap());
+        let err = verify(&[0x51], &[0x51; 1000]).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Stack size exceeds the maximum of 1000");
+        assert!(verify(&[0x6b, 0x51, 0x51], &[0x51; 999]).is_err());
+    }
+
+    #[test]
+    fn context_verification() {
+        let data = crate::parse::tests::read_testdata("block_265458.dat");
+        let block = crate::parse::parse_blockfile(&data).unwrap().pop().unwrap();
+        let tx = &block.transactions[33];
+        let prevouts = p2pkh_prevouts(tx);
+        let flags = ScriptFlags::consensus();
+        for (input_index, input) in tx.inputs.iter().enumerate() {
+            let context = VerifyContext::new(tx, input_index, &prevouts[input_index].0, 0);
+            assert!(verify_with_context(&context, &input.unlock_script, &[], flags).unwrap());
+        }
+
+        // Each signature only covers its own input
+        let context = VerifyContext::new(tx, 1, &prevouts[0].0, 0);
+        assert!(!verify_with_context(&context, &tx.inputs[0].unlock_script, &[], flags).unwrap());
+        let context = VerifyContext::new(tx, 3, &prevouts[0].0, 0);
+        assert!(verify_with_context(&context, &tx.inputs[0].unlock_script, &[], flags).is_err());
+
+        // Without a transaction, signature-free scripts still run but signatures don't verify
+        let context = VerifyContext::without_transaction(&prevouts[0].0);
+        assert!(!verify_with_context(&context, &tx.inputs[0].unlock_script, &[], flags).unwrap());
+        let lock = [&[0xa8, 0x20][..], &Sha256::digest([0x42]), &[0x87]].concat();
+        let context = VerifyContext::without_transaction(&lock);
+        assert!(verify_with_context(&context, &[0x01, 0x42], &[], flags).unwrap());
+        assert!(!verify_with_context(&context, &[0x01, 0x43], &[], flags).unwrap());
+    }
+
+    #[test]
+    fn signature_verifier() {
+        use std::cell::RefCell;
+
+        // Records the public key of every ECDSA check, and gives a fixed answer
+        // after checking the signature with the default verifier
+        struct MockVerifier {
+            calls: RefCell<Vec<(Vec<u8>, bool)>>,
+            answer: bool,
+        }
+        impl SignatureVerifier for MockVerifier {
+            fn verify_ecdsa(&self, der: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool {
+                self.calls.borrow_mut().push((pubkey.to_vec(), DefaultVerifier.verify_ecdsa(der, pubkey, sighash)));
+                self.answer
+            }
+            fn verify_schnorr(&self, _sig: &[u8], _pubkey: &[u8], _sighash: &[u8; 32]) -> bool {
+                unreachable!("No schnorr signatures in this test")
+            }
+        }
+
+        let data = crate::parse::tests::read_testdata("block_265458.dat");
+        let block = crate::parse::parse_blockfile(&data).unwrap().pop().unwrap();
+        let tx = &block.transactions[33];
+        let prevouts = p2pkh_prevouts(tx);
+        let flags = ScriptFlags::consensus();
+        for answer in [true, false] {
+            let verifier = MockVerifier { calls: RefCell::new(Vec::new()), answer };
+            for (input_index, input) in tx.inputs.iter().enumerate() {
+                let mut context = VerifyContext::new(tx, input_index, &prevouts[input_index].0, 0);
+                context.verifier = &verifier;
+                assert_eq!(verify_with_context(&context, &input.unlock_script, &[], flags).unwrap(), answer);
+            }
+            // Each P2PKH input checks one signature against the key pushed by its unlock script
+            let expected = tx.inputs.iter().map(|input| match parse_script(&input.unlock_script).unwrap().opcodes.last() {
+                Some(Opcode::PushArray(pubkey)) => (pubkey.clone(), true),
+                opcode => panic!("Unexpected opcode {:?}", opcode),
+            }).collect::<Vec<(Vec<u8>, bool)>>();
+            assert_eq!(verifier.calls.into_inner(), expected);
+        }
+
+        // The verifier isn't called without a transaction, since there is no sighash
+        let verifier = MockVerifier { calls: RefCell::new(Vec::new()), answer: true };
+        let mut context = VerifyContext::without_transaction(&prevouts[0].0);
+        context.verifier = &verifier;
+        assert!(!verify_with_context(&context, &tx.inputs[0].unlock_script, &[], flags).unwrap());
+        assert!(verifier.calls.into_inner().is_empty());
+    }
+
+    // Signs the sighash with the given key, returning the DER signature with a
+    // SIGHASH_ALL byte appended
+    fn sign(key: &k256::ecdsa::SigningKey, sighash: [u8; 32]) -> Vec<u8> {
+        use k256::ecdsa::signature::hazmat::PrehashSigner;
+        let signature: Signature = key.sign_prehash(&sighash).unwrap();
+        let mut sig = signature.to_der().as_bytes().to_vec();
+        sig.push(crate::sighash::SIGHASH_ALL as u8);
+        sig
+    }
+
+    #[test]
+    fn checksigadd() {
+        use crate::{Hash, TransactionFlags, TransactionInput, TransactionOutput};
+        use k256::schnorr::signature::hazmat::PrehashSigner;
+
+        let keys = [[0x42; 32], [0x43; 32]].map(|secret| schnorr::SigningKey::from_bytes(&secret).unwrap());
+        let [pubkey_1, pubkey_2] = keys.each_ref().map(|key| hex(&key.verifying_key().to_bytes()));
+        let script = from_asm(&format!("<{}> OP_CHECKSIG <{}> OP_CHECKSIGADD OP_2 OP_EQUAL", pubkey_1, pubkey_2)).unwrap();
+        let tx = Transaction {
+            version: 2,
+            flags: TransactionFlags::WITNESS,
+            inputs: vec![TransactionInput {
+                txid: Hash([0x11; 32]),
+                vout: 0,
+                unlock_script: vec![],
+                sequence: 0xffffffff,
+                witness_stuff: vec![],
+                prevout_value: None,
+                prevout_script: None,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 90_000,
+                lock_script: vec![0x51],
+            }],
+            locktime: 0,
+        };
+        let prevouts = [([&[0x51, 0x20][..], &[0x33; 32]].concat(), 100_000)];
+        let leaf_hash = tapleaf_hash(TAPSCRIPT_LEAF_VERSION, &script);
+        let sighash = taproot_sighash(&tx, 0, &prevouts, SIGHASH_DEFAULT, None, Some(&leaf_hash)).unwrap();
+        let [sig_1, sig_2]: [Vec<u8>; 2] = keys.each_ref().map(|key| {
+            let signature: schnorr::Signature = key.sign_prehash(&sighash).unwrap();
+            signature.to_bytes().to_vec()
+        });
+
+        // Runs the script as tapscript with the given stack, bottom first
+        let run = |stack: &[&Vec<u8>], flags: ScriptFlags| -> Result<bool, ScriptError> {
+            let context = SigningContext::new(&tx, 0, &prevouts);
+            let mut executor = Executor::new(flags, Some(context));
+            executor.stack = stack.iter().map(|entry| StackEntry::Bytes(entry.to_vec())).collect();
+            executor.script_code = script.clone();
+            executor.run(parse_and_validate(&script, flags)?, &mut InputState::new(DEFAULT_WORK_BUDGET))?;
+            Ok(executor.succeeded())
+        };
+        let flags = ScriptFlags::consensus() | ScriptFlags::TAPSCRIPT;
+
+        // Each valid signature adds one, and an empty signature adds nothing
+        assert!(run(&[&sig_2, &sig_1], flags).unwrap());
+        assert!(!run(&[&vec![], &sig_1], flags).unwrap());
+        assert!(!run(&[&sig_2, &vec![]], flags).unwrap());
+        // A non-empty signature that doesn't verify fails the script
+        let err = run(&[&sig_1, &sig_1], flags).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Schnorr signature failed to verify");
+
+        // Outside of tapscript the opcode is invalid
+        let err = run(&[&sig_2, &sig_1], ScriptFlags::consensus()).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Invalid opcode OP_CHECKSIGADD found in non-tapscript script");
+    }
+
+    #[test]
+    fn op_success() {
+        let flags = ScriptFlags::consensus() | ScriptFlags::TAPSCRIPT;
+        // OP_CAT, OP_MUL, 0xbb and 0xfe are OP_SUCCESSx in tapscript, and make the
+        // script succeed even with a RETURN or an invalid opcode before them
+        for op in [0x7e, 0x95, 0xbb, 0xfe] {
+            assert!(verify_with_flags(&[0x6a, 0xff, op], &[], flags).unwrap());
+            assert!(verify_with_flags(&[0x6a, 0xff, op], &[], ScriptFlags::consensus()).is_err());
+        }
+        // Other invalid opcodes are still invalid
+        assert!(verify_with_flags(&[0x6a, 0xff], &[], flags).is_err());
+        assert!(verify_with_flags(&[0x65], &[], flags).is_err());
+    }
+
+    #[test]
+    fn taproot_key_path() {
+        use crate::{Hash, TransactionFlags, TransactionInput, TransactionOutput};
+        use k256::schnorr::signature::hazmat::PrehashSigner;
+
+        // The output key would normally be an internal key tweaked with the
+        // script tree, but the key-path verification doesn't depend on that.
+        let key = schnorr::SigningKey::from_bytes(&[0x42; 32]).unwrap();
+        let lock = [&[0x51, 0x20][..], &key.verifying_key().to_bytes()[..]].concat();
+        let mut tx = Transaction {
+            version: 2,
+            flags: TransactionFlags::WITNESS,
+            inputs: (0..2).map(|i| TransactionInput {
+                txid: Hash([0x11 + i; 32]),
+                vout: 0,
+                unlock_script: vec![],
+                sequence: 0xffffffff,
+                witness_stuff: vec![],
+                prevout_value: None,
+                prevout_script: None,
+            }).collect(),
+            outputs: vec![TransactionOutput {
+                value: 190_000,
+                lock_script: vec![0x51],
+            }],
+            locktime: 0,
+        };
+        let prevouts = [(lock.clone(), 100_000), (lock.clone(), 100_000)];
+        let sign = |tx: &Transaction, input_index, sighash_type, annex: Option<&[u8]>| {
+            let sighash = taproot_sighash(tx, input_index, &prevouts, sighash_type, annex, None).unwrap();
+            let signature: schnorr::Signature = key.sign_prehash(&sighash).unwrap();
+            let mut sig = signature.to_bytes().to_vec();
+            if sighash_type != SIGHASH_DEFAULT {
+                sig.push(sighash_type as u8);
+            }
+            sig
+        };
+        let annex = vec![0x50, 0x01];
+        let sig_0 = sign(&tx, 0, SIGHASH_DEFAULT, None);
+        let sig_1 = sign(&tx, 1, crate::sighash::SIGHASH_ALL, Some(&annex));
+        tx.inputs[0].witness_stuff = vec![sig_0.clone()];
+        tx.inputs[1].witness_stuff = vec![sig_1, annex];
+        assert!(verify_transaction(&tx, &prevouts).unwrap());
+
+        // The signatures commit to the outputs, and to the amounts being spent
+        let mut modified = tx.clone();
+        modified.outputs[0].value = 1;
+        assert!(!verify_transaction(&modified, &prevouts).unwrap());
+        let other_prevouts = [(lock.clone(), 100_000), (lock.clone(), 100_001)];
+        assert!(!verify_transaction(&tx, &other_prevouts).unwrap());
+
+        // An explicit sighash type byte can't be SIGHASH_DEFAULT, or undefined
+        let mut modified = tx.clone();
+        modified.inputs[0].witness_stuff[0].push(0x00);
+        assert!(verify_transaction(&modified, &prevouts).is_err());
+        modified.inputs[0].witness_stuff[0][64] = 0x04;
+        assert!(verify_transaction(&modified, &prevouts).is_err());
+        modified.inputs[0].witness_stuff.clear();
+        assert!(verify_transaction(&modified, &prevouts).is_err());
+
+        // Script-path spends aren't verified yet, so they must not be accepted
+        modified.inputs[0].witness_stuff = vec![vec![0x01], vec![0x51], vec![0xc0; 33]];
+        assert!(verify_transaction(&modified, &prevouts).is_err());
+
+        // Before taproot activated these outputs were anyone-can-spend, and
+        // wrapping a version 1 program in P2SH doesn't make it a taproot output
+        let mut modified = tx.clone();
+        modified.inputs[0].witness_stuff[0][0] ^= 1;
+        assert!(!verify_transaction(&modified, &prevouts).unwrap());
+        let context = SigningContext::new(&modified, 0, &prevouts);
+        let flags = ScriptFlags::consensus() - ScriptFlags::TAPROOT;
+        assert!(verify_input(&lock, &[], &modified.inputs[0].witness_stuff, flags, Some(context), &mut InputState::new(DEFAULT_WORK_BUDGET)).unwrap());
+        let mut unlock = Vec::new();
+        Opcode::PushArray(lock.clone()).serialize_le(&mut unlock);
+        let p2sh = p2sh_script(&hash160(&lock));
+        assert!(verify_input(&p2sh, &unlock, &modified.inputs[0].witness_stuff, ScriptFlags::consensus(), Some(context), &mut InputState::new(DEFAULT_WORK_BUDGET)).unwrap());
+    }
+
+    #[test]
+    fn lax_der_signatures() {
+        use crate::{Hash, TransactionFlags, TransactionInput, TransactionOutput};
+
+        let key = k256::ecdsa::SigningKey::from_bytes(&[0x42; 32].into()).unwrap();
+        let pubkey = key.verifying_key().to_encoded_point(true).as_bytes().to_vec();
+        let lock = p2pkh_script(&hash160(&pubkey));
+        let mut tx = Transaction {
+            version: 1,
+            flags: TransactionFlags::empty(),
+            inputs: vec![TransactionInput {
+                txid: Hash([0x11; 32]),
+                vout: 0,
+                unlock_script: vec![],
+                sequence: 0xffffffff,
+                witness_stuff: vec![],
+                prevout_value: None,
+                prevout_script: None,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 90_000,
+                lock_script: p2pkh_script(&[0x22; 20]),
+            }],
+            locktime: 0,
+        };
+
+        // Re-encode the signature with over-padded integers and a long-form
+        // sequence length, which OpenSSL accepted before BIP66
+        let sig = sign(&key, legacy_sighash(&tx, 0, &lock, crate::sighash::SIGHASH_ALL));
+        let signature = Signature::from_der(&sig[..sig.len() - 1]).unwrap();
+        let mut r = vec![0x00, 0x00];
+        r.extend(signature.r().to_bytes());
+        let mut s = vec![0x00];
+        s.extend(signature.s().to_bytes());
+        let mut lax = der_signature(&r, &s);
+        lax.insert(1, 0x81);
+        assert!(!is_valid_der_signature(&lax));
+
+        tx.inputs[0].unlock_script = from_asm(&format!("<{}> <{}>", hex(&lax), hex(&pubkey))).unwrap();
+        let context = VerifyContext::new(&tx, 0, &lock, 0);
+        assert!(verify_with_context(&context, &tx.inputs[0].unlock_script, &[], ScriptFlags::P2SH).unwrap());
+        let err = verify_with_context(&context, &tx.inputs[0].unlock_script, &[], ScriptFlags::P2SH | ScriptFlags::DERSIG).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "Signature is not strictly DER-encoded");
+
+        // Unparseable and out-of-range signatures just fail to verify
+        assert_eq!(lax_der_to_strict(&lax[..lax.len() - 1]), Some(sig[..sig.len() - 1].to_vec()));
+        assert_eq!(lax_der_to_strict(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x03, 0x01, 0x01]), None);
+        assert_eq!(lax_der_to_strict(&der_signature(&[0x01; 33], &s)[..]), None);
+        assert_eq!(lax_der_to_strict(&der_signature(&[0x00], &s)[..]), None);
+        assert_eq!(lax_der_to_strict(&[0x30, 0x84, 0xff]), None);
+        let truncated = from_asm(&format!("<{}> <{}>", hex(&lax[..20]), hex(&pubkey))).unwrap();
+        assert!(!verify_with_context(&context, &truncated, &[], ScriptFlags::P2SH).unwrap());
+    }
+
+    #[test]
+    fn segwit_and_p2sh_verification() {
+        use crate::{Hash, TransactionFlags, TransactionInput, TransactionOutput};
+
+        let key = k256::ecdsa::SigningKey::from_bytes(&[0x42; 32].into()).unwrap();
+        let pubkey = key.verifying_key().to_encoded_point(true).as_bytes().to_vec();
+        let pubkey_hash = hash160(&pubkey);
+        let mut tx = Transaction {
+            version: 2,
+            flags: TransactionFlags::WITNESS,
+            inputs: vec![TransactionInput {
+                txid: Hash([0x11; 32]),
+                vout: 1,
+                unlock_script: vec![],
+                sequence: 0xffffffff,
+                witness_stuff: vec![],
+                prevout_value: None,
+                prevout_script: None,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 90_000,
+                lock_script: p2wpkh_script(&[0x22; 20]),
+            }],
+            locktime: 0,
+        };
+
+        // P2WPKH commits to the amount being spent
+        let script_code = p2pkh_script(&pubkey_hash);
+        let sig = sign(&key, segwit_v0_sighash(&tx, 0, &script_code, 100_000, crate::sighash::SIGHASH_ALL));
+        tx.inputs[0].witness_stuff = vec![sig.clone(), pubkey.clone()];
+        let lock = p2wpkh_script(&pubkey_hash);
+        assert!(verify_transaction(&tx, &[(lock.clone(), 100_000)]).unwrap());
+        assert!(!verify_transaction(&tx, &[(lock.clone(), 100_001)]).unwrap());
+        tx.inputs[0].unlock_script = vec![0x51];
+        assert!(verify_transaction(&tx, &[(lock, 100_000)]).is_err());
+
+        // The same witness works for P2SH-wrapped P2WPKH, if the unlock script
+        // pushes the witness program
+        let redeem = p2wpkh_script(&pubkey_hash);
+        tx.inputs[0].unlock_script = from_asm(&format!("<{}>", hex(&redeem))).unwrap();
+        assert!(verify_transaction(&tx, &[(p2sh_script(&hash160(&redeem)), 100_000)]).unwrap());
+        assert!(!verify_transaction(&tx, &[(p2sh_script(&[0x33; 20]), 100_000)]).unwrap());
+
+        // P2WSH executes the witness script, which must match the program
+        let witness_script = from_asm(&format!("<{}> OP_CHECKSIG", hex(&pubkey))).unwrap();
+        let sig = sign(&key, segwit_v0_sighash(&tx, 0, &witness_script, 100_000, crate::sighash::SIGHASH_ALL));
+        tx.inputs[0].unlock_script = vec![];
+        tx.inputs[0].witness_stuff = vec![sig, witness_script.clone()];
+        let lock = p2wsh_script(&Sha256::digest(&witness_script).into());
+        assert!(verify_transaction(&tx, &[(lock, 100_000)]).unwrap());
+        let err = verify_transaction(&tx, &[(p2wsh_script(&[0x44; 32]), 100_000)]).unwrap_err();
+        assert_eq!(err.source().unwrap().to_string(), "P2WSH witness script does not match the hash in the witness program");
+
+        // Legacy P2SH runs the redeem script with a legacy sighash, and rejects
+        // witness data
+        let legacy_sig = sign(&key, legacy_sighash(&tx, 0, &witness_script, crate::sighash::SIGHASH_ALL));
+        tx.inputs[0].unlock_script = from_asm(&format!("<{}> <{}>", hex(&legacy_sig), hex(&witness_script))).unwrap();
+        let lock = p2sh_script(&hash160(&witness_script));
+        assert!(verify_transaction(&tx, &[(lock.clone(), 0)]).is_err());
+        tx.inputs[0].witness_stuff = vec![];
+        assert!(verify_transaction(&tx, &[(lock.clone(), 0)]).unwrap());
+        // The redeem script isn't run without the P2SH flag, so only its hash is checked
+        let bad_unlock = from_asm(&format!("<> <{}>", hex(&witness_script))).unwrap();
+        assert!(!verify(&lock, &bad_unlock).unwrap());
+        assert!(verify_with_flags(&lock, &bad_unlock, ScriptFlags::empty()).unwrap());
+    }
+}
@@ -0,0 +1,373 @@
+//! A module that exposes the computation of signature hashes, which are the
+//! message that is signed by the signatures in a transaction's scripts. The
+//! sighash type (the last byte of a signature) controls which parts of the
+//! transaction are committed to by the signature.
+
+use crate::{LittleEndianSerialization, Opcode, Transaction, TransactionFlags, TransactionOutput};
+use crate::hash::{sha256d, tagged_hash};
+use sha2::{Digest, Sha256};
+
+/// Sighash type for taproot signatures that commits to all inputs and outputs,
+/// like SIGHASH_ALL. It is implied by 64-byte signatures, which don't have an
+/// explicit sighash type byte.
+pub const SIGHASH_DEFAULT: u32 = 0x00;
+
+/// Sighash type that commits to all inputs and outputs.
+pub const SIGHASH_ALL: u32 = 0x01;
+/// Sighash type that commits to all inputs but none of the outputs.
+pub const SIGHASH_NONE: u32 = 0x02;
+/// Sighash type that commits to all inputs and only the output with the same
+/// index as the input being signed.
+pub const SIGHASH_SINGLE: u32 = 0x03;
+/// Modifier that can be combined with the other sighash types, so that the
+/// signature only commits to the input being signed rather than all inputs.
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+/// Computes the legacy (pre-segwit) signature hash for the input at `input_index`
+/// of the given transaction. The `script_code` is the script being executed,
+/// which is usually the lock script of the output being spent; any
+/// OP_CODESEPARATOR opcodes in it are removed. The returned bytes are the
+/// double SHA-256 hash in natural (not display) order, which is what gets
+/// signed.
+///
+/// As in the Bitcoin Core reference implementation, if the input index is out
+/// of range, or if the sighash type is SIGHASH_SINGLE and there is no output
+/// with the same index as the input, the result is the value one (i.e. a 0x01
+/// byte followed by zeros) rather than an error.
+pub fn legacy_sighash(transaction: &Transaction, input_index: usize, script_code: &[u8], sighash_type: u32) -> [u8; 32] {
+    let mut one = [0; 32];
+    one[0] = 1;
+
+    let base_type = sighash_type & 0x1f;
+    if input_index >= transaction.inputs.len() {
+        return one;
+    }
+    if base_type == SIGHASH_SINGLE && input_index >= transaction.outputs.len() {
+        return one;
+    }
+
+    let script_code = remove_code_separators(script_code);
+    let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+    let inputs = transaction.inputs.iter().enumerate()
+        .filter(|(ix, _)| !anyone_can_pay || *ix == input_index)
+        .map(|(ix, input)| {
+            let mut input = input.clone();
+            input.witness_stuff = Vec::new();
+            if ix == input_index {
+                input.unlock_script = script_code.clone();
+            } else {
+                input.unlock_script = Vec::new();
+                // The other inputs' sequence numbers aren't committed to if the
+                // outputs aren't all committed to.
+                if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+                    input.sequence = 0;
+                }
+            }
+            input
+        })
+        .collect();
+    let outputs = match base_type {
+        SIGHASH_NONE => Vec::new(),
+        SIGHASH_SINGLE => {
+            // Outputs before the one being signed are blanked out
+            let blank = TransactionOutput {
+                value: u64::MAX,
+                lock_script: Vec::new(),
+            };
+            let mut outputs = vec![blank; input_index];
+            outputs.push(transaction.outputs[input_index].clone());
+            outputs
+        }
+        _ => transaction.outputs.clone(),
+    };
+
+    let stripped = Transaction {
+        version: transaction.version,
+        flags: TransactionFlags::empty(),
+        inputs,
+        outputs,
+        locktime: transaction.locktime,
+    };
+    let mut serialized = Vec::new();
+    stripped.serialize_le(&mut serialized);
+    sighash_type.serialize_le(&mut serialized);
+    sha256d(&serialized)
+}
+
+/// Computes the segwit version 0 signature hash defined by BIP143 for the input
+/// at `input_index` of the given transaction. Unlike the legacy algorithm this
+/// also commits to `amount`, the value of the output being spent. The
+/// `script_code` is used as-is: for P2WPKH it is the equivalent P2PKH script,
+/// and for P2WSH it is the witness script. The returned bytes are in natural
+/// order, as for `legacy_sighash`.
+///
+/// # Panics
+///
+/// Panics if `input_index` is out of range.
+pub fn segwit_v0_sighash(transaction: &Transaction, input_index: usize, script_code: &[u8], amount: u64, sighash_type: u32) -> [u8; 32] {
+    let base_type = sighash_type & 0x1f;
+    let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+    let commits_all_outputs = base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE;
+
+    let hash_prevouts = if anyone_can_pay {
+        [0; 32]
+    } else {
+        let mut serialized = Vec::new();
+        for input in &transaction.inputs {
+            input.txid.serialize_le(&mut serialized);
+            input.vout.serialize_le(&mut serialized);
+        }
+        sha256d(&serialized)
+    };
+    let hash_sequence = if anyone_can_pay || !commits_all_outputs {
+        [0; 32]
+    } else {
+        let mut serialized = Vec::new();
+        for input in &transaction.inputs {
+            input.sequence.serialize_le(&mut serialized);
+        }
+        sha256d(&serialized)
+    };
+    let hash_outputs = if commits_all_outputs {
+        let mut serialized = Vec::new();
+        for output in &transaction.outputs {
+            serialize_output(output, &mut serialized);
+        }
+        sha256d(&serialized)
+    } else if base_type == SIGHASH_SINGLE && input_index < transaction.outputs.len() {
+        let mut serialized = Vec::new();
+        serialize_output(&transaction.outputs[input_index], &mut serialized);
+        sha256d(&serialized)
+    } else {
+        [0; 32]
+    };
+
+    let input = &transaction.inputs[input_index];
+    let mut serialized = Vec::new();
+    transaction.version.serialize_le(&mut serialized);
+    serialized.extend(hash_prevouts);
+    serialized.extend(hash_sequence);
+    input.txid.serialize_le(&mut serialized);
+    input.vout.serialize_le(&mut serialized);
+    script_code.len().serialize_le(&mut serialized);
+    serialized.extend(script_code);
+    amount.serialize_le(&mut serialized);
+    input.sequence.serialize_le(&mut serialized);
+    serialized.extend(hash_outputs);
+    transaction.locktime.serialize_le(&mut serialized);
+    sighash_type.serialize_le(&mut serialized);
+    sha256d(&serialized)
+}
+
+/// The leaf version of tapscript (BIP342) leaves in a taproot script tree.
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+
+/// Computes the hash of a leaf in a taproot script tree (BIP341), which commits
+/// to the leaf version and the script. Tapscript signatures commit to the hash
+/// of the leaf being executed.
+pub fn tapleaf_hash(leaf_version: u8, script: &[u8]) -> [u8; 32] {
+    let mut serialized = vec![leaf_version];
+    script.len().serialize_le(&mut serialized);
+    serialized.extend(script);
+    tagged_hash("TapLeaf", &serialized)
+}
+
+/// Computes the taproot signature hash defined by BIP341 for the input at
+/// `input_index` of the given transaction. Unlike the earlier algorithms this
+/// commits to the lock script and value of the outputs spent by all the inputs,
+/// which must be given in `prevouts` in the same order as the inputs. The annex
+/// is the optional last witness element starting with 0x50. For key-path spends
+/// `leaf_hash` is None; for script-path spends (BIP342) it is the `tapleaf_hash`
+/// of the script being executed.
+///
+/// Returns None if the sighash type is not one of the defined types, if it is
+/// SIGHASH_SINGLE and there is no output with the same index as the input, or
+/// if `prevouts` doesn't match the inputs. Unlike the legacy algorithm, no
+/// signature is valid in those cases.
+pub fn taproot_sighash(transaction: &Transaction, input_index: usize, prevouts: &[(Vec<u8>, u64)], sighash_type: u32, annex: Option<&[u8]>, leaf_hash: Option<&[u8; 32]>) -> Option<[u8; 32]> {
+    if !matches!(sighash_type, 0x00..=0x03 | 0x81..=0x83) {
+        return None;
+    }
+    if prevouts.len() != transaction.inputs.len() || input_index >= transaction.inputs.len() {
+        return None;
+    }
+    let base_type = sighash_type & 0x03;
+    let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+
+    // The epoch, which allows the algorithm to be replaced in the future
+    let mut serialized = vec![0x00, sighash_type as u8];
+    transaction.version.serialize_le(&mut serialized);
+    transaction.locktime.serialize_le(&mut serialized);
+    if !anyone_can_pay {
+        let mut outpoints = Vec::new();
+        let mut amounts = Vec::new();
+        let mut lock_scripts = Vec::new();
+        let mut sequences = Vec::new();
+        for (input, (lock_script, amount)) in transaction.inputs.iter().zip(prevouts) {
+            input.txid.serialize_le(&mut outpoints);
+            input.vout.serialize_le(&mut outpoints);
+            amount.serialize_le(&mut amounts);
+            lock_script.len().serialize_le(&mut lock_scripts);
+            lock_scripts.extend(lock_script);
+            input.sequence.serialize_le(&mut sequences);
+        }
+        for data in [outpoints, amounts, lock_scripts, sequences] {
+            serialized.extend(Sha256::digest(data));
+        }
+    }
+    if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+        let mut outputs = Vec::new();
+        for output in &transaction.outputs {
+            serialize_output(output, &mut outputs);
+        }
+        serialized.extend(Sha256::digest(outputs));
+    }
+
+    let spend_type = (u8::from(leaf_hash.is_some()) << 1) | u8::from(annex.is_some());
+    serialized.push(spend_type);
+    if anyone_can_pay {
+        let input = &transaction.inputs[input_index];
+        let (lock_script, amount) = &prevouts[input_index];
+        input.txid.serialize_le(&mut serialized);
+        input.vout.serialize_le(&mut serialized);
+        amount.serialize_le(&mut serialized);
+        lock_script.len().serialize_le(&mut serialized);
+        serialized.extend(lock_script);
+        input.sequence.serialize_le(&mut serialized);
+    } else {
+        (input_index as u32).serialize_le(&mut serialized);
+    }
+    if let Some(annex) = annex {
+        let mut data = Vec::new();
+        annex.len().serialize_le(&mut data);
+        data.extend(annex);
+        serialized.extend(Sha256::digest(data));
+    }
+    if base_type == SIGHASH_SINGLE {
+        let mut output = Vec::new();
+        serialize_output(transaction.outputs.get(input_index)?, &mut output);
+        serialized.extend(Sha256::digest(output));
+    }
+
+    if let Some(leaf_hash) = leaf_hash {
+        serialized.extend(leaf_hash);
+        // The key version, and the position of the last executed OP_CODESEPARATOR
+        // (none, since they aren't supported yet)
+        serialized.push(0x00);
+        0xffffffffu32.serialize_le(&mut serialized);
+    }
+    Some(tagged_hash("TapSighash", &serialized))
+}
+
+fn serialize_output(output: &TransactionOutput, dest: &mut Vec<u8>) {
+    output.value.serialize_le(dest);
+    output.lock_script.len().serialize_le(dest);
+    dest.extend(&output.lock_script);
+}
+
+// Returns a copy of the script with all OP_CODESEPARATOR opcodes removed. The
+// other opcodes are copied exactly as they are, even if they are non-minimal
+// pushes. If the script fails to parse, the unparseable part is kept as-is.
+fn remove_code_separators(script: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(script.len());
+    let mut ix = 0;
+    while ix < script.len() {
+        let start = ix;
+        match Opcode::deserialize_le(script, &mut ix) {
+            Ok(Opcode::CodeSeparator) => (),
+            Ok(_) => result.extend(&script[start..ix]),
+            Err(_) => {
+                result.extend(&script[start..]);
+                break;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Hash;
+    use super::*;
+
+    // A transaction with three inputs and three outputs
+    fn transaction() -> Transaction {
+        let spends: Vec<(Hash, u32)> = (0..3).map(|i| (Hash([0x10 + i; 32]), u32::from(i))).collect();
+        let mut transaction = crate::tests::transaction(&spends, &[1000, 2000, 3000]);
+        for (i, input) in transaction.inputs.iter_mut().enumerate() {
+            input.sequence = 0xfffffffe - i as u32;
+        }
+        transaction.locktime = 500;
+        transaction
+    }
+
+    type Modification = (&'static str, fn(&mut Transaction));
+
+    // Applies each of a set of modifications to the transaction and checks whether
+    // the sighash for input 1 changes. Returns a list of which ones changed it.
+    fn changes(sighash_type: u32) -> Vec<&'static str> {
+        let original = legacy_sighash(&transaction(), 1, &[0xac], sighash_type);
+        let modifications: Vec<Modification> = vec![
+            ("version", |t| t.version = 2),
+            ("locktime", |t| t.locktime = 0),
+            ("own input", |t| t.inputs[1].vout = 7),
+            ("own sequence", |t| t.inputs[1].sequence = 0),
+            ("own scriptSig", |t| t.inputs[1].unlock_script = vec![0x52]),
+            ("other input", |t| t.inputs[0].vout = 7),
+            ("other sequence", |t| t.inputs[2].sequence = 0),
+            ("other scriptSig", |t| t.inputs[0].unlock_script = vec![0x52]),
+            ("add input", |t| t.inputs.push(t.inputs[0].clone())),
+            ("earlier output", |t| t.outputs[0].value = 1),
+            ("own output", |t| t.outputs[1].value = 1),
+            ("later output", |t| t.outputs[2].value = 1),
+            ("add output", |t| t.outputs.push(t.outputs[0].clone())),
+        ];
+        modifications.into_iter().filter(|(_, modify)| {
+            let mut modified = transaction();
+            modify(&mut modified);
+            legacy_sighash(&modified, 1, &[0xac], sighash_type) != original
+        }).map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn sighash_types() {
+        let all_inputs = ["version", "locktime", "own input", "own sequence", "other input", "other sequence", "add input"];
+        let all_outputs = ["earlier output", "own output", "later output", "add output"];
+
+        assert_eq!(changes(SIGHASH_ALL), [&all_inputs[..], &all_outputs[..]].concat());
+        assert_eq!(changes(SIGHASH_NONE), ["version", "locktime", "own input", "own sequence", "other input", "add input"]);
+        assert_eq!(changes(SIGHASH_SINGLE), ["version", "locktime", "own input", "own sequence", "other input", "add input", "own output"]);
+        assert_eq!(changes(SIGHASH_ALL | SIGHASH_ANYONECANPAY), ["version", "locktime", "own input", "own sequence", "earlier output", "own output", "later output", "add output"]);
+        assert_eq!(changes(SIGHASH_NONE | SIGHASH_ANYONECANPAY), ["version", "locktime", "own input", "own sequence"]);
+        assert_eq!(changes(SIGHASH_SINGLE | SIGHASH_ANYONECANPAY), ["version", "locktime", "own input", "own sequence", "own output"]);
+
+        // Different sighash types produce different hashes, since the type is
+        // also committed to.
+        let tx = transaction();
+        assert_ne!(legacy_sighash(&tx, 1, &[0xac], SIGHASH_ALL), legacy_sighash(&tx, 1, &[0xac], 0x04));
+    }
+
+    #[test]
+    fn sighash_one() {
+        let mut one = [0; 32];
+        one[0] = 1;
+        let mut tx = transaction();
+        assert_eq!(legacy_sighash(&tx, 3, &[0xac], SIGHASH_ALL), one);
+        assert_ne!(legacy_sighash(&tx, 2, &[0xac], SIGHASH_SINGLE), one);
+        tx.outputs.pop();
+        assert_eq!(legacy_sighash(&tx, 2, &[0xac], SIGHASH_SINGLE), one);
+        assert_eq!(legacy_sighash(&tx, 2, &[0xac], SIGHASH_SINGLE | SIGHASH_ANYONECANPAY), one);
+        assert_ne!(legacy_sighash(&tx, 2, &[0xac], SIGHASH_ALL), one);
+    }
+
+    #[test]
+    fn code_separators() {
+        assert_eq!(remove_code_separators(&[0xab, 0x76, 0xab, 0xac]), [0x76, 0xac]);
+        // Data that looks like OP_CODESEPARATOR is kept, as are non-minimal pushes
+        assert_eq!(remove_code_separators(&[0x4c, 0x01, 0xab, 0xab]), [0x4c, 0x01, 0xab]);
+        assert_eq!(remove_code_separators(&[0xab, 0x4c, 0x05, 0xab]), [0x4c, 0x05, 0xab]);
+
+        let tx = transaction();
+        assert_eq!(legacy_sighash(&tx, 0, &[0xab, 0xac], SIGHASH_ALL), legacy_sighash(&tx, 0, &[0xac], SIGHASH_ALL));
+    }
+}
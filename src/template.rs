@@ -0,0 +1,116 @@
+//! A module that exposes a builder for constructing blocks by hand, such as in
+//! tests and tools. This is unrelated to the validation pipeline in the
+//! `builder` module.
+
+use crate::{Block, BlockHeader, Hash, Network, Transaction};
+
+/// The `bits` used for blocks unless otherwise specified. This is the RegTest
+/// proof-of-work limit, so blocks can be mined almost immediately.
+pub const DEFAULT_BITS: u32 = 0x207fffff;
+
+/// A builder for a Block. The merkle root in the header is computed from the
+/// transactions when the block is built, and all other header fields have
+/// defaults that can be overridden.
+#[derive(Clone, Debug)]
+pub struct BlockTemplate {
+    network: Network,
+    header: BlockHeader,
+    transactions: Vec<Transaction>,
+}
+
+impl BlockTemplate {
+    /// Creates a template for a RegTest block that is a child of the given block.
+    /// The genesis block can be created by passing `Hash::zero()`.
+    pub fn new(prev_block_hash: Hash) -> Self {
+        Self {
+            network: Network::RegTest,
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash,
+                bits: DEFAULT_BITS,
+                ..BlockHeader::default()
+            },
+            transactions: Vec::new(),
+        }
+    }
+
+    /// Sets the network for the block.
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Sets the version in the block header.
+    pub fn version(mut self, version: u32) -> Self {
+        self.header.version = version;
+        self
+    }
+
+    /// Sets the timestamp in the block header.
+    pub fn time(mut self, time: u32) -> Self {
+        self.header.time = time;
+        self
+    }
+
+    /// Sets the `bits` in the block header.
+    pub fn bits(mut self, bits: u32) -> Self {
+        self.header.bits = bits;
+        self
+    }
+
+    /// Sets the nonce in the block header.
+    pub fn nonce(mut self, nonce: u32) -> Self {
+        self.header.nonce = nonce;
+        self
+    }
+
+    /// Replaces the transactions in the block with the given list.
+    pub fn transactions(mut self, transactions: Vec<Transaction>) -> Self {
+        self.transactions = transactions;
+        self
+    }
+
+    /// Appends a transaction to the block.
+    pub fn transaction(mut self, transaction: Transaction) -> Self {
+        self.transactions.push(transaction);
+        self
+    }
+
+    /// Builds the block, filling in the merkle root in the header.
+    pub fn build(self) -> Block {
+        let mut block = Block {
+            network: self.network,
+            header: self.header,
+            transactions: self.transactions,
+        };
+        block.header.merkle_root = block.computed_merkle_root();
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::transaction;
+    use super::*;
+
+    #[test]
+    fn two_transaction_block() {
+        let coinbase = transaction(&[(Hash::zero(), 0xffffffff)], &[5000]);
+        let spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        let block = BlockTemplate::new(Hash([0x22; 32]))
+            .time(1234)
+            .transaction(coinbase.clone())
+            .transaction(spend.clone())
+            .build();
+
+        assert_eq!(block.network, Network::RegTest);
+        assert_eq!(block.header.prev_block_hash, Hash([0x22; 32]));
+        assert_eq!(block.header.time, 1234);
+        assert_eq!(block.header.bits, DEFAULT_BITS);
+        assert_eq!(block.transactions.len(), 2);
+        assert_eq!(block.transactions[0].txid(), coinbase.txid());
+        assert_eq!(block.transactions[1].txid(), spend.txid());
+        assert_eq!(block.computed_merkle_root(), block.header.merkle_root);
+        assert_ne!(block.header.merkle_root, Hash::zero());
+    }
+}
@@ -1,38 +1,261 @@
 //! A module that exposes a block validation API.
+//!
+//! Changes to the chain are logged with the `LOG_TARGET` target, along with
+//! the block hash (and height, where known) as structured key-values.
 
-use crate::{Block, BlockValidationError, Hash};
+use crate::{checked_sum_transaction_values, Block, BlockHeader, BlockValidationError, Hash, Network, Transaction, TransactionOutput};
+use crate::hash::sha256d;
+use crate::script::{count_sigops, p2sh_sigop_count, verify_transaction, witness_sigop_count};
 use log::info;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::time::SystemTime;
 
-const MAX_SUPPORTED_BLOCK_VERSION: u32 = 4;
+/// The target used for log records emitted by the validator.
+pub const LOG_TARGET: &str = "blocktastic::validator";
+
+const VERSIONBITS_TOP_MASK: u32 = 0xe0000000;
+const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
 const TWO_HOURS_IN_SECONDS: u64 = 2 * 60 * 60;
 const MAX_ACTIVE_HEIGHT: usize = 144; // One day's worth of blocks
+const MEDIAN_TIME_SPAN: usize = 11;
+const MIN_COINBASE_SCRIPT_LENGTH: usize = 2;
+const MAX_COINBASE_SCRIPT_LENGTH: usize = 100;
+const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+const WITNESS_SCALE_FACTOR: usize = 4;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+const SEQUENCE_FINAL: u32 = 0xffffffff;
+
+const MAINNET_CHECKPOINTS: &[(usize, &str)] = &[
+    (0, "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+    (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+    (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+    (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+    (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+    (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+    (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+    (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+    (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+    (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+    (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+    (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+    (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+    (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+];
+// The heights at which BIP16 (P2SH) activated.
+const MAINNET_BIP16_HEIGHT: usize = 173805;
+const TESTNET_BIP16_HEIGHT: usize = 514;
+const REGTEST_BIP16_HEIGHT: usize = 0;
+// The heights at which BIP34 activated, making coinbases unique.
+const MAINNET_BIP34_HEIGHT: usize = 227931;
+const TESTNET_BIP34_HEIGHT: usize = 21111;
+const REGTEST_BIP34_HEIGHT: usize = 1;
+// The heights at which the CSV soft fork (BIP68, BIP112 and BIP113) activated.
+const MAINNET_CSV_HEIGHT: usize = 419328;
+const TESTNET_CSV_HEIGHT: usize = 770112;
+const REGTEST_CSV_HEIGHT: usize = 1;
+// The heights at which BIP34, BIP66 and BIP65 activated, each of which made lower
+// block versions invalid.
+const MAINNET_MIN_VERSIONS: &[(usize, u32)] = &[(MAINNET_BIP34_HEIGHT, 2), (363725, 3), (388381, 4)];
+const TESTNET_MIN_VERSIONS: &[(usize, u32)] = &[(TESTNET_BIP34_HEIGHT, 2), (330776, 3), (581885, 4)];
+const REGTEST_MIN_VERSIONS: &[(usize, u32)] = &[(REGTEST_BIP34_HEIGHT, 2), (1, 3), (1, 4)];
+
+// The two mainnet blocks that violate BIP30, by duplicating the coinbases of
+// earlier blocks that were still unspent. They were accepted before BIP30 was
+// adopted, so they're exempt from the check.
+const BIP30_EXCEPTIONS: &[(usize, &str)] = &[
+    (91842, "00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec"),
+    (91880, "00000000000743f190a18c5577a3c2d2a1f610ae9601ac046a38084ccb7cd721"),
+];
+
+const TESTNET_CHECKPOINTS: &[(usize, &str)] = &[
+    (0, "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+    (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+];
+const REGTEST_CHECKPOINTS: &[(usize, &str)] = &[
+    (0, "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+];
+
+/// Configuration for a BlockValidator. The default configuration has no
+/// network-specific settings; use `for_network` to get the settings for a
+/// particular network.
+#[derive(Clone, Debug)]
+pub struct ValidatorConfig {
+    /// Map from height to the expected block id at that height. Any block at a
+    /// checkpointed height with a different id is rejected. This prevents deep
+    /// reorganizations of the chain from replacing blocks below a checkpoint.
+    pub checkpoints: HashMap<usize, Hash>,
+    /// Pairs of activation height and minimum block version. Blocks at or above
+    /// an activation height must have at least the corresponding version, which
+    /// is how the version-based soft forks (BIP34, BIP66 and BIP65) were enforced.
+    /// Higher versions, including BIP9 version bits, are always allowed.
+    pub min_versions: Vec<(usize, u32)>,
+    /// The height at which BIP16 (pay-to-script-hash) activated. From this height
+    /// on, the sigops in the redeem scripts of P2SH spends count towards the
+    /// block's sigop limit. If None, they are never counted.
+    pub bip16_height: Option<usize>,
+    /// The height at which BIP34 activated. Blocks from then on have unique
+    /// coinbases, so the BIP30 check that no transaction duplicates the txid of
+    /// one with unspent outputs is only done below this height, as in the
+    /// reference implementation. If None, the check is done for every block.
+    pub bip34_height: Option<usize>,
+    /// The height at which the CSV soft fork activated. From this height on,
+    /// time-based locktimes are compared against the median time past of the
+    /// parent block (BIP113) rather than the block's own timestamp. If None,
+    /// the block's own timestamp is always used.
+    pub csv_height: Option<usize>,
+    /// The maximum number of seconds that a block timestamp may be ahead of the
+    /// current time. Defaults to two hours.
+    pub max_future_block_time: u64,
+    /// The id and height of a block whose scripts, and those of its ancestors, are
+    /// assumed to be valid (like `assumevalid` in the reference implementation).
+    /// Script verification, which is the most expensive part of validation, is
+    /// skipped for those blocks; all the other checks, such as proof-of-work and
+    /// merkle roots, are still done. This trusts whoever chose the block to have
+    /// verified the scripts in its ancestry, so it should only be set to a block
+    /// that is widely known to be valid. The block's header must be in the header
+    /// tree (e.g. via handle_header) for this to take effect; until then, and for
+    /// blocks that aren't its ancestors, scripts are verified as usual.
+    pub assume_valid: Option<(Hash, usize)>,
+}
+
+impl Default for ValidatorConfig {
+    fn default() -> Self {
+        Self {
+            checkpoints: HashMap::new(),
+            min_versions: Vec::new(),
+            bip16_height: None,
+            bip34_height: None,
+            csv_height: None,
+            max_future_block_time: TWO_HOURS_IN_SECONDS,
+            assume_valid: None,
+        }
+    }
+}
+
+impl ValidatorConfig {
+    /// Returns the default configuration for the given network, including the
+    /// checkpoints used by the Bitcoin Core reference implementation.
+    pub fn for_network(network: &Network) -> Self {
+        let (checkpoints, min_versions, bip16_height, bip34_height, csv_height) = match network {
+            Network::MainNet => (MAINNET_CHECKPOINTS, MAINNET_MIN_VERSIONS, Some(MAINNET_BIP16_HEIGHT), Some(MAINNET_BIP34_HEIGHT), Some(MAINNET_CSV_HEIGHT)),
+            Network::TestNet3 => (TESTNET_CHECKPOINTS, TESTNET_MIN_VERSIONS, Some(TESTNET_BIP16_HEIGHT), Some(TESTNET_BIP34_HEIGHT), Some(TESTNET_CSV_HEIGHT)),
+            Network::RegTest => (REGTEST_CHECKPOINTS, REGTEST_MIN_VERSIONS, Some(REGTEST_BIP16_HEIGHT), Some(REGTEST_BIP34_HEIGHT), Some(REGTEST_CSV_HEIGHT)),
+            Network::Unknown(_) => (&[][..], &[][..], None, None, None),
+        };
+        Self {
+            checkpoints: checkpoints.iter().map(|(height, hex)| (*height, Hash::from_display_hex(hex).unwrap())).collect(),
+            min_versions: min_versions.to_vec(),
+            bip16_height,
+            bip34_height,
+            csv_height,
+            ..Self::default()
+        }
+    }
+}
+
+/// A source of the current time, which the validator uses to reject blocks with
+/// timestamps too far in the future. This is implemented for closures that
+/// return a time, which makes it easy to use a fixed time in tests.
+pub trait Clock: Send {
+    /// Returns the current time in seconds since the UNIX epoch.
+    fn now(&self) -> u64;
+}
+
+/// The default clock, which uses the system time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        // A system time before the epoch is treated as the epoch itself
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+}
+
+impl<F: Fn() -> u64 + Send> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+/// A function that observes block timestamps; see BlockValidator::set_time_observer.
+/// It is called with the block height, the block's timestamp, and the median time
+/// past of its parent.
+pub type TimeObserver = dyn FnMut(usize, u32, u32) + Send;
+
+/// A function that takes blocks the validator no longer needs; see
+/// BlockValidator::set_block_recycler.
+pub type BlockRecycler = dyn FnMut(Block) + Send;
 
 /// A state machine to validate blocks as they are received. This structure accepts
 /// blocks one at a time, and checks to see if it is valid, updating internal state
 /// as necessary. It can handle multiple active chains, such as when competing
 /// miners produce different valid blocks for a given block height. It will eventually
 /// discard abandoned chains if there is a clear "winner" chain.
-#[derive(Default)]
 pub struct BlockValidator {
-    /// Map from block id to block height for archived blocks. Genesis block is height 0.
-    /// Archived blocks are always a linear chain; branches will have been pruned away.
-    archived_blocks: HashMap<Hash, usize>,
+    /// Map from block id to block header and height for archived blocks. Genesis block
+    /// is height 0. Archived blocks are always a linear chain; branches will have been
+    /// pruned away. The chain starts at the genesis block, or at the base block of
+    /// the UTXO snapshot if one was loaded.
+    archived_blocks: HashMap<Hash, ArchivedBlock>,
+    /// The height of the first archived block, which is 0 unless a UTXO snapshot
+    /// was loaded, in which case the blocks below it are unknown.
+    snapshot_height: usize,
     /// Map from hash to block and associated metadata for active blocks. Active blocks
     /// are recent blocks that have been validated and connected to the chain. Active
-    /// blocks form a tree rooted at the most recent archived block. Generally the longest
-    /// path in the tree is the one with the most proof-of-work, and therefore the
-    /// canonical blockchain, but that may change. Once the longest path in the active
-    /// block tree is longer than max_active_height, the oldest active blocks on that
-    /// path are archived and shorter branches emanating from those archived blocks
-    /// get pruned away.
+    /// blocks form a tree rooted at the most recent archived block. The path in the
+    /// tree with the most proof-of-work is the canonical blockchain, but that may
+    /// change. Once that path in the active block tree is longer than
+    /// max_active_height, the oldest active blocks on that path are archived and the
+    /// other branches emanating from those archived blocks get pruned away.
     active_blocks: HashMap<Hash, ActiveBlock>,
     /// Maximum height of the active tree; beyond this blocks get archived to prevent
     /// exceeding the maximum. Defaults to MAX_ACTIVE_HEIGHT but is copied to this field
     /// for easier testing.
     max_active_height: usize,
+    /// The id of the block at the end of the best chain, i.e. the first-seen block
+    /// with the most chain work. None until the genesis block has been validated.
+    tip: Option<Hash>,
+    /// Map from block id to header and height for headers that have been validated via
+    /// handle_header but whose blocks are neither active nor archived. Together with
+    /// the active and archived blocks these form the header tree, which runs ahead of
+    /// the block tree and is used for header-first sync. Headers on side branches are
+    /// pruned once their height is archived.
+    headers: HashMap<Hash, HeaderEntry>,
+    /// The ids of the entries in `headers`, by height, for pruning.
+    header_heights: BTreeMap<usize, Vec<Hash>>,
+    /// The id of the header at the end of the best header chain, i.e. the first-seen
+    /// header at the greatest height. None until the genesis header has been validated.
+    header_tip: Option<Hash>,
+    /// The outputs that are unspent as of the most recent archived block, keyed by
+    /// txid and output index. Spends and outputs in active blocks are not included
+    /// here, since those blocks may still be abandoned in favour of another branch.
+    /// Each output is stored along with the height of the block that created it.
+    archived_utxos: HashMap<(Hash, u32), (TransactionOutput, usize)>,
+    /// The configuration provided when the validator was created.
+    config: ValidatorConfig,
+    /// The source of the current time. Defaults to the system time.
+    clock: Box<dyn Clock>,
+    /// Called with the height, timestamp, and parent's median time past of each
+    /// block that attaches to an active chain, for monitoring timestamp anomalies.
+    time_observer: Option<Box<TimeObserver>>,
+    /// Called with each block that is dropped after being given to handle_block,
+    /// so that its buffers can be reused.
+    block_recycler: Option<Box<BlockRecycler>>,
+    /// If true, the proof-of-work check is skipped. This can only be set in tests
+    /// or with the `testing` feature enabled.
+    skip_pow: bool,
+    /// The ids of the assume-valid block and its ancestors, by height. Filled in
+    /// once the assume-valid block's header is in the header tree.
+    assume_valid_chain: HashMap<usize, Hash>,
+}
+
+impl Default for BlockValidator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Result from validation of a single block.
@@ -45,6 +268,9 @@ pub enum ValidationResult {
     /// In this case the block may have been received out-of-order, and should
     /// be tried again later after the indicated parent block has been validated.
     Orphan(Block),
+    /// The header could not be validated because the parent header could not be
+    /// found. This is only returned from handle_header.
+    OrphanHeader(BlockHeader),
 }
 
 impl fmt::Debug for ValidationResult {
@@ -53,6 +279,7 @@ impl fmt::Debug for ValidationResult {
             ValidationResult::Valid(h) => write!(f, "ValidationResult::Valid({})", h),
             ValidationResult::Invalid(e) => write!(f, "ValidationResult::Invalid({})", e),
             ValidationResult::Orphan(b) => write!(f, "ValidationResult::Orphan({})", b.id()),
+            ValidationResult::OrphanHeader(h) => write!(f, "ValidationResult::OrphanHeader({})", h.id()),
         }
     }
 }
@@ -60,55 +287,357 @@ impl fmt::Debug for ValidationResult {
 struct ActiveBlock {
     block: Block,
     height: usize,
+    size: usize,
+    /// The total work of the chain ending at this block; see ArchivedBlock.
+    chain_work: u128,
+    /// Map from txid to index in the block, so that looking up outputs doesn't
+    /// need to hash every transaction again.
+    txids: HashMap<Hash, usize>,
+    /// The outputs spent by the (non-coinbase) transactions in the block.
+    spent: HashSet<(Hash, u32)>,
+}
+
+struct ArchivedBlock {
+    header: BlockHeader,
+    height: usize,
+    /// The serialized size of the block, so that storage use can be estimated
+    /// without keeping the full block.
+    size: usize,
+    /// The total work of the chain ending at this block, counted from the genesis
+    /// block, or from just after the snapshot's base block if a UTXO snapshot was
+    /// loaded. Only differences in chain work matter, for choosing the best chain.
+    chain_work: u128,
+}
+
+struct HeaderEntry {
+    header: BlockHeader,
+    height: usize,
 }
 
 impl BlockValidator {
-    /// Create a new validator.
+    /// Create a new validator with the default configuration.
     pub fn new() -> Self {
+        Self::with_config(ValidatorConfig::default())
+    }
+
+    /// Create a new validator with the given configuration.
+    pub fn with_config(config: ValidatorConfig) -> Self {
         Self {
+            archived_blocks: HashMap::new(),
+            snapshot_height: 0,
+            active_blocks: HashMap::new(),
             max_active_height: MAX_ACTIVE_HEIGHT,
-            ..Self::default()
+            tip: None,
+            headers: HashMap::new(),
+            header_heights: BTreeMap::new(),
+            header_tip: None,
+            archived_utxos: HashMap::new(),
+            config,
+            clock: Box::new(SystemClock),
+            time_observer: None,
+            block_recycler: None,
+            skip_pow: false,
+            assume_valid_chain: HashMap::new(),
+        }
+    }
+
+    /// Replaces the clock used to get the current time, which is otherwise the
+    /// system time. This is mostly useful for tests and for replaying historical
+    /// blocks.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Sets a function that gets called with the height, header timestamp, and
+    /// median time past (of the parent) of every non-genesis block given to
+    /// handle_block whose parent is an active block. It is called before the block
+    /// is validated, so it also sees blocks that end up rejected, such as those
+    /// with a timestamp that isn't after their parent's. This allows callers
+    /// to monitor for time-warp attacks and other timestamp anomalies.
+    pub fn set_time_observer(&mut self, observer: impl FnMut(usize, u32, u32) + Send + 'static) {
+        self.time_observer = Some(Box::new(observer));
+    }
+
+    /// Sets a function that gets called with every block that the validator is
+    /// done with: invalid blocks, blocks that are archived (of which only the
+    /// header is kept), and blocks that are pruned or rolled back. Otherwise these
+    /// blocks are just dropped. This allows their buffers to be reused for parsing
+    /// later blocks with `parse::BlockReader`.
+    pub fn set_block_recycler(&mut self, recycler: impl FnMut(Block) + Send + 'static) {
+        self.block_recycler = Some(Box::new(recycler));
+    }
+
+    fn recycle(&mut self, block: Block) {
+        if let Some(recycler) = self.block_recycler.as_mut() {
+            recycler(block);
         }
     }
 
+    /// Disables (or re-enables) the proof-of-work check during validation, while
+    /// keeping all other validation. This allows tests to construct blocks by hand
+    /// without having to find a valid nonce. This is only available with the
+    /// `testing` feature, and must never be used in production as it allows
+    /// anybody to trivially create blocks.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn skip_pow_check(&mut self, skip: bool) {
+        self.skip_pow = skip;
+    }
+
     /// Give the validator one block to validate. If the block is valid, the
     /// validator's internal state gets updated and the block is attached to
     /// one of the active chains. Otherwise there should be no changes to
     /// the internal state.
     pub fn handle_block(&mut self, block: Block) -> ValidationResult {
-        if self.archived_blocks.contains_key(&block.header.prev_block_hash) {
-            return ValidationResult::Invalid(
-                BlockValidationError::new(format!("Candidate block {} has a previous block {} that is archived", block.id(), block.header.prev_block_hash))
-            );
-        }
+        // Right after a UTXO snapshot is loaded there are no active blocks, and the
+        // first one attaches to the snapshot's base block, which is archived.
+        let archived_parent = self.archived_blocks.get(&block.header.prev_block_hash);
+        let snapshot_child_height = match archived_parent {
+            Some(archived) if self.active_blocks.is_empty() => Some(archived.height + 1),
+            Some(archived) => {
+                let error = BlockValidationError::ArchivedParent { block: block.id(), parent: block.header.prev_block_hash, parent_height: archived.height };
+                self.recycle(block);
+                return ValidationResult::Invalid(error);
+            }
+            None => None,
+        };
 
         let is_genesis_block = block.header.prev_block_hash == Hash::zero();
 
         let height = match self.active_blocks.get(&block.header.prev_block_hash) {
             Some(parent) => parent.height + 1,
             None if is_genesis_block => 0,
-            None => return ValidationResult::Orphan(block),
+            None => match snapshot_child_height {
+                Some(height) => height,
+                None => return ValidationResult::Orphan(block),
+            },
         };
+        // The archived blocks are a linear chain from the genesis block or the
+        // snapshot's base block, so nothing new can go at or below their heights
+        if let Some(archived_height) = self.archived_height() {
+            if height <= archived_height {
+                let error = BlockValidationError::BelowArchivedHeight { block: block.id(), height, archived_height };
+                self.recycle(block);
+                return ValidationResult::Invalid(error);
+            }
+        }
+
+        if !is_genesis_block {
+            if let Some(median_time_past) = self.median_time_past(&block.header.prev_block_hash) {
+                if let Some(observer) = self.time_observer.as_mut() {
+                    observer(height, block.header.time, median_time_past);
+                }
+            }
+        }
 
         if let Err(e) = self.validate_block(&block, height) {
+            self.recycle(block);
             return ValidationResult::Invalid(e);
         }
 
         let hash = block.id();
+        // The parent is unknown only for the genesis block
+        let chain_work = self.get_chain_work(&block.header.prev_block_hash).unwrap_or(0).saturating_add(block.header.work());
         let active_block = ActiveBlock {
+            size: block.serialized_size(),
+            chain_work,
+            txids: block.transactions.iter().enumerate().map(|(ix, transaction)| (transaction.txid(), ix)).collect(),
+            spent: block.transactions.iter()
+                .filter(|transaction| !transaction.is_coinbase())
+                .flat_map(|transaction| transaction.inputs.iter().map(|input| (input.txid, input.vout)))
+                .collect(),
             block,
             height,
         };
-        info!("Adding block {} to chain at height {}", hash, height);
+        info!(target: LOG_TARGET, hash:% = hash, height; "Adding block {} to chain at height {}", hash, height);
         self.active_blocks.insert(hash, active_block);
 
-        if height - self.archived_blocks.len() >= self.max_active_height {
-            self.archive_old_blocks(&hash);
+        // Only a block with strictly more work replaces the tip, so the first-seen
+        // block wins among chains with equal work
+        let tip_work = self.tip.and_then(|tip| self.get_chain_work(&tip));
+        if tip_work.map_or(true, |tip_work| chain_work > tip_work) {
+            self.tip = Some(hash);
+        }
+        // The header is now part of the header tree via the active block
+        self.remove_header(&hash);
+        self.update_header_tip(hash, height);
+
+        // Archiving happens along the best chain, so that it is never pruned away
+        // in favour of a longer chain with less work
+        let tip = self.tip.unwrap();
+        let tip_height = self.get_height(&tip).unwrap();
+        let active_height = self.archived_height().map_or(Some(tip_height), |archived_height| tip_height.checked_sub(archived_height + 1));
+        if active_height.is_some_and(|active_height| active_height >= self.max_active_height) {
+            self.archive_old_blocks(&tip);
         }
 
         ValidationResult::Valid(hash)
     }
 
+    /// Give the validator a batch of blocks to validate. The blocks are handled
+    /// as by handle_block, except that a block whose parent is also in the batch
+    /// is always handled after its parent, regardless of where it appears in the
+    /// batch; otherwise the order of the batch is kept. The results are returned
+    /// in the same order as the blocks were given.
+    ///
+    /// Blocks whose parent is neither in the batch nor known to the validator are
+    /// returned as orphans, and are not retried. Use the orphanage in
+    /// `BlockChainBuilder` to handle blocks that arrive out of order across
+    /// batches.
+    pub fn handle_blocks(&mut self, blocks: Vec<Block>) -> Vec<ValidationResult> {
+        let ids = blocks.iter().map(|block| block.id()).collect::<Vec<Hash>>();
+        let indices = ids.iter().enumerate().map(|(ix, id)| (*id, ix)).collect::<HashMap<Hash, usize>>();
+        // The number of ancestors of each block within the batch; handling the blocks
+        // in increasing order of depth means parents are handled first.
+        let mut depths: Vec<Option<usize>> = vec![None; blocks.len()];
+        for start in 0..blocks.len() {
+            let mut chain = Vec::new();
+            let mut ix = start;
+            let mut depth = loop {
+                if let Some(depth) = depths[ix] {
+                    break depth;
+                }
+                chain.push(ix);
+                match indices.get(&blocks[ix].header.prev_block_hash) {
+                    // Cycles would need a hash collision, but don't loop forever
+                    Some(parent) if chain.len() <= blocks.len() => ix = *parent,
+                    _ => break 0,
+                }
+            };
+            for ix in chain.into_iter().rev() {
+                depths[ix] = Some(depth);
+                depth += 1;
+            }
+        }
+        let mut order = (0..blocks.len()).collect::<Vec<usize>>();
+        order.sort_by_key(|ix| depths[*ix]);
+
+        let mut blocks = blocks.into_iter().map(Some).collect::<Vec<Option<Block>>>();
+        let mut results = (0..blocks.len()).map(|_| None).collect::<Vec<Option<ValidationResult>>>();
+        for ix in order {
+            results[ix] = Some(self.handle_block(blocks[ix].take().unwrap()));
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Give the validator one block header to validate, without the transactions
+    /// in the block. This allows header-first sync, where the chain of headers is
+    /// downloaded and checked (for proof-of-work, timestamps, and difficulty) before
+    /// the block bodies. Valid headers are added to a header tree that is separate
+    /// from the tree of full blocks, so that the header chain can run ahead of the
+    /// block chain. The parent of the header must already be in the header tree,
+    /// otherwise ValidationResult::OrphanHeader is returned.
+    ///
+    /// Accepting a header does not affect the handling of the corresponding block
+    /// when its body arrives later: handle_block always performs the full validation,
+    /// including re-checking the header. Blocks accepted by handle_block also have
+    /// their headers added to the header tree if they weren't already there.
+    pub fn handle_header(&mut self, header: BlockHeader) -> ValidationResult {
+        let hash = header.id();
+        if self.get_header_entry(&hash).is_some() {
+            return ValidationResult::Valid(hash);
+        }
+
+        let (parent, height) = match self.get_header_entry(&header.prev_block_hash) {
+            Some((parent, parent_height)) => (Some(parent), parent_height + 1),
+            None if header.prev_block_hash == Hash::zero() => (None, 0),
+            None => return ValidationResult::OrphanHeader(header),
+        };
+
+        if let Err(e) = self.validate_header(&header, height, parent) {
+            return ValidationResult::Invalid(e);
+        }
+
+        info!(target: LOG_TARGET, hash:% = hash, height; "Adding header {} to header chain at height {}", hash, height);
+        self.insert_header(hash, header, height);
+        self.update_header_tip(hash, height);
+        ValidationResult::Valid(hash)
+    }
+
+    fn insert_header(&mut self, hash: Hash, header: BlockHeader, height: usize) {
+        if self.headers.insert(hash, HeaderEntry { header, height }).is_none() {
+            self.header_heights.entry(height).or_default().push(hash);
+        }
+    }
+
+    fn remove_header(&mut self, hash: &Hash) {
+        if let Some(entry) = self.headers.remove(hash) {
+            let ids = self.header_heights.get_mut(&entry.height).unwrap();
+            ids.retain(|id| id != hash);
+            if ids.is_empty() {
+                self.header_heights.remove(&entry.height);
+            }
+        }
+    }
+
+    fn update_header_tip(&mut self, hash: Hash, height: usize) {
+        let tip_height = self.header_tip.and_then(|tip| self.get_header_entry(&tip)).map(|(_, height)| height);
+        if tip_height.map_or(true, |tip_height| height > tip_height) {
+            self.header_tip = Some(hash);
+        }
+    }
+
+    // Returns the header and height of the given block in the header tree, which
+    // includes the active and archived blocks.
+    fn get_header_entry(&self, hash: &Hash) -> Option<(&BlockHeader, usize)> {
+        match (self.active_blocks.get(hash), self.archived_blocks.get(hash)) {
+            (Some(active), _) => Some((&active.block.header, active.height)),
+            (None, Some(archived)) => Some((&archived.header, archived.height)),
+            (None, None) => self.headers.get(hash).map(|entry| (&entry.header, entry.height)),
+        }
+    }
+
+    /// Replaces the validator's blocks and UTXO set with a snapshot of the UTXO set
+    /// as of the block `tip` at the given height, so that validation can continue
+    /// from the block after it without syncing from genesis (like `loadtxoutset` in
+    /// the reference implementation). Each UTXO is given as the txid and index of
+    /// the output, along with the output and the height of the block that created
+    /// it.
+    ///
+    /// The header of `tip` must already have been validated with handle_header,
+    /// since the next block's header is checked against it. Once loaded, `tip`
+    /// is the only archived block, and the best chain starts at it rather than at
+    /// the genesis block. The UTXOs are trusted, and are not checked against the
+    /// header chain in any way.
+    pub fn load_utxo_snapshot(&mut self, height: usize, tip: Hash, utxos: impl Iterator<Item = (Hash, u32, TransactionOutput, usize)>) -> Result<(), BlockValidationError> {
+        let header = match self.get_header_entry(&tip) {
+            Some((_, entry_height)) if entry_height != height => {
+                return Err(BlockValidationError::SnapshotHeightMismatch { block: tip, expected: entry_height, actual: height });
+            }
+            Some((header, _)) => header.clone(),
+            None => return Err(BlockValidationError::UnknownBlock { block: tip }),
+        };
+
+        info!(target: LOG_TARGET, hash:% = tip, height; "Loading UTXO snapshot at {} with height {}", tip, height);
+        // The active blocks are dropped, but their headers stay in the header tree
+        for (hash, active) in std::mem::take(&mut self.active_blocks) {
+            self.insert_header(hash, active.block.header.clone(), active.height);
+            self.recycle(active.block);
+        }
+        self.archived_blocks.clear();
+        self.remove_header(&tip);
+        self.archived_blocks.insert(tip, ArchivedBlock {
+            header,
+            height,
+            // The block itself isn't known, so it doesn't count towards storage use
+            size: 0,
+            chain_work: 0,
+        });
+        self.snapshot_height = height;
+        self.archived_utxos = utxos.map(|(txid, vout, output, created)| ((txid, vout), (output, created))).collect();
+        self.tip = Some(tip);
+        if self.header_tip.and_then(|header_tip| self.get_header_entry(&header_tip)).is_none() {
+            self.header_tip = Some(tip);
+        }
+        Ok(())
+    }
+
+    /// Returns the id of the header at the end of the best header chain, or None if
+    /// no headers have been validated yet. This includes headers of full blocks that
+    /// were validated with handle_block.
+    pub fn header_tip(&self) -> Option<Hash> {
+        self.header_tip
+    }
+
     fn archive_old_blocks(&mut self, leaf_hash: &Hash) {
         let mut iter_hash = *leaf_hash;
         let mut active_root = iter_hash;
@@ -121,16 +650,43 @@ impl BlockValidator {
         }
 
         // Archive iter_hash and active ancestors until there are no more active ancestors.
+        let mut newly_archived = Vec::new();
         loop {
             iter_hash = match self.active_blocks.remove(&iter_hash) {
                 Some(removed) => {
-                    info!("Archiving {} with height {}", &iter_hash, removed.height);
-                    self.archived_blocks.insert(iter_hash, removed.height);
-                    removed.block.header.prev_block_hash
+                    info!(target: LOG_TARGET, hash:% = iter_hash, height = removed.height; "Archiving {} with height {}", &iter_hash, removed.height);
+                    let prev_block_hash = removed.block.header.prev_block_hash;
+                    self.archived_blocks.insert(iter_hash, ArchivedBlock {
+                        header: removed.block.header.clone(),
+                        height: removed.height,
+                        size: removed.size,
+                        chain_work: removed.chain_work,
+                    });
+                    newly_archived.push(removed);
+                    prev_block_hash
                 }
                 None => break,
             };
         }
+        // The blocks were collected from newest to oldest, but need to be applied to the
+        // archived UTXO set in chain order.
+        for removed in newly_archived.iter().rev() {
+            // Transactions can spend outputs created earlier in the same block, so
+            // they need to be applied in block order too
+            let mut txids = removed.txids.iter().collect::<Vec<(&Hash, &usize)>>();
+            txids.sort_unstable_by_key(|(_, ix)| **ix);
+            for (txid, ix) in txids {
+                let transaction = &removed.block.transactions[*ix];
+                if !transaction.is_coinbase() {
+                    for input in &transaction.inputs {
+                        self.archived_utxos.remove(&(input.txid, input.vout));
+                    }
+                }
+                for (vout, output) in transaction.outputs.iter().enumerate() {
+                    self.archived_utxos.insert((*txid, vout as u32), (output.clone(), removed.height));
+                }
+            }
+        }
 
         // Next we want to prune away the dead branches (i.e. any node where following the
         // parent links takes you to an archived node without passing through active_root.
@@ -154,6 +710,21 @@ impl BlockValidator {
 
         // Pruning done, now swap our final result back in
         std::mem::swap(&mut self.active_blocks, &mut retained_active_blocks);
+        for (_, pruned) in retained_active_blocks {
+            self.recycle(pruned.block);
+        }
+
+        // Headers without blocks at or below the archived height are on side branches,
+        // since the blocks on the best chain up to there are all archived
+        if let Some(newest) = newly_archived.first() {
+            let retained = self.header_heights.split_off(&(newest.height + 1));
+            for hash in std::mem::replace(&mut self.header_heights, retained).into_values().flatten() {
+                self.headers.remove(&hash);
+            }
+        }
+        for archived in newly_archived {
+            self.recycle(archived.block);
+        }
     }
 
     // Returns the leafmost node that is an ancestor of the given hash but that is NOT in
@@ -169,92 +740,708 @@ impl BlockValidator {
         root
     }
 
+    /// Returns the minimum timestamp that a valid child of the current best chain tip
+    /// can have. This is one greater than both the median time of the last 11 blocks
+    /// on the best chain and the timestamp of the tip itself, since the validator
+    /// also requires each block to be newer than its parent. Returns None if there
+    /// are no blocks yet.
+    pub fn min_next_timestamp(&self) -> Option<u32> {
+        let tip = self.tip?;
+        let median_time_past = self.median_time_past(&tip)?;
+        median_time_past.max(self.get_header(&tip)?.time).checked_add(1)
+    }
+
+    /// Finds the most recent block that is an ancestor of (or the same as) both of
+    /// the given blocks. This is the point at which the chains ending at the two
+    /// blocks forked. The blocks may be active or archived. Returns None if either
+    /// block is unknown to the validator, or if the two blocks have no common
+    /// ancestor.
+    pub fn common_ancestor(&self, a: &Hash, b: &Hash) -> Option<Hash> {
+        let (mut a, mut a_height) = (*a, self.get_height(a)?);
+        let (mut b, mut b_height) = (*b, self.get_height(b)?);
+        // First walk up from the deeper block until they're at the same height,
+        // and then walk up from both until they meet.
+        while a_height > b_height {
+            a = self.get_header(&a)?.prev_block_hash;
+            a_height -= 1;
+        }
+        while b_height > a_height {
+            b = self.get_header(&b)?.prev_block_hash;
+            b_height -= 1;
+        }
+        while a != b {
+            a = self.get_header(&a)?.prev_block_hash;
+            b = self.get_header(&b)?.prev_block_hash;
+        }
+        Some(a)
+    }
+
+    /// Returns the total serialized size in bytes of all the blocks on the best
+    /// chain, from the genesis block to the current tip. This is an estimate of
+    /// the storage required for the chain.
+    pub fn best_chain_bytes(&self) -> u64 {
+        self.best_chain_iter().map(|hash| {
+            let size = match self.active_blocks.get(&hash) {
+                Some(active) => active.size,
+                None => self.archived_blocks.get(&hash).unwrap().size,
+            };
+            size as u64
+        }).sum()
+    }
+
+    /// Builds a block locator for the best chain, as used in the P2P `getheaders`
+    /// and `getblocks` messages. This is a list of block ids starting at the tip,
+    /// with the 10 most recent blocks followed by blocks at exponentially increasing
+    /// distances back, and always ending with the genesis block. Returns an empty
+    /// list if there are no blocks yet.
+    pub fn build_locator(&self) -> Vec<Hash> {
+        let chain = self.best_chain_iter().collect::<Vec<Hash>>();
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut ix = 0;
+        while ix < chain.len() {
+            locator.push(chain[ix]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            ix += step;
+        }
+        if let Some(genesis) = chain.last() {
+            if locator.last() != Some(genesis) {
+                locator.push(*genesis);
+            }
+        }
+        locator
+    }
+
+    /// Returns the ids of the blocks on the best chain, in order from the genesis
+    /// block to the current tip. This covers both the archived blocks and the
+    /// active blocks leading to the tip. Returns an empty list if there are no
+    /// blocks yet.
+    pub fn best_chain(&self) -> Vec<Hash> {
+        let mut chain = self.best_chain_iter().collect::<Vec<Hash>>();
+        chain.reverse();
+        chain
+    }
+
+    // Iterates over the ids of the blocks on the best chain, starting at the tip
+    // and walking back to the genesis block.
+    fn best_chain_iter(&self) -> impl Iterator<Item = Hash> + '_ {
+        std::iter::successors(self.tip, move |hash| {
+            let prev_block_hash = self.get_header(hash)?.prev_block_hash;
+            self.get_header(&prev_block_hash).map(|_| prev_block_hash)
+        })
+    }
+
+    /// Returns the output with the given txid and index if it is unspent on the best
+    /// chain, i.e. it was created by a block on the best chain and hasn't been spent
+    /// by that block or any later block on the best chain. Spends on other branches
+    /// are ignored. Returns None if the output doesn't exist, has been spent, or was
+    /// created in a block that the validator never saw.
+    pub fn get_utxo(&self, txid: &Hash, vout: u32) -> Option<&TransactionOutput> {
+        self.find_utxo(&self.tip?, txid, vout).map(|(output, _)| output)
+    }
+
+    // Returns the given output, along with the height of the block that created it,
+    // if it is unspent on the chain ending at the given block, which is either
+    // active or the most recent archived block.
+    fn find_utxo(&self, chain_tip: &Hash, txid: &Hash, vout: u32) -> Option<(&TransactionOutput, usize)> {
+        // Walk back from the tip through the active blocks; whichever of the spend or
+        // the creation of the output is found first decides the answer. The archived
+        // UTXO set already accounts for everything at and below the archived block.
+        let mut iter_hash = *chain_tip;
+        while let Some(active) = self.active_blocks.get(&iter_hash) {
+            if active.spent.contains(&(*txid, vout)) {
+                return None;
+            }
+            if let Some(ix) = active.txids.get(txid) {
+                return active.block.transactions[*ix].outputs.get(vout as usize).map(|output| (output, active.height));
+            }
+            iter_hash = active.block.header.prev_block_hash;
+        }
+        self.archived_utxos.get(&(*txid, vout)).map(|(output, height)| (output, *height))
+    }
+
+    /// Returns the number of confirmations of the given block, which is 1 for the
+    /// tip of the best chain and one more for each block before it. Archived blocks
+    /// are always on the best chain, so they always have confirmations. Returns
+    /// None for blocks that are only on a side branch, and for unknown blocks.
+    pub fn confirmations(&self, id: &Hash) -> Option<usize> {
+        let tip = self.tip?;
+        let tip_height = self.get_height(&tip)?;
+        let height = match self.archived_blocks.get(id) {
+            Some(archived) => archived.height,
+            None => {
+                let active = self.active_blocks.get(id)?;
+                // The block is on the best chain if walking back from the tip to its
+                // height arrives at it
+                let mut iter_hash = tip;
+                for _ in active.height..tip_height {
+                    iter_hash = self.active_blocks.get(&iter_hash)?.block.header.prev_block_hash;
+                }
+                if iter_hash != *id {
+                    return None;
+                }
+                active.height
+            }
+        };
+        Some(tip_height - height + 1)
+    }
+
+    /// Returns the number of confirmations of the given output, which is the number
+    /// of confirmations of the block that created it. As with get_utxo, only outputs
+    /// that are unspent on the best chain are considered, so this returns None for
+    /// outputs that have been spent, whether the creating block is archived or not.
+    pub fn outpoint_confirmations(&self, txid: &Hash, vout: u32) -> Option<usize> {
+        let tip = self.tip?;
+        let (_, height) = self.find_utxo(&tip, txid, vout)?;
+        Some(self.get_height(&tip)? - height + 1)
+    }
+
+    /// Returns true if the output with the given txid and index is unspent on the
+    /// best chain. See get_utxo for details.
+    pub fn is_unspent(&self, txid: &Hash, vout: u32) -> bool {
+        self.get_utxo(txid, vout).is_some()
+    }
+
+    /// Returns the ids and heights of the tips of all the active chains, i.e. the
+    /// active blocks that have no children. The tips are sorted by decreasing
+    /// height, so the first one is at the end of the longest chain (although
+    /// not necessarily the best chain, which is the one with the most work).
+    pub fn active_chain_tips(&self) -> Vec<(Hash, usize)> {
+        let parents = self.active_blocks.values().map(|active| active.block.header.prev_block_hash).collect::<HashSet<Hash>>();
+        let mut tips = self.active_blocks.iter()
+            .filter(|(hash, _)| !parents.contains(hash))
+            .map(|(hash, active)| (*hash, active.height))
+            .collect::<Vec<(Hash, usize)>>();
+        tips.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.0.cmp(&b.0.0)));
+        tips
+    }
+
+    /// Returns the ids of the given active block and its ancestors, starting with
+    /// the given block and walking back through the active blocks to the most
+    /// recent archived block (which is included). Returns an empty list if the
+    /// given block is not active.
+    pub fn ancestry(&self, tip: &Hash) -> Vec<Hash> {
+        let mut ancestry = Vec::new();
+        let mut iter_hash = *tip;
+        while let Some(active) = self.active_blocks.get(&iter_hash) {
+            ancestry.push(iter_hash);
+            iter_hash = active.block.header.prev_block_hash;
+        }
+        if !ancestry.is_empty() && self.archived_blocks.contains_key(&iter_hash) {
+            ancestry.push(iter_hash);
+        }
+        ancestry
+    }
+
+    /// Rolls back the validator's state to the given active block, by removing all
+    /// the active blocks that descend from it. Blocks on other branches are kept.
+    /// The tip is reset to the given block, unless another branch now has more
+    /// work, in which case the tip is at the end of that branch. The header tree is not
+    /// affected, so the removed blocks can be given to the validator again later.
+    /// Returns an error if the block is archived (since archived history can't be
+    /// rolled back) or unknown.
+    pub fn rollback_to(&mut self, hash: &Hash) -> Result<(), BlockValidationError> {
+        if let Some(archived) = self.archived_blocks.get(hash) {
+            return Err(BlockValidationError::RollbackIntoArchive { block: *hash, height: archived.height });
+        }
+        let chain_work = match self.active_blocks.get(hash) {
+            Some(active) => active.chain_work,
+            None => return Err(BlockValidationError::UnknownBlock { block: *hash }),
+        };
+
+        let descendants = self.active_blocks.keys()
+            .filter(|active| self.ancestry(active).iter().skip(1).any(|ancestor| ancestor == hash))
+            .copied()
+            .collect::<Vec<Hash>>();
+        for descendant in descendants {
+            info!(target: LOG_TARGET, hash:% = descendant; "Rolling back {}", descendant);
+            let removed = self.active_blocks.remove(&descendant).unwrap();
+            self.insert_header(descendant, removed.block.header.clone(), removed.height);
+            self.recycle(removed.block);
+        }
+
+        let mut tip = *hash;
+        let mut tip_work = chain_work;
+        for (other_tip, _) in self.active_chain_tips() {
+            let other_work = self.active_blocks[&other_tip].chain_work;
+            if other_work > tip_work {
+                tip = other_tip;
+                tip_work = other_work;
+            }
+        }
+        self.tip = Some(tip);
+        Ok(())
+    }
+
+    // Returns the total work of the chain ending at the given block, which may be
+    // active or archived.
+    fn get_chain_work(&self, hash: &Hash) -> Option<u128> {
+        match self.active_blocks.get(hash) {
+            Some(active) => Some(active.chain_work),
+            None => self.archived_blocks.get(hash).map(|archived| archived.chain_work),
+        }
+    }
+
+    // Returns the height of the most recent archived block, or None if no blocks
+    // have been archived.
+    fn archived_height(&self) -> Option<usize> {
+        (self.snapshot_height + self.archived_blocks.len()).checked_sub(1)
+    }
+
+    // Returns the height of the given block, which may be active or archived.
+    fn get_height(&self, hash: &Hash) -> Option<usize> {
+        match self.active_blocks.get(hash) {
+            Some(active) => Some(active.height),
+            None => self.archived_blocks.get(hash).map(|archived| archived.height),
+        }
+    }
+
+    // Returns the header of the given block, which may be active or archived.
+    fn get_header(&self, hash: &Hash) -> Option<&BlockHeader> {
+        match self.active_blocks.get(hash) {
+            Some(active) => Some(&active.block.header),
+            None => self.archived_blocks.get(hash).map(|archived| &archived.header),
+        }
+    }
+
+    // Returns the median timestamp of the last MEDIAN_TIME_SPAN blocks ending at the
+    // given block (inclusive). If the chain is shorter than that, the median is taken
+    // over all the blocks in the chain. The header tree is used, so that after a UTXO
+    // snapshot the headers before the snapshot's base block are included.
+    fn median_time_past(&self, hash: &Hash) -> Option<u32> {
+        median_time_past(hash, |h| self.get_header_entry(h).map(|(header, _)| header))
+    }
+
     fn validate_block(&mut self, block: &Block, height: usize) -> Result<(), BlockValidationError> {
         // TODO: implement more things here. This is just enough scaffolding to avoid lint errors
-        if block.header.version > MAX_SUPPORTED_BLOCK_VERSION {
-            return Err(BlockValidationError::new(format!("Block with unknown version: expected {} but got {}", MAX_SUPPORTED_BLOCK_VERSION, block.header.version)));
+        let parent = match height {
+            0 => None,
+            _ => Some(self.get_header(&block.header.prev_block_hash).unwrap()),
+        };
+        self.validate_header(&block.header, height, parent)?;
+        // Once CSV is active, time-based locktimes are compared against the median
+        // time past of the parent rather than the block's own timestamp (BIP113).
+        let csv_active = self.config.csv_height.is_some_and(|csv_height| height >= csv_height);
+        let lock_time_cutoff = match height {
+            0 => block.header.time,
+            _ if !csv_active => block.header.time,
+            _ => self.median_time_past(&block.header.prev_block_hash).unwrap(),
+        };
+
+        // Every block has at least a coinbase transaction. Without this check an empty
+        // block would pass the merkle root check if its header has a zero merkle root,
+        // since that is what computed_merkle_root returns for no transactions.
+        if block.transactions.is_empty() {
+            return Err(BlockValidationError::NoTransactions);
         }
         if block.computed_merkle_root() != block.header.merkle_root {
-            return Err(BlockValidationError::new(format!("Block with incorrect merkle root: expected {} but got {}", block.computed_merkle_root(), block.header.merkle_root)));
+            return Err(BlockValidationError::MerkleMismatch { computed: block.computed_merkle_root(), header: block.header.merkle_root });
         }
-        let seconds_since_epoch = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|_| BlockValidationError::new(String::from("Unable to compute current time relative to the UNIX epoch!")))?
-            .as_secs();
-        if u64::from(block.header.time) > seconds_since_epoch + TWO_HOURS_IN_SECONDS {
-            return Err(BlockValidationError::new(format!("Block timestamp {} was more than two hours in the future from current timestamp {}", block.header.time, seconds_since_epoch)));
+        if block.is_merkle_tree_mutated() {
+            return Err(BlockValidationError::MutatedMerkleTree { merkle_root: block.header.merkle_root });
+        }
+
+        // Once BIP16 is active, the sigops in P2SH redeem scripts count towards the
+        // block limit, which needs the lock scripts of the outputs being spent on
+        // this block's branch.
+        let parent_hash = block.header.prev_block_hash;
+        let bip16_active = self.config.bip16_height.is_some_and(|bip16_height| height >= bip16_height);
+        validate_transactions(block, bip16_active, |txid, vout| {
+            self.find_utxo(&parent_hash, txid, vout).map(|(output, _)| output.lock_script.as_slice())
+        })?;
+        // Before BIP34 made coinbases unique, two coinbases could have the same txid,
+        // in which case the second would overwrite the outputs of the first (BIP30).
+        // After BIP34 activates this can't happen, so the check is skipped.
+        let bip34_active = self.config.bip34_height.is_some_and(|bip34_height| height >= bip34_height);
+        let is_exception = BIP30_EXCEPTIONS.iter()
+            .any(|(exception_height, hex)| *exception_height == height && block.id() == Hash::from_display_hex(hex).unwrap());
+        if !bip34_active && !is_exception {
+            self.check_no_unspent_duplicates(block, &parent_hash)?;
         }
+        for transaction in block.transactions.iter().filter(|t| !t.is_coinbase()) {
+            if !is_final(transaction, height, lock_time_cutoff) {
+                return Err(BlockValidationError::NonFinalTransaction { txid: transaction.txid(), locktime: transaction.locktime });
+            }
+        }
+        if !self.is_assumed_valid(&block.id(), height) {
+            self.verify_scripts(block, &parent_hash)?;
+        }
+        Ok(())
+    }
 
-        let target = match Hash::from_bits(block.header.bits) {
-            None => return Err(BlockValidationError::new(format!("Target difficulty could not be computed from {:#x}", block.header.bits))),
-            Some(target) => target,
+    // Returns true if the block with the given id and height is the assume-valid
+    // block or one of its ancestors, so that its scripts needn't be verified.
+    fn is_assumed_valid(&mut self, hash: &Hash, height: usize) -> bool {
+        let (assume_valid, assume_valid_height) = match self.config.assume_valid {
+            Some(assume_valid) => assume_valid,
+            None => return false,
         };
-        // TODO: check against difficulty 1 values (network-dependent) https://developer.bitcoin.org/reference/block_chain.html#target-nbits
-        if block.id() >= target {
-            return Err(BlockValidationError::new(format!("Block header hash {} was not less than the target hash {}", block.id(), target)));
+        if height > assume_valid_height {
+            return false;
+        }
+        if self.assume_valid_chain.is_empty() {
+            if self.get_header_entry(&assume_valid).map(|(_, height)| height) != Some(assume_valid_height) {
+                return false;
+            }
+            let mut chain = HashMap::new();
+            let mut iter_hash = assume_valid;
+            while let Some((header, height)) = self.get_header_entry(&iter_hash) {
+                chain.insert(height, iter_hash);
+                iter_hash = header.prev_block_hash;
+            }
+            self.assume_valid_chain = chain;
         }
+        self.assume_valid_chain.get(&height) == Some(hash)
+    }
 
-        // For the genesis block, the above checks are all that we need to do.
-        if height == 0 {
-            return Ok(());
+    // Verifies the scripts of the block's transactions against the outputs they
+    // spend, which are looked up on the chain ending at the given block and among
+    // the earlier transactions in the block. Verification is only as complete as
+    // `script::verify_transaction`, so scripts using features it doesn't support
+    // make the block invalid.
+    fn verify_scripts(&self, block: &Block, chain_tip: &Hash) -> Result<(), BlockValidationError> {
+        let mut block_outputs: HashMap<(Hash, u32), &TransactionOutput> = HashMap::new();
+        for transaction in &block.transactions {
+            let txid = transaction.txid();
+            if !transaction.is_coinbase() {
+                let prevouts = transaction.inputs.iter().map(|input| {
+                    block_outputs.get(&(input.txid, input.vout)).copied()
+                        .or_else(|| self.find_utxo(chain_tip, &input.txid, input.vout).map(|(output, _)| output))
+                        .map(|output| (output.lock_script.clone(), output.value))
+                }).collect::<Option<Vec<(Vec<u8>, u64)>>>();
+                // TODO: spending an unknown output should make the block invalid, but
+                // that isn't checked yet, so such transactions just aren't verified
+                if let Some(prevouts) = prevouts {
+                    let reason = match verify_transaction(transaction, &prevouts) {
+                        Ok(true) => None,
+                        Ok(false) => Some(String::from("An input's scripts leave a false value on the stack")),
+                        Err(e) => Some(std::error::Error::source(&e).map_or_else(|| e.to_string(), |source| source.to_string())),
+                    };
+                    if let Some(reason) = reason {
+                        return Err(BlockValidationError::ScriptVerificationFailed { txid, reason });
+                    }
+                }
+            }
+            for (vout, output) in transaction.outputs.iter().enumerate() {
+                block_outputs.insert((txid, vout as u32), output);
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that none of the transactions in the block have the same txid as a
+    // transaction with unspent outputs on the chain ending at the given block.
+    fn check_no_unspent_duplicates(&self, block: &Block, chain_tip: &Hash) -> Result<(), BlockValidationError> {
+        let txids = block.transactions.iter().map(|transaction| (transaction.txid(), transaction.outputs.len() as u32)).collect::<Vec<(Hash, u32)>>();
+        let is_unspent = |txid: &Hash, outputs: u32| (0..outputs).any(|vout| self.find_utxo(chain_tip, txid, vout).is_some());
+        match txids.iter().find(|(txid, outputs)| is_unspent(txid, *outputs)) {
+            Some((txid, _)) => Err(BlockValidationError::OverwritesUnspentTransaction { txid: *txid }),
+            None => Ok(()),
+        }
+    }
+
+    // Checks on a block header. The parent is None for the genesis block, and otherwise
+    // is the parent header.
+    fn validate_header(&self, header: &BlockHeader, height: usize, parent: Option<&BlockHeader>) -> Result<(), BlockValidationError> {
+        let minimum = self.config.min_versions.iter()
+            .filter(|(activation_height, _)| height >= *activation_height)
+            .map(|(_, version)| *version)
+            .max();
+        if let Some(minimum) = minimum {
+            // The version is signed in the reference implementation, so versions with
+            // the top bit set are lower than any minimum
+            if (header.version as i32) < minimum as i32 {
+                return Err(BlockValidationError::ObsoleteVersion { height, minimum, actual: header.version });
+            }
+        }
+        if let Some(checkpoint) = self.config.checkpoints.get(&height) {
+            if header.id() != *checkpoint {
+                return Err(BlockValidationError::CheckpointMismatch { height, expected: *checkpoint, actual: header.id() });
+            }
+        }
+        let seconds_since_epoch = self.clock.now();
+        if u64::from(header.time) > seconds_since_epoch.saturating_add(self.config.max_future_block_time) {
+            return Err(BlockValidationError::TimestampTooFarAhead { timestamp: header.time, now: seconds_since_epoch, max_future_block_time: self.config.max_future_block_time });
+        }
+
+        // TODO: check against difficulty 1 values (network-dependent) https://developer.bitcoin.org/reference/block_chain.html#target-nbits
+        match check_pow(header) {
+            // The target must still be valid even when skipping the check itself
+            Err(BlockValidationError::PowTooHigh { .. }) if self.skip_pow => (),
+            result => result?,
         }
 
-        // All other blocks have a parent
-        let parent = self.active_blocks.get(&block.header.prev_block_hash).unwrap();
+        // For the genesis block, the above checks are all that we need to do.
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
 
-        if block.header.time <= parent.block.header.time {
-            return Err(BlockValidationError::new(format!("Block with time {} was not newer than parent block with time {}", block.header.time, parent.block.header.time)));
+        if header.time <= parent.time {
+            return Err(BlockValidationError::TimestampNotAfterParent { timestamp: header.time, parent_timestamp: parent.time });
         }
 
         if (height % 2016) == 0 {
             // TODO: recompute new difficulty and ensure it matches
-        } else if block.header.bits != parent.block.header.bits {
-            return Err(BlockValidationError::new(format!("Block changed the difficulty threshold prematurely; height {} is {} mod 2016", height, height % 2016)));
+        } else if header.bits != parent.bits {
+            return Err(BlockValidationError::PrematureDifficultyChange { height });
         }
 
         Ok(())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::sync::atomic::{AtomicU32, Ordering};
-    use super::*;
+// Returns the median timestamp of the last MEDIAN_TIME_SPAN headers ending at the
+// given block (inclusive), using the given function to look up headers.
+fn median_time_past<'a>(hash: &Hash, get_header: impl Fn(&Hash) -> Option<&'a BlockHeader>) -> Option<u32> {
+    let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+    let mut iter_hash = *hash;
+    while times.len() < MEDIAN_TIME_SPAN {
+        match get_header(&iter_hash) {
+            Some(header) => {
+                times.push(header.time);
+                iter_hash = header.prev_block_hash;
+            }
+            None => break,
+        };
+    }
+    if times.is_empty() {
+        return None;
+    }
+    times.sort_unstable();
+    Some(times[times.len() / 2])
+}
 
-    static TIMESTAMP: AtomicU32 = AtomicU32::new(0);
+/// Returns the BIP9 deployment bits signaled by the given header. Headers using
+/// version bits have 001 as the top three bits of their version, and each of the
+/// remaining 29 bits signals readiness for one soft fork deployment. Returns 0
+/// for headers that don't use version bits.
+pub fn signaled_bits(header: &BlockHeader) -> u32 {
+    if header.version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS {
+        header.version & !VERSIONBITS_TOP_MASK
+    } else {
+        0
+    }
+}
 
-    fn genesis_block() -> Block {
-        block(Hash::zero())
+/// Checks the proof-of-work of a block header in isolation: the target encoded
+/// in the header's `bits` must be valid, and the header hash must be below it.
+/// This doesn't check that the target is the correct one for the header's
+/// position in the chain.
+pub fn check_pow(header: &BlockHeader) -> Result<(), BlockValidationError> {
+    let target = Hash::from_bits_strict(header.bits).ok_or(BlockValidationError::InvalidTarget { bits: header.bits })?;
+    let hash = header.id();
+    if hash >= target {
+        return Err(BlockValidationError::PowTooHigh { hash, target });
     }
+    Ok(())
+}
 
-    fn block(parent: Hash) -> Block {
-        let mut block = Block::default();
-        block.header.prev_block_hash = parent;
-        block.header.time = TIMESTAMP.load(Ordering::Relaxed) + 1;
-        block.header.bits = 0x20ffffff;
+/// Returns true if the transaction can be included in a block at the given
+/// height and time. This is the case if the locktime is zero, if the locktime
+/// is below the height or time (depending on whether it is above the threshold
+/// of 500,000,000 that separates heights from timestamps), or if all the inputs
+/// have a final sequence number, which disables the locktime.
+pub fn is_final(tx: &Transaction, height: usize, block_time: u32) -> bool {
+    if tx.locktime == 0 {
+        return true;
+    }
+    let cutoff = match tx.locktime < LOCKTIME_THRESHOLD {
+        true => height as u64,
+        false => block_time as u64,
+    };
+    if (tx.locktime as u64) < cutoff {
+        return true;
+    }
+    tx.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+}
 
-        TIMESTAMP.store(block.header.time, Ordering::Relaxed);
+// Checks the transactions in the block that can be checked without executing
+// scripts. The lock scripts of outputs spent by the block are looked up with
+// `prevout_script`, unless they are created earlier in the block itself. Outputs
+// that can't be found are treated as not being P2SH. P2SH redeem scripts only
+// count towards the sigop limit if `bip16_active` is set.
+fn validate_transactions<'a>(block: &'a Block, bip16_active: bool, prevout_script: impl Fn(&Hash, u32) -> Option<&'a [u8]>) -> Result<(), BlockValidationError> {
+    if let Some(coinbase) = block.coinbase() {
+        let script_length = coinbase.inputs[0].unlock_script.len();
+        if !(MIN_COINBASE_SCRIPT_LENGTH..=MAX_COINBASE_SCRIPT_LENGTH).contains(&script_length) {
+            return Err(BlockValidationError::BadCoinbaseLength { length: script_length });
+        }
 
-        block
+        // The coinbase outputs cannot be spent until they mature, so in particular
+        // they cannot be spent by another transaction in the same block.
+        let coinbase_txid = coinbase.txid();
+        for (txid, vin, input) in block.iter_inputs().skip(1) {
+            if input.txid == coinbase_txid {
+                return Err(BlockValidationError::CoinbaseSpentInSameBlock { txid, vin });
+            }
+        }
     }
 
-    fn block_with_nonce(parent: Hash, nonce: u32) -> Block {
-        let mut block = block(parent);
-        block.header.nonce = nonce;
-        block
+    let mut txids = HashSet::with_capacity(block.transactions.len());
+    for transaction in &block.transactions {
+        let txid = transaction.txid();
+        if !txids.insert(txid) {
+            return Err(BlockValidationError::DuplicateTransaction { txid });
+        }
+        checked_sum_transaction_values(txid, transaction.outputs.iter().map(|output| output.value))?;
     }
 
-    fn validate_hash(validator: &mut BlockValidator, block: Block) -> Hash {
-        match validator.handle_block(block) {
-            ValidationResult::Valid(h) => h,
-            result => panic!("Got {:?} but expected ValidationResult::Valid", result),
+    // Legacy and P2SH sigops cost WITNESS_SCALE_FACTOR each, and witness sigops
+    // cost one each.
+    let block_outputs: HashMap<(Hash, u32), &[u8]> = block.iter_outputs()
+        .map(|(txid, vout, output)| ((txid, vout), output.lock_script.as_slice()))
+        .collect();
+    let (sigops, cost) = block.transactions.iter().map(|transaction| {
+        let legacy = transaction.inputs.iter().map(|input| count_sigops(&input.unlock_script, false)).sum::<usize>()
+            + transaction.outputs.iter().map(|output| count_sigops(&output.lock_script, false)).sum::<usize>();
+        if transaction.is_coinbase() {
+            return (legacy, legacy * WITNESS_SCALE_FACTOR);
         }
+        let (p2sh, witness) = transaction.inputs.iter().map(|input| {
+            let lock = block_outputs.get(&(input.txid, input.vout)).copied()
+                .or_else(|| prevout_script(&input.txid, input.vout));
+            lock.map_or((0, 0), |lock| (
+                if bip16_active { p2sh_sigop_count(lock, &input.unlock_script) } else { 0 },
+                witness_sigop_count(lock, &input.unlock_script, &input.witness_stuff),
+            ))
+        }).fold((0, 0), |(p2sh, witness), (input_p2sh, input_witness)| (p2sh + input_p2sh, witness + input_witness));
+        (legacy + p2sh + witness, (legacy + p2sh) * WITNESS_SCALE_FACTOR + witness)
+    }).fold((0, 0), |(sigops, cost), (tx_sigops, tx_cost)| (sigops + tx_sigops, cost + tx_cost));
+    if cost > MAX_BLOCK_SIGOPS_COST {
+        return Err(BlockValidationError::TooManySigops { sigops, cost, max_cost: MAX_BLOCK_SIGOPS_COST });
     }
 
-    #[test]
-    fn simple_archiving_test() {
-        let mut validator = BlockValidator::new();
-        validator.max_active_height = 3;
+    validate_witness_commitment(block)
+}
+
+// Checks the witness commitment in the coinbase as described in BIP141. Blocks
+// without a commitment must not have any witness data.
+fn validate_witness_commitment(block: &Block) -> Result<(), BlockValidationError> {
+    let commitment = match block.witness_commitment() {
+        Some(commitment) => commitment,
+        None => {
+            if let Some(transaction) = block.transactions.iter().find(|t| t.has_witness()) {
+                return Err(BlockValidationError::UnexpectedWitness { txid: transaction.txid() });
+            }
+            return Ok(());
+        }
+    };
+
+    let coinbase = block.coinbase().expect("Blocks with a witness commitment have a coinbase");
+    let reserved_value = match coinbase.inputs[0].witness_stuff.as_slice() {
+        [reserved_value] if reserved_value.len() == 32 => reserved_value,
+        _ => return Err(BlockValidationError::BadWitnessReservedValue),
+    };
+    let witness_root = block.computed_witness_merkle_root().reverse();
+    let computed = Hash(sha256d(&[&witness_root.0[..], reserved_value].concat())).reverse();
+    if computed != commitment {
+        return Err(BlockValidationError::WitnessCommitmentMismatch { computed, commitment });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use crate::{LittleEndianSerialization, Opcode, Transaction, TransactionFlags, TransactionOutput};
+    use crate::tests::transaction;
+    use crate::parse::parse_blockfile;
+    use crate::parse::tests::read_testdata;
+    use crate::template::BlockTemplate;
+    use sha2::{Digest, Sha256};
+    use super::*;
+
+    static TIMESTAMP: AtomicU32 = AtomicU32::new(0);
+    static COINBASE_NUMBER: AtomicU32 = AtomicU32::new(0);
+
+    // Creates a validator for tests. The blocks created in tests aren't mined, so
+    // this skips the proof-of-work check.
+    fn validator() -> BlockValidator {
+        let mut validator = BlockValidator::new();
+        validator.skip_pow_check(true);
+        validator
+    }
+
+    fn genesis_block() -> Block {
+        block(Hash::zero())
+    }
+
+    fn block(parent: Hash) -> Block {
+        block_with_transactions(parent, vec![coinbase_transaction()])
+    }
+
+    fn block_with_nonce(parent: Hash, nonce: u32) -> Block {
+        let mut block = block(parent);
+        block.header.nonce = nonce;
+        block
+    }
+
+    // Each coinbase pushes a distinct number, like the height required by BIP34,
+    // so that coinbases in different blocks have different txids.
+    fn coinbase_transaction() -> Transaction {
+        let mut coinbase = transaction(&[(Hash::zero(), 0xffffffff)], &[5000]);
+        let number = COINBASE_NUMBER.fetch_add(1, Ordering::Relaxed);
+        coinbase.inputs[0].unlock_script = [&[0x04][..], &number.to_le_bytes()].concat();
+        coinbase
+    }
+
+    fn block_with_transactions(parent: Hash, transactions: Vec<Transaction>) -> Block {
+        let time = TIMESTAMP.fetch_add(1, Ordering::Relaxed) + 1;
+        BlockTemplate::new(parent).time(time).transactions(transactions).build()
+    }
+
+    fn validate_hash(validator: &mut BlockValidator, block: Block) -> Hash {
+        match validator.handle_block(block) {
+            ValidationResult::Valid(h) => h,
+            result => panic!("Got {:?} but expected ValidationResult::Valid", result),
+        }
+    }
+
+    // A captured log record: the target, the message, and the hash and height
+    // key-values if present
+    type CapturedRecord = (String, String, Option<String>, Option<String>);
+
+    static RECORDS: Mutex<Vec<CapturedRecord>> = Mutex::new(Vec::new());
+
+    // A logger that keeps every record so that tests can check what was logged
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let value = |key| record.key_values().get(log::kv::Key::from_str(key)).map(|v| v.to_string());
+            RECORDS.lock().unwrap().push((record.target().to_string(), record.args().to_string(), value("hash"), value("height")));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn log_targets() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let mut validator = validator();
+        let hash = validate_hash(&mut validator, genesis_block());
+        let records = RECORDS.lock().unwrap();
+        let (target, message, _, height) = records.iter().find(|(_, _, h, _)| h.as_ref() == Some(&hash.to_string())).unwrap();
+        assert_eq!(target, LOG_TARGET);
+        assert_eq!(message, &format!("Adding block {} to chain at height 0", hash));
+        assert_eq!(height.as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn simple_archiving_test() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
 
         let genesis_hash = validate_hash(&mut validator, genesis_block());
         let child_hash = validate_hash(&mut validator, block(genesis_hash));
@@ -267,7 +1454,7 @@ mod test {
 
     #[test]
     fn tree_archiving_test() {
-        let mut validator = BlockValidator::new();
+        let mut validator = validator();
         validator.max_active_height = 3;
 
         let genesis = validate_hash(&mut validator, genesis_block());
@@ -299,4 +1486,1140 @@ mod test {
         assert_eq!(validator.archived_blocks.len(), 1);
         assert!(validator.archived_blocks.contains_key(&genesis));
     }
+
+    #[test]
+    fn block_recycler() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+        let recycled = Arc::new(Mutex::new(Vec::new()));
+        let recycled_clone = Arc::clone(&recycled);
+        validator.set_block_recycler(move |block| recycled_clone.lock().unwrap().push(block.id()));
+
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let child_1a = validate_hash(&mut validator, block_with_nonce(genesis, 1));
+        let child_1b = validate_hash(&mut validator, block_with_nonce(genesis, 2));
+        let child_2a = validate_hash(&mut validator, block(child_1a));
+        let mut invalid = block(child_2a);
+        invalid.transactions.clear();
+        let invalid_hash = invalid.id();
+        assert!(matches!(validator.handle_block(invalid), ValidationResult::Invalid(BlockValidationError::NoTransactions)));
+        assert_eq!(*recycled.lock().unwrap(), vec![invalid_hash]);
+
+        // Archiving the genesis block hands it over, along with the pruned "b" branch
+        validate_hash(&mut validator, block(child_2a));
+        let recycled = recycled.lock().unwrap();
+        assert_eq!(recycled.len(), 3);
+        assert!(recycled.contains(&genesis));
+        assert!(recycled.contains(&child_1b));
+    }
+
+    #[test]
+    fn min_next_timestamp_test() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+        assert_eq!(validator.min_next_timestamp(), None);
+
+        let mut genesis = genesis_block();
+        genesis.header.time = 1000;
+        let mut tip = validate_hash(&mut validator, genesis);
+        assert_eq!(validator.min_next_timestamp(), Some(1001));
+
+        for time in [1010, 1020, 1030, 1040, 1300, 1400, 1500, 1600, 1700, 1800] {
+            let mut child = block(tip);
+            child.header.time = time;
+            tip = validate_hash(&mut validator, child);
+        }
+        // The last 11 timestamps are 1000 1010 1020 1030 1040 [1300] 1400 1500 1600 1700 1800,
+        // but the next block must also be newer than the tip
+        assert_eq!(validator.median_time_past(&tip), Some(1300));
+        assert_eq!(validator.min_next_timestamp(), Some(1801));
+
+        let mut child = block(tip);
+        child.header.time = 1900;
+        tip = validate_hash(&mut validator, child);
+        // The genesis timestamp has now dropped out of the window
+        // 1010 1020 1030 1040 1300 [1400] 1500 1600 1700 1800 1900
+        assert_eq!(validator.median_time_past(&tip), Some(1400));
+        assert_eq!(validator.min_next_timestamp(), Some(1901));
+        assert_eq!(validator.tip, Some(tip));
+    }
+
+    #[test]
+    fn time_observer() {
+        let mut validator = validator();
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let observed_clone = Arc::clone(&observed);
+        validator.set_time_observer(move |height, time, median_time_past| {
+            observed_clone.lock().unwrap().push((height, time, median_time_past));
+        });
+
+        let mut genesis = genesis_block();
+        genesis.header.time = 1000;
+        let genesis = validate_hash(&mut validator, genesis);
+        let mut child = block(genesis);
+        child.header.time = 1600;
+        let child = validate_hash(&mut validator, child);
+        // The median of 1000 and 1600 is taken to be the upper one
+        let mut warped = block(child);
+        warped.header.time = 900;
+        assert!(matches!(validator.handle_block(warped), ValidationResult::Invalid(_)));
+
+        assert_eq!(*observed.lock().unwrap(), vec![(1, 1600, 1000), (2, 900, 1600)]);
+    }
+
+    #[test]
+    fn output_value_range() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let mut spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        spend.outputs[0].value = crate::MAX_MONEY;
+        spend.outputs.push(spend.outputs[0].clone());
+        match validator.handle_block(block_with_transactions(genesis, vec![coinbase_transaction(), spend.clone()])) {
+            ValidationResult::Invalid(BlockValidationError::ValueOverflow { txid, value }) => {
+                assert_eq!(txid, spend.txid());
+                assert_eq!(value, 2 * crate::MAX_MONEY);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        spend.outputs.pop();
+        validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase_transaction(), spend]));
+    }
+
+    #[test]
+    fn coinbase_spend_in_same_block() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let coinbase = coinbase_transaction();
+        let spend = transaction(&[(coinbase.txid(), 0)], &[5000]);
+        let result = validator.handle_block(block_with_transactions(genesis, vec![coinbase, spend]));
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+
+        let coinbase = coinbase_transaction();
+        let spend = transaction(&[(Hash([0x11; 32]), 1)], &[5000]);
+        validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase, spend]));
+    }
+
+    #[test]
+    fn duplicate_transactions() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        // Not adjacent, so the merkle tree isn't mutated
+        let spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        let other = transaction(&[(Hash([0x22; 32]), 0)], &[5000]);
+        let block = block_with_transactions(genesis, vec![coinbase_transaction(), spend.clone(), other, spend.clone()]);
+        assert!(!block.is_merkle_tree_mutated());
+        let result = validator.handle_block(block);
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::DuplicateTransaction { txid }) if txid == spend.txid()), "Got {:?}", result);
+    }
+
+    #[test]
+    fn bip30() {
+        let mut validator = validator();
+        validator.max_active_height = 2;
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let coinbase = coinbase_transaction();
+        let first = validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase.clone()]));
+
+        // The historical scenario: a later coinbase with the same txid as one whose
+        // output is still unspent
+        let result = validator.handle_block(block_with_transactions(first, vec![coinbase.clone()]));
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::OverwritesUnspentTransaction { txid }) if txid == coinbase.txid()), "Got {:?}", result);
+
+        // Still caught once the original coinbase is archived
+        let mut tip = first;
+        for _ in 0..3 {
+            tip = validate_hash(&mut validator, block(tip));
+        }
+        assert!(validator.archived_utxos.contains_key(&(coinbase.txid(), 0)));
+        let result = validator.handle_block(block_with_transactions(tip, vec![coinbase.clone()]));
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::OverwritesUnspentTransaction { .. })), "Got {:?}", result);
+
+        // Once the output is spent, the txid can be reused
+        let spend = transaction(&[(coinbase.txid(), 0)], &[5000]);
+        tip = validate_hash(&mut validator, block_with_transactions(tip, vec![coinbase_transaction(), spend]));
+        validate_hash(&mut validator, block_with_transactions(tip, vec![coinbase]));
+    }
+
+    #[test]
+    fn bip30_after_bip34() {
+        // Once BIP34 is active coinbases are unique, so the check is skipped
+        let mut validator = validator();
+        validator.config.bip34_height = Some(2);
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let coinbase = coinbase_transaction();
+        let first = validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase.clone()]));
+        validate_hash(&mut validator, block_with_transactions(first, vec![coinbase]));
+    }
+
+    #[test]
+    fn mutated_merkle_tree() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let transactions = vec![
+            coinbase_transaction(),
+            transaction(&[(Hash([0x11; 32]), 0)], &[5000]),
+            transaction(&[(Hash([0x22; 32]), 0)], &[5000]),
+        ];
+        let valid = block_with_transactions(genesis, transactions.clone());
+
+        let mut mutated_transactions = transactions;
+        mutated_transactions.push(mutated_transactions[2].clone());
+        let mut mutated = valid.clone();
+        mutated.transactions = mutated_transactions;
+        assert_eq!(mutated.computed_merkle_root(), valid.header.merkle_root);
+        assert_eq!(mutated.id(), valid.id());
+
+        let result = validator.handle_block(mutated);
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::MutatedMerkleTree { .. })), "Got {:?}", result);
+        validate_hash(&mut validator, valid);
+    }
+
+    #[test]
+    fn skip_pow_check_test() {
+        let mut validator = BlockValidator::new();
+
+        let mut genesis = genesis_block();
+        genesis.header.bits = 0x1d00ffff;
+        let result = validator.handle_block(genesis.clone());
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+
+        validator.skip_pow_check(true);
+        let mut tip = validate_hash(&mut validator, genesis);
+        for _ in 0..5 {
+            let mut child = block(tip);
+            child.header.bits = 0x1d00ffff;
+            tip = validate_hash(&mut validator, child);
+        }
+
+        // Other validation still happens
+        let mut child = block(tip);
+        child.header.bits = 0x1d00ffff;
+        child.header.merkle_root = Hash([0x11; 32]);
+        let result = validator.handle_block(child);
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+
+        validator.skip_pow_check(false);
+        let mut child = block(tip);
+        child.header.bits = 0x1d00ffff;
+        let result = validator.handle_block(child);
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+    }
+
+    #[test]
+    fn witness_commitment() {
+        let block_481829 = parse_blockfile(&read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        assert!(block_481829.witness_commitment().is_some());
+        validate_transactions(&block_481829, true, |_, _| None).unwrap();
+
+        let coinbase = coinbase_transaction();
+        let spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        let mut block = block_with_transactions(Hash::zero(), vec![coinbase, spend]);
+
+        // A near-miss prefix is not a commitment, which is fine if there is no witness data
+        let mut near_miss = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xee];
+        near_miss.extend([0; 32]);
+        block.transactions[0].outputs.push(TransactionOutput { value: 0, lock_script: near_miss });
+        assert!(block.witness_commitment().is_none());
+        validate_transactions(&block, true, |_, _| None).unwrap();
+
+        block.transactions[0].inputs[0].witness_stuff = vec![vec![0; 32]];
+        block.transactions[1].flags = TransactionFlags::WITNESS;
+        block.transactions[1].inputs[0].witness_stuff = vec![vec![0x01, 0x02]];
+        assert!(validate_transactions(&block, true, |_, _| None).is_err());
+
+        let witness_root = block.computed_witness_merkle_root().reverse();
+        let commitment = sha256d(&[witness_root.0, [0; 32]].concat());
+        let mut exact = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        exact.extend(commitment);
+        block.transactions[0].outputs.push(TransactionOutput { value: 0, lock_script: exact });
+        assert_eq!(block.witness_commitment(), Some(Hash(commitment).reverse()));
+        validate_transactions(&block, true, |_, _| None).unwrap();
+
+        // Changing the witness data invalidates the commitment
+        block.transactions[1].inputs[0].witness_stuff = vec![vec![0x01, 0x03]];
+        assert!(validate_transactions(&block, true, |_, _| None).is_err());
+    }
+
+    #[test]
+    fn common_ancestor_test() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let child_1 = validate_hash(&mut validator, block(genesis));
+        let child_2a = validate_hash(&mut validator, block_with_nonce(child_1, 1));
+        let child_2b = validate_hash(&mut validator, block_with_nonce(child_1, 2));
+        let child_3b = validate_hash(&mut validator, block(child_2b));
+
+        assert_eq!(validator.common_ancestor(&child_2a, &child_3b), Some(child_1));
+        assert_eq!(validator.common_ancestor(&child_3b, &child_2a), Some(child_1));
+        assert_eq!(validator.common_ancestor(&child_3b, &child_2b), Some(child_2b));
+        assert_eq!(validator.common_ancestor(&child_2a, &child_2a), Some(child_2a));
+        assert_eq!(validator.common_ancestor(&child_2a, &Hash([0x11; 32])), None);
+
+        // Extend the "b" branch so that the genesis block gets archived
+        let child_4b = validate_hash(&mut validator, block(child_3b));
+        assert!(validator.archived_blocks.contains_key(&genesis));
+        assert_eq!(validator.common_ancestor(&child_4b, &genesis), Some(genesis));
+        assert_eq!(validator.common_ancestor(&child_4b, &child_1), Some(child_1));
+    }
+
+    #[test]
+    fn header_chain() {
+        let mut validator = validator();
+        assert_eq!(validator.header_tip(), None);
+
+        let genesis = genesis_block();
+        let child_1 = block(genesis.id());
+        let child_2a = block_with_nonce(child_1.id(), 1);
+        let child_2b = block_with_nonce(child_1.id(), 2);
+        let child_3b = block(child_2b.id());
+
+        // Headers received out of order are orphans
+        let result = validator.handle_header(child_1.header.clone());
+        assert!(matches!(result, ValidationResult::OrphanHeader(_)), "Got {:?}", result);
+
+        for block in [&genesis, &child_1, &child_2a, &child_2b] {
+            let result = validator.handle_header(block.header.clone());
+            assert!(matches!(result, ValidationResult::Valid(h) if h == block.id()), "Got {:?}", result);
+        }
+        // The first-seen header wins at equal height
+        assert_eq!(validator.header_tip(), Some(child_2a.id()));
+
+        let mut bad_difficulty = block(child_2b.id());
+        bad_difficulty.header.bits = 0x1f00ffff;
+        let result = validator.handle_header(bad_difficulty.header);
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+
+        let mut too_old = block(child_2b.id());
+        too_old.header.time = child_1.header.time;
+        let result = validator.handle_header(too_old.header);
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+
+        assert!(matches!(validator.handle_header(child_3b.header.clone()), ValidationResult::Valid(_)));
+        assert_eq!(validator.header_tip(), Some(child_3b.id()));
+
+        // The block tree is unaffected until the bodies arrive, at which point
+        // they get fully validated.
+        assert_eq!(validator.tip, None);
+        validate_hash(&mut validator, genesis);
+        let child_1 = validate_hash(&mut validator, child_1);
+        assert_eq!(validator.tip, Some(child_1));
+        assert_eq!(validator.header_tip(), Some(child_3b.id()));
+        // Headers aren't kept separately once their blocks are stored
+        assert!(!validator.headers.contains_key(&child_1));
+        assert_eq!(validator.headers.len(), 3);
+
+        // Once the "b" branch is archived, the "a" header is pruned
+        validator.max_active_height = 1;
+        validate_hash(&mut validator, child_2b);
+        validate_hash(&mut validator, child_3b.clone());
+        assert!(validator.archived_blocks.contains_key(&child_3b.header.prev_block_hash));
+        assert!(validator.headers.is_empty());
+        assert!(validator.header_heights.is_empty());
+        assert_eq!(validator.header_tip(), Some(child_3b.id()));
+    }
+
+    #[test]
+    fn best_chain_bytes_test() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+        assert_eq!(validator.best_chain_bytes(), 0);
+
+        let genesis = genesis_block();
+        let mut expected = genesis.serialized_size();
+        let mut tip = validate_hash(&mut validator, genesis);
+        assert_eq!(validator.best_chain_bytes(), expected as u64);
+
+        // A side branch doesn't count
+        let side = block_with_transactions(tip, vec![coinbase_transaction()]);
+        let side = validate_hash(&mut validator, side);
+
+        for i in 0..5 {
+            let mut transactions = vec![coinbase_transaction()];
+            for j in 0..i {
+                transactions.push(transaction(&[(Hash([j as u8 + 1; 32]), i)], &[5000]));
+            }
+            let child = block_with_transactions(tip, transactions);
+            expected += child.serialized_size();
+            tip = validate_hash(&mut validator, child);
+        }
+        assert_eq!(validator.tip, Some(tip));
+        assert!(!validator.archived_blocks.is_empty());
+        assert!(!validator.active_blocks.contains_key(&side));
+        assert_eq!(validator.best_chain_bytes(), expected as u64);
+    }
+
+    #[test]
+    fn version_bits() {
+        let block_481829 = parse_blockfile(&read_testdata("block_481829.dat")).unwrap().pop().unwrap();
+        assert_eq!(block_481829.header.version, 0x20000002);
+        // Signaling for segwit, which used bit 1
+        assert_eq!(signaled_bits(&block_481829.header), 0x2);
+        let mut header = block_481829.header.clone();
+        header.version = 4;
+        assert_eq!(signaled_bits(&header), 0);
+        header.version = 0x60000002;
+        assert_eq!(signaled_bits(&header), 0);
+
+        let mut validator = validator();
+        validator.config.min_versions = ValidatorConfig::for_network(&Network::RegTest).min_versions;
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let mut child = block(genesis);
+        child.header.version = block_481829.header.version;
+        let child = validate_hash(&mut validator, child);
+        let mut grandchild = block(child);
+        grandchild.header.version = 3;
+        assert!(matches!(validator.handle_block(grandchild), ValidationResult::Invalid(BlockValidationError::ObsoleteVersion { height: 2, minimum: 4, actual: 3 })));
+        let mut grandchild = block(child);
+        grandchild.header.version = 0xe0000000;
+        assert!(matches!(validator.handle_block(grandchild), ValidationResult::Invalid(BlockValidationError::ObsoleteVersion { .. })));
+    }
+
+    #[test]
+    fn handle_blocks() {
+        let mut validator = validator();
+        let genesis = genesis_block();
+        let mut blocks = vec![genesis];
+        for _ in 0..5 {
+            let parent = blocks.last().unwrap().id();
+            blocks.push(block(parent));
+        }
+        let ids = blocks.iter().map(|block| block.id()).collect::<Vec<Hash>>();
+        let results = validator.handle_blocks(blocks[..3].to_vec());
+        assert!(results.iter().zip(&ids).all(|(result, id)| matches!(result, ValidationResult::Valid(h) if h == id)), "Got {:?}", results);
+
+        // Parents are handled first even when they come later in the batch, and
+        // blocks with no known parent are orphans
+        let orphan = block(Hash([0x11; 32]));
+        let batch = vec![blocks[5].clone(), orphan, blocks[3].clone(), blocks[4].clone()];
+        let results = validator.handle_blocks(batch);
+        assert!(matches!(results[0], ValidationResult::Valid(h) if h == ids[5]), "Got {:?}", results);
+        assert!(matches!(results[1], ValidationResult::Orphan(_)), "Got {:?}", results);
+        assert!(matches!(results[2], ValidationResult::Valid(h) if h == ids[3]), "Got {:?}", results);
+        assert!(matches!(results[3], ValidationResult::Valid(h) if h == ids[4]), "Got {:?}", results);
+        assert_eq!(validator.best_chain(), ids);
+    }
+
+    #[test]
+    fn best_chain_test() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+        assert!(validator.best_chain().is_empty());
+
+        let mut chain = vec![validate_hash(&mut validator, genesis_block())];
+        // A side branch isn't included
+        validate_hash(&mut validator, block_with_nonce(chain[0], 1));
+        let n = 10;
+        for _ in 0..n {
+            let tip = *chain.last().unwrap();
+            chain.push(validate_hash(&mut validator, block(tip)));
+        }
+        assert!(!validator.archived_blocks.is_empty());
+        assert_eq!(validator.best_chain().len(), n + 1);
+        assert_eq!(validator.best_chain(), chain);
+    }
+
+    #[test]
+    fn confirmations() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+        let genesis = validate_hash(&mut validator, genesis_block());
+        assert_eq!(validator.confirmations(&genesis), Some(1));
+
+        let coinbase = coinbase_transaction();
+        let mut chain = vec![genesis, validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase.clone()]))];
+        let side = validate_hash(&mut validator, block_with_nonce(genesis, 1));
+        for _ in 0..5 {
+            let tip = *chain.last().unwrap();
+            chain.push(validate_hash(&mut validator, block(tip)));
+        }
+        assert!(validator.archived_blocks.contains_key(&chain[1]));
+        for (height, hash) in chain.iter().enumerate() {
+            assert_eq!(validator.confirmations(hash), Some(chain.len() - height));
+        }
+        assert_eq!(validator.confirmations(&side), None);
+        assert_eq!(validator.confirmations(&Hash([0x11; 32])), None);
+
+        // A side branch that is still active
+        let active_side = validate_hash(&mut validator, block_with_nonce(chain[5], 1));
+        assert_eq!(validator.confirmations(&active_side), None);
+        assert_eq!(validator.confirmations(&chain[5]), Some(2));
+
+        assert_eq!(validator.outpoint_confirmations(&coinbase.txid(), 0), Some(6));
+        assert_eq!(validator.outpoint_confirmations(&coinbase.txid(), 1), None);
+        let tip = *chain.last().unwrap();
+        let spender = transaction(&[(coinbase.txid(), 0)], &[5000]);
+        let tip = validate_hash(&mut validator, block_with_transactions(tip, vec![coinbase_transaction(), spender.clone()]));
+        assert_eq!(validator.outpoint_confirmations(&coinbase.txid(), 0), None);
+        assert_eq!(validator.outpoint_confirmations(&spender.txid(), 0), Some(1));
+        assert_eq!(validator.confirmations(&tip), Some(1));
+    }
+
+    #[test]
+    fn utxo_snapshot() {
+        let mut validator = validator();
+        validator.max_active_height = 2;
+        let mut headers = vec![genesis_block().header];
+        for _ in 0..3 {
+            let header = block(headers.last().unwrap().id()).header;
+            headers.push(header);
+        }
+        for header in &headers {
+            assert!(matches!(validator.handle_header(header.clone()), ValidationResult::Valid(_)));
+        }
+        let base = headers[3].id();
+
+        let output = TransactionOutput { value: 5000, lock_script: vec![0x51] };
+        let duplicate = transaction(&[(Hash([0x33; 32]), 0)], &[5000]);
+        let snapshot = vec![
+            (Hash([0x11; 32]), 0, output.clone(), 1),
+            (Hash([0x22; 32]), 1, output.clone(), 3),
+            (duplicate.txid(), 0, output.clone(), 2),
+        ];
+        match validator.load_utxo_snapshot(2, base, snapshot.clone().into_iter()) {
+            Err(BlockValidationError::SnapshotHeightMismatch { block, expected: 3, actual: 2 }) => assert_eq!(block, base),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(matches!(validator.load_utxo_snapshot(3, Hash([0x44; 32]), snapshot.clone().into_iter()), Err(BlockValidationError::UnknownBlock { .. })));
+        validator.load_utxo_snapshot(3, base, snapshot.into_iter()).unwrap();
+        assert_eq!(validator.best_chain(), vec![base]);
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let observed_clone = Arc::clone(&observed);
+        validator.set_time_observer(move |height, _, median_time_past| {
+            observed_clone.lock().unwrap().push((height, median_time_past));
+        });
+
+        // Blocks can't go at or below the snapshot's height
+        match validator.handle_block(genesis_block()) {
+            ValidationResult::Invalid(BlockValidationError::BelowArchivedHeight { height: 0, archived_height: 3, .. }) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(validator.best_chain(), vec![base]);
+        assert_eq!(validator.outpoint_confirmations(&Hash([0x11; 32]), 0), Some(3));
+        assert_eq!(validator.outpoint_confirmations(&Hash([0x22; 32]), 1), Some(1));
+
+        // The next block is checked against the snapshot's UTXOs
+        let result = validator.handle_block(block_with_transactions(base, vec![coinbase_transaction(), duplicate]));
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::OverwritesUnspentTransaction { .. })));
+        // The median time past covers the headers before the snapshot's base block
+        assert_eq!(*observed.lock().unwrap(), vec![(4, headers[2].time)]);
+        let spender = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        let mut chain = vec![base, validate_hash(&mut validator, block_with_transactions(base, vec![coinbase_transaction(), spender.clone()]))];
+        assert!(!validator.is_unspent(&Hash([0x11; 32]), 0));
+        assert!(validator.is_unspent(&Hash([0x22; 32]), 1));
+        assert_eq!(validator.outpoint_confirmations(&spender.txid(), 0), Some(1));
+
+        // Only the first block can attach to the snapshot block, after which it
+        // has an active child like any other archived block
+        assert!(matches!(validator.handle_block(block_with_nonce(base, 1)), ValidationResult::Invalid(BlockValidationError::ArchivedParent { .. })));
+        for _ in 0..3 {
+            let tip = *chain.last().unwrap();
+            chain.push(validate_hash(&mut validator, block(tip)));
+        }
+        assert_eq!(validator.best_chain(), chain);
+        assert_eq!(validator.active_blocks.len(), 2);
+        assert!(validator.archived_blocks.contains_key(&chain[2]));
+        assert!(!validator.is_unspent(&Hash([0x11; 32]), 0));
+        assert!(validator.is_unspent(&spender.txid(), 0));
+    }
+
+    #[test]
+    fn build_locator_test() {
+        let mut validator = validator();
+        assert!(validator.build_locator().is_empty());
+
+        let mut chain = vec![validate_hash(&mut validator, genesis_block())];
+        assert_eq!(validator.build_locator(), vec![chain[0]]);
+
+        for _ in 1..100 {
+            let tip = *chain.last().unwrap();
+            chain.push(validate_hash(&mut validator, block(tip)));
+        }
+        let heights = [99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 88, 84, 76, 60, 28, 0];
+        let expected = heights.iter().map(|h| chain[*h]).collect::<Vec<Hash>>();
+        assert_eq!(validator.build_locator(), expected);
+    }
+
+    #[test]
+    fn coinbase_script_length() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        for (length, valid) in [(1, false), (2, true), (50, true), (100, true), (101, false)] {
+            let mut coinbase = coinbase_transaction();
+            coinbase.inputs[0].unlock_script = vec![0x51; length];
+            let result = validator.handle_block(block_with_transactions(genesis, vec![coinbase]));
+            match result {
+                ValidationResult::Valid(_) => assert!(valid, "Length {} was valid", length),
+                ValidationResult::Invalid(BlockValidationError::BadCoinbaseLength { length: l }) => assert!(!valid && l == length),
+                result => panic!("Length {} got {:?}", length, result),
+            }
+        }
+    }
+
+    #[test]
+    fn locktime_finality() {
+        let mut tx = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        tx.inputs[0].sequence = 0xfffffffe;
+
+        tx.locktime = 0;
+        assert!(is_final(&tx, 0, 0));
+
+        // Height-based locktime
+        tx.locktime = 100;
+        assert!(!is_final(&tx, 99, 0));
+        assert!(!is_final(&tx, 100, 0));
+        assert!(is_final(&tx, 101, 0));
+        assert!(!is_final(&tx, 100, LOCKTIME_THRESHOLD + 1000));
+
+        // Time-based locktime
+        tx.locktime = LOCKTIME_THRESHOLD + 100;
+        assert!(!is_final(&tx, 1_000_000, LOCKTIME_THRESHOLD + 99));
+        assert!(!is_final(&tx, 1_000_000, LOCKTIME_THRESHOLD + 100));
+        assert!(is_final(&tx, 0, LOCKTIME_THRESHOLD + 101));
+
+        // Final sequence numbers on all inputs disable the locktime
+        tx.inputs[0].sequence = SEQUENCE_FINAL;
+        assert!(is_final(&tx, 0, 0));
+        tx.inputs.push(tx.inputs[0].clone());
+        tx.inputs[1].sequence = 0;
+        assert!(!is_final(&tx, 0, 0));
+    }
+
+    #[test]
+    fn non_final_transactions() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let mut spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        spend.inputs[0].sequence = 0;
+        spend.locktime = 1;
+        let result = validator.handle_block(block_with_transactions(genesis, vec![coinbase_transaction(), spend.clone()]));
+        match result {
+            ValidationResult::Invalid(BlockValidationError::NonFinalTransaction { txid, locktime: 1 }) => assert_eq!(txid, spend.txid()),
+            result => panic!("Got {:?}", result),
+        }
+
+        // The coinbase is exempt
+        let mut coinbase = coinbase_transaction();
+        coinbase.inputs[0].sequence = 0;
+        coinbase.locktime = 1;
+        let child = validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase]));
+
+        // At height 2 the locktime has been reached
+        validate_hash(&mut validator, block_with_transactions(child, vec![coinbase_transaction(), spend]));
+    }
+
+    #[test]
+    fn median_time_past_locktime() {
+        let mut validator = validator();
+        validator.config.csv_height = Some(2);
+        let mut genesis = genesis_block();
+        genesis.header.time = LOCKTIME_THRESHOLD + 100;
+        let genesis = validate_hash(&mut validator, genesis);
+
+        // Before CSV the block's own timestamp is used, which is past the locktime
+        let mut spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        spend.inputs[0].sequence = 0;
+        spend.locktime = LOCKTIME_THRESHOLD + 150;
+        let mut child = block_with_transactions(genesis, vec![coinbase_transaction(), spend]);
+        child.header.time = LOCKTIME_THRESHOLD + 200;
+        let child = validate_hash(&mut validator, child);
+
+        // Afterwards it's the median time past, which isn't
+        let mut spend = transaction(&[(Hash([0x22; 32]), 0)], &[5000]);
+        spend.inputs[0].sequence = 0;
+        spend.locktime = LOCKTIME_THRESHOLD + 250;
+        let mut grandchild = block_with_transactions(child, vec![coinbase_transaction(), spend]);
+        grandchild.header.time = LOCKTIME_THRESHOLD + 300;
+        let result = validator.handle_block(grandchild);
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::NonFinalTransaction { .. })), "Got {:?}", result);
+    }
+
+    #[test]
+    fn sigop_limit() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        // 20,000 legacy sigops is exactly at the limit
+        let mut spend = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        spend.outputs[0].lock_script = vec![0xac; 19_980];
+        spend.outputs[0].lock_script.push(0xae);
+        validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase_transaction(), spend.clone()]));
+
+        spend.inputs[0].unlock_script.push(0xad);
+        let result = validator.handle_block(block_with_transactions(genesis, vec![coinbase_transaction(), spend]));
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+    }
+
+    #[test]
+    fn p2sh_sigop_limit() {
+        let mut validator = validator();
+        validator.config.bip16_height = Some(2);
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        // A 2-of-3 multisig redeem script counts as 3 sigops when spent via P2SH.
+        // The result of the multisig is dropped so that the empty signatures pass.
+        let mut redeem_script = vec![0x52];
+        for _ in 0..3 {
+            Opcode::PushArray(vec![0x02; 33]).serialize_le(&mut redeem_script);
+        }
+        redeem_script.extend([0x53, 0xae, 0x75, 0x51]);
+        let mut funding = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        funding.outputs[0].lock_script = crate::script::p2sh_script(&crate::hash::hash160(&redeem_script));
+        let funding_txid = funding.txid();
+        let child_1 = validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase_transaction(), funding]));
+
+        // Together with the legacy sigops in the output this is exactly at the limit
+        let mut spend = transaction(&[(funding_txid, 0)], &[5000]);
+        spend.inputs[0].unlock_script = vec![0x00, 0x00, 0x00];
+        Opcode::PushArray(redeem_script).serialize_le(&mut spend.inputs[0].unlock_script);
+        spend.outputs[0].lock_script = vec![0xac; 19_997];
+        validate_hash(&mut validator, block_with_transactions(child_1, vec![coinbase_transaction(), spend.clone()]));
+
+        spend.outputs[0].lock_script.push(0xac);
+        match validator.handle_block(block_with_transactions(child_1, vec![coinbase_transaction(), spend.clone()])) {
+            ValidationResult::Invalid(BlockValidationError::TooManySigops { sigops: 20_001, .. }) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Before BIP16 activates the redeem script's sigops aren't counted either
+        validator.config.bip16_height = Some(3);
+        validate_hash(&mut validator, block_with_transactions(child_1, vec![coinbase_transaction(), spend.clone()]));
+        validator.config.bip16_height = Some(2);
+
+        // The redeem script's sigops aren't counted if the spent output isn't P2SH
+        spend.inputs[0].txid = Hash([0x22; 32]);
+        validate_hash(&mut validator, block_with_transactions(child_1, vec![coinbase_transaction(), spend]));
+    }
+
+    #[test]
+    fn witness_sigop_limit() {
+        // Witness sigops cost a quarter of legacy ones: 19,000 legacy sigops in the
+        // output plus 4,000 witness sigops is exactly at the limit
+        let p2wsh_witness_script = vec![0xac; 1_999];
+        let mut wrapped_witness_script = vec![0xac; 2_000];
+        let p2wpkh_lock = crate::script::p2wpkh_script(&[0x33; 20]);
+        let p2wsh_lock = crate::script::p2wsh_script(&Sha256::digest(&p2wsh_witness_script).into());
+        let wrapped_program = crate::script::p2wsh_script(&Sha256::digest(&wrapped_witness_script).into());
+        let wrapped_lock = crate::script::p2sh_script(&crate::hash::hash160(&wrapped_program));
+        let prevout_script = |txid: &Hash, _| match txid.0[0] {
+            0x11 => Some(p2wpkh_lock.as_slice()),
+            0x22 => Some(p2wsh_lock.as_slice()),
+            0x33 => Some(wrapped_lock.as_slice()),
+            _ => None,
+        };
+
+        let mut spend = transaction(&[(Hash([0x11; 32]), 0), (Hash([0x22; 32]), 0), (Hash([0x33; 32]), 0)], &[5000]);
+        spend.flags = TransactionFlags::WITNESS;
+        spend.inputs[0].witness_stuff = vec![vec![0x30], vec![0x02; 33]];
+        spend.inputs[1].witness_stuff = vec![p2wsh_witness_script];
+        Opcode::PushArray(wrapped_program).serialize_le(&mut spend.inputs[2].unlock_script);
+        spend.inputs[2].witness_stuff = vec![wrapped_witness_script.clone()];
+        spend.outputs[0].lock_script = vec![0xac; 19_000];
+        // Commits to the witness data, which is checked after the sigops
+        let commit = |spend: Transaction| {
+            let mut coinbase = coinbase_transaction();
+            coinbase.flags = TransactionFlags::WITNESS;
+            coinbase.inputs[0].witness_stuff = vec![vec![0; 32]];
+            let block = block_with_transactions(Hash::zero(), vec![coinbase.clone(), spend.clone()]);
+            let witness_root = block.computed_witness_merkle_root().reverse();
+            let mut commitment = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+            commitment.extend(sha256d(&[witness_root.0, [0; 32]].concat()));
+            coinbase.outputs.push(TransactionOutput { value: 0, lock_script: commitment });
+            block_with_transactions(Hash::zero(), vec![coinbase, spend])
+        };
+        let at_limit = commit(spend.clone());
+        validate_transactions(&at_limit, true, prevout_script).unwrap();
+
+        wrapped_witness_script.push(0xac);
+        spend.inputs[2].witness_stuff = vec![wrapped_witness_script];
+        let over_limit = commit(spend);
+        match validate_transactions(&over_limit, true, prevout_script) {
+            Err(BlockValidationError::TooManySigops { sigops: 23_001, cost: 80_001, .. }) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn assume_valid() {
+        // Spends the coinbase of the given block with an unlock script that fails
+        let bad_spend = |block: &Block| {
+            let mut spend = transaction(&[(block.transactions[0].txid(), 0)], &[5000]);
+            spend.inputs[0].unlock_script = vec![0x6a];
+            spend
+        };
+        let is_script_failure = |result: ValidationResult| matches!(result,
+            ValidationResult::Invalid(BlockValidationError::ScriptVerificationFailed { reason, .. }) if reason == "Encountered RETURN opcode");
+
+        let genesis = genesis_block();
+        let child_1 = block(genesis.id());
+        let child_2 = block_with_transactions(child_1.id(), vec![coinbase_transaction(), bad_spend(&child_1)]);
+        let child_3 = block(child_2.id());
+        let child_4_bad = block_with_transactions(child_3.id(), vec![coinbase_transaction(), bad_spend(&child_3)]);
+        let mut good_spend = bad_spend(&child_3);
+        good_spend.inputs[0].unlock_script = vec![0x51];
+        let child_4_good = block_with_transactions(child_3.id(), vec![coinbase_transaction(), good_spend]);
+        let fork_2_bad = block_with_transactions(child_1.id(), vec![coinbase_transaction(), bad_spend(&child_1)]);
+
+        // Without assume-valid the scripts are verified
+        let mut unassuming = validator();
+        validate_hash(&mut unassuming, genesis.clone());
+        validate_hash(&mut unassuming, child_1.clone());
+        assert!(is_script_failure(unassuming.handle_block(child_2.clone())));
+
+        let mut validator = validator();
+        validator.config.assume_valid = Some((child_3.id(), 3));
+        for block in [&genesis, &child_1, &child_2, &child_3, &fork_2_bad] {
+            assert!(matches!(validator.handle_header(block.header.clone()), ValidationResult::Valid(_)));
+        }
+        validate_hash(&mut validator, genesis);
+        validate_hash(&mut validator, child_1);
+        // Not an ancestor of the assume-valid block, so the scripts are verified
+        assert!(is_script_failure(validator.handle_block(fork_2_bad)));
+        validate_hash(&mut validator, child_2);
+        validate_hash(&mut validator, child_3);
+        // Above the assume-valid block, so the scripts are verified
+        assert!(is_script_failure(validator.handle_block(child_4_bad)));
+        validate_hash(&mut validator, child_4_good);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let genesis = genesis_block();
+        let child_1a = block_with_nonce(genesis.id(), 1);
+        let child_1b = block_with_nonce(genesis.id(), 2);
+        let child_2b = block(child_1b.id());
+
+        let mut config = ValidatorConfig::default();
+        config.checkpoints.insert(1, child_1b.id());
+        let mut validator = BlockValidator::with_config(config);
+        validator.skip_pow_check(true);
+
+        validate_hash(&mut validator, genesis);
+        let result = validator.handle_block(child_1a.clone());
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+        let result = validator.handle_header(child_1a.header);
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+        validate_hash(&mut validator, child_1b);
+        validate_hash(&mut validator, child_2b);
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let mut header = parse_blockfile(&read_testdata("block_265458.dat")).unwrap().pop().unwrap().header;
+        assert!(check_pow(&header).is_ok());
+
+        header.nonce += 1;
+        let result = check_pow(&header);
+        assert!(matches!(result, Err(BlockValidationError::PowTooHigh { hash, .. }) if hash == header.id()), "Got {:?}", result);
+
+        header.bits = 0x01fedcba;
+        assert!(matches!(check_pow(&header), Err(BlockValidationError::InvalidTarget { bits: 0x01fedcba })));
+    }
+
+    #[test]
+    fn network_checkpoints() {
+        let block_0 = parse_blockfile(&read_testdata("block_0.dat")).unwrap().pop().unwrap();
+        let config = ValidatorConfig::for_network(&Network::MainNet);
+        assert_eq!(config.checkpoints.get(&0), Some(&block_0.id()));
+        assert_eq!(config.bip16_height, Some(173805));
+        assert_eq!(config.bip34_height, Some(227931));
+        assert_eq!(config.csv_height, Some(419328));
+
+        let mut validator = BlockValidator::with_config(config);
+        validate_hash(&mut validator, block_0.clone());
+
+        let mut validator = BlockValidator::with_config(ValidatorConfig::for_network(&Network::TestNet3));
+        let result = validator.handle_block(block_0);
+        assert!(matches!(result, ValidationResult::Invalid(_)), "Got {:?}", result);
+    }
+
+    #[test]
+    fn error_variants() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let expect_error = |validator: &mut BlockValidator, block: Block| match validator.handle_block(block) {
+            ValidationResult::Invalid(e) => e,
+            result => panic!("Got {:?} but expected ValidationResult::Invalid", result),
+        };
+
+        validator.config.min_versions = vec![(1, 2)];
+        let mut child = block(genesis);
+        child.header.version = 1;
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::ObsoleteVersion { height: 1, minimum: 2, actual: 1 }));
+        validator.config.min_versions.clear();
+
+        let mut child = block(genesis);
+        child.header.merkle_root = Hash([0x11; 32]);
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::MerkleMismatch { header, .. } if header == Hash([0x11; 32])));
+
+        let mut child = block(genesis);
+        child.header.time = u32::MAX;
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::TimestampTooFarAhead { timestamp: u32::MAX, .. }));
+
+        let mut child = block(genesis);
+        child.header.time = 0;
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::TimestampNotAfterParent { timestamp: 0, .. }));
+
+        let mut child = block(genesis);
+        child.header.bits = 0x1f7fffff;
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::PrematureDifficultyChange { height: 1 }));
+
+        let mut child = block(genesis);
+        child.header.bits = 0x20ffffff;
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::InvalidTarget { bits: 0x20ffffff }));
+
+        validator.skip_pow_check(false);
+        let mut child = block(genesis);
+        child.header.bits = 0x03000001;
+        assert!(matches!(expect_error(&mut validator, child), BlockValidationError::PowTooHigh { .. }));
+
+        let error = BlockValidationError::PrematureDifficultyChange { height: 2017 };
+        assert_eq!(error.to_string(), "Block changed the difficulty threshold prematurely; height 2017 is 1 mod 2016");
+    }
+
+    #[test]
+    fn empty_block() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let empty = block_with_transactions(genesis, vec![]);
+        assert_eq!(empty.header.merkle_root, Hash::zero());
+        match validator.handle_block(empty) {
+            ValidationResult::Invalid(e @ BlockValidationError::NoTransactions) => assert_eq!(e.to_string(), "Block has no transactions"),
+            result => panic!("Got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn mined_blocks() {
+        let mut validator = BlockValidator::new();
+        let mut tip = Hash::zero();
+        for _ in 0..10 {
+            let mut child = block_with_transactions(tip, vec![coinbase_transaction()]);
+            assert!(child.header.mine());
+            assert!(child.id() < child.header.target().unwrap());
+            tip = validate_hash(&mut validator, child);
+        }
+
+        let mut impossible = block(tip);
+        impossible.header.bits = 0x03000001;
+        impossible.header.nonce = u32::MAX - 100;
+        assert!(!impossible.header.mine());
+        assert_eq!(impossible.header.nonce, u32::MAX);
+
+        impossible.header.bits = 0x20ffffff;
+        assert!(!impossible.header.mine());
+    }
+
+    #[test]
+    fn future_block_time() {
+        let mut validator = validator();
+        validator.set_clock(|| 1_000_000);
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let mut child = block(genesis);
+        child.header.time = 1_000_000 + 3 * 60 * 60;
+        let result = validator.handle_block(child.clone());
+        match result {
+            ValidationResult::Invalid(e @ BlockValidationError::TimestampTooFarAhead { now: 1_000_000, max_future_block_time: 7200, .. }) => {
+                assert_eq!(e.to_string(), "Block timestamp 1010800 was more than 7200 seconds in the future from current timestamp 1000000");
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let config = ValidatorConfig {
+            max_future_block_time: 4 * 60 * 60,
+            ..ValidatorConfig::default()
+        };
+        let mut validator = BlockValidator::with_config(config);
+        validator.skip_pow_check(true);
+        validator.set_clock(|| 1_000_000);
+        child.header.prev_block_hash = validate_hash(&mut validator, genesis_block());
+        validate_hash(&mut validator, child.clone());
+
+        // An unlimited tolerance accepts any timestamp rather than overflowing
+        let config = ValidatorConfig {
+            max_future_block_time: u64::MAX,
+            ..ValidatorConfig::default()
+        };
+        let mut validator = BlockValidator::with_config(config);
+        validator.skip_pow_check(true);
+        validator.set_clock(|| 1_000_000);
+        child.header.prev_block_hash = validate_hash(&mut validator, genesis_block());
+        child.header.time = u32::MAX;
+        validate_hash(&mut validator, child);
+    }
+
+    #[test]
+    fn future_block_time_boundary() {
+        struct FixedClock(u64);
+        impl Clock for FixedClock {
+            fn now(&self) -> u64 {
+                self.0
+            }
+        }
+
+        let mut validator = validator();
+        validator.set_clock(FixedClock(2_000_000));
+        let genesis = validate_hash(&mut validator, genesis_block());
+
+        let mut child = block(genesis);
+        child.header.time = 2_000_000 + 2 * 60 * 60 + 1;
+        let result = validator.handle_block(child);
+        assert!(matches!(result, ValidationResult::Invalid(BlockValidationError::TimestampTooFarAhead { .. })), "Got {:?}", result);
+
+        let mut child = block(genesis);
+        child.header.time = 2_000_000 + 2 * 60 * 60;
+        validate_hash(&mut validator, child);
+
+        // The system clock is somewhere after this was written
+        assert!(SystemClock.now() > 1_500_000_000);
+    }
+
+    #[test]
+    fn active_chain_tips_test() {
+        let mut validator = validator();
+        validator.max_active_height = 3;
+        assert!(validator.active_chain_tips().is_empty());
+
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let child_1 = validate_hash(&mut validator, block(genesis));
+        let child_2 = validate_hash(&mut validator, block(child_1));
+        let child_3a = validate_hash(&mut validator, block_with_nonce(child_2, 1));
+        let child_3b = validate_hash(&mut validator, block_with_nonce(child_2, 2));
+        let child_4b = validate_hash(&mut validator, block(child_3b));
+
+        // The genesis block and child_1 got archived
+        assert_eq!(validator.active_chain_tips(), vec![(child_4b, 4), (child_3a, 3)]);
+        assert_eq!(validator.ancestry(&child_4b), vec![child_4b, child_3b, child_2, child_1]);
+        assert_eq!(validator.ancestry(&child_3a), vec![child_3a, child_2, child_1]);
+        assert!(validator.ancestry(&child_1).is_empty());
+        assert!(validator.ancestry(&genesis).is_empty());
+        assert!(validator.ancestry(&Hash([0x11; 32])).is_empty());
+    }
+
+    #[test]
+    fn rollback_test() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let child_1 = validate_hash(&mut validator, block(genesis));
+        let block_2a = block_with_nonce(child_1, 1);
+        let child_2a = validate_hash(&mut validator, block_2a.clone());
+        let child_3a = validate_hash(&mut validator, block(child_2a));
+        assert_eq!(validator.build_locator()[0], child_3a);
+
+        validator.rollback_to(&child_1).unwrap();
+        assert_eq!(validator.build_locator(), vec![child_1, genesis]);
+        assert_eq!(validator.active_chain_tips(), vec![(child_1, 1)]);
+        assert!(matches!(validator.handle_block(block(child_3a)), ValidationResult::Orphan(_)));
+
+        // A different branch can be added, and the rolled back blocks can be re-added
+        let child_2b = validate_hash(&mut validator, block_with_nonce(child_1, 2));
+        let child_3b = validate_hash(&mut validator, block(child_2b));
+        assert_eq!(validator.build_locator(), vec![child_3b, child_2b, child_1, genesis]);
+        validate_hash(&mut validator, block_2a);
+        assert_eq!(validator.build_locator()[0], child_3b);
+
+        // Rolling back to a block on a shorter branch keeps the longer branch as the tip
+        validator.rollback_to(&child_2a).unwrap();
+        assert_eq!(validator.build_locator()[0], child_3b);
+
+        match validator.rollback_to(&Hash([0x11; 32])) {
+            Err(BlockValidationError::UnknownBlock { block }) => assert_eq!(block, Hash([0x11; 32])),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        validator.max_active_height = 3;
+        validate_hash(&mut validator, block(child_3b));
+        match validator.rollback_to(&genesis) {
+            Err(BlockValidationError::RollbackIntoArchive { block, height: 0 }) => assert_eq!(block, genesis),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn utxo_tracking() {
+        let mut validator = validator();
+        let genesis = validate_hash(&mut validator, genesis_block());
+        let funding = transaction(&[(Hash([0x11; 32]), 0)], &[5000]);
+        let funding_txid = funding.txid();
+        let child_1 = validate_hash(&mut validator, block_with_transactions(genesis, vec![coinbase_transaction(), funding]));
+        assert!(validator.is_unspent(&funding_txid, 0));
+        assert_eq!(validator.get_utxo(&funding_txid, 0).unwrap().value, 5000);
+        assert!(!validator.is_unspent(&funding_txid, 1));
+
+        // A spend on a competing branch that isn't the best chain doesn't count
+        let child_2a = validate_hash(&mut validator, block(child_1));
+        let spend = transaction(&[(funding_txid, 0)], &[5000]);
+        validate_hash(&mut validator, block_with_transactions(child_1, vec![coinbase_transaction(), spend.clone()]));
+        assert!(validator.is_unspent(&funding_txid, 0));
+
+        validate_hash(&mut validator, block_with_transactions(child_2a, vec![coinbase_transaction(), spend.clone()]));
+        assert!(!validator.is_unspent(&funding_txid, 0));
+        assert!(validator.is_unspent(&spend.txid(), 0));
+
+        // The answers stay the same once the blocks are archived
+        validator.max_active_height = 2;
+        let mut tip = validator.tip.unwrap();
+        for nonce in 0..3 {
+            tip = validate_hash(&mut validator, block_with_nonce(tip, nonce));
+        }
+        assert!(validator.archived_blocks.contains_key(&child_2a));
+        assert!(!validator.is_unspent(&funding_txid, 0));
+        assert!(validator.is_unspent(&spend.txid(), 0));
+    }
+
+    #[test]
+    fn most_work_tip() {
+        let mut validator = validator();
+        // The difficulty can't change until the first retarget, so competing genesis
+        // blocks are the simplest way to get branches with different work per block
+        let easy_genesis = genesis_block();
+        let easy_coinbase = easy_genesis.transactions[0].txid();
+        let mut easy_chain = vec![validate_hash(&mut validator, easy_genesis)];
+        for _ in 0..3 {
+            let tip = *easy_chain.last().unwrap();
+            easy_chain.push(validate_hash(&mut validator, block(tip)));
+        }
+        assert_eq!(validator.best_chain(), easy_chain);
+
+        let hard_block = |parent| {
+            let mut block = block(parent);
+            block.header.bits = 0x1f7fffff;
+            block
+        };
+        let hard_genesis = hard_block(Hash::zero());
+        assert_eq!(hard_genesis.header.work(), 512);
+        let hard_coinbase = hard_genesis.transactions[0].txid();
+        let hard = validate_hash(&mut validator, hard_genesis);
+
+        // The hard genesis block has more work than the whole easy chain, even
+        // though the easy chain is longer
+        assert_eq!(validator.best_chain(), vec![hard]);
+        assert_eq!(validator.active_chain_tips()[0].0, easy_chain[3]);
+        assert!(validator.is_unspent(&hard_coinbase, 0));
+        assert!(!validator.is_unspent(&easy_coinbase, 0));
+        assert!(validator.get_utxo(&easy_coinbase, 0).is_none());
+        assert_eq!(validator.confirmations(&hard), Some(1));
+        assert_eq!(validator.confirmations(&easy_chain[3]), None);
+
+        // Rolling back the easy chain leaves the hard chain as the tip
+        validator.rollback_to(&easy_chain[1]).unwrap();
+        assert_eq!(validator.best_chain(), vec![hard]);
+        // Archiving follows the best chain and prunes the longer easy chain
+        validator.max_active_height = 2;
+        let child = validate_hash(&mut validator, hard_block(hard));
+        let grandchild = validate_hash(&mut validator, hard_block(child));
+        assert!(validator.archived_blocks.contains_key(&hard));
+        assert_eq!(validator.best_chain(), vec![hard, child, grandchild]);
+        assert!(!validator.active_blocks.contains_key(&easy_chain[1]));
+    }
 }